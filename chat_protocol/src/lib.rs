@@ -0,0 +1,943 @@
+use std::fmt;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// The wire protocol that chat_server and chat_client speak, as a crate of its own so bots and bridges can speak it
+// too without depending on the whole server.  The server and the client both use it from here.
+//
+// This follows semver.  Anything that would change what's already on the wire, or break code that uses these types, is
+// a new major version.  New capabilities and notice kinds come often enough that the types for them are
+// non_exhaustive, so adding one is only a minor version.
+//
+// Every message on the wire is a single line ending in a newline.  TCP is a stream, not a series of messages, so two
+// quick writes can show up in one read and one long write can show up across two.  The newline is how the other side
+// finds where each message really ends.
+
+// Sent by the client before /user to ask for optional behavior.  The server ignores anything it doesn't recognize, so
+// newer clients can still talk to older servers.
+pub const CAPS_COMMAND: &str = "/caps";
+
+// The last line the server sends to someone it's throwing out, right before it closes the connection, so the client
+// can say why rather than just that the server went away.  Nothing else the server sends starts with a /.
+pub const KICKED_COMMAND: &str = "/kicked";
+
+// For clients that asked for notices in their handshake, everything the server says itself (rather than passing on
+// from someone in the room) is sent as this command, then the kind of notice, then the text.  Everyone else gets the
+// text on its own, usually after "*** ".
+pub const NOTICE_COMMAND: &str = "/notice";
+
+// The heartbeat (see heartbeat.rs in chat_server).  Either side can send PING_COMMAND on a line of its own at any
+// time, and the other answers right away with PONG_COMMAND.  The client always pings, while the server only pings
+// clients that asked for it in their handshake, since anything else wouldn't know to answer.
+pub const PING_COMMAND: &str = "/ping";
+pub const PONG_COMMAND: &str = "/pong";
+
+// Why the server threw us out, e.g. "You have been kicked by alice: spamming".  On the wire it's KICKED_COMMAND and
+// then the reason.
+#[derive(Clone, Debug)]
+pub struct Kicked {
+    pub reason: String,
+}
+
+impl Kicked {
+    pub fn parse(line: &str) -> Option<Kicked> {
+        let rest = line.strip_prefix(KICKED_COMMAND)?;
+        if !rest.is_empty() && !rest.starts_with(' ') {
+            return None;
+        }
+
+        Some(Kicked {
+            reason: String::from(rest.trim()),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        format!("{} {}", KICKED_COMMAND, self.reason)
+    }
+}
+
+// Everything a client can ask for in its handshake.  Every field is off unless the client asks.  Build one from
+// Capabilities::default() and turn on what you want.
+#[derive(Default, Clone, Debug)]
+#[non_exhaustive]
+pub struct Capabilities {
+    // For metered or very slow connections.  The server leaves out anything that isn't the conversation itself, which
+    // for now means the join/leave churn.
+    pub lite: bool,
+    // For people watching the chat live.  Nagle's algorithm is turned off and every message is written as soon as it
+    // arrives, at the cost of more (and smaller) packets.
+    pub nodelay: bool,
+    // For loggers and bridges that care about throughput, not latency.  Messages are held longer so they can go out
+    // in bigger batches.  If a client asks for both, nodelay wins.
+    pub bulk: bool,
+    // For clients that want to show server notices differently from chat, or hide them.  Notices are sent as
+    // NOTICE_COMMAND lines instead of plain text.
+    pub notices: bool,
+    // For clients that answer PING_COMMAND.  The server pings them every so often and hangs up on them once they stop
+    // answering.
+    pub heartbeat: bool,
+    // For clients that want to show when each message was said.  Messages from the room come as TIMESTAMP_COMMAND
+    // lines.
+    pub timestamps: bool,
+    // What the client's commands start with, as "prefix=!", for clients that would rather not use / (say, because
+    // they're bridged to a network where / means something else).  Left out, the server's default is used.
+    pub prefix: Option<char>,
+    // What the client calls itself, as "client=chat_client/0.1.0", so people looking at their /sessions can tell their
+    // connections apart
+    pub client: Option<String>,
+    // For clients that show who's in the room.  The server sends a ROSTER_COMMAND line once they're in, and again
+    // whenever someone comes, goes or changes their name.
+    pub roster: bool,
+    // For clients that want everything in the same order whichever linked server they're on, or that merge what
+    // several servers send them.  Messages from the room come as TIMESTAMP_COMMAND lines carrying the hybrid logical
+    // clock (see Clocked) rather than the time, whether or not they asked for timestamps.
+    pub clock: bool,
+    // For clients that can take files (see FileMessage).  Nobody can offer a file to a client that didn't ask, since it
+    // wouldn't know what to make of one.
+    pub files: bool,
+    // For clients that show direct messages apart from the room.  They come as DIRECT_COMMAND lines (see Direct)
+    // instead of notices.
+    pub direct: bool,
+}
+
+// A prefix can be any single character that couldn't start a word or be mistaken for the gap between words
+pub fn is_valid_prefix(prefix: char) -> bool {
+    !prefix.is_alphanumeric() && !prefix.is_whitespace()
+}
+
+impl Capabilities {
+    // Takes the part of a /caps line after the command, e.g. "lite"
+    pub fn parse(list: &str) -> Capabilities {
+        let mut capabilities = Capabilities::default();
+        for name in list.split_whitespace() {
+            match name {
+                "lite" => capabilities.lite = true,
+                "nodelay" => capabilities.nodelay = true,
+                "bulk" => capabilities.bulk = true,
+                "notices" => capabilities.notices = true,
+                "heartbeat" => capabilities.heartbeat = true,
+                "timestamps" => capabilities.timestamps = true,
+                "roster" => capabilities.roster = true,
+                "clock" => capabilities.clock = true,
+                "files" => capabilities.files = true,
+                "direct" => capabilities.direct = true,
+                // Anything that isn't exactly one character is as good as not asking
+                _ => {
+                    if let Some(prefix) = name.strip_prefix("prefix=") {
+                        capabilities.prefix = prefix.parse().ok();
+                    }
+                    if let Some(client) = name.strip_prefix("client=") {
+                        capabilities.client = Some(String::from(client));
+                    }
+                }
+            }
+        }
+
+        capabilities
+    }
+
+    // The opposite of parse, used by the client to build its /caps line
+    pub fn to_command(&self) -> Option<String> {
+        let mut names = Vec::new();
+        if self.lite {
+            names.push("lite");
+        }
+        if self.nodelay {
+            names.push("nodelay");
+        }
+        if self.bulk {
+            names.push("bulk");
+        }
+        if self.notices {
+            names.push("notices");
+        }
+        if self.heartbeat {
+            names.push("heartbeat");
+        }
+        if self.timestamps {
+            names.push("timestamps");
+        }
+        if self.roster {
+            names.push("roster");
+        }
+        if self.clock {
+            names.push("clock");
+        }
+        if self.files {
+            names.push("files");
+        }
+        if self.direct {
+            names.push("direct");
+        }
+        let prefix = self.prefix.map(|prefix| format!("prefix={}", prefix));
+        if let Some(prefix) = &prefix {
+            names.push(prefix);
+        }
+        let client = self
+            .client
+            .as_ref()
+            .map(|client| format!("client={}", client));
+        if let Some(client) = &client {
+            names.push(client);
+        }
+
+        if names.is_empty() {
+            None
+        } else {
+            Some(format!("{} {}", CAPS_COMMAND, names.join(" ")))
+        }
+    }
+}
+
+// What a notice is about, so a client can pick how loudly to show each one
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum NoticeKind {
+    // People coming, going and changing names
+    Presence,
+    // Kicks, bans and mutes
+    Moderation,
+    // The message of the day
+    Motd,
+    // Something we asked for went wrong, or wasn't allowed
+    Error,
+    // Everything else, like replies to commands and the daily digest
+    Info,
+}
+
+impl NoticeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NoticeKind::Presence => "presence",
+            NoticeKind::Moderation => "moderation",
+            NoticeKind::Motd => "motd",
+            NoticeKind::Error => "error",
+            NoticeKind::Info => "info",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<NoticeKind> {
+        match name {
+            "presence" => Some(NoticeKind::Presence),
+            "moderation" => Some(NoticeKind::Moderation),
+            "motd" => Some(NoticeKind::Motd),
+            "error" => Some(NoticeKind::Error),
+            "info" => Some(NoticeKind::Info),
+            _ => None,
+        }
+    }
+}
+
+// One notice, e.g. "/notice presence alice has joined the room." on the wire
+#[derive(Clone, Debug)]
+pub struct Notice {
+    pub kind: NoticeKind,
+    pub text: String,
+}
+
+impl Notice {
+    pub fn parse(line: &str) -> Option<Notice> {
+        let rest = line.strip_prefix(NOTICE_COMMAND)?.strip_prefix(' ')?;
+        let (kind, text) = rest.split_once(' ').unwrap_or((rest, ""));
+
+        Some(Notice {
+            kind: NoticeKind::parse(kind)?,
+            text: String::from(text),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        format!("{} {} {}", NOTICE_COMMAND, self.kind.as_str(), self.text)
+    }
+
+    // How the notice looks to a client that didn't ask for notices.  Presence and the MOTD have always been sent as
+    // they are, everything else gets marked with stars.
+    pub fn to_plain(&self) -> String {
+        match self.kind {
+            NoticeKind::Presence | NoticeKind::Motd => self.text.clone(),
+            _ => format!("*** {}", self.text),
+        }
+    }
+}
+
+// Sent in place of the usual "sender: body" chat line when the message mentions the client (see mentions.rs in
+// chat_server), but only to clients that asked for notices.  On the wire it's the command, the kind of mention, the
+// sender, then the body.
+pub const MENTION_COMMAND: &str = "/mention";
+
+// For clients that asked for timestamps, everything from the room comes wrapped in this command, the time the room
+// sent it out (in milliseconds since the unix epoch, so UTC), then the line just as it would have been otherwise
+pub const TIMESTAMP_COMMAND: &str = "/at";
+
+// Whether the mention was meant for us in particular, or for everyone at once with @all or @here.  Clients will
+// usually want to be louder about the first.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MentionKind {
+    Direct,
+    Everyone,
+}
+
+impl MentionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MentionKind::Direct => "direct",
+            MentionKind::Everyone => "everyone",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<MentionKind> {
+        match name {
+            "direct" => Some(MentionKind::Direct),
+            "everyone" => Some(MentionKind::Everyone),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mention {
+    pub kind: MentionKind,
+    pub sender: String,
+    pub body: String,
+}
+
+impl Mention {
+    pub fn parse(line: &str) -> Option<Mention> {
+        let rest = line.strip_prefix(MENTION_COMMAND)?.strip_prefix(' ')?;
+        let (kind, rest) = rest.split_once(' ')?;
+        let (sender, body) = rest.split_once(' ').unwrap_or((rest, ""));
+
+        Some(Mention {
+            kind: MentionKind::parse(kind)?,
+            sender: String::from(sender),
+            body: String::from(body),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            MENTION_COMMAND,
+            self.kind.as_str(),
+            self.sender,
+            self.body
+        )
+    }
+}
+
+// Chat from a bot account starts with this and a space, e.g. "weather: [bot] Sunny in London", so people can tell it
+// apart from someone typing.  The server won't let anyone else start a message with it.
+pub const BOT_TAG: &str = "[bot]";
+
+// For clients that asked for the roster, everyone in the room, e.g. "/roster alice bob" on the wire.  Names can't have
+// spaces in them, so they're just separated by spaces.
+pub const ROSTER_COMMAND: &str = "/roster";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Roster {
+    pub names: Vec<String>,
+}
+
+impl Roster {
+    pub fn parse(line: &str) -> Option<Roster> {
+        let rest = line.strip_prefix(ROSTER_COMMAND)?;
+        if !rest.is_empty() && !rest.starts_with(' ') {
+            return None;
+        }
+
+        Some(Roster {
+            names: rest.split_whitespace().map(String::from).collect(),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        let mut line = String::from(ROSTER_COMMAND);
+        for name in &self.names {
+            line.push(' ');
+            line.push_str(name);
+        }
+        line
+    }
+}
+
+// A direct message, which only the two people in it see.  Someone types /msg <name> <text> and the server hands it to
+// every connection the other person has as "/dm from <sender> <text>", and back to every one the sender has as
+// "/dm to <name> <text>", so the conversation looks the same wherever either of them is reading it.  Peer is always the
+// other person.
+pub const DIRECT_COMMAND: &str = "/dm";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Direct {
+    pub peer: String,
+    // Whether we sent it, rather than being sent it
+    pub sent: bool,
+    pub body: String,
+}
+
+impl Direct {
+    pub fn parse(line: &str) -> Option<Direct> {
+        let rest = line.strip_prefix(DIRECT_COMMAND)?.strip_prefix(' ')?;
+        let (direction, rest) = rest.split_once(' ')?;
+        let sent = match direction {
+            "from" => false,
+            "to" => true,
+            _ => return None,
+        };
+        let (peer, body) = rest.split_once(' ').unwrap_or((rest, ""));
+
+        Some(Direct {
+            peer: String::from(peer),
+            sent,
+            body: String::from(body),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        let direction = if self.sent { "to" } else { "from" };
+        format!(
+            "{} {} {} {}",
+            DIRECT_COMMAND, direction, self.peer, self.body
+        )
+    }
+
+    // How it looks to a client that didn't ask for direct messages, as a notice
+    pub fn to_plain(&self) -> String {
+        if self.sent {
+            format!("You whisper to {}: {}", self.peer, self.body)
+        } else {
+            format!("{} whispers: {}", self.peer, self.body)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Timestamped {
+    pub time: SystemTime,
+    pub line: String,
+}
+
+impl Timestamped {
+    // Takes the time from a Clocked line too, for clients that only want to show when things were said
+    pub fn parse(line: &str) -> Option<Timestamped> {
+        let rest = line.strip_prefix(TIMESTAMP_COMMAND)?.strip_prefix(' ')?;
+        let (millis, line) = rest.split_once(' ')?;
+        let millis = millis.split_once('.').map_or(millis, |(millis, _)| millis);
+
+        Some(Timestamped {
+            time: UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?),
+            line: String::from(line),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        let millis = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        format!("{} {} {}", TIMESTAMP_COMMAND, millis, self.line)
+    }
+}
+
+// A reading from a hybrid logical clock, which is the time in milliseconds since the unix epoch, and a count that
+// breaks ties between things that happened in the same millisecond.  It only ever goes forward on each server, and
+// never falls behind anything the server has heard from another, so ordering by it puts every reply after what it
+// answered even when the servers' clocks disagree.  On the wire it's "<millis>.<counter>".
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Hlc {
+    pub millis: u64,
+    pub counter: u32,
+}
+
+impl Hlc {
+    pub fn parse(text: &str) -> Option<Hlc> {
+        let (millis, counter) = text.split_once('.')?;
+        Some(Hlc {
+            millis: millis.parse().ok()?,
+            counter: counter.parse().ok()?,
+        })
+    }
+
+    // Roughly when it happened, as far as the clocks could agree
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.millis)
+    }
+}
+
+impl fmt::Display for Hlc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.millis, self.counter)
+    }
+}
+
+// How far ahead of our own clock we'll believe another server's.  One that's further ahead than this (a clock set to
+// next year, say) would otherwise drag every reading after it along too.
+const MAX_DRIFT_MILLIS: u64 = 60 * 1000;
+
+// The clock the readings come from, one per server.  Each thing that happens here gets now, and each thing heard from
+// another server goes through observe first, so whatever we do next is later than it.
+#[derive(Default, Debug)]
+pub struct HybridClock {
+    last: Hlc,
+}
+
+impl HybridClock {
+    pub fn new() -> HybridClock {
+        HybridClock::default()
+    }
+
+    // A reading for something happening here, given what our own clock says.  If that's gone backwards, or hasn't
+    // moved since the last reading, the count goes up instead.
+    pub fn now(&mut self, wall: SystemTime) -> Hlc {
+        let wall = millis_since_epoch(wall);
+        self.last = if wall > self.last.millis {
+            Hlc {
+                millis: wall,
+                counter: 0,
+            }
+        } else {
+            Hlc {
+                millis: self.last.millis,
+                counter: self.last.counter.saturating_add(1),
+            }
+        };
+        self.last
+    }
+
+    // Moves the clock past a reading from another server, and returns our reading for having heard it
+    pub fn observe(&mut self, remote: Hlc, wall: SystemTime) -> Hlc {
+        let wall = millis_since_epoch(wall);
+        let remote = Hlc {
+            millis: remote.millis.min(wall.saturating_add(MAX_DRIFT_MILLIS)),
+            ..remote
+        };
+        let millis = wall.max(self.last.millis).max(remote.millis);
+        let counter = match (millis == self.last.millis, millis == remote.millis) {
+            (true, true) => self.last.counter.max(remote.counter).saturating_add(1),
+            (true, false) => self.last.counter.saturating_add(1),
+            (false, true) => remote.counter.saturating_add(1),
+            (false, false) => 0,
+        };
+        self.last = Hlc { millis, counter };
+        self.last
+    }
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+// For clients that asked for the clock, everything from the room comes wrapped in TIMESTAMP_COMMAND with the reading
+// in place of the time, e.g. "/at 1700000000000.2 alice: hi".  Messages from linked servers keep the reading they were
+// given where they were said, so they can arrive out of order, and a client that cares can put them back in order.
+// Two servers can give the same reading to things that happened at once, and those can go in either order.
+#[derive(Clone, Debug)]
+pub struct Clocked {
+    pub clock: Hlc,
+    pub line: String,
+}
+
+impl Clocked {
+    pub fn parse(line: &str) -> Option<Clocked> {
+        let rest = line.strip_prefix(TIMESTAMP_COMMAND)?.strip_prefix(' ')?;
+        let (clock, line) = rest.split_once(' ')?;
+
+        Some(Clocked {
+            clock: Hlc::parse(clock)?,
+            line: String::from(line),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        format!("{} {} {}", TIMESTAMP_COMMAND, self.clock, self.line)
+    }
+}
+
+// Files sent from one person to another.  Everything about a transfer is a FILE_COMMAND line, then what it's about,
+// then the id the sender picked for it, which is how both ends and the server tell their transfers apart:
+//
+//     /file offer <id> <to> <size> <port or -> <name>     the sender offers a file, listening on port if it can
+//     /file offer <id> <from> <size> <address or -> <name>     which the receiver gets with where to find the sender
+//     /file accept <id>                                   the receiver wants it through the server
+//     /file accept <id> <chunk size>                      which the sender gets, with how big each chunk can be
+//     /file chunk <id> <offset> <base64>                  a piece of it, from the sender to the receiver
+//     /file done <id>                                     from either side, it's all there
+//     /file cancel <id> <reason>                          from anyone, including the server, it's off
+//
+// With an address in the offer, the receiver first tries connecting to the sender directly, sends the id on a line of
+// its own, and reads the file back as it is.  Only if that doesn't work (usually because the sender is behind NAT)
+// does it accept through the server, which passes the chunks along.  Either way the receiver says done once it has it
+// all.  A server can turn files off, and then it cancels every offer.
+pub const FILE_COMMAND: &str = "/file";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum FileMessage {
+    // Peer is who it's to on the way to the server, and who it's from on the way out.  Likewise direct is the port the
+    // sender is listening on, and then the whole address.
+    Offer {
+        id: String,
+        peer: String,
+        size: u64,
+        direct: Option<String>,
+        name: String,
+    },
+    Accept {
+        id: String,
+        chunk: Option<usize>,
+    },
+    Chunk {
+        id: String,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Done {
+        id: String,
+    },
+    Cancel {
+        id: String,
+        reason: String,
+    },
+}
+
+impl FileMessage {
+    pub fn parse(line: &str) -> Option<FileMessage> {
+        let rest = line.strip_prefix(FILE_COMMAND)?.strip_prefix(' ')?;
+        let (kind, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        let (id, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if id.is_empty() {
+            return None;
+        }
+        let id = String::from(id);
+
+        Some(match kind {
+            "offer" => {
+                let mut fields = rest.splitn(4, ' ');
+                let peer = String::from(fields.next()?);
+                let size = fields.next()?.parse().ok()?;
+                let direct = match fields.next()? {
+                    "-" => None,
+                    direct => Some(String::from(direct)),
+                };
+                let name = String::from(fields.next()?);
+                if peer.is_empty() || name.is_empty() {
+                    return None;
+                }
+                FileMessage::Offer {
+                    id,
+                    peer,
+                    size,
+                    direct,
+                    name,
+                }
+            }
+            "accept" if rest.is_empty() => FileMessage::Accept { id, chunk: None },
+            "accept" => FileMessage::Accept {
+                id,
+                chunk: Some(rest.parse().ok()?),
+            },
+            "chunk" => {
+                let (offset, data) = rest.split_once(' ')?;
+                FileMessage::Chunk {
+                    id,
+                    offset: offset.parse().ok()?,
+                    data: decode_base64(data)?,
+                }
+            }
+            "done" => FileMessage::Done { id },
+            "cancel" => FileMessage::Cancel {
+                id,
+                reason: String::from(rest),
+            },
+            _ => return None,
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            FileMessage::Offer {
+                id,
+                peer,
+                size,
+                direct,
+                name,
+            } => format!(
+                "{} offer {} {} {} {} {}",
+                FILE_COMMAND,
+                id,
+                peer,
+                size,
+                direct.as_deref().unwrap_or("-"),
+                name
+            ),
+            FileMessage::Accept { id, chunk: None } => format!("{} accept {}", FILE_COMMAND, id),
+            FileMessage::Accept {
+                id,
+                chunk: Some(chunk),
+            } => format!("{} accept {} {}", FILE_COMMAND, id, chunk),
+            FileMessage::Chunk { id, offset, data } => format!(
+                "{} chunk {} {} {}",
+                FILE_COMMAND,
+                id,
+                offset,
+                encode_base64(data)
+            ),
+            FileMessage::Done { id } => format!("{} done {}", FILE_COMMAND, id),
+            FileMessage::Cancel { id, reason } => {
+                format!("{} cancel {} {}", FILE_COMMAND, id, reason)
+            }
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            FileMessage::Offer { id, .. }
+            | FileMessage::Accept { id, .. }
+            | FileMessage::Chunk { id, .. }
+            | FileMessage::Done { id }
+            | FileMessage::Cancel { id, .. } => id,
+        }
+    }
+
+    // How long a chunk line is for this many bytes of the file, at most, with an id of up to MAX_FILE_ID bytes
+    pub fn chunk_line_len(chunk: usize) -> usize {
+        let header =
+            FILE_COMMAND.len() + " chunk ".len() + MAX_FILE_ID + " ".len() + 20 + " ".len();
+        header + chunk.div_ceil(3) * 4
+    }
+}
+
+// The longest id a sender can pick
+pub const MAX_FILE_ID: usize = 32;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Chunks are binary, and lines are text, so they go as standard base64 with padding
+fn encode_base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(text.len() / 4 * 3);
+    for group in text.chunks(4) {
+        let padding = group.iter().rev().take_while(|byte| **byte == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, byte) in group[..4 - padding].iter().enumerate() {
+            let value = BASE64.iter().position(|letter| letter == byte)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+// Collects bytes as they're read off a stream and hands back complete lines.  Whatever is left after the last newline
+// stays in the buffer until the rest of it arrives.
+//
+// Lines are only decoded once they're whole, so a character split across two reads is put back together before anyone
+// looks at it.  Splitting on the newline byte can't cut a character in half either, since every byte of a multibyte
+// UTF-8 character has its top bit set.  Bytes that still aren't UTF-8 come out as replacement characters.
+//
+// The buffer grows to fit whatever's sent, so with a limit of zero a line can be any length.  With a limit, a line that
+// goes past it is thrown away as it arrives rather than held on to, and comes back as LineTooLong once.
+#[derive(Default)]
+pub struct LineReader {
+    buffer: Vec<u8>,
+    max_line: usize,
+    // Partway through a line we've already given up on, so everything up to its newline goes
+    discarding: bool,
+}
+
+#[derive(Debug)]
+pub struct LineTooLong;
+
+impl LineReader {
+    pub fn new() -> LineReader {
+        LineReader::default()
+    }
+
+    pub fn with_max_line(max_line: usize) -> LineReader {
+        LineReader {
+            max_line,
+            ..LineReader::default()
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    // The line comes back without its newline (or a \r before it, for anyone typing at us through telnet)
+    pub fn next_line(&mut self) -> Option<Result<String, LineTooLong>> {
+        let end = match self.buffer.iter().position(|&byte| byte == b'\n') {
+            Some(end) => end,
+            None => {
+                // No newline yet, but if it's already too long there's no point keeping what we have
+                if self.max_line > 0 && self.buffer.len() > self.max_line {
+                    self.buffer.clear();
+                    if !self.discarding {
+                        self.discarding = true;
+                        return Some(Err(LineTooLong));
+                    }
+                }
+                return None;
+            }
+        };
+        let line: Vec<u8> = self.buffer.drain(..=end).collect();
+
+        // The end of the one we gave up on, which has been reported already
+        if self.discarding {
+            self.discarding = false;
+            return self.next_line();
+        }
+
+        let line = String::from_utf8_lossy(&line[..end]);
+        let line = line.trim_end_matches('\r');
+        if self.max_line > 0 && line.len() > self.max_line {
+            return Some(Err(LineTooLong));
+        }
+        Some(Ok(line.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn peer_ahead_pulls_the_clock_forward() {
+        let mut clock = HybridClock::new();
+        let before = clock.now(at(1_000));
+        let remote = Hlc {
+            millis: 31_000,
+            counter: 4,
+        };
+
+        let heard = clock.observe(remote, at(1_000));
+        assert_eq!(
+            heard,
+            Hlc {
+                millis: 31_000,
+                counter: 5
+            }
+        );
+        assert!(heard > before && heard > remote);
+
+        // Our own clock hasn't caught up yet, so the count carries on from the remote's
+        let next = clock.now(at(1_001));
+        assert_eq!(
+            next,
+            Hlc {
+                millis: 31_000,
+                counter: 6
+            }
+        );
+        assert!(next > heard);
+    }
+
+    #[test]
+    fn peer_too_far_ahead_is_capped() {
+        let mut clock = HybridClock::new();
+        let remote = Hlc {
+            millis: 1_000 + MAX_DRIFT_MILLIS + 1_000_000,
+            counter: 0,
+        };
+
+        let heard = clock.observe(remote, at(1_000));
+        assert_eq!(heard.millis, 1_000 + MAX_DRIFT_MILLIS);
+        assert!(clock.now(at(1_001)) > heard);
+    }
+
+    #[test]
+    fn peer_behind_leaves_the_wall_clock_in_charge() {
+        let mut clock = HybridClock::new();
+        clock.now(at(5_000));
+        let remote = Hlc {
+            millis: 2_000,
+            counter: 9,
+        };
+
+        assert_eq!(
+            clock.observe(remote, at(6_000)),
+            Hlc {
+                millis: 6_000,
+                counter: 0
+            }
+        );
+    }
+
+    #[test]
+    fn same_millisecond_breaks_ties_with_the_counter() {
+        let mut clock = HybridClock::new();
+        let first = clock.now(at(7_000));
+        let second = clock.now(at(7_000));
+        assert_eq!(first.counter, 0);
+        assert_eq!(second.counter, 1);
+
+        // Both sides at the same time, so the count goes past whichever is higher
+        let remote = Hlc {
+            millis: 7_000,
+            counter: 3,
+        };
+        assert_eq!(
+            clock.observe(remote, at(7_000)),
+            Hlc {
+                millis: 7_000,
+                counter: 4
+            }
+        );
+        assert_eq!(
+            clock.observe(remote, at(7_000)),
+            Hlc {
+                millis: 7_000,
+                counter: 5
+            }
+        );
+    }
+
+    #[test]
+    fn wall_clock_stepping_back_never_moves_the_clock_back() {
+        let mut clock = HybridClock::new();
+        let mut last = clock.now(at(10_000));
+        for wall in [9_000, 500, 10_000, 9_999] {
+            let next = clock.now(at(wall));
+            assert!(next > last, "{} came after {}", next, last);
+            assert_eq!(next.millis, 10_000);
+            last = next;
+        }
+
+        // Once the wall clock is past where we were, it takes over again
+        assert_eq!(
+            clock.now(at(10_001)),
+            Hlc {
+                millis: 10_001,
+                counter: 0
+            }
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// A range of addresses in CIDR notation, like 10.0.0.0/8 or 2001:db8::/32.  A bare address is a range of just that
+// one.
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual stack socket show up as addresses like ::ffff:10.0.0.1, which should still count as
+        // being in 10.0.0.0/8
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Cidr, String> {
+        let (address, prefix_len) = match text.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (text, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|_| format!("{} isn't an address or a CIDR range", text))?
+            .to_canonical();
+
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max)
+                .ok_or_else(|| format!("{} needs a prefix length from 0 to {}", text, max))?,
+            None => max,
+        };
+
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+// So serde can take ranges straight from strings in the config
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Cidr, String> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+// Who may connect at all, by address, from the config's [access].  Deny always wins, and once there's anything in allow
+// only addresses in it get in.  Both empty lets everyone in.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(text: &str) -> Cidr {
+        text.parse().unwrap()
+    }
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    // A /0 would shift the mask by the whole width, which checked_shl turns into no bits to compare at all
+    #[test]
+    fn slash_zero_contains_everything_of_its_kind() {
+        assert!(cidr("0.0.0.0/0").contains(ip("10.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("ffff::")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn full_length_prefix_is_only_that_address() {
+        assert!(cidr("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+        assert_eq!(cidr("10.0.0.1"), cidr("10.0.0.1/32"));
+        assert_eq!(cidr("2001:db8::1"), cidr("2001:db8::1/128"));
+    }
+
+    #[test]
+    fn prefix_in_between_masks_the_host_bits() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.1.2")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("192.168.1.0/23").contains(ip("192.168.0.200")));
+        assert!(!cidr("192.168.1.0/24").contains(ip("192.168.0.200")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn out_of_range_prefix_is_refused() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_count_as_ipv4() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.0.0.1")));
+        // The range itself can be written mapped too
+        assert_eq!(cidr("::ffff:10.0.0.1"), cidr("10.0.0.1/32"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access = AccessList {
+            allow: vec![cidr("10.0.0.0/8")],
+            deny: vec![cidr("10.0.0.13")],
+        };
+        assert!(access.permits(ip("10.0.0.12")));
+        assert!(!access.permits(ip("10.0.0.13")));
+        assert!(!access.permits(ip("::ffff:10.0.0.13")));
+        assert!(!access.permits(ip("192.168.0.1")));
+        assert!(AccessList::default().permits(ip("192.168.0.1")));
+    }
+}
@@ -0,0 +1,556 @@
+use argon2::password_hash::PasswordHash;
+use argon2::password_hash::PasswordHasher;
+use argon2::password_hash::PasswordVerifier;
+use argon2::password_hash::SaltString;
+use argon2::Argon2;
+use rand_core::OsRng;
+use rand_core::RngCore;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::totp;
+
+// What we remember about a registered nickname.  We never keep the password itself, only an argon2 hash of it, which
+// has the salt and the hashing parameters baked into the string.  Welcomed is whether they've had the greeting for new
+// accounts (see WelcomeConfig).  Recovery is there while an op has handed out a token for resetting the password, two
+// factor once they've started setting up an authenticator app, and bot if an op made the account for a bot.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub password_hash: String,
+    #[serde(default = "registered_before_greetings")]
+    pub welcomed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery: Option<Recovery>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub two_factor: Option<TwoFactor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bot: Option<BotScope>,
+}
+
+// An account an op made for a bot with /bot create, which can only post to the one room.  There's no password, the bot
+// logs in with the token it was given instead, which is hashed in password_hash like a password would be.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BotScope {
+    pub room: String,
+}
+
+// A token for resetting a forgotten password.  Like the password we only keep a hash of it, along with when it stops
+// working, in seconds since the Unix epoch.  It's gone as soon as it's used.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Recovery {
+    pub token_hash: String,
+    pub expires: u64,
+}
+
+// Two-factor authentication with an authenticator app (see totp.rs).  We need the secret itself to work out the codes,
+// so it's kept as it is, in base32, but the backup codes are hashed like passwords and each one is gone once it's used.
+// Nothing is asked for at login until they've confirmed it with a code from the app, so a bad scan can't lock anybody
+// out.  Last step is the step of the newest code used, so a code somebody saw can't be used again.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TwoFactor {
+    pub secret: String,
+    pub confirmed: bool,
+    #[serde(default)]
+    pub backup_code_hashes: Vec<String>,
+    #[serde(default)]
+    pub last_step: u64,
+}
+
+// How many backup codes they get when two factor is turned on, for when they don't have the app to hand
+const BACKUP_CODES: usize = 8;
+
+// Accounts saved before there was a greeting have been around long enough not to need one
+fn registered_before_greetings() -> bool {
+    true
+}
+
+// The layout of the accounts file on disk.  A BTreeMap keeps the accounts sorted, so the file doesn't get shuffled
+// every time it's saved.
+#[derive(Serialize, Deserialize, Default)]
+struct AccountsFile {
+    #[serde(default)]
+    accounts: BTreeMap<String, Account>,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    AlreadyRegistered,
+    NotRegistered,
+    EmptyPassword,
+    InvalidToken,
+    TwoFactorOn,
+    TwoFactorOff,
+    InvalidCode,
+    NotABot,
+    Hash(argon2::password_hash::Error),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountError::AlreadyRegistered => write!(f, "that name is already registered"),
+            AccountError::NotRegistered => write!(f, "that name isn't registered"),
+            AccountError::EmptyPassword => write!(f, "a password is required"),
+            AccountError::InvalidToken => write!(f, "that token is wrong, used or expired"),
+            AccountError::TwoFactorOn => write!(f, "two-factor authentication is already on"),
+            AccountError::TwoFactorOff => write!(f, "two-factor authentication isn't on"),
+            AccountError::InvalidCode => write!(f, "that code is wrong or has already been used"),
+            AccountError::NotABot => write!(f, "that isn't a bot account"),
+            AccountError::Hash(err) => write!(f, "unable to hash password: {}", err),
+        }
+    }
+}
+
+fn hash(secret: &str) -> Result<String, AccountError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .map_err(AccountError::Hash)?
+        .to_string())
+}
+
+fn matches(secret: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(secret.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Recovery and bot tokens, long enough that nobody's guessing one
+fn token() -> String {
+    let mut bytes = [0; 12];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Backup codes are two groups of five hex digits, easy enough to copy down.  Dashes and case don't matter when they're
+// typed back in.
+fn backup_code() -> String {
+    let mut bytes = [0; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let digits: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}", &digits[..5], &digits[5..])
+}
+
+fn normalize_backup_code(code: &str) -> String {
+    code.chars()
+        .filter(|&c| c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+// Names are compared without regard to case, otherwise "Alice" could walk right past alice's registration
+fn key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+// Registered nicknames, kept in memory and written out to a TOML file whenever they change.  Everything goes through
+// the mutex, so the store can be shared between client handlers with a plain Arc.
+pub struct AccountStore {
+    path: PathBuf,
+    accounts: Mutex<BTreeMap<String, Account>>,
+    // Only one save at a time, so two quick registrations can't interleave their writes
+    save_lock: Mutex<()>,
+}
+
+impl AccountStore {
+    // A missing file just means nobody has registered yet
+    pub fn load(path: impl AsRef<Path>) -> io::Result<AccountStore> {
+        let path = path.as_ref().to_path_buf();
+        let file = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => AccountsFile::default(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(AccountStore {
+            path,
+            accounts: Mutex::new(file.accounts),
+            save_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(&key(name))
+    }
+
+    // Hashing is deliberately slow, so we do it before taking the lock and then check again that nobody beat us to
+    // the name in the meantime.
+    pub fn register(&self, name: &str, password: &str) -> Result<(), AccountError> {
+        if password.is_empty() {
+            return Err(AccountError::EmptyPassword);
+        }
+        if self.is_registered(name) {
+            return Err(AccountError::AlreadyRegistered);
+        }
+
+        let password_hash = hash(password)?;
+
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(&key(name)) {
+            return Err(AccountError::AlreadyRegistered);
+        }
+        accounts.insert(
+            key(name),
+            Account {
+                password_hash,
+                welcomed: false,
+                recovery: None,
+                two_factor: None,
+                bot: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Makes an account for a bot that can only post to room, and hands back the token it logs in with.  Like recovery
+    // tokens, the token itself is only ever in what we hand back.
+    pub fn create_bot(&self, name: &str, room: &str) -> Result<String, AccountError> {
+        if self.is_registered(name) {
+            return Err(AccountError::AlreadyRegistered);
+        }
+
+        let token = token();
+        let password_hash = hash(&token)?;
+
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(&key(name)) {
+            return Err(AccountError::AlreadyRegistered);
+        }
+        // Nobody reads a bot's greeting
+        accounts.insert(
+            key(name),
+            Account {
+                password_hash,
+                welcomed: true,
+                recovery: None,
+                two_factor: None,
+                bot: Some(BotScope {
+                    room: String::from(room),
+                }),
+            },
+        );
+
+        Ok(token)
+    }
+
+    // A new token for a bot, for when the old one has leaked.  The old one stops working straight away.
+    pub fn reissue_bot_token(&self, name: &str) -> Result<String, AccountError> {
+        let token = token();
+        let password_hash = hash(&token)?;
+
+        match self.accounts.lock().unwrap().get_mut(&key(name)) {
+            Some(account) if account.bot.is_some() => account.password_hash = password_hash,
+            Some(_) => return Err(AccountError::NotABot),
+            None => return Err(AccountError::NotRegistered),
+        }
+
+        Ok(token)
+    }
+
+    // Only bots can be deleted, people's accounts are theirs
+    pub fn delete_bot(&self, name: &str) -> Result<(), AccountError> {
+        let mut accounts = self.accounts.lock().unwrap();
+        match accounts.get(&key(name)) {
+            Some(account) if account.bot.is_some() => {}
+            Some(_) => return Err(AccountError::NotABot),
+            None => return Err(AccountError::NotRegistered),
+        }
+        accounts.remove(&key(name));
+
+        Ok(())
+    }
+
+    // The room the name can post to, if it's a bot
+    pub fn bot_room(&self, name: &str) -> Option<String> {
+        let accounts = self.accounts.lock().unwrap();
+        let bot = accounts.get(&key(name))?.bot.as_ref()?;
+        Some(bot.room.clone())
+    }
+
+    // Every bot and its room, sorted by name
+    pub fn bots(&self) -> Vec<(String, String)> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(name, account)| Some((name.clone(), account.bot.as_ref()?.room.clone())))
+            .collect()
+    }
+
+    pub fn verify(&self, name: &str, password: &str) -> bool {
+        let password_hash = match self.accounts.lock().unwrap().get(&key(name)) {
+            Some(account) => account.password_hash.clone(),
+            None => return false,
+        };
+
+        matches(password, &password_hash)
+    }
+
+    // Makes a new recovery token for the account, replacing any earlier one.  The token itself is only ever in what
+    // we hand back, so whoever asked for it has to pass it on.
+    pub fn issue_recovery(&self, name: &str, lifetime: Duration) -> Result<String, AccountError> {
+        if !self.is_registered(name) {
+            return Err(AccountError::NotRegistered);
+        }
+
+        let token = token();
+        let recovery = Recovery {
+            token_hash: hash(&token)?,
+            expires: now() + lifetime.as_secs(),
+        };
+
+        match self.accounts.lock().unwrap().get_mut(&key(name)) {
+            Some(account) => account.recovery = Some(recovery),
+            None => return Err(AccountError::NotRegistered),
+        }
+
+        Ok(token)
+    }
+
+    // Sets a new password with a recovery token.  A token works once, and an expired one is thrown away when someone
+    // tries it.  Every way this can fail gives the same error, so nobody can use it to find out which names have a
+    // token waiting.
+    pub fn reset_password(
+        &self,
+        name: &str,
+        token: &str,
+        password: &str,
+    ) -> Result<(), AccountError> {
+        if password.is_empty() {
+            return Err(AccountError::EmptyPassword);
+        }
+
+        let recovery = match self.accounts.lock().unwrap().get_mut(&key(name)) {
+            Some(account) => match &account.recovery {
+                Some(recovery) if recovery.expires > now() => recovery.clone(),
+                Some(_) => {
+                    account.recovery = None;
+                    return Err(AccountError::InvalidToken);
+                }
+                None => return Err(AccountError::InvalidToken),
+            },
+            None => return Err(AccountError::InvalidToken),
+        };
+
+        // Hashing is slow, so it's done without holding the lock, which means checking afterwards that the token we
+        // checked is still the one on the account
+        if !matches(token, &recovery.token_hash) {
+            return Err(AccountError::InvalidToken);
+        }
+        let password_hash = hash(password)?;
+
+        let mut accounts = self.accounts.lock().unwrap();
+        match accounts.get_mut(&key(name)) {
+            Some(account)
+                if account.recovery.as_ref().map(|current| &current.token_hash)
+                    == Some(&recovery.token_hash) =>
+            {
+                account.password_hash = password_hash;
+                account.recovery = None;
+                Ok(())
+            }
+            _ => Err(AccountError::InvalidToken),
+        }
+    }
+
+    // Whether logging in to the name takes a code as well as the password
+    pub fn needs_code(&self, name: &str) -> bool {
+        match self.accounts.lock().unwrap().get(&key(name)) {
+            Some(account) => account
+                .two_factor
+                .as_ref()
+                .is_some_and(|two_factor| two_factor.confirmed),
+            None => false,
+        }
+    }
+
+    // Starts setting up two factor with a fresh secret, and hands back the otpauth URI for their app.  Until they
+    // confirm it, asking again just starts over with a new secret.
+    pub fn enable_two_factor(&self, name: &str) -> Result<String, AccountError> {
+        let mut secret = [0; 20];
+        OsRng.fill_bytes(&mut secret);
+
+        match self.accounts.lock().unwrap().get_mut(&key(name)) {
+            Some(account) => match &account.two_factor {
+                Some(two_factor) if two_factor.confirmed => Err(AccountError::TwoFactorOn),
+                _ => {
+                    account.two_factor = Some(TwoFactor {
+                        secret: totp::to_base32(&secret),
+                        confirmed: false,
+                        backup_code_hashes: Vec::new(),
+                        last_step: 0,
+                    });
+                    Ok(totp::uri(name, &secret))
+                }
+            },
+            None => Err(AccountError::NotRegistered),
+        }
+    }
+
+    // Turns two factor on once they've shown their app has the secret, and hands back their backup codes.  Like
+    // recovery tokens, the codes themselves are only ever in what we hand back.
+    pub fn confirm_two_factor(&self, name: &str, code: &str) -> Result<Vec<String>, AccountError> {
+        match self.accounts.lock().unwrap().get(&key(name)) {
+            Some(Account {
+                two_factor: Some(two_factor),
+                ..
+            }) if two_factor.confirmed => return Err(AccountError::TwoFactorOn),
+            Some(Account {
+                two_factor: Some(_),
+                ..
+            }) => {}
+            Some(_) => return Err(AccountError::TwoFactorOff),
+            None => return Err(AccountError::NotRegistered),
+        }
+        if !self.check_app_code(name, code, false) {
+            return Err(AccountError::InvalidCode);
+        }
+
+        let codes: Vec<String> = (0..BACKUP_CODES).map(|_| backup_code()).collect();
+        let hashes = codes
+            .iter()
+            .map(|code| hash(&normalize_backup_code(code)))
+            .collect::<Result<Vec<String>, AccountError>>()?;
+
+        match self.accounts.lock().unwrap().get_mut(&key(name)) {
+            Some(Account {
+                two_factor: Some(two_factor),
+                ..
+            }) => {
+                two_factor.confirmed = true;
+                two_factor.backup_code_hashes = hashes;
+                Ok(codes)
+            }
+            _ => Err(AccountError::TwoFactorOff),
+        }
+    }
+
+    // Turns two factor off, which takes a code, so a session someone walked away from isn't enough
+    pub fn disable_two_factor(&self, name: &str, code: &str) -> Result<(), AccountError> {
+        if !self.needs_code(name) {
+            return Err(AccountError::TwoFactorOff);
+        }
+        if !self.check_code(name, code) {
+            return Err(AccountError::InvalidCode);
+        }
+
+        if let Some(account) = self.accounts.lock().unwrap().get_mut(&key(name)) {
+            account.two_factor = None;
+        }
+        Ok(())
+    }
+
+    // Checks a code from their app or one of their backup codes, using it up either way
+    pub fn check_code(&self, name: &str, code: &str) -> bool {
+        self.check_app_code(name, code, true) || self.use_backup_code(name, code)
+    }
+
+    // Confirmed says whether to only take codes once two factor is on, rather than while it's being set up
+    fn check_app_code(&self, name: &str, code: &str, confirmed: bool) -> bool {
+        let mut accounts = self.accounts.lock().unwrap();
+        let two_factor = match accounts.get_mut(&key(name)) {
+            Some(Account {
+                two_factor: Some(two_factor),
+                ..
+            }) if two_factor.confirmed == confirmed => two_factor,
+            _ => return false,
+        };
+        let secret = match totp::from_base32(&two_factor.secret) {
+            Some(secret) => secret,
+            None => return false,
+        };
+
+        match totp::verify(&secret, code, totp::current_step()) {
+            Some(step) if step > two_factor.last_step => {
+                two_factor.last_step = step;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Backup codes are hashed, so like reset_password this checks them without holding the lock and then makes sure
+    // the one that matched hasn't been used in the meantime
+    fn use_backup_code(&self, name: &str, code: &str) -> bool {
+        let hashes = match self.accounts.lock().unwrap().get(&key(name)) {
+            Some(Account {
+                two_factor: Some(two_factor),
+                ..
+            }) if two_factor.confirmed => two_factor.backup_code_hashes.clone(),
+            _ => return false,
+        };
+
+        let code = normalize_backup_code(code);
+        let used = match hashes.into_iter().find(|hash| matches(&code, hash)) {
+            Some(used) => used,
+            None => return false,
+        };
+
+        match self.accounts.lock().unwrap().get_mut(&key(name)) {
+            Some(Account {
+                two_factor: Some(two_factor),
+                ..
+            }) => match two_factor
+                .backup_code_hashes
+                .iter()
+                .position(|hash| *hash == used)
+            {
+                Some(index) => {
+                    two_factor.backup_code_hashes.remove(index);
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Marks the account as having had the greeting.  Only true the first time, so whoever gets true sends it.
+    pub fn mark_welcomed(&self, name: &str) -> bool {
+        match self.accounts.lock().unwrap().get_mut(&key(name)) {
+            Some(account) if !account.welcomed => {
+                account.welcomed = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Writes to a temporary file first and then renames it over the old one, so a crash halfway through a save can't
+    // leave us with half an accounts file.  This touches the disk, so call it from the blocking pool.
+    pub fn save(&self) -> io::Result<()> {
+        let _saving = self.save_lock.lock().unwrap();
+
+        let file = AccountsFile {
+            accounts: self.accounts.lock().unwrap().clone(),
+        };
+        let contents = toml::to_string(&file)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
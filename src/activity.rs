@@ -0,0 +1,81 @@
+use chrono::NaiveDate;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+use crate::digest::start_of;
+use crate::storage::HourlyActivity;
+use crate::storage::Storage;
+
+// CSV for spreadsheets, JSON for anything that wants to do its own charting
+#[derive(Clone, Copy)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    History(rusqlite::Error),
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::History(err) => write!(f, "unable to read history: {}", err),
+            ExportError::Io(err) => write!(f, "unable to write export: {}", err),
+            ExportError::Json(err) => write!(f, "unable to encode export: {}", err),
+        }
+    }
+}
+
+// Room names are picked by people, so they could have commas or quotes in them
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+fn write_csv(rows: &[HourlyActivity], output: &mut impl Write) -> io::Result<()> {
+    writeln!(output, "room,hour,messages,speakers")?;
+    for row in rows {
+        writeln!(
+            output,
+            "{},{},{},{}",
+            csv_field(&row.room),
+            row.hour,
+            row.messages,
+            row.speakers
+        )?;
+    }
+
+    Ok(())
+}
+
+// Per-hour activity for every day from first through last (both included), for one room or all of them.  This is meant
+// for building heatmaps of when a community is around, so only chat counts, not joins or notices.
+pub fn export(
+    storage: &Storage,
+    room: Option<&str>,
+    first: NaiveDate,
+    last: NaiveDate,
+    format: ExportFormat,
+    output: &mut impl Write,
+) -> Result<(), ExportError> {
+    let end = last.succ_opt().unwrap_or(last);
+    let rows = storage
+        .hourly_activity(room, start_of(first), start_of(end))
+        .map_err(ExportError::History)?;
+
+    match format {
+        ExportFormat::Csv => write_csv(&rows, output).map_err(ExportError::Io),
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *output, &rows).map_err(ExportError::Json)?;
+            writeln!(output).map_err(ExportError::Io)
+        }
+    }
+}
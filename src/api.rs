@@ -0,0 +1,320 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tracing::info;
+use tracing::warn;
+
+use crate::rate_limit::TokenBucket;
+use crate::storage::HistoryEntry;
+
+// A small HTTP API, for things that want to talk to a room without speaking the chat protocol: a CI job saying a
+// deploy is done, monitoring posting an alert, something keeping a copy of the history.  There are two calls:
+//
+//     POST /rooms/lobby/messages                    {"text": "Deployed v1.2"}
+//     GET  /rooms/lobby/messages?since=1700000000000
+//
+// Both need a bot account that posts to the room (see /bot in chat_server.rs), sent as
+// "Authorization: Bearer <name>:<token>".  Posting is chat from the bot, tagged like anything a bot says, with each
+// line of text a message of its own, and held to the same rate limit as a connection.  Reading hands back the history
+// after since (milliseconds since the unix epoch, or from the start if it's left out), oldest first, and at most
+// MAX_MESSAGES at a time, so whatever's polling asks again from the time of the last one it got.
+//
+// Like the metrics it's plain HTTP on its own port, answered one request at a time, which is plenty for what it's for.
+// Anything reaching it from outside the machine should come through a proxy doing TLS, since tokens go by in the clear.
+
+// The most messages one GET hands back
+const MAX_MESSAGES: usize = 100;
+
+// Nobody needs more than this to ask for something, or to post a few lines
+const MAX_HEADERS: usize = 8 * 1024;
+const MAX_BODY: usize = 16 * 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Why a request didn't work, which becomes the status and a short explanation in the response
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    TooManyRequests(String),
+    Unavailable(String),
+}
+
+impl ApiError {
+    fn status(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "400 Bad Request",
+            ApiError::Unauthorized(_) => "401 Unauthorized",
+            ApiError::Forbidden(_) => "403 Forbidden",
+            ApiError::NotFound(_) => "404 Not Found",
+            ApiError::TooManyRequests(_) => "429 Too Many Requests",
+            ApiError::Unavailable(_) => "503 Service Unavailable",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::TooManyRequests(message)
+            | ApiError::Unavailable(message) => message,
+        }
+    }
+}
+
+// What the API needs from the server, which the server's context provides.  Everything here is about one room, and
+// it's up to the server to say whether there is one.
+pub trait Rooms: Send + Sync {
+    // Whether name is a bot with that token, allowed to post to the room
+    fn authorize(&self, room: &str, name: &str, token: &str) -> Result<(), ApiError>;
+
+    // One line of chat from the bot, on its way to the room
+    fn post(&self, room: &str, name: &str, line: &str) -> Result<(), ApiError>;
+
+    fn history(
+        &self,
+        room: &str,
+        since: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, ApiError>;
+}
+
+// The limits a connection would have, for a bot posting through the API
+pub struct Limits {
+    pub max_message_bytes: usize,
+    pub messages_per_second: f64,
+    pub burst: u32,
+}
+
+// Answers requests on listener until the process ends, on a thread of its own
+pub fn serve(listener: TcpListener, rooms: Arc<dyn Rooms>, limits: Limits) {
+    thread::spawn(move || {
+        // Each bot's bucket lasts as long as we do, so asking again doesn't start it off full
+        let mut buckets = HashMap::new();
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &*rooms, &limits, &mut buckets));
+            if let Err(err) = result {
+                warn!("API request failed: {}", err);
+            }
+        }
+    });
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct Post {
+    text: String,
+}
+
+fn respond(
+    mut stream: TcpStream,
+    rooms: &dyn Rooms,
+    limits: &Limits,
+    buckets: &mut HashMap<String, TokenBucket>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let (status, body) = match read_request(&mut stream) {
+        Ok(Some(request)) => match handle(&request, rooms, limits, buckets) {
+            Ok(body) => ("200 OK", body),
+            Err(err) => (err.status(), json!({ "error": err.message() })),
+        },
+        Ok(None) => (
+            "400 Bad Request",
+            json!({ "error": "That isn't an HTTP request we can read" }),
+        ),
+        Err(err) => return Err(err),
+    };
+
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn handle(
+    request: &Request,
+    rooms: &dyn Rooms,
+    limits: &Limits,
+    buckets: &mut HashMap<String, TokenBucket>,
+) -> Result<serde_json::Value, ApiError> {
+    let room = request
+        .path
+        .strip_prefix("/rooms/")
+        .and_then(|rest| rest.strip_suffix("/messages"))
+        .filter(|room| !room.is_empty() && !room.contains('/'))
+        .ok_or_else(|| ApiError::NotFound(String::from("Not found, try /rooms/<room>/messages")))?;
+
+    let (name, token) = request
+        .authorization
+        .as_deref()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .and_then(|credentials| credentials.trim().split_once(':'))
+        .ok_or_else(|| {
+            ApiError::Unauthorized(String::from(
+                "This needs a bot's token, as Authorization: Bearer <name>:<token>",
+            ))
+        })?;
+    rooms.authorize(room, name, token)?;
+
+    match request.method.as_str() {
+        "GET" => {
+            let since = match query_value(&request.query, "since") {
+                Some(since) => match since.parse::<u64>() {
+                    Ok(millis) => UNIX_EPOCH + Duration::from_millis(millis),
+                    Err(_) => {
+                        return Err(ApiError::BadRequest(String::from(
+                            "since has to be milliseconds since the unix epoch",
+                        )))
+                    }
+                },
+                None => UNIX_EPOCH,
+            };
+            let messages = rooms.history(room, since, MAX_MESSAGES)?;
+            Ok(json!({ "messages": messages }))
+        }
+        "POST" => {
+            let post: Post = serde_json::from_slice(&request.body).map_err(|err| {
+                ApiError::BadRequest(format!("The body has to be {{\"text\": \"...\"}}: {}", err))
+            })?;
+            let lines: Vec<&str> = post
+                .text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect();
+            if lines.is_empty() {
+                return Err(ApiError::BadRequest(String::from(
+                    "There's no text to post",
+                )));
+            }
+            if lines
+                .iter()
+                .any(|line| line.len() > limits.max_message_bytes)
+            {
+                return Err(ApiError::BadRequest(format!(
+                    "A line is too long, the limit is {} bytes",
+                    limits.max_message_bytes
+                )));
+            }
+
+            // All of it or none of it, so a post is never cut off halfway
+            let bucket = buckets
+                .entry(name.to_lowercase())
+                .or_insert_with(|| TokenBucket::new(limits.messages_per_second, limits.burst));
+            if bucket.available() < lines.len() {
+                return Err(ApiError::TooManyRequests(String::from(
+                    "Posting too fast, slow down",
+                )));
+            }
+            bucket.spend(lines.len());
+
+            for line in &lines {
+                rooms.post(room, name, line)?;
+            }
+            info!(
+                bot = name,
+                room,
+                lines = lines.len(),
+                "Posted through the API"
+            );
+            Ok(json!({ "posted": lines.len() }))
+        }
+        _ => Err(ApiError::BadRequest(String::from(
+            "Only GET and POST are supported",
+        ))),
+    }
+}
+
+// Reads one request, headers and body.  None if it isn't something we can make sense of.
+fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    let end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > MAX_HEADERS {
+            return Ok(None);
+        }
+        match stream.read(&mut chunk)? {
+            0 => return Ok(None),
+            read => buffer.extend_from_slice(&chunk[..read]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Ok(None),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    // Header names are case insensitive, and we only need two of them
+    let mut authorization = None;
+    let mut content_length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            match name.trim().to_lowercase().as_str() {
+                "authorization" => authorization = Some(String::from(value.trim())),
+                "content-length" => match value.trim().parse() {
+                    Ok(length) if length <= MAX_BODY => content_length = length,
+                    _ => return Ok(None),
+                },
+                _ => {}
+            }
+        }
+    }
+
+    let mut body = buffer.split_off(end + 4);
+    if body.len() < content_length {
+        let mut rest = vec![0; content_length - body.len()];
+        stream.read_exact(&mut rest)?;
+        body.extend_from_slice(&rest);
+    }
+    body.truncate(content_length);
+
+    Ok(Some(Request {
+        method: String::from(method),
+        path: String::from(path),
+        query: String::from(query),
+        authorization,
+        body,
+    }))
+}
+
+// The value of one parameter in a query string like "since=123&other=x".  Nothing we take needs decoding.
+fn query_value<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
@@ -0,0 +1,605 @@
+use std::io;
+
+use crate::config::ServerConfig;
+
+// An alternative to ChatServer for a lot of people at once.  ChatServer ties up a thread for every client, so the pool
+// size caps how many can be connected.  This one runs each client as a task on tokio and fans the room out through a
+// tokio broadcast channel, so an idle connection costs a little memory rather than a thread.  It's picked with
+// "server --async", in a build with the "async" feature.
+//
+// It speaks the same protocol, so the same client works with either, but for now it only covers the core of the chat:
+// the handshake, picking and changing names, talking, /who, answering pings and /quit.  Accounts, moderation, history
+// and the rest of ChatServer's features aren't here yet, and anyone who tries them is told so.  Bans, registered names
+// and the rate limit are still respected, since otherwise switching servers would be a way round them.
+//
+// Like TLS, the feature is off by default so a plain build doesn't need tokio.  Without it run only returns an error.
+
+#[cfg(feature = "async")]
+pub use enabled::*;
+
+#[cfg(not(feature = "async"))]
+pub use disabled::*;
+
+#[cfg(feature = "async")]
+mod enabled {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::SystemTime;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+    use tokio::sync::broadcast;
+    use tokio::sync::watch;
+    use tokio::task::JoinSet;
+    use tracing::debug;
+    use tracing::info;
+    use tracing::info_span;
+    use tracing::warn;
+    use tracing::Instrument;
+
+    use crate::accounts::AccountStore;
+    use crate::bans::BanList;
+    use crate::chat_server::ROOM_NAME;
+    use crate::fanout::Overflow;
+    use crate::listener;
+    use crate::mentions;
+    use crate::names::NamePolicy;
+    use crate::protocol;
+    use crate::protocol::Capabilities;
+    use crate::protocol::Kicked;
+    use crate::protocol::LineReader;
+    use crate::protocol::LineTooLong;
+    use crate::protocol::Mention;
+    use crate::protocol::MentionKind;
+    use crate::protocol::Notice;
+    use crate::protocol::NoticeKind;
+    use crate::protocol::Roster;
+    use crate::protocol::Timestamped;
+    use crate::protocol::BOT_TAG;
+    use crate::protocol::PONG_COMMAND;
+    use crate::rate_limit::TokenBucket;
+    use crate::state::Command;
+    use crate::state::ConnectionState;
+
+    // What goes through the room, which is only what the commands here can cause
+    enum Event {
+        // Mentioned is who it names with an @, lowercased, worked out once rather than by everyone it goes to
+        Chat {
+            sender: String,
+            body: String,
+            mentioned: Vec<String>,
+        },
+        Presence(String),
+        // Someone thrown out, which stands in for their "has left"
+        Removed(String),
+    }
+
+    // Each one is sent out in an Arc, so the broadcast channel hands every client the same message rather than a copy
+    struct RoomEvent {
+        event: Event,
+        sent: SystemTime,
+    }
+
+    // Everything the tasks share, like ChatServer's ServerContext but with a lot less in it
+    struct Shared {
+        config: ServerConfig,
+        names: NamePolicy,
+        accounts: AccountStore,
+        bans: BanList,
+        // Everyone who has picked a name, by connection id
+        online: Mutex<HashMap<u64, String>>,
+        connected: AtomicUsize,
+        room: broadcast::Sender<Arc<RoomEvent>>,
+    }
+
+    impl Shared {
+        // Takes the name for this connection, unless someone else already has it in any mix of upper and lower case
+        fn claim_name(&self, id: u64, name: &str) -> bool {
+            let mut online = self.online.lock().unwrap();
+            let taken = online
+                .iter()
+                .any(|(other, user)| *other != id && user.eq_ignore_ascii_case(name));
+            if !taken {
+                online.insert(id, String::from(name));
+            }
+            !taken
+        }
+
+        fn online(&self) -> Vec<String> {
+            let mut names: Vec<String> = self.online.lock().unwrap().values().cloned().collect();
+            names.sort_unstable_by_key(|name| name.to_lowercase());
+            names
+        }
+
+        // Sending only fails when nobody is subscribed, and then there's nobody to tell
+        fn broadcast(&self, event: Event) {
+            let event = RoomEvent {
+                event,
+                sent: SystemTime::now(),
+            };
+            self.room.send(Arc::new(event)).ok();
+        }
+    }
+
+    // One connection, like ChatServer's Session.  Whatever we owe them collects in output, which the task writes out
+    // after everything it handles.
+    struct Client {
+        id: u64,
+        user: String,
+        state: ConnectionState,
+        capabilities: Capabilities,
+        prefix: char,
+        output: String,
+        // Only there once they've joined the room, which here is as soon as they have a name
+        room: Option<broadcast::Receiver<Arc<RoomEvent>>>,
+        // How fast they're allowed to send us lines, and how many they've sent over the limit since the last one that
+        // got through
+        rate_limit: TokenBucket,
+        dropped: u32,
+        // What the room is told instead of "has left", if we threw them out
+        removed: Option<String>,
+    }
+
+    impl Client {
+        fn push_line(&mut self, line: &str) {
+            self.output.push_str(line);
+            self.output.push('\n');
+        }
+
+        fn notice(&mut self, text: impl Into<String>) {
+            self.send_notice(NoticeKind::Info, text);
+        }
+
+        fn error(&mut self, text: impl Into<String>) {
+            self.send_notice(NoticeKind::Error, text);
+        }
+
+        fn send_notice(&mut self, kind: NoticeKind, text: impl Into<String>) {
+            let notice = Notice {
+                kind,
+                text: text.into(),
+            };
+            let line = if self.capabilities.notices {
+                notice.to_line()
+            } else {
+                notice.to_plain()
+            };
+            self.push_line(&line);
+        }
+
+        // Adds a message from the room, looking just the way ChatServer would send it
+        fn queue(&mut self, room_event: &RoomEvent) {
+            let typed = self.capabilities.notices;
+            let line = match &room_event.event {
+                Event::Presence(_) if self.capabilities.lite => return,
+                Event::Presence(text) => {
+                    let notice = Notice {
+                        kind: NoticeKind::Presence,
+                        text: text.clone(),
+                    };
+                    if typed {
+                        notice.to_line()
+                    } else {
+                        notice.to_plain()
+                    }
+                }
+                Event::Removed(text) => {
+                    let notice = Notice {
+                        kind: NoticeKind::Moderation,
+                        text: text.clone(),
+                    };
+                    if typed {
+                        notice.to_line()
+                    } else {
+                        notice.to_plain()
+                    }
+                }
+                Event::Chat {
+                    sender,
+                    body,
+                    mentioned,
+                } if typed
+                    && !sender.eq_ignore_ascii_case(&self.user)
+                    && mentioned
+                        .iter()
+                        .any(|name| name.eq_ignore_ascii_case(&self.user)) =>
+                {
+                    Mention {
+                        kind: MentionKind::Direct,
+                        sender: sender.clone(),
+                        body: body.clone(),
+                    }
+                    .to_line()
+                }
+                Event::Chat { sender, body, .. } => format!("{}: {}", sender, body),
+            };
+
+            if self.capabilities.timestamps {
+                let time = room_event.sent;
+                self.push_line(&Timestamped { time, line }.to_line());
+            } else {
+                self.push_line(&line);
+            }
+        }
+
+        fn handle_line(&mut self, shared: &Shared, line: &str) {
+            let command = Command::parse(line, self.prefix);
+            if let Err(err) = self.state.check(&command) {
+                self.error(err.describe(self.prefix));
+                return;
+            }
+
+            match command {
+                Command::Caps(list) => {
+                    self.capabilities = Capabilities::parse(list);
+                    match self.capabilities.prefix {
+                        Some(prefix) if protocol::is_valid_prefix(prefix) => self.prefix = prefix,
+                        Some(prefix) => self.error(format!(
+                            "{} can't be a command prefix, using {}",
+                            prefix, self.prefix
+                        )),
+                        None => {}
+                    }
+                    self.state = ConnectionState::Handshaking;
+                }
+                Command::User(name) => self.rename(shared, "user", name),
+                Command::Nick(name) => self.rename(shared, "nick", name),
+                Command::Who(room) if !room.is_empty() && room != ROOM_NAME => self.error(format!(
+                    "There's no room called {}, only {}",
+                    room, ROOM_NAME
+                )),
+                Command::Who(_) => {
+                    let names = shared.online();
+                    self.notice(format!(
+                        "In {} ({}): {}",
+                        ROOM_NAME,
+                        names.len(),
+                        names.join(", ")
+                    ));
+                }
+                Command::Ping => self.push_line(PONG_COMMAND),
+                Command::Pong => {}
+                Command::Quit => self.state = ConnectionState::Closing,
+                Command::Say("") => self.error(format!("Usage: {}say <message>", self.prefix)),
+                Command::Say(message) | Command::Chat(message) => self.chat(shared, message),
+                // There are no plugins here, so a command we don't know is only ever chat
+                Command::Other(..) => self.chat(shared, line),
+                command => {
+                    if let Some(name) = command.name() {
+                        self.error(format!(
+                            "{}{} isn't available on this server",
+                            self.prefix, name
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Nobody can log in here, so nobody is a bot, and the tag that marks bots' chat is refused like it is on
+        // ChatServer.  Otherwise anyone could pass for a bot just by typing it.
+        fn chat(&mut self, shared: &Shared, message: &str) {
+            if message.starts_with(BOT_TAG) {
+                self.error(format!("Only bots can start a message with {}", BOT_TAG));
+                return;
+            }
+            shared.broadcast(Event::Chat {
+                sender: self.user.clone(),
+                body: String::from(message),
+                mentioned: mentions::mentioned(message),
+            });
+        }
+
+        // The first name gets them into the room, after that it's a change of name
+        fn rename(&mut self, shared: &Shared, command: &str, name: &str) {
+            if name.is_empty() {
+                self.error(format!("Usage: {}{} <name>", self.prefix, command));
+                return;
+            }
+            if let Err(err) = shared.names.check(name) {
+                self.error(err.to_string());
+                return;
+            }
+            if name == self.user {
+                self.error(format!("You're already {}", name));
+                return;
+            }
+            if shared.bans.is_nick_banned(name) {
+                self.error(format!("{} is banned from this server", name));
+                return;
+            }
+            // There's no logging in here, so nobody gets to be a registered name at all
+            if shared.accounts.is_registered(name) {
+                self.error(format!(
+                    "{} is a registered name, and logging in isn't available on this server",
+                    name
+                ));
+                return;
+            }
+            if !shared.claim_name(self.id, name) {
+                self.error(format!("Someone called {} is already here", name));
+                return;
+            }
+
+            let previous = std::mem::replace(&mut self.user, String::from(name));
+            if self.state == ConnectionState::InRoom {
+                shared.broadcast(Event::Presence(format!(
+                    "{} is now known as {}.",
+                    previous, name
+                )));
+                return;
+            }
+
+            // Subscribing before the join goes out means they see their own arrival
+            self.room = Some(shared.room.subscribe());
+            self.state = ConnectionState::InRoom;
+            shared.broadcast(Event::Presence(format!("{} has joined the room.", name)));
+            if let Some(motd) = &shared.config.motd {
+                self.send_notice(NoticeKind::Motd, motd.as_str());
+            }
+        }
+    }
+
+    // The next message from the room, or never if they haven't joined yet
+    async fn next_event(
+        room: &mut Option<broadcast::Receiver<Arc<RoomEvent>>>,
+    ) -> Result<Arc<RoomEvent>, broadcast::error::RecvError> {
+        match room {
+            Some(room) => room.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn serve_client(
+        shared: Arc<Shared>,
+        id: u64,
+        stream: TcpStream,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let (mut reader, mut writer) = stream.into_split();
+        let mut buffer = vec![0; 4096];
+        let mut lines = LineReader::with_max_line(shared.config.max_message_bytes);
+        let mut client = Client {
+            id,
+            user: String::new(),
+            state: ConnectionState::Connected,
+            capabilities: Capabilities::default(),
+            prefix: shared.config.command_prefix,
+            output: String::new(),
+            room: None,
+            rate_limit: TokenBucket::new(
+                shared.config.rate_limit.messages_per_second,
+                shared.config.rate_limit.burst,
+            ),
+            dropped: 0,
+            removed: None,
+        };
+
+        loop {
+            tokio::select! {
+                read = reader.read(&mut buffer) => {
+                    let bytes_read = match read {
+                        Ok(0) | Err(_) => break,
+                        Ok(bytes_read) => bytes_read,
+                    };
+                    lines.push(&buffer[..bytes_read]);
+                    while let Some(line) = lines.next_line() {
+                        let line = match line {
+                            Ok(line) => line,
+                            Err(LineTooLong) => {
+                                client.error(format!(
+                                    "Message too long, the limit is {} bytes",
+                                    shared.config.max_message_bytes
+                                ));
+                                continue;
+                            }
+                        };
+                        if client.rate_limit.try_take() {
+                            client.dropped = 0;
+                            client.handle_line(&shared, line.trim());
+                            continue;
+                        }
+
+                        // Over the limit, the same as ChatServer: the line goes nowhere, they're warned once, and if
+                        // they keep it up they're gone
+                        client.dropped += 1;
+                        if client.dropped == 1 {
+                            warn!("Throttled");
+                            client.error("You're sending messages too fast, slow down");
+                        }
+                        let disconnect_after = shared.config.rate_limit.disconnect_after;
+                        if disconnect_after > 0 && client.dropped >= disconnect_after {
+                            warn!("Disconnected for flooding");
+                            client.removed = Some(format!("{} was disconnected for flooding", client.user));
+                            let reason = String::from("You have been disconnected for flooding");
+                            client.push_line(&Kicked { reason }.to_line());
+                            client.state = ConnectionState::Closing;
+                            break;
+                        }
+                    }
+                }
+                event = next_event(&mut client.room) => match event {
+                    Ok(event) => {
+                        client.queue(&event);
+                        // Everyone here is in the room as soon as they have a name, so online is the roster
+                        if client.capabilities.roster
+                            && matches!(event.event, Event::Presence(_) | Event::Removed(_))
+                        {
+                            let roster = Roster {
+                                names: shared.online(),
+                            };
+                            client.push_line(&roster.to_line());
+                        }
+                    }
+                    // The channel keeps the newest queue_size messages for everyone, so a client that falls further
+                    // behind than that has already lost the oldest ones.  All that's left to decide is whether they
+                    // stay.
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        match shared.config.broadcast.slow_clients {
+                            Overflow::DropOldest => {
+                                debug!(dropped = missed, "Fell behind");
+                                client.error(format!("You fell behind and missed {} message(s)", missed));
+                            }
+                            Overflow::Disconnect => {
+                                warn!("Disconnected for falling behind");
+                                let reason = String::from("You have been disconnected for falling behind");
+                                client.push_line(&Kicked { reason }.to_line());
+                                client.state = ConnectionState::Closing;
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = shutdown.changed() => {
+                    client.send_notice(NoticeKind::Info, "The server is shutting down");
+                    client.state = ConnectionState::Closing;
+                }
+            }
+
+            if !client.output.is_empty() {
+                let output = std::mem::take(&mut client.output);
+                if writer.write_all(output.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            if client.state == ConnectionState::Closing {
+                writer.shutdown().await.ok();
+                break;
+            }
+        }
+
+        // However they went, the room hears about it once
+        if shared.online.lock().unwrap().remove(&id).is_some() {
+            match client.removed.take() {
+                Some(notice) => shared.broadcast(Event::Removed(notice)),
+                None => shared.broadcast(Event::Presence(format!(
+                    "{} has left the room.",
+                    client.user
+                ))),
+            }
+        }
+        shared.connected.fetch_sub(1, Ordering::SeqCst);
+        info!("Disconnected");
+    }
+
+    // Turns away someone we won't serve, with the reason as the only thing they hear from us
+    async fn reject(mut stream: TcpStream, address: SocketAddr, reason: &str) {
+        let goodbye = format!(
+            "{}\n",
+            Kicked {
+                reason: String::from(reason)
+            }
+            .to_line()
+        );
+        if let Err(err) = stream.write_all(goodbye.as_bytes()).await {
+            debug!(peer = %address, "Unable to say goodbye: {}", err);
+        }
+        stream.shutdown().await.ok();
+    }
+
+    async fn serve(shared: Arc<Shared>, listener: TcpListener) {
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let mut clients = JoinSet::new();
+        let mut next_id = 0;
+
+        // Made once, so a Ctrl-C that arrives between two accepts isn't missed
+        let ctrl_c = tokio::signal::ctrl_c();
+        tokio::pin!(ctrl_c);
+
+        loop {
+            let (stream, address) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Unable to accept: {}", err);
+                        continue;
+                    }
+                },
+                _ = &mut ctrl_c => break,
+            };
+
+            if !shared.config.access.permits(address.ip()) {
+                debug!("Not allowed by access list, dropping {}", address);
+                continue;
+            }
+            if shared.bans.is_ip_banned(address.ip()) {
+                info!("Banned, rejecting {}", address);
+                tokio::spawn(reject(stream, address, "You are banned from this server"));
+                continue;
+            }
+            let max_clients = shared.config.async_server.max_clients;
+            if shared.connected.load(Ordering::SeqCst) >= max_clients {
+                warn!("Server full, rejecting {}", address);
+                tokio::spawn(reject(
+                    stream,
+                    address,
+                    "The server is full, try again later",
+                ));
+                continue;
+            }
+
+            next_id += 1;
+            shared.connected.fetch_add(1, Ordering::SeqCst);
+            let span = info_span!("client", id = next_id, peer = %address);
+            span.in_scope(|| info!("Connected"));
+            clients.spawn(
+                serve_client(shared.clone(), next_id, stream, shutdown.clone()).instrument(span),
+            );
+        }
+
+        // Everyone is told why before we go, the same as ChatServer does
+        info!("Shutting down");
+        shutdown_sender.send(true).ok();
+        while clients.join_next().await.is_some() {}
+    }
+
+    pub fn run(config: ServerConfig) -> io::Result<()> {
+        // Better to refuse than to quietly serve in the clear to clients expecting TLS
+        if config.tls.is_some() {
+            return Err(io::Error::other(
+                "TLS isn't available with --async yet, leave out [tls] to use it",
+            ));
+        }
+        let accounts = AccountStore::load(&config.accounts_path)?;
+        let bans = BanList::load(&config.banlist_path)?;
+        // Retrying a taken port sleeps, which is fine here but not once we're inside the runtime
+        let listener = listener::bind(&config).map_err(|err| io::Error::other(err.to_string()))?;
+        listener.set_nonblocking(true)?;
+        let runtime = tokio::runtime::Runtime::new()?;
+
+        runtime.block_on(async {
+            let listener = TcpListener::from_std(listener)?;
+            info!(address = %listener.local_addr()?, "Listening (async)");
+
+            let (room, _) = broadcast::channel(config.broadcast.queue_size);
+            let shared = Arc::new(Shared {
+                names: NamePolicy::new(&config.names),
+                config,
+                accounts,
+                bans,
+                online: Mutex::new(HashMap::new()),
+                connected: AtomicUsize::new(0),
+                room,
+            });
+            serve(shared, listener).await;
+
+            Ok::<(), io::Error>(())
+        })
+    }
+}
+
+#[cfg(not(feature = "async"))]
+mod disabled {
+    use super::*;
+
+    pub fn run(_config: ServerConfig) -> io::Result<()> {
+        Err(io::Error::other(
+            "Async support was not compiled in, rebuild with --features async",
+        ))
+    }
+}
@@ -0,0 +1,200 @@
+use chrono::SecondsFormat;
+use chrono::Utc;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+// How each failure is written to the auth log
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthLogFormat {
+    // One plain line, easy to match with a fail2ban regex like "authentication failure for .* from <HOST>"
+    Text,
+    // One JSON object per line, for log shippers
+    Json,
+}
+
+// What somebody got wrong
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Failure {
+    Password,
+    TwoFactorCode,
+    RecoveryToken,
+    // They tried again while locked out.  It's logged, so fail2ban can see them keep at it, but doesn't count again.
+    LockedOut,
+}
+
+impl Failure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Failure::Password => "password",
+            Failure::TwoFactorCode => "two_factor_code",
+            Failure::RecoveryToken => "recovery_token",
+            Failure::LockedOut => "locked_out",
+        }
+    }
+}
+
+pub fn format_line(format: AuthLogFormat, address: IpAddr, user: &str, failure: Failure) -> String {
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    match format {
+        AuthLogFormat::Text => format!(
+            "{} chat_server: authentication failure for {} from {} ({})",
+            time,
+            user,
+            address,
+            failure.as_str()
+        ),
+        AuthLogFormat::Json => serde_json::json!({
+            "time": time,
+            "event": "authentication_failure",
+            "user": user,
+            "address": address.to_string(),
+            "reason": failure.as_str(),
+        })
+        .to_string(),
+    }
+}
+
+// The file is opened again for every line rather than held open, so logrotate can move it out from under us without
+// anyone having to tell the server.  This touches the disk, so call it from the blocking pool.
+pub fn append(path: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+// Recent failures from one address or against one name
+#[derive(Default)]
+struct Failures {
+    recent: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn locked_for(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
+
+#[derive(Default)]
+struct Tracked {
+    addresses: HashMap<IpAddr, Failures>,
+    // Lowercased, like account names
+    names: HashMap<String, Failures>,
+}
+
+// Keeps out whoever's guessing.  Too many failures inside the window from one address, or against one name from
+// anywhere, and that address or name can't try again until the lockout runs out.  Locking the name as well means
+// spreading the guesses over lots of addresses doesn't help, at the cost of letting someone lock a real user out for
+// a while.
+pub struct Lockouts {
+    max_failures: usize,
+    window: Duration,
+    lockout: Duration,
+    tracked: Mutex<Tracked>,
+}
+
+impl Lockouts {
+    // Zero max_failures never locks anybody out
+    pub fn new(max_failures: u32, window: Duration, lockout: Duration) -> Lockouts {
+        Lockouts {
+            max_failures: max_failures as usize,
+            window,
+            lockout,
+            tracked: Mutex::new(Tracked::default()),
+        }
+    }
+
+    // How much longer this address or name is locked out for, whichever is longer
+    pub fn locked_for(&self, address: IpAddr, name: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let tracked = self.tracked.lock().unwrap();
+        let address = tracked
+            .addresses
+            .get(&address)
+            .and_then(|failures| failures.locked_for(now));
+        let name = tracked
+            .names
+            .get(&name.to_lowercase())
+            .and_then(|failures| failures.locked_for(now));
+
+        address.max(name)
+    }
+
+    // Counts a failure against both, and says whether that was the one that locked either of them out
+    pub fn fail(&self, address: IpAddr, name: &str) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut tracked = self.tracked.lock().unwrap();
+
+        // Anything that's neither locked nor failed lately is forgotten, so this doesn't grow forever
+        let window = self.window;
+        let keep = |failures: &mut Failures| {
+            while failures
+                .recent
+                .front()
+                .is_some_and(|failed| now.duration_since(*failed) > window)
+            {
+                failures.recent.pop_front();
+            }
+            failures.locked_for(now).is_some() || !failures.recent.is_empty()
+        };
+        tracked.addresses.retain(|_, failures| keep(failures));
+        tracked.names.retain(|_, failures| keep(failures));
+
+        let address = self.count(tracked.addresses.entry(address).or_default(), now);
+        let name = self.count(tracked.names.entry(name.to_lowercase()).or_default(), now);
+
+        address || name
+    }
+
+    fn count(&self, failures: &mut Failures, now: Instant) -> bool {
+        failures.recent.push_back(now);
+        if failures.recent.len() < self.max_failures {
+            return false;
+        }
+
+        failures.recent.clear();
+        failures.locked_until = Some(now + self.lockout);
+        true
+    }
+
+    // How many addresses and names are locked out right now
+    pub fn locked(&self) -> (usize, usize) {
+        let now = Instant::now();
+        let tracked = self.tracked.lock().unwrap();
+        let count = |failures: &Failures| failures.locked_for(now).is_some();
+        (
+            tracked
+                .addresses
+                .values()
+                .filter(|failures| count(failures))
+                .count(),
+            tracked
+                .names
+                .values()
+                .filter(|failures| count(failures))
+                .count(),
+        )
+    }
+
+    // Getting it right wipes the slate for both
+    pub fn succeed(&self, address: IpAddr, name: &str) {
+        let mut tracked = self.tracked.lock().unwrap();
+        tracked.addresses.remove(&address);
+        tracked.names.remove(&name.to_lowercase());
+    }
+}
@@ -0,0 +1,134 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// One entry in the ban list.  Banning someone who's connected takes both their name and their address, so they can't
+// just come back under another name, while banning an address (or a name that isn't here right now) only has the one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ban {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nick: Option<String>,
+    pub by: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl Ban {
+    // Whether /unban <target> means this one, by address or by name
+    fn matches(&self, target: &str) -> bool {
+        let ip = target.parse::<IpAddr>().ok();
+        (ip.is_some() && self.ip == ip)
+            || self
+                .nick
+                .as_ref()
+                .is_some_and(|nick| nick.eq_ignore_ascii_case(target))
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.nick, &self.ip) {
+            (Some(nick), Some(ip)) => write!(f, "{} ({})", nick, ip)?,
+            (Some(nick), None) => write!(f, "{}", nick)?,
+            (None, Some(ip)) => write!(f, "{}", ip)?,
+            (None, None) => write!(f, "nobody")?,
+        }
+        write!(f, ", banned by {}", self.by)?;
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The layout of the ban list on disk.  Bans are kept in the order they were made.
+#[derive(Serialize, Deserialize, Default)]
+struct BanFile {
+    #[serde(default)]
+    bans: Vec<Ban>,
+}
+
+// Banned addresses and names, kept in memory and written out to a TOML file whenever they change.  This works just like
+// the AccountStore, and is checked for every connection we accept.
+pub struct BanList {
+    path: PathBuf,
+    bans: Mutex<Vec<Ban>>,
+    // Only one save at a time, so two quick bans can't interleave their writes
+    save_lock: Mutex<()>,
+}
+
+impl BanList {
+    // A missing file just means nobody has been banned yet
+    pub fn load(path: impl AsRef<Path>) -> io::Result<BanList> {
+        let path = path.as_ref().to_path_buf();
+        let file = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BanFile::default(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(BanList {
+            path,
+            bans: Mutex::new(file.bans),
+            save_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.bans
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|ban| ban.ip == Some(ip))
+    }
+
+    pub fn is_nick_banned(&self, name: &str) -> bool {
+        self.bans.lock().unwrap().iter().any(|ban| {
+            ban.nick
+                .as_ref()
+                .is_some_and(|nick| nick.eq_ignore_ascii_case(name))
+        })
+    }
+
+    pub fn add(&self, ban: Ban) {
+        self.bans.lock().unwrap().push(ban);
+    }
+
+    // Lifts every ban on the address or name, and says how many there were
+    pub fn remove(&self, target: &str) -> usize {
+        let mut bans = self.bans.lock().unwrap();
+        let before = bans.len();
+        bans.retain(|ban| !ban.matches(target));
+        before - bans.len()
+    }
+
+    pub fn list(&self) -> Vec<Ban> {
+        self.bans.lock().unwrap().clone()
+    }
+
+    // Same as AccountStore::save, a temporary file renamed over the old one.  This touches the disk, so call it from the
+    // blocking pool.
+    pub fn save(&self) -> io::Result<()> {
+        let _saving = self.save_lock.lock().unwrap();
+
+        let file = BanFile {
+            bans: self.bans.lock().unwrap().clone(),
+        };
+        let contents = toml::to_string(&file)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+use std::time::Instant;
+
+// Collects outgoing lines for one connection so several of them can go out in a single write.  In a busy room this
+// turns a pile of tiny writes (a syscall each, and a TCP packet each) into one.  Since every message is its own line
+// the client can't tell the difference, it just finds more than one line in a read.
+//
+// Two budgets keep batching from getting in the way: once the batch is max_bytes it goes out no matter what, and no
+// line waits longer than max_delay for company.  A max_delay of zero turns batching off.
+//
+// Whatever the limits, the batch only goes out once the last one has been written, so a connection that can't keep up
+// still ends up with bigger writes instead of a pile of blocked small ones.
+pub struct Batch {
+    buffer: Vec<u8>,
+    started: Option<Instant>,
+    max_bytes: usize,
+    max_delay: Duration,
+}
+
+impl Batch {
+    pub fn new(max_bytes: usize, max_delay: Duration) -> Batch {
+        Batch {
+            buffer: Vec::with_capacity(max_bytes),
+            started: None,
+            max_bytes,
+            max_delay,
+        }
+    }
+
+    pub fn set_limits(&mut self, max_bytes: usize, max_delay: Duration) {
+        self.max_bytes = max_bytes;
+        self.max_delay = max_delay;
+    }
+
+    pub fn push_line(&mut self, line: &str) {
+        if self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+
+        self.buffer.extend_from_slice(line.as_bytes());
+        self.buffer.push(b'\n');
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.buffer.len() >= self.max_bytes
+    }
+
+    // True when there's something to send and we've either run out of room or waited long enough
+    pub fn is_due(&self) -> bool {
+        match self.started {
+            Some(started) => self.is_full() || started.elapsed() >= self.max_delay,
+            None => false,
+        }
+    }
+
+    // How long until the batch is due, so whoever is waiting to send it knows how long they can sleep.  None when
+    // there's nothing in it.
+    pub fn due_in(&self) -> Option<Duration> {
+        let started = self.started?;
+        if self.is_full() {
+            return Some(Duration::ZERO);
+        }
+        Some(self.max_delay.saturating_sub(started.elapsed()))
+    }
+
+    // Hands back everything collected so far and starts a fresh batch
+    pub fn take(&mut self) -> Vec<u8> {
+        self.started = None;
+        std::mem::replace(&mut self.buffer, Vec::with_capacity(self.max_bytes))
+    }
+}
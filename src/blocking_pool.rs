@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tracing::error;
+use tracing::info;
+
+use crate::thread_pool::panic_message;
+use crate::thread_pool::Job;
+use crate::thread_pool::JobsPanicked;
+
+// How long an extra thread hangs around with nothing to do before it gives up and exits
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+struct State {
+    queue: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+    shutdown: bool,
+}
+
+// Everything the pool and its threads share.  The condvar is how an idle thread sleeps until there's a job (or until
+// we're shutting down), and also how drop waits for the last thread to leave.
+struct Shared {
+    state: Mutex<State>,
+    job_ready: Condvar,
+    thread_exited: Condvar,
+    panicked: JobsPanicked,
+}
+
+// The ThreadPool has a fixed number of workers, which is what we want for chat traffic: it keeps the room and the
+// client handlers from fighting over an unbounded number of threads.  Blocking IO (disk writes, DNS, webhooks) is the
+// opposite.  Those jobs spend nearly all their time waiting, and if a slow disk or a dead webhook endpoint ties up
+// the workers the chat stops.  So they get their own elastic pool instead, which keeps a few threads warm, grows when
+// every thread is stuck waiting, and shrinks back down once things calm down.
+pub struct BlockingPool {
+    shared: Arc<Shared>,
+    min_threads: usize,
+    max_threads: usize,
+}
+
+impl BlockingPool {
+    pub fn new(min_threads: usize, max_threads: usize) -> BlockingPool {
+        assert!(max_threads > 0 && min_threads <= max_threads);
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                threads: 0,
+                idle: 0,
+                shutdown: false,
+            }),
+            job_ready: Condvar::new(),
+            thread_exited: Condvar::new(),
+            panicked: JobsPanicked::default(),
+        });
+
+        let pool = BlockingPool {
+            shared,
+            min_threads,
+            max_threads,
+        };
+
+        {
+            let mut state = pool.shared.state.lock().unwrap();
+            for _ in 0..min_threads {
+                pool.spawn_thread(&mut state);
+            }
+        }
+
+        pool
+    }
+
+    // How many jobs have panicked, for the metrics
+    pub fn jobs_panicked(&self) -> JobsPanicked {
+        self.shared.panicked.clone()
+    }
+
+    pub fn execute<T>(&self, func: T)
+    where
+        T: FnOnce() + Send + 'static,
+    {
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.push_back(Box::new(func));
+
+        // Only grow if nobody is free to pick the job up.  Past the max the job just waits its turn in the queue.
+        if state.idle < state.queue.len() && state.threads < self.max_threads {
+            self.spawn_thread(&mut state);
+        }
+
+        self.shared.job_ready.notify_one();
+    }
+
+    // We count the thread while still holding the lock so two quick executes can't both decide to grow past the max
+    fn spawn_thread(&self, state: &mut State) {
+        state.threads += 1;
+
+        let shared = self.shared.clone();
+        let min_threads = self.min_threads;
+        thread::spawn(move || BlockingPool::run(shared, min_threads));
+    }
+
+    fn run(shared: Arc<Shared>, min_threads: usize) {
+        let mut state = shared.state.lock().unwrap();
+
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                // Never hold the lock while running a job, or the whole pool would be as slow as the slowest job.  A
+                // panic is caught the same as in the ThreadPool, since otherwise the thread would be gone without
+                // ever being taken off the count, and drop would wait for it forever.
+                drop(state);
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                    shared.panicked.record();
+                    error!("Blocking pool job panicked: {}", panic_message(&*payload));
+                }
+                state = shared.state.lock().unwrap();
+                continue;
+            }
+
+            if state.shutdown {
+                break;
+            }
+
+            state.idle += 1;
+            let (next, timeout) = shared.job_ready.wait_timeout(state, KEEP_ALIVE).unwrap();
+            state = next;
+            state.idle -= 1;
+
+            // Nothing showed up for a while, so give the thread back unless we're already down to our minimum
+            if timeout.timed_out() && state.queue.is_empty() && state.threads > min_threads {
+                break;
+            }
+        }
+
+        state.threads -= 1;
+        shared.thread_exited.notify_all();
+    }
+}
+
+// Like the ThreadPool, finish whatever has been queued and wait for every thread to exit when we leave scope
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.shutdown = true;
+        self.shared.job_ready.notify_all();
+
+        while state.threads > 0 {
+            state = self.shared.thread_exited.wait(state).unwrap();
+        }
+
+        info!("Blocking pool shut down");
+    }
+}
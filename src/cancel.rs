@@ -0,0 +1,29 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+// How whoever started a ChatServer or ChatClient tells it to stop.  Hand run() a clone and keep one, and cancel() it
+// from any thread when it's time.  Neither stops the instant it's cancelled, they notice the next time they check
+// (well under a second) and then shut down the same way they would for a Ctrl-C or a /quit.
+//
+// The binary cancels its token from a Ctrl-C handler.  Anything embedding the server or client decides for itself,
+// which is why they don't install a handler of their own.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    // Cancels every clone of this token.  Cancelling twice is the same as once.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
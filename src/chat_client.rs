@@ -1,16 +1,80 @@
+use chrono::DateTime;
+use chrono::Local;
+use chrono::TimeDelta;
 use popol::Events;
 use popol::Sources;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::net::TcpStream;
+use std::mem;
+use std::net::SocketAddr;
 use std::os::unix::prelude::AsRawFd;
-use std::process;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SendError;
+use std::sync::mpsc::TryRecvError;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::thread::JoinHandle;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::cancel::CancellationToken;
+use crate::config::ClientConfig;
+use crate::config::ConfigError;
+use crate::error::Error;
+use crate::exec;
+use crate::exec::Captured;
+use crate::files;
+use crate::files::Progress;
+use crate::happy_eyeballs;
+use crate::heartbeat::Heartbeat;
+use crate::line_editor::LineEditor;
+use crate::line_editor::Printer;
+use crate::line_editor::RawMode;
+use crate::line_editor::Typed;
+use crate::protocol;
+use crate::protocol::Capabilities;
+use crate::protocol::Direct;
+use crate::protocol::FileMessage;
+use crate::protocol::Kicked;
+use crate::protocol::LineReader;
+use crate::protocol::Mention;
+use crate::protocol::MentionKind;
+use crate::protocol::Notice;
+use crate::protocol::NoticeKind;
+use crate::protocol::Roster;
+use crate::protocol::Timestamped;
+use crate::protocol::BOT_TAG;
+use crate::protocol::PING_COMMAND;
+use crate::protocol::PONG_COMMAND;
+use crate::rate_limit::TokenBucket;
+use crate::srv;
+use crate::tls::TlsConnector;
+use crate::transport::Stream;
+use crate::tui;
+
+// How long we wait before trying to get back in after the connection drops.  It doubles with every failed try, up to
+// the max.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// How long each address gets to answer before we count it as down
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// We ping the server this often (see heartbeat.rs), and warn once it has left this many pings in a row unanswered
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const WARN_AFTER_MISSED: u32 = 2;
 
 // Derive tells the compiler to add these traits automatically for us.  Enums are a composite type, so this
 // works as long as the variants within the enum also define these types (or can derive them).
@@ -20,63 +84,1859 @@ enum Source {
     Server,
 }
 
-// Our public struct, with no fields
-pub struct ChatClient {}
+// Our public struct, set up with ChatClient::builder().  It holds where to find the server and who to be once we're
+// there, what to ask of the server in our handshake (see Capabilities), and how we show what comes back and take what's
+// typed.  The builder's methods say what each setting does.
+pub struct ChatClient {
+    tls: bool,
+    ca_cert: Option<PathBuf>,
+    lite: bool,
+    nodelay: bool,
+    bulk: bool,
+    color: bool,
+    reconnect: bool,
+    prefix: char,
+    servers: Vec<String>,
+    upload_limit: Option<u32>,
+    nickname: String,
+    password: Option<String>,
+    timeouts: Timeouts,
+    roster: bool,
+    line_editing: bool,
+    exec: bool,
+    downloads: PathBuf,
+    direct_files: bool,
+    scrollback: usize,
+}
+
+// How long we give things.  Each starts out as the constant of the same name, and the builder can change any of them.
+#[derive(Clone, Copy)]
+struct Timeouts {
+    connect: Duration,
+    heartbeat: Duration,
+    reconnect_delay: Duration,
+    max_reconnect_delay: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: CONNECT_TIMEOUT,
+            heartbeat: HEARTBEAT_INTERVAL,
+            reconnect_delay: RECONNECT_DELAY,
+            max_reconnect_delay: MAX_RECONNECT_DELAY,
+        }
+    }
+}
+
+// Why the client gave up, which run hands back inside Error::Client and connect's finish() hands back as it is.
+// Whatever the user needed to know has already gone out as events, so run has written it to the output, except for
+// Tls, which happens before there are any events.  This is so whoever's running us can tell what happened (the binary
+// turns it into an exit code).
+#[derive(Debug)]
+pub enum ChatClientError {
+    // TLS couldn't be set up, usually because of a bad ca_cert
+    Tls(io::Error),
+    // None of the servers answered when we started, and this is why the last one didn't
+    Unreachable(io::Error),
+    // The server threw us out, and why
+    Kicked(String),
+    // The connection dropped and we weren't to reconnect
+    Disconnected,
+}
+
+impl fmt::Display for ChatClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChatClientError::Tls(err) => write!(f, "unable to set up TLS: {}", err),
+            ChatClientError::Unreachable(err) => write!(f, "unable to connect: {}", err),
+            ChatClientError::Kicked(reason) => write!(f, "thrown out: {}", reason),
+            ChatClientError::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+// What connect() hands back as it happens, in the order it happens.  Everything the server sends that isn't just
+// between it and us is a MessageReceived, as the line it sent with any timestamp taken off into time.  The rest come
+// from us.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClientEvent {
+    // We're in, and whether it's after losing an earlier connection
+    Connected {
+        address: String,
+        reconnected: bool,
+    },
+    // A line from the server, and when it went through the room if the server said
+    MessageReceived {
+        line: String,
+        time: Option<SystemTime>,
+    },
+    // Everyone in the room, whenever that changes (only with roster set)
+    RosterUpdate(Vec<String>),
+    // Something to tell the user, like the answer to /servers or that we're about to reconnect
+    Status(String),
+    // Something went wrong that we're carrying on past, like a server that wouldn't answer
+    Error(String),
+    // The connection's gone, and why.  If we're reconnecting, a Connected follows once we're back.
+    Disconnected(String),
+}
+
+// Our end of the events from connect() (or BotClient::connect, which has BotEvents instead).  Once we've stopped the
+// events run out, and finish() says why we stopped.
+pub struct EventReceiver<E = ClientEvent> {
+    receiver: mpsc::Receiver<E>,
+    thread: JoinHandle<Result<(), ChatClientError>>,
+}
+
+impl<E> EventReceiver<E> {
+    // Waits for the next event.  None once we've stopped.
+    pub fn recv(&self) -> Option<E> {
+        self.receiver.recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<E, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    pub fn try_recv(&self) -> Result<E, TryRecvError> {
+        self.receiver.try_recv()
+    }
+
+    // Every event until we stop, e.g. for event in events.iter()
+    pub fn iter(&self) -> mpsc::Iter<'_, E> {
+        self.receiver.iter()
+    }
+
+    // Waits until we've stopped and says how it went, the same as run would have.  Any events not read yet are lost.
+    pub fn finish(self) -> Result<(), ChatClientError> {
+        self.thread.join().unwrap()
+    }
+}
+
+// Our end of the commands for connect(), which can be cloned for as many threads as need it.  Dropping every one of
+// them is the same as quitting.
+#[derive(Clone)]
+pub struct CommandSender {
+    sender: mpsc::Sender<String>,
+    cancel: CancellationToken,
+}
+
+impl CommandSender {
+    // A line just as it would be typed, chat or command.  An error means we've already stopped.
+    pub fn send(&self, line: impl Into<String>) -> Result<(), SendError<String>> {
+        self.sender.send(line.into())
+    }
+
+    // The same as sending /quit, except it also works while we're waiting to reconnect and doesn't care what the
+    // prefix is
+    pub fn quit(&self) {
+        self.cancel.cancel();
+    }
+}
+
+// How the network thread tells whoever's on the other end of connect() what's happening.  If they've stopped
+// listening we carry on regardless, since they can still send commands or quit.
+struct Updates(mpsc::Sender<ClientEvent>);
+
+impl Updates {
+    fn send(&self, event: ClientEvent) {
+        let _ = self.0.send(event);
+    }
+
+    fn status(&self, text: impl Into<String>) {
+        self.send(ClientEvent::Status(text.into()));
+    }
+
+    fn error(&self, text: impl Into<String>) {
+        self.send(ClientEvent::Error(text.into()));
+    }
+}
+
+// One setting at a time, e.g. ChatClient::builder().config(config).tls(true).build().  Everything starts off except
+// reconnect, the prefix starts as /, the servers, upload limit, exec and scrollback start as ClientConfig's defaults,
+// the nickname starts as Nobody and there's no password.
+pub struct ChatClientBuilder {
+    config: ClientConfig,
+    tls: bool,
+    ca_cert: Option<PathBuf>,
+    lite: bool,
+    nodelay: bool,
+    bulk: bool,
+    color: bool,
+    reconnect: bool,
+    prefix: char,
+    nickname: String,
+    password: Option<String>,
+    timeouts: Timeouts,
+    roster: bool,
+    line_editing: bool,
+}
+
+impl Default for ChatClientBuilder {
+    fn default() -> ChatClientBuilder {
+        ChatClientBuilder {
+            config: ClientConfig::default(),
+            tls: false,
+            ca_cert: None,
+            lite: false,
+            nodelay: false,
+            bulk: false,
+            color: false,
+            reconnect: true,
+            prefix: '/',
+            nickname: String::from("Nobody"),
+            password: None,
+            timeouts: Timeouts::default(),
+            roster: false,
+            line_editing: false,
+        }
+    }
+}
+
+impl ChatClientBuilder {
+    // The servers, upload limit, exec and scrollback, e.g. from chat_client.toml
+    pub fn config(mut self, config: ClientConfig) -> ChatClientBuilder {
+        self.config = config;
+        self
+    }
+
+    // The first is tried first, and the rest are fallbacks for when it can't be reached or drops us
+    pub fn servers(mut self, servers: Vec<String>) -> ChatClientBuilder {
+        self.config.servers = servers;
+        self
+    }
+
+    // Just the one server, with nothing to fall back on
+    pub fn address(mut self, address: impl Into<String>) -> ChatClientBuilder {
+        self.config.servers = vec![address.into()];
+        self
+    }
+
+    pub fn nickname(mut self, nickname: impl Into<String>) -> ChatClientBuilder {
+        self.nickname = nickname.into();
+        self
+    }
+
+    // The nickname's account password, for logging in with when we connect.  Accounts with two-factor authentication
+    // on need a fresh code every time, so they can't log in this way.
+    pub fn password(mut self, password: impl Into<String>) -> ChatClientBuilder {
+        self.password = Some(password.into());
+        self
+    }
+
+    // Bytes a second, 0 for no limit
+    pub fn upload_limit(mut self, limit: u32) -> ChatClientBuilder {
+        self.config.upload_limit = limit;
+        self
+    }
+
+    // Whether /exec is allowed at all
+    pub fn exec(mut self, exec: bool) -> ChatClientBuilder {
+        self.config.exec = exec;
+        self
+    }
+
+    // Where files sent to us are saved
+    pub fn downloads(mut self, path: impl Into<PathBuf>) -> ChatClientBuilder {
+        self.config.downloads = path.into();
+        self
+    }
+
+    // Whether files we send may go straight to whoever they're for
+    pub fn direct_files(mut self, direct: bool) -> ChatClientBuilder {
+        self.config.direct_files = direct;
+        self
+    }
+
+    // Lines each pane of run_tui keeps in memory, with anything older kept in a temp file
+    pub fn scrollback(mut self, lines: usize) -> ChatClientBuilder {
+        self.config.scrollback = lines;
+        self
+    }
+
+    // Wraps the connection in TLS
+    pub fn tls(mut self, tls: bool) -> ChatClientBuilder {
+        self.tls = tls;
+        self
+    }
+
+    // With TLS, trust this certificate (for self-signed servers) rather than the usual public certificate authorities
+    pub fn ca_cert(mut self, path: impl Into<PathBuf>) -> ChatClientBuilder {
+        self.ca_cert = Some(path.into());
+        self
+    }
+
+    // Asked of the server, for slow or metered connections
+    pub fn lite(mut self, lite: bool) -> ChatClientBuilder {
+        self.lite = lite;
+        self
+    }
+
+    // Asked of the server, to get every message the moment it's sent
+    pub fn nodelay(mut self, nodelay: bool) -> ChatClientBuilder {
+        self.nodelay = nodelay;
+        self
+    }
+
+    // Asked of the server, for things like loggers that would rather have fewer, bigger writes
+    pub fn bulk(mut self, bulk: bool) -> ChatClientBuilder {
+        self.bulk = bulk;
+        self
+    }
+
+    // Colors server notices by kind, which only makes sense when we're writing to a terminal
+    pub fn color(mut self, color: bool) -> ChatClientBuilder {
+        self.color = color;
+        self
+    }
+
+    // Gets back in by ourselves when the connection drops.  /reconnect on|off changes it as we go.
+    pub fn reconnect(mut self, reconnect: bool) -> ChatClientBuilder {
+        self.reconnect = reconnect;
+        self
+    }
+
+    // How long we wait before the first try at getting back in, and the most it can double up to
+    pub fn reconnect_delay(mut self, first: Duration, max: Duration) -> ChatClientBuilder {
+        self.timeouts.reconnect_delay = first;
+        self.timeouts.max_reconnect_delay = max;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> ChatClientBuilder {
+        self.timeouts.connect = timeout;
+        self
+    }
+
+    pub fn heartbeat_interval(mut self, interval: Duration) -> ChatClientBuilder {
+        self.timeouts.heartbeat = interval;
+        self
+    }
+
+    // What our commands start with, which the server is told in the handshake as well.  Typing it twice sends it as it
+    // is.
+    pub fn prefix(mut self, prefix: char) -> ChatClientBuilder {
+        self.prefix = prefix;
+        self
+    }
+
+    // Has the server tell us who's in the room whenever that changes, which comes out of connect() as RosterUpdate
+    // events.  run has nowhere to show it.
+    pub fn roster(mut self, roster: bool) -> ChatClientBuilder {
+        self.roster = roster;
+        self
+    }
+
+    // Edits what's typed in run, with the arrow keys and history (see line_editor.rs).  Only for when input and output
+    // are the terminal.
+    pub fn line_editing(mut self, line_editing: bool) -> ChatClientBuilder {
+        self.line_editing = line_editing;
+        self
+    }
+
+    pub fn build(self) -> Result<ChatClient, ConfigError> {
+        self.config.validate()?;
+        if !protocol::is_valid_prefix(self.prefix) {
+            return Err(ConfigError::Invalid(String::from(
+                "the prefix can't be a letter, digit or space",
+            )));
+        }
+        // A zero delay would never double, and we'd hammer the servers as fast as we could go
+        if self.timeouts.reconnect_delay.is_zero()
+            || self.timeouts.max_reconnect_delay < self.timeouts.reconnect_delay
+        {
+            return Err(ConfigError::Invalid(String::from(
+                "the reconnect delay has to be more than zero, and no more than the max",
+            )));
+        }
+        // Zero means no timeout for a read, but it's an error for a connect
+        if self.timeouts.connect.is_zero() || self.timeouts.heartbeat.is_zero() {
+            return Err(ConfigError::Invalid(String::from(
+                "the connect timeout and heartbeat interval have to be more than zero",
+            )));
+        }
+
+        Ok(ChatClient {
+            tls: self.tls,
+            ca_cert: self.ca_cert,
+            lite: self.lite,
+            nodelay: self.nodelay,
+            bulk: self.bulk,
+            color: self.color,
+            reconnect: self.reconnect,
+            prefix: self.prefix,
+            servers: self.config.servers,
+            upload_limit: Some(self.config.upload_limit).filter(|limit| *limit > 0),
+            exec: self.config.exec,
+            downloads: self.config.downloads,
+            direct_files: self.config.direct_files,
+            scrollback: self.config.scrollback,
+            nickname: self.nickname,
+            password: self.password,
+            timeouts: self.timeouts,
+            roster: self.roster,
+            line_editing: self.line_editing,
+        })
+    }
+}
+
+// How it went the last time we tried a server
+enum Status {
+    Untried,
+    Connected,
+    Dropped,
+    Failed(String),
+}
+
+// The servers we can connect to, which one we're on, and whether we go back to them by ourselves when the connection
+// drops.  /servers shows all of it.  Entries like "srv:example.com" stand for whatever that domain's SRV record lists
+// (see srv.rs), looked up again every time we go round, so we follow the servers if they move.  Also how fast we're
+// allowed to send to whichever one we're on, and how long we give them.
+struct Servers {
+    entries: Vec<String>,
+    addresses: Vec<String>,
+    statuses: Vec<Status>,
+    current: usize,
+    reconnect: bool,
+    upload_limit: Option<u32>,
+    timeouts: Timeouts,
+}
+
+impl Servers {
+    fn new(
+        entries: Vec<String>,
+        reconnect: bool,
+        upload_limit: Option<u32>,
+        timeouts: Timeouts,
+    ) -> Servers {
+        Servers {
+            entries,
+            addresses: Vec::new(),
+            statuses: Vec::new(),
+            current: 0,
+            reconnect,
+            upload_limit,
+            timeouts,
+        }
+    }
+
+    // Goes round the list once starting from first, saying what went wrong with each one that doesn't answer.  If none
+    // of them do, the error is the last one's.
+    fn connect(
+        &mut self,
+        first: usize,
+        tls: &Option<TlsConnector>,
+        updates: &Updates,
+    ) -> io::Result<Stream> {
+        // The lookups can change the list under us, so we start from the same address rather than the same place
+        let start = self.addresses.get(first).cloned();
+        self.resolve(updates);
+        if self.addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no servers to connect to",
+            ));
+        }
+        let first = start
+            .and_then(|start| self.addresses.iter().position(|address| *address == start))
+            .unwrap_or(0);
+
+        let mut last_err = None;
+        for offset in 0..self.addresses.len() {
+            let index = (first + offset) % self.addresses.len();
+            match ChatClient::open(&self.addresses[index], self.timeouts.connect, tls) {
+                Ok(stream) => {
+                    self.current = index;
+                    self.statuses[index] = Status::Connected;
+                    return Ok(stream);
+                }
+                Err(err) => {
+                    updates.error(format!(
+                        "Unable to connect to {}: {}",
+                        self.addresses[index], err
+                    ));
+                    self.statuses[index] = Status::Failed(err.to_string());
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        // The list isn't empty, so we tried something
+        Err(last_err.unwrap())
+    }
+
+    // Turns the entries into addresses, keeping how it went with any address we've tried before
+    fn resolve(&mut self, updates: &Updates) {
+        let mut addresses = Vec::new();
+        for entry in &self.entries {
+            let domain = match entry.strip_prefix(srv::PREFIX) {
+                Some(domain) => domain,
+                None => {
+                    addresses.push(entry.clone());
+                    continue;
+                }
+            };
+            match srv::lookup(domain) {
+                Ok(targets) if targets.is_empty() => updates.error(format!(
+                    "No servers listed under {}",
+                    srv::record_name(domain)
+                )),
+                Ok(targets) => addresses.extend(
+                    targets
+                        .iter()
+                        .map(|target| format!("{}:{}", target.host, target.port)),
+                ),
+                Err(err) => updates.error(format!(
+                    "Unable to look up {}: {}",
+                    srv::record_name(domain),
+                    err
+                )),
+            }
+        }
+
+        let statuses = addresses
+            .iter()
+            .map(
+                |address| match self.addresses.iter().position(|old| old == address) {
+                    Some(index) => mem::replace(&mut self.statuses[index], Status::Untried),
+                    None => Status::Untried,
+                },
+            )
+            .collect();
+        self.addresses = addresses;
+        self.statuses = statuses;
+    }
+
+    fn dropped(&mut self) {
+        self.statuses[self.current] = Status::Dropped;
+    }
+
+    fn current(&self) -> &str {
+        &self.addresses[self.current]
+    }
+
+    // After losing a connection we start with the next one along, so a server that just dropped us is tried last
+    fn next(&self) -> usize {
+        (self.current + 1) % self.addresses.len().max(1)
+    }
+
+    fn report(&self) -> String {
+        let mut report = String::from("Servers, in the order we try them:");
+        for (index, (address, status)) in self.addresses.iter().zip(&self.statuses).enumerate() {
+            let status = match status {
+                Status::Untried => String::from("not tried"),
+                Status::Connected => String::from("connected"),
+                Status::Dropped => String::from("disconnected"),
+                Status::Failed(err) => format!("unable to connect: {}", err),
+            };
+            report.push_str(&format!("\n  {}. {} ({})", index + 1, address, status));
+        }
+
+        report
+    }
+}
+
+// The arguments, if what was typed is the named command, e.g. command("!filter notices off", '!', "filter")
+fn command<'a>(message: &'a str, prefix: char, name: &str) -> Option<&'a str> {
+    let rest = message.strip_prefix(prefix)?.strip_prefix(name)?;
+    if rest.is_empty() || rest.starts_with(' ') {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+// Two prefixes is how to say something that starts with one, e.g. escape("/shrug", '/') is "//shrug"
+fn escape(line: &str, prefix: char) -> String {
+    if line.trim_start().starts_with(prefix) {
+        format!("{}{}", prefix, line.trim_start())
+    } else {
+        String::from(line)
+    }
+}
+
+// Where /exec is up to.  A command waits in pending for a yes before it's run, and then on its own thread, since it can
+// take a while and we've a connection to keep up meanwhile.  What it printed comes back on the receiver.
+struct Exec {
+    enabled: bool,
+    pending: Option<String>,
+    running: Option<(String, mpsc::Receiver<io::Result<Captured>>)>,
+}
+
+impl Exec {
+    fn new(enabled: bool) -> Exec {
+        Exec {
+            enabled,
+            pending: None,
+            running: None,
+        }
+    }
+
+    // True if what was typed was for us, and shouldn't go to the server.  Whatever's typed after /exec <command>
+    // answers whether to run it.  Only a yes does, and anything but a no is taken as it would have been anyway.
+    fn typed(&mut self, message: &str, prefix: char, updates: &Updates) -> bool {
+        if let Some(pending) = self.pending.take() {
+            match message.to_lowercase().as_str() {
+                "y" | "yes" => {
+                    self.start(pending, updates);
+                    return true;
+                }
+                "n" | "no" => {
+                    updates.status("Cancelled, nothing was run");
+                    return true;
+                }
+                _ => updates.status("Cancelled, nothing was run"),
+            }
+        }
+
+        let to_run = match command(message, prefix, "exec") {
+            Some(to_run) => to_run,
+            None => return false,
+        };
+        if !self.enabled {
+            updates.error("/exec is turned off, set exec = true in chat_client.toml to use it");
+        } else if to_run.is_empty() {
+            updates.error(format!("Usage: {}exec <command>", prefix));
+        } else if self.running.is_some() {
+            updates.error(format!("The last {}exec is still running", prefix));
+        } else {
+            updates.status(format!(
+                "Run `{}` here and send what it prints to the room? (y/n)",
+                to_run
+            ));
+            self.pending = Some(String::from(to_run));
+        }
+        true
+    }
+
+    fn start(&mut self, to_run: String, updates: &Updates) {
+        updates.status(format!("Running `{}`", to_run));
+        let (sender, receiver) = mpsc::channel();
+        let command = to_run.clone();
+        thread::spawn(move || {
+            let _ = sender.send(exec::run(&command));
+        });
+        self.running = Some((to_run, receiver));
+    }
+
+    // The lines to send, once the command is done.  They're fenced like a markdown code block, so they read as output
+    // rather than as something we said.
+    fn finished(&mut self, prefix: char, updates: &Updates) -> Option<Vec<String>> {
+        let result = match self.running.as_ref()?.1.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(io::Error::other("it stopped unexpectedly")),
+        };
+        let (to_run, _) = self.running.take()?;
+
+        let captured = match result {
+            Ok(captured) => captured,
+            Err(err) => {
+                updates.error(format!("Unable to run `{}`: {}", to_run, err));
+                return None;
+            }
+        };
+        if !captured.status.success() {
+            updates.status(format!("`{}` finished with {}", to_run, captured.status));
+        }
+
+        let mut lines = vec![
+            String::from("```"),
+            escape(&format!("$ {}", to_run), prefix),
+        ];
+        lines.extend(captured.lines.iter().map(|line| escape(line, prefix)));
+        if captured.truncated {
+            lines.push(String::from("... (cut short)"));
+        }
+        lines.push(String::from("```"));
+        Some(lines)
+    }
+}
+
+// The commands that are carried out here rather than by the server, at least to begin with
+struct Helpers {
+    exec: Exec,
+    files: Files,
+}
+
+// How many chunks of a file can be waiting to go at once.  More than this and anything typed meanwhile would be stuck
+// behind a whole file's worth of them.
+const QUEUED_CHUNKS: usize = 8;
+
+// Files on their way to or from us (see FileMessage).  /send <user> <path> offers one, and whoever it's for can take it
+// with /receive or turn it down with /cancel, which also stops one that's on its way.  /files lists them all.  A file
+// goes straight from one client to the other when it can, and otherwise through the server in chunks, which we feed
+// into the outbox a few at a time.  Anything on its way through the server is lost along with the connection.
+struct Files {
+    downloads: PathBuf,
+    direct: bool,
+    prefix: char,
+    sending: HashMap<String, Sending>,
+    receiving: HashMap<String, Receiving>,
+    // From the threads doing direct transfers (see files.rs)
+    updates: mpsc::Receiver<files::Update>,
+    update_sender: mpsc::Sender<files::Update>,
+}
+
+struct Sending {
+    to: String,
+    name: String,
+    path: PathBuf,
+    size: u64,
+    // Once they've taken it through the server, the file and how big each chunk can be
+    relay: Option<(File, usize)>,
+    sent: u64,
+    progress: Progress,
+    // Tells the direct listener to stop waiting
+    stop: Arc<AtomicBool>,
+}
+
+struct Receiving {
+    from: String,
+    name: String,
+    size: u64,
+    direct: Option<SocketAddr>,
+    // Where it's going, once we've said we'll take it, and the partial file while it comes through the server
+    path: Option<PathBuf>,
+    file: Option<File>,
+    received: u64,
+    progress: Progress,
+}
+
+impl Files {
+    fn new(downloads: PathBuf, direct: bool, prefix: char) -> Files {
+        let (update_sender, updates) = mpsc::channel();
+        Files {
+            downloads,
+            direct,
+            prefix,
+            sending: HashMap::new(),
+            receiving: HashMap::new(),
+            updates,
+            update_sender,
+        }
+    }
+
+    // True if what was typed was for us, and shouldn't go to the server
+    fn typed(
+        &mut self,
+        message: &str,
+        prefix: char,
+        outbox: &mut Outbox,
+        updates: &Updates,
+    ) -> bool {
+        if let Some(arguments) = command(message, prefix, "send") {
+            match arguments.split_once(' ') {
+                Some((to, path)) if !path.trim().is_empty() => {
+                    self.send(to, PathBuf::from(path.trim()), outbox, updates)
+                }
+                _ => updates.error(format!("Usage: {}send <user> <path>", prefix)),
+            }
+        } else if let Some(id) = command(message, prefix, "receive") {
+            match self.find(id, true) {
+                Ok(id) => self.receive(&id, outbox, updates),
+                Err(err) => updates.error(err),
+            }
+        } else if let Some(id) = command(message, prefix, "cancel") {
+            match self.find(id, false) {
+                Ok(id) => {
+                    let name = self
+                        .sending
+                        .get(&id)
+                        .map(|sending| sending.name.clone())
+                        .or_else(|| {
+                            self.receiving
+                                .get(&id)
+                                .map(|receiving| receiving.name.clone())
+                        })
+                        .unwrap_or_default();
+                    self.forget(&id);
+                    outbox.push(
+                        &FileMessage::Cancel {
+                            id,
+                            reason: String::new(),
+                        }
+                        .to_line(),
+                    );
+                    updates.status(format!("Called off {}", name));
+                }
+                Err(err) => updates.error(err),
+            }
+        } else if command(message, prefix, "files").is_some() {
+            updates.status(self.report());
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn send(&mut self, to: &str, path: PathBuf, outbox: &mut Outbox, updates: &Updates) {
+        let size = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => return updates.error(format!("{} isn't a file", path.display())),
+            Err(err) => {
+                return updates.error(format!("Unable to send {}: {}", path.display(), err))
+            }
+        };
+        let name = match files::offered_name(&path) {
+            Some(name) => name,
+            None => return updates.error(format!("{} isn't a file", path.display())),
+        };
+
+        // If we can't listen there's still the server
+        let id = files::new_id();
+        let stop = Arc::new(AtomicBool::new(false));
+        let port = if self.direct {
+            files::listen(&id, &path, stop.clone(), self.update_sender.clone()).ok()
+        } else {
+            None
+        };
+
+        outbox.push(
+            &FileMessage::Offer {
+                id: id.clone(),
+                peer: String::from(to),
+                size,
+                direct: port.map(|port| port.to_string()),
+                name: name.clone(),
+            }
+            .to_line(),
+        );
+        updates.status(format!(
+            "Offering {} ({}) to {}",
+            name,
+            files::size(size),
+            to
+        ));
+        self.sending.insert(
+            id,
+            Sending {
+                to: String::from(to),
+                name,
+                path,
+                size,
+                relay: None,
+                sent: 0,
+                progress: Progress::new(size),
+                stop,
+            },
+        );
+    }
+
+    // Takes it directly if the sender's listening and we're allowed, otherwise through the server
+    fn receive(&mut self, id: &str, outbox: &mut Outbox, updates: &Updates) {
+        let receiving = match self.receiving.get_mut(id) {
+            Some(receiving) => receiving,
+            None => return,
+        };
+        let path = files::download_path(&self.downloads, &receiving.name);
+        let file = match files::create_partial(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                updates.error(format!("Unable to save to {}: {}", path.display(), err));
+                return;
+            }
+        };
+        receiving.path = Some(path);
+
+        match receiving.direct {
+            Some(address) if self.direct => {
+                updates.status(format!(
+                    "Fetching {} from {} directly",
+                    receiving.name, receiving.from
+                ));
+                let sender = self.update_sender.clone();
+                files::fetch(id, address, receiving.size, file, sender);
+            }
+            _ => {
+                receiving.file = Some(file);
+                self.take_through_server(id, outbox, updates);
+            }
+        }
+    }
+
+    fn take_through_server(&mut self, id: &str, outbox: &mut Outbox, updates: &Updates) {
+        let receiving = match self.receiving.get(id) {
+            Some(receiving) => receiving,
+            None => return,
+        };
+        updates.status(format!(
+            "Taking {} from {} through the server",
+            receiving.name, receiving.from
+        ));
+        let id = String::from(id);
+        let empty = receiving.size == 0;
+        outbox.push(
+            &FileMessage::Accept {
+                id: id.clone(),
+                chunk: None,
+            }
+            .to_line(),
+        );
+        // With nothing to come there's nothing to wait for
+        if empty {
+            self.finish(&id, outbox, updates);
+        }
+    }
+
+    // Whatever the server had for us about a file
+    fn received(&mut self, message: FileMessage, outbox: &mut Outbox, updates: &Updates) {
+        match message {
+            FileMessage::Offer {
+                id,
+                peer,
+                size,
+                direct,
+                name,
+            } => {
+                updates.status(format!(
+                    "{} wants to send you {} ({}), {3}receive {4} to take it or {3}cancel {4} to turn it down",
+                    peer,
+                    name,
+                    files::size(size),
+                    self.prefix,
+                    id
+                ));
+                self.receiving.insert(
+                    id,
+                    Receiving {
+                        from: peer,
+                        name,
+                        size,
+                        direct: direct.and_then(|direct| direct.parse().ok()),
+                        path: None,
+                        file: None,
+                        received: 0,
+                        progress: Progress::new(size),
+                    },
+                );
+            }
+            FileMessage::Accept { id, chunk } => {
+                let sending = match self.sending.get_mut(&id) {
+                    Some(sending) => sending,
+                    None => return,
+                };
+                sending.stop.store(true, Ordering::Relaxed);
+                match File::open(&sending.path) {
+                    Ok(file) => {
+                        updates.status(format!(
+                            "{} is taking {} through the server",
+                            sending.to, sending.name
+                        ));
+                        sending.relay = Some((file, chunk.unwrap_or(1024).max(1)));
+                    }
+                    Err(err) => {
+                        updates.error(format!("Unable to send {}: {}", sending.name, err));
+                        self.forget(&id);
+                        let reason = String::from("It couldn't be read");
+                        outbox.push(&FileMessage::Cancel { id, reason }.to_line());
+                    }
+                }
+            }
+            FileMessage::Chunk { id, offset, data } => {
+                let receiving = match self.receiving.get_mut(&id) {
+                    Some(receiving) => receiving,
+                    None => return,
+                };
+                let written = match &mut receiving.file {
+                    Some(file) if offset == receiving.received => file.write_all(&data),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "it came out of order",
+                    )),
+                };
+                if let Err(err) = written {
+                    updates.error(format!("Unable to save {}: {}", receiving.name, err));
+                    self.forget(&id);
+                    let reason = String::from("It couldn't be saved");
+                    outbox.push(&FileMessage::Cancel { id, reason }.to_line());
+                    return;
+                }
+
+                receiving.received += data.len() as u64;
+                if let Some(percent) = receiving.progress.update(receiving.received) {
+                    updates.status(format!(
+                        "Receiving {} from {}: {}%",
+                        receiving.name, receiving.from, percent
+                    ));
+                }
+                if receiving.received >= receiving.size {
+                    self.finish(&id, outbox, updates);
+                }
+            }
+            FileMessage::Done { id } => {
+                if let Some(sending) = self.sending.remove(&id) {
+                    sending.stop.store(true, Ordering::Relaxed);
+                    updates.status(format!("{} has {}", sending.to, sending.name));
+                }
+            }
+            FileMessage::Cancel { id, reason } => {
+                if let Some(sending) = self.sending.get(&id) {
+                    updates.error(format!(
+                        "Not sending {} to {}: {}",
+                        sending.name, sending.to, reason
+                    ));
+                } else if let Some(receiving) = self.receiving.get(&id) {
+                    updates.error(format!(
+                        "Not receiving {} from {}: {}",
+                        receiving.name, receiving.from, reason
+                    ));
+                }
+                self.forget(&id);
+            }
+            // Anything newer than us
+            _ => {}
+        }
+    }
+
+    // What the direct transfers have to say, and the next few chunks of whatever's going through the server
+    fn poll(&mut self, outbox: &mut Outbox, updates: &Updates) {
+        while let Ok(update) = self.updates.try_recv() {
+            match update {
+                files::Update::Progress(id, done) => {
+                    if let Some(sending) = self.sending.get_mut(&id) {
+                        if let Some(percent) = sending.progress.update(done) {
+                            updates.status(format!(
+                                "Sending {} to {}: {}%",
+                                sending.name, sending.to, percent
+                            ));
+                        }
+                    } else if let Some(receiving) = self.receiving.get_mut(&id) {
+                        if let Some(percent) = receiving.progress.update(done) {
+                            updates.status(format!(
+                                "Receiving {} from {}: {}%",
+                                receiving.name, receiving.from, percent
+                            ));
+                        }
+                    }
+                }
+                // Ours is done when they say they have it
+                files::Update::Finished(id) => {
+                    if self.receiving.contains_key(&id) {
+                        self.finish(&id, outbox, updates);
+                    }
+                }
+                // They'll fall back on the server themselves if it's ours that failed
+                files::Update::Failed(id, err) => {
+                    let path = match self.receiving.get_mut(&id) {
+                        Some(receiving) => receiving.path.clone(),
+                        None => continue,
+                    };
+                    updates.status(format!(
+                        "Unable to fetch it directly ({}), trying the server",
+                        err
+                    ));
+                    let file = path.as_deref().map(files::create_partial);
+                    match (self.receiving.get_mut(&id), file) {
+                        (Some(receiving), Some(Ok(file))) => {
+                            receiving.received = 0;
+                            receiving.progress = Progress::new(receiving.size);
+                            receiving.file = Some(file);
+                            self.take_through_server(&id, outbox, updates);
+                        }
+                        _ => {
+                            updates.error("Unable to start saving it again");
+                            self.forget(&id);
+                            let reason = String::from("It couldn't be saved");
+                            outbox.push(&FileMessage::Cancel { id, reason }.to_line());
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut failed = Vec::new();
+        for (id, sending) in &mut self.sending {
+            let (file, chunk) = match &mut sending.relay {
+                Some(relay) => relay,
+                None => continue,
+            };
+            while sending.sent < sending.size && outbox.len() < QUEUED_CHUNKS {
+                let mut data = vec![0; (*chunk).min((sending.size - sending.sent) as usize)];
+                if let Err(err) = file.read_exact(&mut data) {
+                    updates.error(format!("Unable to send {}: {}", sending.name, err));
+                    failed.push(id.clone());
+                    break;
+                }
+                let offset = sending.sent;
+                sending.sent += data.len() as u64;
+                outbox.push(
+                    &FileMessage::Chunk {
+                        id: id.clone(),
+                        offset,
+                        data,
+                    }
+                    .to_line(),
+                );
+                if let Some(percent) = sending.progress.update(sending.sent) {
+                    updates.status(format!(
+                        "Sending {} to {}: {}%",
+                        sending.name, sending.to, percent
+                    ));
+                }
+            }
+        }
+        for id in failed {
+            self.forget(&id);
+            let reason = String::from("It couldn't be read");
+            outbox.push(&FileMessage::Cancel { id, reason }.to_line());
+        }
+    }
+
+    // All of it's here, so it gets its real name, and the sender hears it's safe
+    fn finish(&mut self, id: &str, outbox: &mut Outbox, updates: &Updates) {
+        let receiving = match self.receiving.remove(id) {
+            Some(receiving) => receiving,
+            None => return,
+        };
+        drop(receiving.file);
+        let path = match receiving.path {
+            Some(path) => path,
+            None => return,
+        };
+        match fs::rename(files::partial(&path), &path) {
+            Ok(()) => updates.status(format!(
+                "Saved {} from {} as {}",
+                receiving.name,
+                receiving.from,
+                path.display()
+            )),
+            Err(err) => updates.error(format!("Unable to save {}: {}", receiving.name, err)),
+        }
+        let id = String::from(id);
+        outbox.push(&FileMessage::Done { id }.to_line());
+    }
+
+    // Stops whatever we're doing about it, and throws away anything half saved
+    fn forget(&mut self, id: &str) {
+        if let Some(sending) = self.sending.remove(id) {
+            sending.stop.store(true, Ordering::Relaxed);
+        }
+        if let Some(receiving) = self.receiving.remove(id) {
+            drop(receiving.file);
+            if let Some(path) = receiving.path {
+                fs::remove_file(files::partial(&path)).ok();
+            }
+        }
+    }
+
+    // The connection's gone, and with it the server's idea of what we were doing
+    fn lost(&mut self, updates: &Updates) {
+        let ids: Vec<String> = self
+            .sending
+            .keys()
+            .chain(self.receiving.keys())
+            .cloned()
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+        for id in &ids {
+            self.forget(id);
+        }
+        updates.error(format!(
+            "Called off {} file transfer(s) when the connection dropped",
+            ids.len()
+        ));
+    }
+
+    // The id of a transfer, from the start of it, or from nothing at all when there's only the one it could be.  Offers
+    // only, for /receive.
+    fn find(&self, given: &str, offers: bool) -> Result<String, String> {
+        let candidates: Vec<&String> = self
+            .receiving
+            .iter()
+            .filter(|(_, receiving)| !offers || receiving.path.is_none())
+            .map(|(id, _)| id)
+            .chain(self.sending.keys().filter(|_| !offers))
+            .filter(|id| id.starts_with(given))
+            .collect();
+        match candidates[..] {
+            [id] => Ok(id.clone()),
+            [] if given.is_empty() && offers => Err(String::from("Nobody's offered you a file")),
+            [] if given.is_empty() => Err(String::from("No files on their way")),
+            [] => Err(format!("There's nothing called {}", given)),
+            _ => Err(format!("Which one?  {}files lists them", self.prefix)),
+        }
+    }
+
+    fn report(&self) -> String {
+        let mut lines = Vec::new();
+        for (id, sending) in &self.sending {
+            let how_far = match sending.relay {
+                Some(_) => format!(
+                    "{}% through the server",
+                    sending.sent * 100 / sending.size.max(1)
+                ),
+                None => String::from("waiting for them"),
+            };
+            lines.push(format!(
+                "{}: {} ({}) to {}, {}",
+                id,
+                sending.name,
+                files::size(sending.size),
+                sending.to,
+                how_far
+            ));
+        }
+        for (id, receiving) in &self.receiving {
+            let how_far = match (&receiving.path, &receiving.file) {
+                (None, _) => String::from("waiting for you"),
+                (Some(_), Some(_)) => format!(
+                    "{}% through the server",
+                    receiving.received * 100 / receiving.size.max(1)
+                ),
+                (Some(_), None) => String::from("coming directly"),
+            };
+            lines.push(format!(
+                "{}: {} ({}) from {}, {}",
+                id,
+                receiving.name,
+                files::size(receiving.size),
+                receiving.from,
+                how_far
+            ));
+        }
+        if lines.is_empty() {
+            return String::from("No files on their way");
+        }
+        lines.join("\n")
+    }
+}
+
+// Lines waiting to go to the server.  Every message is one line on the wire, and they go out as fast as the socket
+// takes them, or with an upload limit only as fast as that allows, so pasting something huge doesn't fill a slow link
+// for minutes.  Keepalives skip ahead of whatever's waiting (though not into the middle of a line that's partly gone),
+// so the server doesn't think we're gone while the paste trickles out.
+struct Outbox {
+    lines: VecDeque<Vec<u8>>,
+    // How much of the front line has already gone
+    sent: usize,
+    limit: Option<TokenBucket>,
+}
+
+impl Outbox {
+    // The limit is in bytes a second, and we can send a second's worth at once after being quiet for a while
+    fn new(upload_limit: Option<u32>) -> Outbox {
+        Outbox {
+            lines: VecDeque::new(),
+            sent: 0,
+            limit: upload_limit.map(|limit| TokenBucket::new(f64::from(limit), limit)),
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        self.lines.push_back(Outbox::line(line));
+    }
+
+    // How many lines are waiting, including any that's partly gone
+    fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    fn push_urgent(&mut self, line: &str) {
+        let position = if self.sent > 0 { 1 } else { 0 };
+        self.lines
+            .insert(position.min(self.lines.len()), Outbox::line(line));
+    }
+
+    fn line(line: &str) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(line.len() + 1);
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        bytes
+    }
+
+    // Sends what the socket and the limit will take without waiting.  If it won't go at all, the read side will find
+    // out why soon enough.
+    fn flush(&mut self, stream: &mut Stream) {
+        while let Some(line) = self.lines.front() {
+            let remaining = &line[self.sent..];
+            let allowed = match &mut self.limit {
+                Some(limit) => limit.available().min(remaining.len()),
+                None => remaining.len(),
+            };
+            if allowed == 0 {
+                break;
+            }
+
+            let written = match stream.write(&remaining[..allowed]) {
+                Ok(written) => written,
+                Err(_) => break,
+            };
+            if let Some(limit) = &mut self.limit {
+                limit.spend(written);
+            }
+            self.sent += written;
+            if self.sent == line.len() {
+                self.lines.pop_front();
+                self.sent = 0;
+            }
+        }
+        stream.flush().ok();
+    }
+}
+
+// Everything the network thread hears from whoever's using us: what they type, and whether they've quit
+struct Requests {
+    typed: mpsc::Receiver<String>,
+    cancel: CancellationToken,
+}
+
+// Why a connection to the server ended
+enum Ended {
+    // We typed /quit
+    Quit,
+    // Thrown out, so there's no point going back
+    Kicked(String),
+    // Anything else, which is worth another try
+    Lost,
+}
+
+// How we show what the server sends.  Chat is written as it is, and notices get marked (and colored if we can) so
+// they stand out from what people are saying.  /filter notices off hides them.  Chat that mentions us is highlighted.
+// /timestamps on puts the time each message went through the room in front of it, in our own time zone.
+//
+// Whether or not the times are shown, a client left running for days gets a line like "— Tuesday, March 4 —" when
+// the day changes between two messages, and "(no activity for 3 hours)" when the room went quiet for a while, so it's
+// easy to tell which conversation was which when scrolling back.
+//
+// Deciding what a line is and how it looks are kept apart, so the TUI (see tui.rs) can show the same lines its own way
+// while the plain terminal uses paint.
+pub struct Renderer {
+    color: bool,
+    show_notices: bool,
+    timestamps: bool,
+    // When the last message we showed went through the room, which is what the markers are measured from
+    last: Option<DateTime<Local>>,
+}
+
+// What sort of line something is, as far as showing it goes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Tone {
+    Chat,
+    Mention(MentionKind),
+    Notice(NoticeKind),
+    // A /msg to or from us
+    Direct,
+    // A day separator or a quiet spell
+    Marker,
+}
+
+// A line ready to show: what it is, the time to put in front of it if timestamps are on, and the text
+pub struct Shown {
+    pub tone: Tone,
+    pub time: Option<DateTime<Local>>,
+    pub text: String,
+}
+
+// The shortest quiet spell worth pointing out
+const QUIET: TimeDelta = TimeDelta::hours(1);
+
+impl Renderer {
+    pub fn new(color: bool) -> Renderer {
+        Renderer {
+            color,
+            show_notices: true,
+            timestamps: false,
+            last: None,
+        }
+    }
+
+    // None if the line is filtered out
+    pub fn render(&self, line: &str, time: Option<SystemTime>) -> Option<Shown> {
+        let (tone, text) = self.render_line(line)?;
+        Some(Shown {
+            tone,
+            time: time.filter(|_| self.timestamps).map(DateTime::from),
+            text,
+        })
+    }
+
+    // What goes between the last message we showed and one from time: a day separator if the day's changed, or
+    // a gap marker if it's been quiet, or nothing.  Anything older than the last message doesn't move it back.
+    pub fn marker(&mut self, time: SystemTime) -> Option<Shown> {
+        let time: DateTime<Local> = time.into();
+        let last = match self.last {
+            Some(last) if last > time => return None,
+            Some(last) => last,
+            None => {
+                self.last = Some(time);
+                return None;
+            }
+        };
+        self.last = Some(time);
+
+        let text = if time.date_naive() != last.date_naive() {
+            format!("\u{2014} {} \u{2014}", time.format("%A, %B %-d"))
+        } else if time - last >= QUIET {
+            let hours = (time - last).num_hours();
+            format!(
+                "(no activity for {} hour{})",
+                hours,
+                if hours == 1 { "" } else { "s" }
+            )
+        } else {
+            return None;
+        };
+        Some(Shown {
+            tone: Tone::Marker,
+            time: None,
+            text,
+        })
+    }
+
+    fn render_line(&self, line: &str) -> Option<(Tone, String)> {
+        if let Some(direct) = Direct::parse(line) {
+            return Some((Tone::Direct, direct.to_plain()));
+        }
+
+        // Someone mentioned us, which is chat, so it's never filtered
+        if let Some(mention) = Mention::parse(line) {
+            return Some((
+                Tone::Mention(mention.kind),
+                format!("{}: {}", mention.sender, mention.body),
+            ));
+        }
+
+        let notice = match Notice::parse(line) {
+            Some(notice) => notice,
+            None => return Some((Tone::Chat, String::from(line))),
+        };
+
+        // Errors are the answer to something we just typed, so they're never hidden
+        if !self.show_notices && notice.kind != NoticeKind::Error {
+            return None;
+        }
+        Some((Tone::Notice(notice.kind), format!("*** {}", notice.text)))
+    }
+
+    // How a line looks on a plain terminal.  With color, a mention of us in particular rings the bell as well, while
+    // @all is just highlighted.  Without it, mentions get marked instead.
+    fn paint(&self, shown: &Shown) -> String {
+        let style = match shown.tone {
+            Tone::Chat => None,
+            Tone::Mention(MentionKind::Direct) => Some(("!! ", "\x07\x1b[1;33m")),
+            Tone::Mention(MentionKind::Everyone) => Some(("! ", "\x1b[1m")),
+            Tone::Direct => Some(("", "\x1b[35m")),
+            Tone::Marker => Some(("", "\x1b[2m")),
+            Tone::Notice(kind) => Some((
+                "",
+                match kind {
+                    NoticeKind::Presence => "\x1b[2m",
+                    NoticeKind::Moderation => "\x1b[33m",
+                    NoticeKind::Motd => "\x1b[1;36m",
+                    NoticeKind::Error => "\x1b[31m",
+                    // Info, and any kinds newer than us
+                    _ => "\x1b[36m",
+                },
+            )),
+        };
+        let text = match style {
+            Some((_, style)) if self.color => format!("{}{}\x1b[0m", style, shown.text),
+            Some((marker, _)) => format!("{}{}", marker, shown.text),
+            None => shown.text.clone(),
+        };
+        match shown.time {
+            Some(time) => format!("[{}] {}", time.format("%H:%M"), text),
+            None => text,
+        }
+    }
+
+    // The commands that are only about how things look, so they never leave run.  Returns what to tell the user, or
+    // None if it isn't one of ours.
+    pub fn command(&mut self, message: &str, prefix: char) -> Option<String> {
+        if let Some(arguments) = command(message, prefix, "filter") {
+            return Some(self.filter(arguments, prefix));
+        }
+        let arguments = command(message, prefix, "timestamps")?;
+        Some(self.timestamps(arguments, prefix))
+    }
+
+    // /filter notices on|off, which never goes to the server.  Returns what to tell the user.
+    fn filter(&mut self, arguments: &str, prefix: char) -> String {
+        let arguments: Vec<&str> = arguments.split_whitespace().collect();
+        match arguments[..] {
+            ["notices", "off"] => {
+                self.show_notices = false;
+                String::from("*** Notices are hidden")
+            }
+            ["notices", "on"] => {
+                self.show_notices = true;
+                String::from("*** Notices are shown")
+            }
+            _ => format!("*** Usage: {}filter notices on|off", prefix),
+        }
+    }
+
+    // /timestamps on|off, also just for us
+    fn timestamps(&mut self, arguments: &str, prefix: char) -> String {
+        match arguments {
+            "on" => {
+                self.timestamps = true;
+                String::from("*** Timestamps are shown")
+            }
+            "off" => {
+                self.timestamps = false;
+                String::from("*** Timestamps are hidden")
+            }
+            _ => format!("*** Usage: {}timestamps on|off", prefix),
+        }
+    }
+}
 
 impl ChatClient {
-    // A typical method definition, takes self first, a string, and a couple objects that implement certain traits
+    pub fn builder() -> ChatClientBuilder {
+        ChatClientBuilder::default()
+    }
+
+    // A typical method definition, takes self first and a couple objects that implement certain traits.  This is
+    // connect() for a terminal: what's read from input is sent as commands, and the events are written to output.
+    // Cancelling the token is the same as typing /quit, and either way we return Ok.  Anything else that stops us is
+    // an error, including not being able to write to the output.
     pub fn run(
         &self,
-        user: String,
-        input: impl io::Read + AsRawFd + Send + 'static, // These are passed to closures and require a static lifetime
-        output: impl io::Write + Send + 'static,         // Removing this is a compile error
-    ) {
+        input: impl io::Read + AsRawFd + Send + 'static, // This is passed to a closure and requires a static lifetime
+        output: impl io::Write,
+        cancel: CancellationToken,
+    ) -> Result<(), Error> {
+        let (events, commands) = self.connect()?;
+        let renderer = Renderer::new(self.color);
+
         // You'll see a lot of Arc and Mutex whenever we deal with shared values in threading, Arc is atomic reference
         // counting, and mutex is an old friend.
-        let (room_sender, room_receiver) = mpsc::channel();
-        let room_sender = Arc::new(Mutex::new(room_sender));
-        let room_receiver = Arc::new(Mutex::new(room_receiver));
+        let (typed_sender, typed) = mpsc::channel();
+        let typed_sender = Arc::new(Mutex::new(typed_sender));
+
+        // With line editing the terminal is only put back the way it was once we return, rather than by the input
+        // thread, which could still be waiting on the keyboard then.  A terminal that won't go raw gets plain reads.
+        let prefix = self.prefix;
+        let raw_mode = if self.line_editing {
+            RawMode::enable().ok()
+        } else {
+            None
+        };
+        let editor = raw_mode
+            .as_ref()
+            .map(|_| Arc::new(Mutex::new(LineEditor::new(prefix))));
+        let mut output: Box<dyn io::Write + '_> = match &editor {
+            Some(editor) => Box::new(Printer::new(output, editor.clone())),
+            None => Box::new(output),
+        };
+
+        // Since we pass input into this closure, this entire function, and even the application, could finish before
+        // it does, which requires the lifetime of input be 'static.
+        let input_thread =
+            thread::spawn(move || ChatClient::handle_input(input, prefix, editor, typed_sender));
+
+        // Once there's nowhere to write there's nobody to show anything to, which is as good as them quitting
+        let relayed = ChatClient::relay(
+            &events,
+            &commands,
+            &typed,
+            renderer,
+            prefix,
+            &mut output,
+            &cancel,
+        );
+        if relayed.is_err() {
+            commands.quit();
+        }
+
+        // If we quit, the input thread has already finished.  Otherwise (say the server threw us out) it could be
+        // waiting on the keyboard for a long while, so it's left to stop on its own the next time it reads anything.
+        if input_thread.is_finished() {
+            input_thread.join().unwrap();
+        }
+
+        let finished = events.finish();
+        relayed?;
+        Ok(finished?)
+    }
+
+    // run, on a screen of its own rather than line by line, in a build with the "tui" feature (see tui.rs).  It takes
+    // over the terminal, so there's no input or output to hand it.
+    pub fn run_tui(&self, cancel: CancellationToken) -> Result<(), Error> {
+        tui::run(|| self.start(true), self.prefix, self.scrollback, cancel)
+    }
+
+    // Sends on what's typed and writes out the events until they run out
+    fn relay(
+        events: &EventReceiver,
+        commands: &CommandSender,
+        typed: &mpsc::Receiver<String>,
+        mut renderer: Renderer,
+        prefix: char,
+        output: &mut impl io::Write,
+        cancel: &CancellationToken,
+    ) -> io::Result<()> {
+        let mut fell_back = false;
+        loop {
+            if cancel.is_cancelled() {
+                commands.quit();
+            }
+
+            // What's typed goes to the server unless it's about how we show things.  Once we've stopped it has nowhere
+            // to go, and the events are about to run out anyway.
+            while let Ok(message) = typed.try_recv() {
+                match renderer.command(message.trim(), prefix) {
+                    Some(reply) => writeln!(output, "{}", reply)?,
+                    None => {
+                        let _ = commands.send(message);
+                    }
+                }
+            }
+
+            match events.recv_timeout(Duration::from_millis(10)) {
+                Ok(event) => ChatClient::show(event, &mut renderer, &mut fell_back, output)?,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            output.flush()?;
+        }
+    }
+
+    // Writes out an event the way run shows it.  Connecting straight to the first server is what everyone expects, so
+    // it only gets a mention if something went wrong first and we had to fall back.
+    fn show(
+        event: ClientEvent,
+        renderer: &mut Renderer,
+        fell_back: &mut bool,
+        output: &mut impl io::Write,
+    ) -> io::Result<()> {
+        match event {
+            ClientEvent::Connected {
+                address,
+                reconnected: true,
+            } => writeln!(output, "*** Reconnected to {}", address),
+            ClientEvent::Connected { address, .. } if *fell_back => {
+                writeln!(output, "*** Connected to {}", address)
+            }
+            ClientEvent::Connected { .. } | ClientEvent::RosterUpdate(_) => Ok(()),
+            ClientEvent::MessageReceived { line, time } => match renderer.render(&line, time) {
+                Some(shown) => {
+                    if let Some(marker) = time.and_then(|time| renderer.marker(time)) {
+                        writeln!(output, "{}", renderer.paint(&marker))?;
+                    }
+                    writeln!(output, "{}", renderer.paint(&shown))
+                }
+                None => Ok(()),
+            },
+            ClientEvent::Status(text) => {
+                for line in text.lines() {
+                    writeln!(output, "*** {}", line)?;
+                }
+                Ok(())
+            }
+            ClientEvent::Error(text) => {
+                *fell_back = true;
+                writeln!(output, "*** {}", text)
+            }
+            ClientEvent::Disconnected(reason) => writeln!(output, "{}", reason),
+        }
+    }
+
+    // Connects in the background and hands back both ends of it: the events as they happen, and where to send what's
+    // typed.  Only a problem with the TLS settings stops us here, anything else comes out as events, and once they
+    // run out finish() says how it ended.  This is all run uses, so anything that wants to show the chat its own way
+    // can do everything the terminal does.
+    pub fn connect(&self) -> Result<(EventReceiver, CommandSender), ChatClientError> {
+        self.start(self.roster)
+    }
+
+    // Connect, with or without the roster whatever the builder said, since the TUI always wants it for its sidebar
+    fn start(&self, roster: bool) -> Result<(EventReceiver, CommandSender), ChatClientError> {
+        // Any problem with the TLS settings should stop us before we start up threads
+        let tls = if self.tls {
+            Some(TlsConnector::new(self.ca_cert.as_deref()).map_err(ChatClientError::Tls)?)
+        } else {
+            None
+        };
+
+        // We always ask for notices, the heartbeat and timestamps, since we know what to do with them
+        let mut capabilities = Capabilities::default();
+        capabilities.lite = self.lite;
+        capabilities.nodelay = self.nodelay;
+        capabilities.bulk = self.bulk;
+        capabilities.notices = true;
+        capabilities.heartbeat = true;
+        capabilities.timestamps = true;
+        capabilities.roster = roster;
+        capabilities.files = true;
+        capabilities.direct = true;
+        capabilities.prefix = Some(self.prefix);
+        capabilities.client = Some(format!("chat_client/{}", env!("CARGO_PKG_VERSION")));
+
+        let (event_sender, receiver) = mpsc::channel();
+        let (sender, typed) = mpsc::channel();
+        let cancel = CancellationToken::new();
+        let requests = Requests {
+            typed,
+            cancel: cancel.clone(),
+        };
 
-        // Since we pass input and output into these closures, this entire function, and even the application, could
-        // finish before they do, which requires the lifetime of input and output be 'static.  The user field is
-        // moved into the closure, so doesn't need anything special.
-        let room_thread = thread::spawn(|| ChatClient::handle_room(user, output, room_receiver));
-        let input_thread = thread::spawn(|| ChatClient::handle_input(input, room_sender));
+        // Who we say we are, which is moved into the closure, so doesn't need anything special
+        let identity = match &self.password {
+            Some(password) => format!("{}login {} {}", self.prefix, self.nickname, password),
+            None => format!("{}user {}", self.prefix, self.nickname),
+        };
+        let servers = Servers::new(
+            self.servers.clone(),
+            self.reconnect,
+            self.upload_limit,
+            self.timeouts,
+        );
+        let updates = Updates(event_sender);
+        let helpers = Helpers {
+            exec: Exec::new(self.exec),
+            files: Files::new(self.downloads.clone(), self.direct_files, self.prefix),
+        };
+        let thread = thread::spawn(move || {
+            ChatClient::handle_room(
+                identity,
+                capabilities,
+                servers,
+                tls,
+                helpers,
+                updates,
+                requests,
+            )
+        });
 
         // This is a compile error
-        // println!("{}", user);
+        // println!("{}", identity);
 
-        // If we exit normally we'll expect our input_thread to end first, which will signal the room_thread with
-        // a message.  If we don't exit normally none of this will matter.
-        input_thread.join().unwrap();
-        room_thread.join().unwrap();
+        Ok((
+            EventReceiver { receiver, thread },
+            CommandSender { sender, cancel },
+        ))
     }
 
     fn handle_room(
-        user: String,
-        mut output: impl io::Write,
-        room_receiver: Arc<Mutex<mpsc::Receiver<String>>>,
-    ) {
+        mut identity: String,
+        capabilities: Capabilities,
+        mut servers: Servers,
+        tls: Option<TlsConnector>,
+        mut helpers: Helpers,
+        updates: Updates,
+        requests: Requests,
+    ) -> Result<(), ChatClientError> {
         // Connect to our server for any chat in our room, with some error handling in case the server isn't there.
-        // Take note of the port, which gives you a good indicator of what tutorial I started with.
-        let mut stream = match TcpStream::connect("127.0.0.1:8080") {
+        // Each address gets one go, and only later connections are retried, since if none of them work to begin with
+        // the addresses are probably wrong.
+        let mut stream = match servers.connect(0, &tls, &updates) {
             Ok(stream) => stream,
-            Err(err) => {
-                print!("{}", err);
-                match err.raw_os_error() {
-                    Some(code) => process::exit(code),
-                    None => process::exit(1),
+            Err(err) => return Err(ChatClientError::Unreachable(err)),
+        };
+        updates.send(ClientEvent::Connected {
+            address: String::from(servers.current()),
+            reconnected: false,
+        });
+
+        // Who we say we are is sent again whenever we reconnect.  It follows any /user or /login typed since.
+        let prefix = capabilities.prefix.unwrap_or('/');
+
+        loop {
+            let ended = ChatClient::converse(
+                stream,
+                &capabilities,
+                &mut identity,
+                &mut servers,
+                &mut helpers,
+                &updates,
+                &requests,
+            );
+            match ended {
+                Ended::Quit => return Ok(()),
+                Ended::Kicked(reason) => return Err(ChatClientError::Kicked(reason)),
+                Ended::Lost if !servers.reconnect => return Err(ChatClientError::Disconnected),
+                Ended::Lost => {
+                    servers.dropped();
+                    // Nothing on its way through the server survives the connection
+                    helpers.files.lost(&updates);
                 }
             }
-        };
 
-        // Before we go nonblocking, let's send an intro
-        let intro = format!("/user {}", user);
-        stream.write(intro.as_bytes()).unwrap();
-        stream.set_nonblocking(true).unwrap();
+            // Each time round the whole list fails the wait doubles, so servers that are down for a while aren't
+            // hammered
+            let mut delay = servers.timeouts.reconnect_delay;
+            stream = loop {
+                updates.status(format!("Reconnecting in {} second(s)", delay.as_secs()));
+                let keep_going = ChatClient::wait_to_reconnect(
+                    delay,
+                    prefix,
+                    &mut servers,
+                    &updates,
+                    &requests,
+                )?;
+                if !keep_going {
+                    return Ok(());
+                }
+
+                if let Ok(stream) = servers.connect(servers.next(), &tls, &updates) {
+                    break stream;
+                }
+                delay = (delay * 2).min(servers.timeouts.max_reconnect_delay);
+            };
+            updates.send(ClientEvent::Connected {
+                address: String::from(servers.current()),
+                reconnected: true,
+            });
+        }
+    }
+
+    fn open(address: &str, timeout: Duration, tls: &Option<TlsConnector>) -> io::Result<Stream> {
+        // A name with both IPv6 and IPv4 addresses gets both tried at once, so one that's broken doesn't hold us up
+        happy_eyeballs::connect(address, timeout).and_then(|stream| match tls {
+            // The certificate has to match the host we connected to, so that's what we hand to TLS
+            Some(tls) => {
+                let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+                tls.connect(host, stream)
+            }
+            None => Ok(Stream::Plain(stream)),
+        })
+    }
+
+    // One connection's worth of chat, from our intro until it ends one way or another
+    fn converse(
+        mut stream: Stream,
+        capabilities: &Capabilities,
+        identity: &mut String,
+        servers: &mut Servers,
+        helpers: &mut Helpers,
+        updates: &Updates,
+        requests: &Requests,
+    ) -> Ended {
+        // Before we go nonblocking, let's send an intro.  Capabilities go first so they're already in effect by the
+        // time the server sees our name.
+        let mut intro = String::new();
+        if let Some(caps) = capabilities.to_command() {
+            intro.push_str(&caps);
+            intro.push('\n');
+        }
+        intro.push_str(identity);
+        let prefix = capabilities.prefix.unwrap_or('/');
+        intro.push('\n');
+        if stream
+            .write_all(intro.as_bytes())
+            .and_then(|_| stream.flush())
+            .is_err()
+        {
+            return Ended::Lost;
+        }
+        if stream.set_nonblocking(true).is_err() {
+            return Ended::Lost;
+        }
+
+        // Our own messages should go out right away too, not wait on Nagle.  If that can't be done they're only a
+        // little slower.
+        if capabilities.nodelay {
+            stream.set_nodelay(true).ok();
+        }
 
         // An undocumented limit of 1024 characters to our messages
         let mut buffer = [0; 1024];
+        let mut lines = LineReader::new();
 
         // Sources and Events are part of popol which is a polling library.  Very similar (if not identical) to c
         // style polling of file descriptors.
@@ -85,17 +1945,32 @@ impl ChatClient {
 
         let mut events = Events::new();
 
+        // A server that stops answering is probably gone, but it could just be slow, so we only warn about it
+        let mut heartbeat = Heartbeat::new(servers.timeouts.heartbeat);
+        let mut warned = false;
+
+        let mut outbox = Outbox::new(servers.upload_limit);
+
         // Going to loop forever, or until an error, or until the server shuts down, or until we explicitly quit
         loop {
+            if requests.cancel.is_cancelled() {
+                return Ended::Quit;
+            }
+
+            if heartbeat.ping_due() {
+                if heartbeat.missed() >= WARN_AFTER_MISSED && !warned {
+                    updates.error("The server isn't responding");
+                    warned = true;
+                }
+                outbox.push_urgent(PING_COMMAND);
+            }
+
             // A timeout waiting for any read or write events on our TcpStream
             match sources.wait_timeout(&mut events, Duration::from_secs(5)) {
                 Ok(_) => {}
                 Err(err) if err.kind() == io::ErrorKind::TimedOut => {
-                    output.write(b"Timed out\n").unwrap();
-                    output.flush().unwrap();
-
-                    // Just exiting instead of unwraveling our other thread
-                    process::exit(1);
+                    updates.send(ClientEvent::Disconnected(String::from("Timed out")));
+                    return Ended::Lost;
                 }
                 Err(_) => {}
             }
@@ -103,50 +1978,217 @@ impl ChatClient {
             // Itererate over our read and write events
             for (key, event) in events.iter() {
                 match key {
-                    Source::Server if event.readable => match stream.read(&mut buffer) {
-                        Ok(bytes_read) => {
-                            // Typical streams: if the stream is readable but returns 0 bytes it was closed on us
-                            if bytes_read == 0 {
-                                output.write(b"Server disconnected\n").unwrap();
-                                output.flush().unwrap();
-
-                                // Just exiting instead of unwraveling our other thread
-                                process::exit(1);
+                    // Read everything that's waiting.  With TLS one read from the socket can decrypt into several
+                    // reads worth of messages, and poll won't tell us about the ones still sitting in rustls.
+                    Source::Server if event.readable => loop {
+                        let bytes_read = match stream.read(&mut buffer) {
+                            Ok(bytes_read) => bytes_read,
+                            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                            // TLS reports a connection dropped without a proper goodbye as an unexpected EOF
+                            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => 0,
+                            Err(_) => break,
+                        };
+
+                        // Typical streams: if the stream is readable but returns 0 bytes it was closed on us
+                        if bytes_read == 0 {
+                            updates.send(ClientEvent::Disconnected(String::from(
+                                "Server disconnected",
+                            )));
+                            return Ended::Lost;
+                        }
+
+                        // The server may send several messages in one go, so pass on each whole line we've got
+                        lines.push(&buffer[..bytes_read]);
+                        // There's no limit on lines from the server, so they're never too long
+                        while let Some(Ok(message)) = lines.next_line() {
+                            // Thrown out, so this is the last thing we'll hear
+                            if let Some(kicked) = Kicked::parse(&message) {
+                                updates.send(ClientEvent::Disconnected(kicked.reason.clone()));
+                                return Ended::Kicked(kicked.reason);
+                            }
+
+                            // The heartbeat is between us and the server, nothing to show
+                            if message == PING_COMMAND {
+                                outbox.push_urgent(PONG_COMMAND);
+                                continue;
+                            }
+                            if message == PONG_COMMAND {
+                                heartbeat.pong();
+                                if warned {
+                                    updates.status("The server is responding again");
+                                    warned = false;
+                                }
+                                continue;
+                            }
+
+                            if let Some(message) = FileMessage::parse(&message) {
+                                helpers.files.received(message, &mut outbox, updates);
+                                continue;
                             }
 
-                            // Write the message that was read in
-                            let message = String::from_utf8(buffer[..bytes_read].to_vec()).unwrap();
-                            output.write(message.as_bytes()).unwrap();
-                            output.write(b"\n").unwrap();
-                            output.flush().unwrap();
+                            // How to show the time is up to whoever's listening, so it comes off the line
+                            let (line, time) = match Timestamped::parse(&message) {
+                                Some(stamped) => (stamped.line, Some(stamped.time)),
+                                None => (message, None),
+                            };
+                            match Roster::parse(&line) {
+                                Some(roster) => {
+                                    updates.send(ClientEvent::RosterUpdate(roster.names))
+                                }
+                                None => updates.send(ClientEvent::MessageReceived { line, time }),
+                            }
                         }
-                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
-                        Err(_) => {}
                     },
                     Source::Server if event.writable => {
-                        match room_receiver.lock().unwrap().try_recv() {
+                        if let Some(lines) = helpers.exec.finished(prefix, updates) {
+                            for line in &lines {
+                                outbox.push(line);
+                            }
+                        }
+                        helpers.files.poll(&mut outbox, updates);
+                        outbox.flush(&mut stream);
+                        match requests.typed.try_recv() {
                             Ok(message) => {
                                 let message = message.trim();
-                                if message == "/quit" {
-                                    return;
+                                if command(message, prefix, "quit").is_some() {
+                                    return Ended::Quit;
+                                }
+                                if helpers.exec.typed(message, prefix, updates) {
+                                    continue;
+                                }
+                                if helpers.files.typed(message, prefix, &mut outbox, updates) {
+                                    continue;
+                                }
+                                if let Some(reply) =
+                                    ChatClient::local_command(message, prefix, servers)
+                                {
+                                    updates.status(reply);
+                                    continue;
+                                }
+
+                                let named = |name| {
+                                    command(message, prefix, name)
+                                        .filter(|arguments| !arguments.is_empty())
+                                };
+                                if named("user").is_some() {
+                                    *identity = String::from(message);
+                                }
+                                // A two-factor code won't work twice, so reconnecting leaves it off and the server
+                                // asks for a fresh one
+                                if let Some(arguments) = named("login") {
+                                    let arguments: Vec<&str> =
+                                        arguments.split_whitespace().collect();
+                                    *identity = match arguments[..] {
+                                        [name, password, _code] => {
+                                            format!("{}login {} {}", prefix, name, password)
+                                        }
+                                        _ => String::from(message),
+                                    };
+                                }
+                                // A new connection isn't in the room yet, where /nick works, so it asks with /user
+                                if let Some(name) = named("nick") {
+                                    *identity = format!("{}user {}", prefix, name);
+                                }
+                                // A token only works once, so after a /reset it's the new password that gets us back in
+                                if let Some(arguments) = named("reset") {
+                                    let arguments: Vec<&str> =
+                                        arguments.split_whitespace().collect();
+                                    if let [name, _token, password] = arguments[..] {
+                                        *identity =
+                                            format!("{}login {} {}", prefix, name, password);
+                                    }
                                 }
 
-                                stream.write(message.as_bytes()).unwrap();
-                                stream.flush().unwrap();
+                                outbox.push(message);
                             }
-                            Err(_) => {
+                            Err(TryRecvError::Empty) => {
                                 // Good ol' busy waiting
                                 thread::sleep(Duration::from_millis(10));
                             }
+                            // Nobody can send us anything any more, which is as good as a /quit
+                            Err(TryRecvError::Disconnected) => return Ended::Quit,
                         }
-                    },
+                    }
                     _ => {}
                 }
             }
         }
     }
 
-    fn handle_input(input: impl io::Read + AsRawFd, room_sender: Arc<Mutex<mpsc::Sender<String>>>) {
+    // Sits out the delay before the next attempt, still listening to what's typed meanwhile.  False if they quit (or
+    // we're cancelled), and an error if they turn reconnecting off, since then there's no next attempt.
+    fn wait_to_reconnect(
+        delay: Duration,
+        prefix: char,
+        servers: &mut Servers,
+        updates: &Updates,
+        requests: &Requests,
+    ) -> Result<bool, ChatClientError> {
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            if requests.cancel.is_cancelled() {
+                return Ok(false);
+            }
+
+            let message = match requests.typed.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => return Ok(false),
+            };
+
+            let message = message.trim();
+            if command(message, prefix, "quit").is_some() {
+                return Ok(false);
+            }
+            match ChatClient::local_command(message, prefix, servers) {
+                Some(reply) => updates.status(reply),
+                None if !message.is_empty() => updates.error("Not connected, that wasn't sent"),
+                None => {}
+            }
+
+            // Turning reconnecting off while we're waiting to reconnect means giving up
+            if !servers.reconnect {
+                return Err(ChatClientError::Disconnected);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Commands about the connection that never go to the server.  Returns what to tell the user, or None if it's for
+    // the server after all.
+    fn local_command(message: &str, prefix: char, servers: &mut Servers) -> Option<String> {
+        if command(message, prefix, "servers").is_some() {
+            return Some(servers.report());
+        }
+
+        // /reconnect on|off, whether we try to get back in when the connection drops
+        let arguments = command(message, prefix, "reconnect")?;
+        Some(match arguments {
+            "on" => {
+                servers.reconnect = true;
+                String::from("Will reconnect if the connection drops")
+            }
+            "off" => {
+                servers.reconnect = false;
+                String::from("Won't reconnect if the connection drops")
+            }
+            _ => format!("Usage: {}reconnect on|off", prefix),
+        })
+    }
+
+    // Reads what's typed and sends it on a line at a time.  With an editor the terminal's raw, so the keys come as
+    // they're pressed and the editor puts them together into lines (see line_editor.rs), otherwise the terminal has
+    // already done that for us.
+    fn handle_input(
+        input: impl io::Read + AsRawFd,
+        prefix: char,
+        editor: Option<Arc<Mutex<LineEditor>>>,
+        room_sender: Arc<Mutex<mpsc::Sender<String>>>,
+    ) {
         let mut sources = Sources::new();
         sources.register(Source::Input, &input, popol::interest::READ);
 
@@ -154,15 +2196,51 @@ impl ChatClient {
         let mut reader = BufReader::new(input);
 
         loop {
-            // Wait for something to happen on our sources.
-            sources.wait(&mut events).unwrap();
+            // Wait for something to happen on our sources.  A signal (like the terminal being resized) can interrupt
+            // the wait, but anything else means we can't read the keyboard any more.
+            match sources.wait(&mut events) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            }
 
             for (key, _event) in events.iter() {
-                match key {
-                    Source::Input => {
-                        let mut one_line = String::new();
-                        match reader.read_line(&mut one_line) {
+                match (key, &editor) {
+                    (Source::Input, Some(editor)) => {
+                        let mut keys = [0; 1024];
+                        let typed = match reader.read(&mut keys) {
+                            Ok(0) => return,
+                            Ok(read) => {
+                                let mut editor = editor.lock().unwrap();
+                                let typed = editor.feed(&keys[..read]);
+                                let mut stdout = io::stdout();
+                                let _ = editor.draw(&mut stdout).and_then(|_| stdout.flush());
+                                typed
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(_) => return,
+                        };
+                        for typed in typed {
+                            let line = match typed {
+                                Typed::Line(line) => line,
+                                Typed::Quit => format!("{}quit", prefix),
+                            };
+                            if room_sender.lock().unwrap().send(line.clone()).is_err() {
+                                return;
+                            }
+                            if command(line.trim(), prefix, "quit").is_some() {
+                                return;
+                            }
+                        }
+                    }
+                    (Source::Input, None) => {
+                        let mut one_line = Vec::new();
+                        match reader.read_until(b'\n', &mut one_line) {
                             Ok(_) => {
+                                // A stray byte that isn't UTF-8 shouldn't stop us reading the keyboard, so it becomes
+                                // a replacement character like it would coming from the server
+                                let one_line = String::from_utf8_lossy(&one_line).into_owned();
+
                                 // Have to do a clone here due to borrowing.  We can't check the
                                 // trimmed value of one_line after sending it because mpsc::Sender ends up moving
                                 // the String.  We could send a clone of the string instead and then check the original
@@ -170,8 +2248,11 @@ impl ChatClient {
 
                                 // This is a compile error
                                 // room_sender.lock().unwrap().send(one_line).unwrap();
-                                room_sender.lock().unwrap().send(one_line.clone()).unwrap();
-                                if one_line.trim() == "/quit" {
+                                // The room's gone if we were cancelled, and there's nobody left to read it
+                                if room_sender.lock().unwrap().send(one_line.clone()).is_err() {
+                                    return;
+                                }
+                                if command(one_line.trim(), prefix, "quit").is_some() {
                                     return;
                                 }
                             }
@@ -185,3 +2266,203 @@ impl ChatClient {
         }
     }
 }
+
+// For chat bots written in Rust.  A BotClient is a ChatClient that hands over what's said in the room already picked
+// apart, rather than as lines for a terminal, e.g.
+//
+//     let client = ChatClient::builder().address("chat:8080").nickname("greeter").password("secret").build()?;
+//     let greet = |bot: &Bot, event| {
+//         if let BotEvent::Message { sender, body, .. } = event {
+//             if body == "!hello" {
+//                 let _ = bot.say(&format!("Hello {}!", sender));
+//             }
+//         }
+//     };
+//     BotClient::new(client).run(greet, CancellationToken::new())?;
+//
+// With a password on the ChatClient the bot logs in to its account when it connects (and reconnects), otherwise it
+// just takes the name.  The room sends us back whatever we say, which is left out so a bot can't set itself off.
+pub struct BotClient {
+    client: ChatClient,
+}
+
+// What a bot hears, in the order it happens
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BotEvent {
+    // We're in, and whether it's after losing an earlier connection
+    Connected {
+        address: String,
+        reconnected: bool,
+    },
+    // Someone talking in the room.  Mention is set if they mentioned us, or everyone.  Bot is set if the sender is a
+    // bot account, whose messages come with the server's tag, which is taken off the body.
+    Message {
+        sender: String,
+        body: String,
+        time: Option<SystemTime>,
+        mention: Option<MentionKind>,
+        bot: bool,
+    },
+    // Something the server said itself, like someone joining or the answer to a command
+    Notice {
+        kind: NoticeKind,
+        text: String,
+    },
+    // Everyone in the room, whenever that changes (only with roster set)
+    RosterUpdate(Vec<String>),
+    // The same as ClientEvent's
+    Status(String),
+    Error(String),
+    Disconnected(String),
+}
+
+impl BotEvent {
+    // None for our own chat coming back to us
+    fn from_event(event: ClientEvent, nickname: &str) -> Option<BotEvent> {
+        Some(match event {
+            ClientEvent::Connected {
+                address,
+                reconnected,
+            } => BotEvent::Connected {
+                address,
+                reconnected,
+            },
+            // A bot only ever talks to the room, so it has nothing to say to a /msg
+            ClientEvent::MessageReceived { line, .. } if Direct::parse(&line).is_some() => {
+                return None
+            }
+            ClientEvent::MessageReceived { line, time } => {
+                if let Some(mention) = Mention::parse(&line) {
+                    let (body, bot) = BotEvent::untag(mention.body);
+                    return Some(BotEvent::Message {
+                        sender: mention.sender,
+                        body,
+                        time,
+                        mention: Some(mention.kind),
+                        bot,
+                    });
+                }
+                if let Some(notice) = Notice::parse(&line) {
+                    return Some(BotEvent::Notice {
+                        kind: notice.kind,
+                        text: notice.text,
+                    });
+                }
+                // We always ask for notices, so anything else is chat.  Names can't have spaces, so the first ": " is
+                // where the name ends.
+                match line.split_once(": ") {
+                    Some((sender, _)) if sender == nickname => return None,
+                    Some((sender, body)) => {
+                        let (body, bot) = BotEvent::untag(String::from(body));
+                        BotEvent::Message {
+                            sender: String::from(sender),
+                            body,
+                            time,
+                            mention: None,
+                            bot,
+                        }
+                    }
+                    None => BotEvent::Notice {
+                        kind: NoticeKind::Info,
+                        text: line,
+                    },
+                }
+            }
+            ClientEvent::RosterUpdate(names) => BotEvent::RosterUpdate(names),
+            ClientEvent::Status(text) => BotEvent::Status(text),
+            ClientEvent::Error(text) => BotEvent::Error(text),
+            ClientEvent::Disconnected(reason) => BotEvent::Disconnected(reason),
+        })
+    }
+
+    // The server won't let anyone but a bot start a message with the tag, so it's safe to go by
+    fn untag(body: String) -> (String, bool) {
+        match body
+            .strip_prefix(BOT_TAG)
+            .and_then(|rest| rest.strip_prefix(' '))
+        {
+            Some(rest) => (String::from(rest), true),
+            None => (body, false),
+        }
+    }
+}
+
+// How a bot talks back, which can be cloned for as many threads as need it
+#[derive(Clone)]
+pub struct Bot {
+    commands: CommandSender,
+    prefix: char,
+}
+
+impl Bot {
+    // Says text in the room just as it is, even if it looks like a command.  Each line of it is a message of its own.
+    // An error means we've already stopped.
+    pub fn say(&self, text: &str) -> Result<(), SendError<String>> {
+        for line in text.lines() {
+            self.commands.send(escape(line, self.prefix))?;
+        }
+        Ok(())
+    }
+
+    // A command, or anything else, just as it would be typed, e.g. bot.send("/join lobby")
+    pub fn send(&self, line: impl Into<String>) -> Result<(), SendError<String>> {
+        self.commands.send(line)
+    }
+
+    pub fn quit(&self) {
+        self.commands.quit();
+    }
+}
+
+impl BotClient {
+    pub fn new(client: ChatClient) -> BotClient {
+        BotClient { client }
+    }
+
+    // The events as a channel, and the Bot to answer them with, for bots with a loop of their own.  Like connect(), only
+    // a problem with the TLS settings stops us here.
+    pub fn connect(&self) -> Result<(EventReceiver<BotEvent>, Bot), ChatClientError> {
+        let (events, commands) = self.client.connect()?;
+        let bot = Bot {
+            commands,
+            prefix: self.client.prefix,
+        };
+
+        // Sorting the events needs a thread of its own, so they can still be waited on with a timeout.  It stops when
+        // they run out, and hands on how the client finished.
+        let nickname = self.client.nickname.clone();
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for event in events.iter() {
+                if let Some(event) = BotEvent::from_event(event, &nickname) {
+                    // Nobody's listening any more, but they can still quit with the Bot
+                    let _ = sender.send(event);
+                }
+            }
+            events.finish()
+        });
+
+        Ok((EventReceiver { receiver, thread }, bot))
+    }
+
+    // Calls handler with every event until we stop, which is when the handler calls bot.quit() or the token is
+    // cancelled, or the client gives up by itself.  Anything slow in the handler holds up the events behind it.
+    pub fn run(
+        &self,
+        mut handler: impl FnMut(&Bot, BotEvent),
+        cancel: CancellationToken,
+    ) -> Result<(), ChatClientError> {
+        let (events, bot) = self.connect()?;
+        loop {
+            if cancel.is_cancelled() {
+                bot.quit();
+            }
+            match events.recv_timeout(Duration::from_millis(100)) {
+                Ok(event) => handler(&bot, event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        events.finish()
+    }
+}
@@ -1,202 +1,4462 @@
-use bus::Bus;
-use bus::BusReader;
+use chrono::DateTime;
+use chrono::Local;
+use chrono::NaiveDate;
 use core::time;
 use popol::Events;
 use popol::Sources;
+use rand_core::OsRng;
+use rand_core::RngCore;
+use signal_hook::consts::SIGUSR1;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
+use std::mem;
+use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use tracing::debug;
+use tracing::debug_span;
+use tracing::error;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+use tracing::Span;
 
+use crate::access::AccessList;
+use crate::accounts::AccountError;
+use crate::accounts::AccountStore;
+use crate::api;
+use crate::api::ApiError;
+use crate::api::Limits;
+use crate::api::Rooms;
+use crate::auth_failures;
+use crate::auth_failures::Failure;
+use crate::auth_failures::Lockouts;
+use crate::bans::Ban;
+use crate::bans::BanList;
+use crate::batch::Batch;
+use crate::blocking_pool::BlockingPool;
+use crate::cancel::CancellationToken;
+use crate::config::ConfigError;
+use crate::config::ServerConfig;
+use crate::digest::Digest;
+use crate::error::Error;
+use crate::fanout::Fanout;
+use crate::fanout::Overflow;
+use crate::fanout::Subscription;
+use crate::heartbeat::Heartbeat;
+use crate::hooks::NoHooks;
+use crate::hooks::ServerHooks;
+use crate::link;
+use crate::link::Links;
+use crate::listener;
+use crate::matrix::MatrixBridge;
+use crate::mentions;
+use crate::mentions::MentionSettings;
+use crate::metrics;
+use crate::metrics::Metrics;
+use crate::names::NamePolicy;
+use crate::overload::OverloadMonitor;
+use crate::overload::Transition;
+use crate::permissions::Authorizer;
+use crate::permissions::Role;
+use crate::plugins::Announcer;
+use crate::plugins::ChatPlugin;
+use crate::plugins::PluginContext;
+use crate::plugins::PluginRegistry;
+use crate::protocol;
+use crate::protocol::Capabilities;
+use crate::protocol::Clocked;
+use crate::protocol::Direct;
+use crate::protocol::FileMessage;
+use crate::protocol::Hlc;
+use crate::protocol::HybridClock;
+use crate::protocol::Kicked;
+use crate::protocol::LineReader;
+use crate::protocol::LineTooLong;
+use crate::protocol::Mention;
+use crate::protocol::MentionKind;
+use crate::protocol::Notice;
+use crate::protocol::NoticeKind;
+use crate::protocol::Roster;
+use crate::protocol::Timestamped;
+use crate::protocol::BOT_TAG;
+use crate::protocol::FILE_COMMAND;
+use crate::protocol::MAX_FILE_ID;
+use crate::protocol::PING_COMMAND;
+use crate::protocol::PONG_COMMAND;
+use crate::rate_limit::TokenBucket;
+use crate::redis;
+use crate::redis::RedisBus;
+use crate::scripts::ScriptHost;
+use crate::state::Command;
+use crate::state::ConnectionState;
+use crate::state::Welcome;
+use crate::stats::RoomStats;
+use crate::storage::HistoryEntry;
+use crate::storage::Storage;
+use crate::storage::StoredMessage;
+use crate::thread_pool::JobHandle;
+use crate::thread_pool::Priority;
 use crate::thread_pool::ThreadPool;
+use crate::tls::TlsAcceptor;
+use crate::transfers::Transfers;
+use crate::transport::Stream;
+use crate::waker;
+use crate::waker::WakeReceiver;
+use crate::waker::Waker;
+use crate::web;
+use crate::web::PublicRooms;
+use crate::websocket::WebSocketAcceptor;
 
 // Derive tells the compiler to add these traits automatically for us.  Enums are a composite type, so this
 // works as long as the variants within the enum also define these types (or can derive them).
 #[derive(Eq, PartialEq, Clone)]
 enum Source {
     Listener,
+    // The [websocket] one, for browsers
+    WebSocketListener,
     Client,
+    // Another thread has something for the client, see waker.rs
+    Waker,
 }
 
-// Our public struct, with no fields
-pub struct ChatServer {}
+// The same for one of the reactor's event loops, which waits on a lot of clients at once (see run_event_loop)
+#[derive(Eq, PartialEq, Clone)]
+enum LoopSource {
+    Client(u64),
+    // Shared by every client on the loop
+    Waker,
+}
+
+// What kind of thing a message is, so each client can decide whether it wants it.  Chat is what people actually said,
+// presence is people coming and going, notices are from the server itself, and pins are highlights an operator wants
+// remembered in the daily digest.
+#[derive(Eq, PartialEq, Clone, Copy)]
+enum MessageKind {
+    Chat,
+    Presence,
+    Notice(NoticeKind),
+    Pin,
+}
+
+impl MessageKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Chat => "chat",
+            MessageKind::Presence => "presence",
+            MessageKind::Notice(_) => "notice",
+            MessageKind::Pin => "pin",
+        }
+    }
+}
+
+// How long a goodbye to someone we've thrown out gets to be delivered before we hang up regardless
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long a client can go quiet in the middle of the TLS or WebSocket handshake before we give up on them
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the accept loop looks up from waiting on connections to see whether we've been told to shut down
+const SHUTDOWN_CHECK: Duration = Duration::from_millis(200);
+
+// How often we look to see whether SIGUSR1 has asked for a snapshot
+const SNAPSHOT_CHECK: Duration = Duration::from_secs(1);
+
+// How often we look for file offers that have gone unanswered for too long
+const OFFER_CHECK: Duration = Duration::from_secs(5);
+
+// There's only the one room for now, but history is stored per room so it's ready for more
+pub const ROOM_NAME: &str = "lobby";
+
+// Which way the member count moved, and who moved it, for the messages that change it (a rename doesn't)
+#[derive(Eq, PartialEq, Clone)]
+enum Membership {
+    Joined(String),
+    Left(String),
+}
+
+// Everything that goes through the room is one of these, rather than a bare String, so the kind and the sender travel
+// along with the body all the way out to each client handler (and into the history).
+#[derive(Clone)]
+struct RoomMessage {
+    kind: MessageKind,
+    sender: Option<String>,
+    body: String,
+    membership: Option<Membership>,
+    // Chat with @all or @here in it that gets to ping everyone, which takes both the sender (see may_mass_mention) and
+    // the room agreeing
+    mass_mention: bool,
+    // Who chat names with an @, lowercased, worked out once for the broadcast instead of by every connection it goes to
+    mentioned: Vec<String>,
+    // When the room sent it out, stamped just before the broadcast
+    sent: Option<SystemTime>,
+    // Where it falls in the room's order, from our clock just before the broadcast, or from the clock where it was said
+    // for one that's relayed, so the order is the same on every server
+    clock: Option<Hlc>,
+    // From another server, over a link or through Redis, so it's only for the clients here and never passed on
+    relayed: bool,
+}
+
+impl RoomMessage {
+    fn new(kind: MessageKind, body: impl Into<String>) -> RoomMessage {
+        RoomMessage {
+            kind,
+            sender: None,
+            body: body.into(),
+            membership: None,
+            mass_mention: false,
+            mentioned: Vec::new(),
+            sent: None,
+            clock: None,
+            relayed: false,
+        }
+    }
+
+    fn joined(name: &str) -> RoomMessage {
+        RoomMessage {
+            membership: Some(Membership::Joined(String::from(name))),
+            ..RoomMessage::new(
+                MessageKind::Presence,
+                format!("{} has joined the room.", name),
+            )
+        }
+    }
+
+    fn left(name: &str) -> RoomMessage {
+        RoomMessage {
+            membership: Some(Membership::Left(String::from(name))),
+            ..RoomMessage::new(
+                MessageKind::Presence,
+                format!("{} has left the room.", name),
+            )
+        }
+    }
+
+    // Someone was thrown out.  It's a notice rather than presence, so even lite clients hear about moderation.
+    fn removed(name: &str, notice: String) -> RoomMessage {
+        RoomMessage {
+            membership: Some(Membership::Left(String::from(name))),
+            ..RoomMessage::new(MessageKind::Notice(NoticeKind::Moderation), notice)
+        }
+    }
+
+    fn chat(sender: &str, body: &str) -> RoomMessage {
+        RoomMessage {
+            kind: MessageKind::Chat,
+            sender: Some(String::from(sender)),
+            body: String::from(body),
+            membership: None,
+            mass_mention: false,
+            mentioned: Vec::new(),
+            sent: None,
+            clock: None,
+            relayed: false,
+        }
+    }
+
+    fn pin(sender: &str, body: &str) -> RoomMessage {
+        RoomMessage {
+            kind: MessageKind::Pin,
+            ..RoomMessage::chat(sender, body)
+        }
+    }
+
+    fn mentions(&self, name: &str) -> bool {
+        self.mentioned
+            .iter()
+            .any(|mentioned| mentioned.eq_ignore_ascii_case(name))
+    }
+
+    // How the message looks on the wire.  Anything that isn't someone talking is a notice, which is only typed for
+    // clients that asked for notices.
+    fn line(&self, typed: bool) -> String {
+        let notice = match (&self.sender, self.kind) {
+            (Some(sender), MessageKind::Pin) => Notice {
+                kind: NoticeKind::Info,
+                text: format!("{} pinned: {}", sender, self.body),
+            },
+            (Some(sender), _) => return format!("{}: {}", sender, self.body),
+            (None, MessageKind::Notice(kind)) => Notice {
+                kind,
+                text: self.body.clone(),
+            },
+            (None, _) => Notice {
+                kind: NoticeKind::Presence,
+                text: self.body.clone(),
+            },
+        };
+
+        if typed {
+            notice.to_line()
+        } else {
+            notice.to_plain()
+        }
+    }
+}
+
+// Things the rest of the server can ask a client handler to do.  Each handler checks its own channel every time around
+// its loop, so these land within a few milliseconds.
+enum Control {
+    Notice(Notice),
+    Kick { by: String, reason: String },
+    Ban { by: String, reason: String },
+    // They ended this session from another one logged in to the same account
+    Logout,
+    // A line for them as it is, like a file on its way through (see transfers.rs)
+    Line(String),
+    // A /msg to or from them, which goes out however their client asked for it
+    Direct(Direct),
+}
+
+// A /room freeze: until when nobody's chat gets through, and what they're told about it
+struct Freeze {
+    until: Instant,
+    reason: String,
+}
+
+// How to reach a connected client's handler from outside it
+struct Connection {
+    user: String,
+    address: IpAddr,
+    // What the client called itself in its handshake, if it did, and when it connected, for /sessions
+    client: Option<String>,
+    connected: SystemTime,
+    // Whether they've logged in to the name, in which case their other connections can share it
+    logged_in: bool,
+    // Whether they're in the room, as opposed to still being welcomed
+    in_room: bool,
+    // What they said with /away, while they're away.  Every connection to the name gets it, so it's the person who's
+    // away rather than one of their windows.
+    away: Option<String>,
+    // Whether their client can take files, which it asks for in its handshake
+    files: bool,
+    control: mpsc::Sender<Control>,
+    // Poked after anything is sent down control, or broadcast to the room, so the handler notices it
+    waker: Arc<Waker>,
+}
+
+// Everything the room and the client handlers share.  It's built once in run and handed around in an Arc, which
+// saves us from passing a longer and longer list of arguments to every handler as features get added.
+struct ServerContext {
+    config: ServerConfig,
+    // This atomic bool is so we can read and write the value safely across threads
+    running: AtomicBool,
+    overload: OverloadMonitor,
+    // The ThreadPool only runs the room and client handlers.  Anything that blocks on the outside world (disk writes,
+    // DNS lookups, webhooks) goes to this pool instead, so a slow disk can't starve chat traffic.
+    io_pool: BlockingPool,
+    accounts: AccountStore,
+    // Who's failed to log in lately, and who's locked out for it
+    lockouts: Lockouts,
+    bans: BanList,
+    // Starts out as the config says, and ops can read it again from the file with /room access reload
+    access: Mutex<AccessList>,
+    // None when history is turned off in the config
+    storage: Option<Storage>,
+    tls: Option<TlsAcceptor>,
+    // None without a [websocket] listener
+    websocket: Option<WebSocketAcceptor>,
+    hooks: Arc<dyn ServerHooks>,
+    plugins: PluginRegistry,
+    // None without a [scripts] dir in the config.  Only the room runs them.
+    scripts: Option<ScriptHost>,
+    // None without a [matrix] homeserver.  The room relays chat to it, and it says what it hears from Matrix itself.
+    matrix: Option<MatrixBridge>,
+    // None without a [link] address or peers.  The room passes what happens here on, and it says what happens elsewhere.
+    links: Option<Arc<Links>>,
+    // None without a [redis] address.  Much the same as the links, for processes sharing the room through Redis.
+    redis: Option<RedisBus>,
+    // Only the room feeds this, but client handlers read it for /room stats
+    stats: Mutex<RoomStats>,
+    metrics: Arc<Metrics>,
+    // Every live connection by id, so one handler (or the room) can reach another
+    connections: Mutex<HashMap<u64, Connection>>,
+    // Starts out as the config says, and ops can flip it with /room mass-mentions
+    mass_mentions: AtomicBool,
+    // When each name (lowercased) last pinged everyone, for the cooldown
+    mass_mentioned: Mutex<HashMap<String, Instant>>,
+    // Who may use which commands, from the config's permissions
+    authorizer: Authorizer,
+    // What names are allowed, from the config's names
+    names: NamePolicy,
+    // Muted names (lowercased) and when each mute runs out.  The room checks this before it broadcasts any chat.
+    mutes: Mutex<HashMap<String, Instant>>,
+    // Only the room reads it, but relayed messages come in from other threads with readings it has to catch up to
+    clock: Mutex<HybridClock>,
+    // Set by /room freeze, and cleared again by the pool's timer once it runs out (or by another /room freeze)
+    freeze: Mutex<Option<Freeze>>,
+    // Files on their way from one person to another with /send
+    transfers: Mutex<Transfers>,
+    // The room broadcasts everything through this.  Client handlers only touch it to subscribe when they join.
+    fanout: Mutex<Fanout<RoomMessage>>,
+    // This is a multiple producer, single consumer, channel for each of our clients to send incoming messages to our
+    // room (to be broadcasted to everyone).
+    message_sender: Mutex<mpsc::Sender<RoomMessage>>,
+}
+
+impl ServerContext {
+    fn send_to_room(&self, kind: MessageKind, text: impl Into<String>) {
+        self.send_message(RoomMessage::new(kind, text));
+    }
+
+    // Once we're shutting down the room may already be gone, and then there's nobody left to tell anyway
+    fn send_message(&self, message: RoomMessage) {
+        self.message_sender.lock().unwrap().send(message).ok();
+    }
+
+    // History is the first thing to go when we're overloaded, the conversation itself is more important
+    fn record_history(self: &Arc<Self>, message: &RoomMessage) {
+        if self.storage.is_none() || self.overload.is_degraded() {
+            return;
+        }
+
+        let context = self.clone();
+        let message = message.clone();
+        let timestamp = message.sent.unwrap_or_else(SystemTime::now);
+        // The write happens on another thread, so we bring our span along for anything it logs
+        let span = Span::current();
+        self.io_pool.execute(move || {
+            let _entered = span.enter();
+            let stored = StoredMessage {
+                room: ROOM_NAME,
+                sender: message.sender.as_deref(),
+                kind: message.kind.as_str(),
+                body: &message.body,
+                timestamp,
+            };
+            if let Some(storage) = &context.storage {
+                if let Err(err) = storage.insert(&stored) {
+                    error!("Unable to save history: {}", err);
+                }
+            }
+        });
+    }
+
+    // Kicks whoever is using the name, and says how many connections that was (more than one if they're logged in from
+    // more than one place)
+    fn kick(&self, name: &str, by: &str, reason: &str) -> usize {
+        self.send_control(
+            |_, connection| connection.user.eq_ignore_ascii_case(name),
+            || Control::Kick {
+                by: String::from(by),
+                reason: String::from(reason),
+            },
+        )
+    }
+
+    // Sends a control message to every connection that matches, and says how many of them got it
+    fn send_control(
+        &self,
+        matches: impl Fn(u64, &Connection) -> bool,
+        control: impl Fn() -> Control,
+    ) -> usize {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .iter()
+            .filter(|(id, connection)| matches(**id, connection))
+            .filter(|(_, connection)| {
+                let sent = connection.control.send(control()).is_ok();
+                connection.waker.wake();
+                sent
+            })
+            .count()
+    }
+
+    // Wakes every client handler, after a broadcast they'd otherwise not notice until their next timeout
+    fn wake_all(&self) {
+        for connection in self.connections.lock().unwrap().values() {
+            connection.waker.wake();
+        }
+    }
+
+    // Everyone who has picked a name, sorted, and only once however many places they're logged in from
+    fn online(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| !connection.user.is_empty())
+            .map(|connection| connection.user.clone())
+            .collect();
+        names.sort_unstable_by_key(|name| name.to_lowercase());
+        names.dedup_by(|name, other| name.eq_ignore_ascii_case(other));
+        names
+    }
+
+    // Like online, but only whoever has made it into the room, which is who the roster lists
+    fn in_room(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| connection.in_room)
+            .map(|connection| connection.user.clone())
+            .collect();
+        names.sort_unstable_by_key(|name| name.to_lowercase());
+        names.dedup_by(|name, other| name.eq_ignore_ascii_case(other));
+        names
+    }
+
+    // Why they're away, if they are.  Empty if they didn't say.
+    fn away(&self, name: &str) -> Option<String> {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| connection.user.eq_ignore_ascii_case(name))
+            .find_map(|connection| connection.away.clone())
+    }
+
+    fn is_online(&self, name: &str) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .any(|connection| connection.user.eq_ignore_ascii_case(name))
+    }
+
+    // The connections double as the list of names in use, so nobody can take a name someone else online has.  Checking
+    // and taking it under the one lock means two people can't both grab the same name at once.  Case doesn't matter,
+    // and someone changing the case of their own name isn't in anybody's way.  The exception is logging in, which can
+    // share the name with connections that are logged in to it too, since they're all the same person.
+    fn claim_name(&self, id: u64, name: &str, logging_in: bool) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let taken = connections.iter().any(|(other, connection)| {
+            *other != id
+                && connection.user.eq_ignore_ascii_case(name)
+                && !(logging_in && connection.logged_in)
+        });
+        if taken {
+            return false;
+        }
+        if let Some(connection) = connections.get_mut(&id) {
+            connection.user = String::from(name);
+        }
+
+        true
+    }
+
+    fn update_connection(&self, id: u64, update: impl FnOnce(&mut Connection)) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            update(connection);
+        }
+    }
+
+    // Someone logged in from more than one place is one member as far as the room is concerned, so it hears about them
+    // arriving with their first connection and leaving with their last.  This marks the connection as in the room or
+    // not, and says whether any other connection in the room still goes by the name.  Doing both under the one lock
+    // means two connections leaving at once can't each think the other is still there.
+    fn mark_in_room(&self, id: u64, name: &str, in_room: bool) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(connection) = connections.get_mut(&id) {
+            connection.in_room = in_room;
+        }
+
+        ServerContext::others_in_room(&connections, id, name)
+    }
+
+    fn others_in_room(connections: &HashMap<u64, Connection>, id: u64, name: &str) -> bool {
+        connections.iter().any(|(other, connection)| {
+            *other != id && connection.in_room && connection.user.eq_ignore_ascii_case(name)
+        })
+    }
+
+    // A notice for whoever is using the name
+    fn notify(&self, name: &str, kind: NoticeKind, text: &str) {
+        self.send_control(
+            |_, connection| connection.user.eq_ignore_ascii_case(name),
+            || {
+                Control::Notice(Notice {
+                    kind,
+                    text: String::from(text),
+                })
+            },
+        );
+    }
+
+    // A line for one connection in particular
+    fn send_line(&self, connection: u64, message: &FileMessage) {
+        let line = message.to_line();
+        self.send_control(|id, _| id == connection, || Control::Line(line.clone()));
+    }
+
+    // Calls off whatever the connection was sending or taking through us, and tells whoever's on the other end
+    fn cancel_transfers(&self, connection: u64) {
+        let cancelled = self.transfers.lock().unwrap().disconnected(connection);
+        for (id, transfer) in cancelled {
+            let (other, reason) = if transfer.sender == connection {
+                (transfer.receiver, format!("{} left", transfer.from))
+            } else {
+                (Some(transfer.sender), format!("{} left", transfer.to))
+            };
+            if let Some(other) = other {
+                self.send_line(other, &FileMessage::Cancel { id, reason });
+            }
+        }
+    }
+
+    // How much longer the name is muted for, if it is.  Mutes that have run out are cleared as they're found, which is
+    // all the expiry they need.
+    fn muted_for(&self, name: &str) -> Option<Duration> {
+        let mut mutes = self.mutes.lock().unwrap();
+        let key = name.to_lowercase();
+        let left = mutes.get(&key)?.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            mutes.remove(&key);
+            return None;
+        }
+
+        Some(left)
+    }
+
+    // Whether @all and @here ping anyone right now.  Ops can switch them off, and big rooms don't get them at all.
+    fn mass_mentions_allowed(&self) -> bool {
+        let limit = self.config.mentions.mass_mentions_max_members;
+        self.mass_mentions.load(Ordering::Relaxed)
+            && (limit == 0 || self.stats.lock().unwrap().members() <= limit)
+    }
+
+    // How much longer the room is frozen for and why, if it is.  Like a mute, one that's run out no longer counts even
+    // before the timer gets to it.
+    fn frozen_for(&self) -> Option<(Duration, String)> {
+        let freeze = self.freeze.lock().unwrap();
+        let freeze = freeze.as_ref()?;
+        let left = freeze.until.saturating_duration_since(Instant::now());
+        (!left.is_zero()).then(|| (left, freeze.reason.clone()))
+    }
+
+    // Being on the list isn't enough, you have to have proven it's you with /login (or /register).  A bot is only ever
+    // a bot, even if someone put its name in ops.
+    fn role(&self, session: &Session) -> Role {
+        if session.bot {
+            Role::Bot
+        } else if self.is_op(session) {
+            Role::Op
+        } else if session.logged_in {
+            Role::User
+        } else {
+            Role::Guest
+        }
+    }
+
+    fn is_op(&self, session: &Session) -> bool {
+        session.logged_in
+            && self
+                .config
+                .ops
+                .iter()
+                .any(|op| op.eq_ignore_ascii_case(&session.user))
+    }
+
+    // Builds the digest for a finished day and sends it wherever the config says.  It reads the whole day's history, so
+    // it runs on the blocking pool.
+    fn publish_digest(self: &Arc<Self>, day: NaiveDate) {
+        let context = self.clone();
+        self.io_pool.execute(move || {
+            let _span = info_span!("digest", room = ROOM_NAME, %day).entered();
+            let storage = match &context.storage {
+                Some(storage) => storage,
+                None => return,
+            };
+            let digest = match Digest::build(storage, ROOM_NAME, day) {
+                Ok(digest) => digest,
+                Err(err) => {
+                    error!("Unable to build digest: {}", err);
+                    return;
+                }
+            };
+
+            let config = &context.config.digest;
+            if config.post_to_room {
+                for line in digest.lines() {
+                    context.send_to_room(MessageKind::Notice(NoticeKind::Info), line);
+                }
+            }
+            if let Some(dir) = &config.export_dir {
+                match digest.export(dir) {
+                    Ok(path) => info!("Digest written to {}", path.display()),
+                    Err(err) => error!("{}", err),
+                }
+            }
+            if let Some(url) = &config.webhook_url {
+                if let Err(err) = digest.send_webhook(url) {
+                    error!("{}", err);
+                }
+            }
+        });
+    }
+
+    // Account changes are saved in the background so the client isn't waiting on the disk
+    fn save_accounts(self: &Arc<Self>) {
+        let context = self.clone();
+        let span = Span::current();
+        self.io_pool.execute(move || {
+            let _entered = span.enter();
+            if let Err(err) = context.accounts.save() {
+                error!("Unable to save accounts: {}", err);
+            }
+        });
+    }
+
+    // Every failed login, reset or code goes through here, to be logged with where it came from and counted towards a
+    // lockout
+    fn auth_failed(self: &Arc<Self>, address: IpAddr, name: &str, failure: Failure) {
+        warn!(user = name, %address, reason = failure.as_str(), "Authentication failure");
+        if failure != Failure::LockedOut && self.lockouts.fail(address, name) {
+            warn!(user = name, %address, "Locked out after too many failures");
+        }
+
+        let path = match &self.config.auth_failures.log_path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let line =
+            auth_failures::format_line(self.config.auth_failures.format, address, name, failure);
+        let span = Span::current();
+        self.io_pool.execute(move || {
+            let _entered = span.enter();
+            if let Err(err) = auth_failures::append(&path, &line) {
+                error!("Unable to write to the auth log: {}", err);
+            }
+        });
+    }
+
+    // The TLS handshake if there's TLS, then the WebSocket one if they came in on the [websocket] listener.  Either
+    // can take a while, so this is never done on the accept loop.
+    fn handshake(&self, stream: TcpStream, websocket: bool) -> io::Result<Stream> {
+        let stream = match &self.tls {
+            Some(tls) => tls.accept(stream)?,
+            None => Stream::Plain(stream),
+        };
+        match &self.websocket {
+            Some(acceptor) if websocket => acceptor.accept(stream),
+            _ => Ok(stream),
+        }
+    }
+
+    // Turns away a connection we've accepted but won't serve, with the reason as the only thing we ever send them.
+    // Saying goodbye properly can take a while (see ChatServer::drain), and so can the handshake, so it happens on the
+    // blocking pool where it can't hold up the accept loop.
+    fn reject(self: &Arc<Self>, stream: TcpStream, websocket: bool, reason: &str) {
+        let context = self.clone();
+        let reason = String::from(reason);
+        self.io_pool.execute(move || {
+            // Nobody gets to tie up a thread by going quiet halfway through the handshake
+            stream.set_read_timeout(Some(DRAIN_TIMEOUT)).ok();
+            stream.set_write_timeout(Some(DRAIN_TIMEOUT)).ok();
+            let mut stream = match context.handshake(stream, websocket) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!("Handshake failed while rejecting: {}", err);
+                    return;
+                }
+            };
+
+            let goodbye = format!("{}\n", Kicked { reason }.to_line());
+            ChatServer::drain(&mut stream, goodbye.as_bytes());
+        });
+    }
+
+    // Same as save_accounts, for the ban list
+    fn save_bans(self: &Arc<Self>) {
+        let context = self.clone();
+        let span = Span::current();
+        self.io_pool.execute(move || {
+            let _entered = span.enter();
+            if let Err(err) = context.bans.save() {
+                error!("Unable to save bans: {}", err);
+            }
+        });
+    }
+}
+
+// The HTTP API's way into the room (see api.rs), as the bot that's asking
+impl Rooms for ServerContext {
+    fn authorize(&self, room: &str, name: &str, token: &str) -> Result<(), ApiError> {
+        if room != ROOM_NAME {
+            return Err(ApiError::NotFound(format!(
+                "There's no room called {}, only {}",
+                room, ROOM_NAME
+            )));
+        }
+        let bot_room = self.accounts.bot_room(name);
+        if bot_room.is_none() || !self.accounts.verify(name, token) {
+            return Err(ApiError::Unauthorized(String::from(
+                "That isn't a bot, or it's the wrong token",
+            )));
+        }
+        if bot_room.as_deref() != Some(room) {
+            return Err(ApiError::Forbidden(format!(
+                "{} doesn't post to {}",
+                name, room
+            )));
+        }
+        Ok(())
+    }
+
+    // The same as the bot saying it over a connection, past the welcome and the command handling, which a bot has no
+    // use for anyway
+    fn post(&self, _room: &str, name: &str, line: &str) -> Result<(), ApiError> {
+        if !self.running.load(Ordering::SeqCst) {
+            return Err(ApiError::Unavailable(String::from(
+                "The server is shutting down",
+            )));
+        }
+        self.metrics.message_received();
+        self.send_message(RoomMessage::chat(name, &format!("{} {}", BOT_TAG, line)));
+        Ok(())
+    }
+
+    fn history(
+        &self,
+        room: &str,
+        since: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, ApiError> {
+        let storage = self.storage.as_ref().ok_or_else(|| {
+            ApiError::NotFound(String::from(
+                "History is turned off, so there's nothing to read",
+            ))
+        })?;
+        storage.messages_since(room, since, limit).map_err(|err| {
+            error!("Unable to read history for the API: {}", err);
+            ApiError::Unavailable(String::from("Unable to read the history right now"))
+        })
+    }
+}
+
+// The public view's way into the history (see web.rs)
+impl PublicRooms for ServerContext {
+    fn recent_chat(
+        &self,
+        room: &str,
+        since: SystemTime,
+        limit: usize,
+    ) -> Option<Vec<HistoryEntry>> {
+        if room != ROOM_NAME {
+            return None;
+        }
+        let storage = self.storage.as_ref()?;
+        storage
+            .recent_chat(room, since, limit)
+            .map_err(|err| error!("Unable to read history for the public view: {}", err))
+            .ok()
+    }
+}
+
+// Everything we know about one connection
+struct Session {
+    // Our key in ServerContext::connections
+    id: u64,
+    user: String,
+    state: ConnectionState,
+    // Set once the client has shown they own a registered name, with /login or by registering it
+    logged_in: bool,
+    // Logged in to a bot account (see /bot), which can only post
+    bot: bool,
+    address: IpAddr,
+    capabilities: Capabilities,
+    // Our subscription to the room, which only exists while they're in it.  Nobody would be reading it for someone
+    // who hasn't joined, and it would only fill up, so we don't have one until then.
+    room_receiver: Option<Subscription<RoomMessage>>,
+    // Anything waiting to be written back to this client, whether it came from the room or is a reply just for them
+    batch: Batch,
+    // Batches on their way out.  Whatever the socket didn't take in one write waits here, and we only ask poll about
+    // writing while there's something in it.
+    outbound: VecDeque<u8>,
+    // How fast they're allowed to send us lines, and how many they've sent over the limit since the last one that got
+    // through
+    rate_limit: TokenBucket,
+    dropped: u32,
+    mentions: MentionSettings,
+    // Only used if they asked for the heartbeat in their handshake
+    heartbeat: Heartbeat,
+    // What their commands start with, the server's default unless they asked for another in their handshake
+    prefix: char,
+    // When they have to be logged in by, if they're using a registered name they haven't logged in to
+    login_deadline: Option<Instant>,
+}
+
+// How a client's connection stands after their handler has dealt with whatever woke it
+enum Outcome {
+    Open,
+    // Over, and there's nobody left to say goodbye to, because they hung up or stopped answering
+    Gone,
+    // Over, and this is the last of what we owe them, to go out before we hang up
+    Goodbye(Vec<u8>),
+}
+
+// One client's connection and everything their handler needs from one event to the next.  With a thread per client the
+// handler is a loop on that thread (see serve_client), and in the reactor it's one of many on an event loop thread
+// (see run_event_loop), but either way it's driven through these same few calls.
+struct Client {
+    stream: Stream,
+    session: Session,
+    control_receiver: mpsc::Receiver<Control>,
+    lines: LineReader,
+}
+
+impl Client {
+    // Everything that doesn't wait on their socket: what the rest of the server asked of us, the heartbeat, the login
+    // deadline, and whatever the room has for them.  Handlers call this every time around their loop.
+    fn poll(&mut self, context: &Arc<ServerContext>) -> Outcome {
+        let session = &mut self.session;
+        while let Ok(control) = self.control_receiver.try_recv() {
+            let (action, by, reason) = match control {
+                Control::Notice(notice) => {
+                    session.send_notice(notice.kind, notice.text);
+                    continue;
+                }
+                Control::Line(line) => {
+                    session.batch.push_line(&line);
+                    continue;
+                }
+                Control::Direct(direct) => {
+                    session.send_direct(&direct);
+                    continue;
+                }
+                Control::Kick { by, reason } => ("kicked", by, reason),
+                Control::Ban { by, reason } => ("banned", by, reason),
+                // As far as the room goes it's the same as leaving, which it won't even hear about if they're still
+                // here on the session that did it
+                Control::Logout => {
+                    info!("Logged out from another session");
+                    ChatServer::close(context, session);
+                    let reason = String::from("You were logged out from another session");
+                    session.batch.push_line(&Kicked { reason }.to_line());
+                    return Outcome::Goodbye(session.take_outbound());
+                }
+            };
+            info!(by = %by, reason = %reason, "{}", action);
+
+            let (notice, reason) = match reason.as_str() {
+                "" => (
+                    format!("{} was {} by {}", session.user, action, by),
+                    format!("You have been {} by {}", action, by),
+                ),
+                reason => (
+                    format!("{} was {} by {} ({})", session.user, action, by, reason),
+                    format!("You have been {} by {}: {}", action, by, reason),
+                ),
+            };
+            return ChatServer::throw_out(context, session, notice, reason);
+        }
+
+        // Someone who has stopped answering our pings is most likely gone without telling us, so there's nobody to
+        // say goodbye to
+        if session.capabilities.heartbeat && session.heartbeat.ping_due() {
+            if session.heartbeat.missed() >= context.config.heartbeat.max_missed {
+                warn!("Missed too many heartbeats");
+                return Outcome::Gone;
+            }
+            session.batch.push_line(PING_COMMAND);
+        }
+
+        if matches!(session.login_deadline, Some(deadline) if Instant::now() >= deadline) {
+            ChatServer::rename_to_guest(context, session);
+        }
+
+        // The room gave up on them for falling too far behind (see fanout.rs), or threw some of what they hadn't read
+        // yet away to keep up
+        if let Some(room_receiver) = &session.room_receiver {
+            if room_receiver.is_cut_off() {
+                warn!("Disconnected for falling behind");
+                let notice = format!("{} was disconnected for falling behind", session.user);
+                let reason = String::from("You have been disconnected for falling behind");
+                return ChatServer::throw_out(context, session, notice, reason);
+            }
+
+            let dropped = room_receiver.take_dropped();
+            if dropped > 0 {
+                debug!(dropped, "Fell behind");
+                session.error(format!("You fell behind and missed {} message(s)", dropped));
+            }
+        }
+
+        // Pick up everything the room has for us, as long as there's room in the batch.  While the last batch is still
+        // going out we leave the room's messages in our queue, so a client that's fallen behind is dealt with there
+        // rather than piling up here.
+        if session.outbound.is_empty() {
+            // The reader is taken out while we work, since deciding how each message looks needs the rest of the
+            // session
+            if let Some(room_receiver) = session.room_receiver.take() {
+                let mut roster_changed = false;
+                while !session.batch.is_full() {
+                    match room_receiver.try_recv() {
+                        Some(message) => {
+                            roster_changed |= message.kind == MessageKind::Presence
+                                || message.membership.is_some();
+                            session.queue(&message);
+                        }
+                        None => break,
+                    }
+                }
+                session.room_receiver = Some(room_receiver);
+
+                // However many people came and went in this batch, the roster only has to go out once, after them
+                if roster_changed && session.capabilities.roster {
+                    let roster = Roster {
+                        names: context.in_room(),
+                    };
+                    session.batch.push_line(&roster.to_line());
+                }
+            }
+
+            if session.batch.is_due() {
+                session.outbound.extend(session.batch.take());
+                if let Err(err) = ChatServer::write_outbound(&mut self.stream, session) {
+                    debug!("Unable to write: {}", err);
+                    return Outcome::Gone;
+                }
+            }
+        }
+
+        Outcome::Open
+    }
+
+    // Their socket has something for us
+    fn read(&mut self, context: &Arc<ServerContext>) -> Outcome {
+        let session = &mut self.session;
+        let mut buffer = [0; 1024];
+        loop {
+            let bytes_read = match self.stream.read(&mut buffer) {
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return Outcome::Gone,
+            };
+
+            // Once again, a zero byte read is a disconnect
+            if bytes_read == 0 {
+                return Outcome::Gone;
+            }
+
+            // A read can hold part of a message, or several of them, so we only act on whole lines
+            self.lines.push(&buffer[..bytes_read]);
+            while let Some(line) = self.lines.next_line() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(LineTooLong) => {
+                        context.metrics.line_too_long();
+                        session.error(format!(
+                            "Message too long, the limit is {} bytes",
+                            context.config.max_message_bytes
+                        ));
+                        continue;
+                    }
+                };
+
+                // A file coming through us is as many chunks as it takes, as fast as the sender can send them.  What
+                // keeps that in check is the size limit, which a chunk can't go past (see file).
+                let chunk = line
+                    .strip_prefix(FILE_COMMAND)
+                    .is_some_and(|rest| rest.starts_with(" chunk "));
+                if chunk || session.rate_limit.try_take() {
+                    session.dropped = 0;
+                    ChatServer::handle_line(context, session, &self.stream, line.trim());
+                    continue;
+                }
+
+                // Over the limit, so the line goes nowhere.  They're warned once, and if they keep it up they're
+                // gone.
+                session.dropped += 1;
+                context.metrics.line_throttled();
+                if session.dropped == 1 {
+                    warn!("Throttled");
+                    session.error("You're sending messages too fast, slow down");
+                }
+
+                let disconnect_after = context.config.rate_limit.disconnect_after;
+                if disconnect_after > 0 && session.dropped >= disconnect_after {
+                    warn!("Disconnected for flooding");
+                    let notice = format!("{} was disconnected for flooding", session.user);
+                    let reason = String::from("You have been disconnected for flooding");
+                    return ChatServer::throw_out(context, session, notice, reason);
+                }
+            }
+
+            // They asked to leave, so whatever we still owe them goes out now rather than with the next batch
+            if session.state == ConnectionState::Closing {
+                return Outcome::Goodbye(session.take_outbound());
+            }
+        }
+
+        Outcome::Open
+    }
+
+    // Their socket has room for more of what we owe them
+    fn write(&mut self) -> Outcome {
+        match ChatServer::write_outbound(&mut self.stream, &mut self.session) {
+            Ok(()) => Outcome::Open,
+            Err(err) => {
+                debug!("Unable to write: {}", err);
+                Outcome::Gone
+            }
+        }
+    }
+
+    // Only wake up for writing while there's something left to write, otherwise a socket that's always writable would
+    // have us spinning
+    fn wants_write(&self) -> bool {
+        !self.session.outbound.is_empty()
+    }
+
+    // How long the handler can wait for something to happen before it has to come back to poll.  The batch might be
+    // due, and we also check in now and then for the things nothing wakes us for, like shutting down and the heartbeat.
+    fn timeout(&self) -> Duration {
+        match self.session.batch.due_in() {
+            Some(due_in) => due_in.min(SHUTDOWN_CHECK),
+            None => SHUTDOWN_CHECK,
+        }
+    }
+
+    // The reactor's drain.  What we owe them goes out as fast as their socket takes it, then our side is shut, then
+    // whatever they still send is read and thrown away until they hang up.  False once they're gone, and the deadline
+    // in leaving is up to whoever calls this.
+    fn say_goodbye(&mut self, leaving: &mut Leaving) -> bool {
+        if let Err(err) = ChatServer::write_outbound(&mut self.stream, &mut self.session) {
+            debug!("Unable to say goodbye: {}", err);
+            return false;
+        }
+        if !self.session.outbound.is_empty() {
+            return true;
+        }
+
+        if !leaving.shut {
+            leaving.shut = true;
+            if let Err(err) = self.stream.shutdown() {
+                debug!("Unable to say goodbye: {}", err);
+                return false;
+            }
+        }
+
+        let mut buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return false,
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+// How to hand a new connection to one of the reactor's event loops
+struct EventLoop {
+    arrivals: mpsc::Sender<Arrival>,
+    waker: Arc<Waker>,
+}
+
+// A new connection on its way to an event loop, with the handshake already done
+struct Arrival {
+    id: u64,
+    address: IpAddr,
+    stream: Stream,
+    span: Span,
+}
+
+// A client on an event loop, along with what the loop keeps track of for them
+struct LoopClient {
+    client: Client,
+    span: Span,
+    // Whether we've asked poll about writing to them
+    writing: bool,
+    // Set once their connection is over and we're only waiting on their goodbye to go out
+    leaving: Option<Leaving>,
+}
+
+struct Leaving {
+    // When we hang up whether they've had it all or not, like drain's timeout
+    deadline: Instant,
+    // Whether our side of the connection is shut yet
+    shut: bool,
+}
+
+impl LoopClient {
+    // Deals with what came of the client's last step, and says whether they're still around.  Once there's a goodbye
+    // they're leaving, and every step after that only moves the goodbye along.
+    fn settle(&mut self, outcome: Outcome) -> bool {
+        match outcome {
+            Outcome::Open => {}
+            Outcome::Gone => return false,
+            Outcome::Goodbye(goodbye) => {
+                self.client.session.outbound.extend(goodbye);
+                self.leaving = Some(Leaving {
+                    deadline: Instant::now() + DRAIN_TIMEOUT,
+                    shut: false,
+                });
+            }
+        }
+
+        match &mut self.leaving {
+            Some(leaving) => self.client.say_goodbye(leaving),
+            None => true,
+        }
+    }
+}
+
+impl Session {
+    // Everything we still owe them, what's already on its way out first, for one last write before hanging up
+    fn take_outbound(&mut self) -> Vec<u8> {
+        let mut output: Vec<u8> = self.outbound.drain(..).collect();
+        output.extend(self.batch.take());
+        output
+    }
+
+    // Batching depends on what the client asked for in its handshake, so this runs again whenever that changes.  Any
+    // messages already waiting go out with the next write under the new limits.
+    fn apply_capabilities(&mut self, config: &ServerConfig, stream: &Stream) {
+        let batching = &config.batching;
+        let (max_bytes, max_delay_ms) = if self.capabilities.nodelay {
+            // Any single line fills a one byte batch, so every message goes out in its own write
+            (1, 0)
+        } else if self.capabilities.bulk {
+            (batching.bulk_max_bytes, batching.bulk_max_delay_ms)
+        } else {
+            (batching.max_bytes, batching.max_delay_ms)
+        };
+
+        self.batch
+            .set_limits(max_bytes, Duration::from_millis(max_delay_ms));
+        stream.set_nodelay(self.capabilities.nodelay).ok();
+    }
+
+    // How a message from the room goes out to this client.  Chat that mentions them is flagged, unless they've said
+    // they don't want to hear about it or they can't tell the difference anyway.
+    fn line_for(&self, message: &RoomMessage) -> String {
+        let typed = self.capabilities.notices;
+        match (&message.sender, message.kind) {
+            (Some(sender), MessageKind::Chat)
+                if typed
+                    && !sender.eq_ignore_ascii_case(&self.user)
+                    && (message.mass_mention || message.mentions(&self.user))
+                    && self.mentions.wants(sender, ROOM_NAME) =>
+            {
+                // Their own name is louder than @all, so it wins when there's both
+                let kind = if message.mentions(&self.user) {
+                    MentionKind::Direct
+                } else {
+                    MentionKind::Everyone
+                };
+                Mention {
+                    kind,
+                    sender: sender.clone(),
+                    body: message.body.clone(),
+                }
+                .to_line()
+            }
+            _ => message.line(typed),
+        }
+    }
+
+    // Something from the server just for them, like the answer to a command
+    fn notice(&mut self, text: impl Into<String>) {
+        self.send_notice(NoticeKind::Info, text);
+    }
+
+    // Like notice, for when what they asked for didn't work
+    fn error(&mut self, text: impl Into<String>) {
+        self.send_notice(NoticeKind::Error, text);
+    }
+
+    fn send_notice(&mut self, kind: NoticeKind, text: impl Into<String>) {
+        let notice = Notice {
+            kind,
+            text: text.into(),
+        };
+        let line = if self.capabilities.notices {
+            notice.to_line()
+        } else {
+            notice.to_plain()
+        };
+        self.batch.push_line(&line);
+    }
+
+    fn send_direct(&mut self, direct: &Direct) {
+        if self.capabilities.direct {
+            self.batch.push_line(&direct.to_line());
+        } else {
+            self.send_notice(NoticeKind::Info, direct.to_plain());
+        }
+    }
+
+    // Adds a message from the room to what we owe them
+    fn queue(&mut self, message: &RoomMessage) {
+        // Lite clients asked us to skip the comings and goings
+        if self.capabilities.lite && message.kind == MessageKind::Presence {
+            return;
+        }
+        let line = self.line_for(message);
+        match (message.sent, message.clock) {
+            (_, Some(clock)) if self.capabilities.clock => {
+                self.batch.push_line(&Clocked { clock, line }.to_line())
+            }
+            (Some(time), _) if self.capabilities.timestamps => {
+                self.batch.push_line(&Timestamped { time, line }.to_line())
+            }
+            _ => self.batch.push_line(&line),
+        }
+    }
+}
+
+// Our public struct, which just holds on to the settings it was started with until it's run.  Set up with
+// ChatServer::builder().  Storage is history that's already open, for whoever wants it somewhere other than the
+// config's path, like a test that wants to look at it afterwards.
+pub struct ChatServer {
+    config: ServerConfig,
+    storage: Option<Storage>,
+    hooks: Arc<dyn ServerHooks>,
+    plugins: PluginRegistry,
+}
+
+// Starts from the defaults, or from a whole config (say one loaded from a file), and changes one setting at a time,
+// e.g. ChatServer::builder().bind("127.0.0.1:9000").pool_size(16).motd("Welcome").build().  Nothing is checked until
+// build, which checks the lot the same way loading a config file does.  Anything without a method of its own can be
+// changed in a ServerConfig and handed over with config.
+#[derive(Default)]
+pub struct ChatServerBuilder {
+    config: ServerConfig,
+    storage: Option<Storage>,
+    hooks: Option<Arc<dyn ServerHooks>>,
+    plugins: PluginRegistry,
+}
+
+impl ChatServerBuilder {
+    // Replaces every setting made so far
+    pub fn config(mut self, config: ServerConfig) -> ChatServerBuilder {
+        self.config = config;
+        self
+    }
+
+    pub fn bind(mut self, address: impl Into<String>) -> ChatServerBuilder {
+        self.config.bind_address = address.into();
+        self
+    }
+
+    pub fn pool_size(mut self, size: usize) -> ChatServerBuilder {
+        self.config.pool_size = size;
+        self
+    }
+
+    pub fn pool_queue_size(mut self, size: usize) -> ChatServerBuilder {
+        self.config.pool_queue_size = size;
+        self
+    }
+
+    // Has to leave room in the pool for the room and the timers, so a smaller pool usually needs this lowered too
+    pub fn max_clients(mut self, max_clients: usize) -> ChatServerBuilder {
+        self.config.max_clients = max_clients;
+        self
+    }
+
+    pub fn motd(mut self, motd: impl Into<String>) -> ChatServerBuilder {
+        self.config.motd = Some(motd.into());
+        self
+    }
+
+    // Rounded down to whole seconds, like shutdown_timeout_secs in the config file
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> ChatServerBuilder {
+        self.config.shutdown_timeout_secs = timeout.as_secs();
+        self
+    }
+
+    // Keeps history in this instead of opening the config's path, which turns history on
+    pub fn storage(mut self, storage: Storage) -> ChatServerBuilder {
+        self.config.history.enabled = true;
+        self.storage = Some(storage);
+        self
+    }
+
+    // Lets the embedding program watch connections and chat, and refuse them (see hooks.rs)
+    pub fn hooks(mut self, hooks: impl ServerHooks + 'static) -> ChatServerBuilder {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    // Adds features of the embedding program's own, like commands (see plugins.rs).  Each call adds one more, and
+    // they're asked in the order they were added.
+    pub fn plugin(mut self, plugin: impl ChatPlugin + 'static) -> ChatServerBuilder {
+        self.plugins.register(Box::new(plugin));
+        self
+    }
+
+    pub fn build(self) -> Result<ChatServer, ConfigError> {
+        self.config.validate()?;
+
+        Ok(ChatServer {
+            config: self.config,
+            storage: self.storage,
+            hooks: self.hooks.unwrap_or_else(|| Arc::new(NoHooks)),
+            plugins: self.plugins,
+        })
+    }
+}
+
+// A server that's been started (see ChatServer::start) and is waiting to accept connections.  Each call to step waits a
+// little while for anyone connecting and takes them on, so the server can be driven from someone else's loop, or
+// run_until does the looping.  Either way it ends with shutdown, which waits for everyone to leave.  Dropping the
+// handle instead still stops the server, but waits for as long as that takes.
+pub struct ChatServerHandle {
+    // First, so it goes first.  Dropping the pool waits for everyone to leave, and they'd never know to.
+    _stop_on_drop: StopOnDrop,
+    listener: TcpListener,
+    websocket_listener: Option<TcpListener>,
+    sources: Sources<Source>,
+    events: Events<Source>,
+    pool: ThreadPool,
+    context: Arc<ServerContext>,
+    room: JobHandle<()>,
+    connected: Arc<AtomicUsize>,
+    max_clients: usize,
+    event_loops: Vec<EventLoop>,
+    next_id: u64,
+}
+
+struct StopOnDrop(Arc<ServerContext>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::SeqCst);
+    }
+}
+
+// Why the server couldn't start, worded for the log
+#[derive(Debug)]
+pub struct StartError(String);
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl ChatServerHandle {
+    // Accepts connections until the token is cancelled or the server stops by itself, then shuts down.  Either way
+    // everyone has left by the time we return, and if it was the listener failing that stopped us, that's the error.
+    pub fn run_until(mut self, cancel: CancellationToken) -> Result<(), Error> {
+        // We don't wait forever for each step, or being cancelled wouldn't be noticed until somebody connected
+        let result = loop {
+            match self.step(SHUTDOWN_CHECK) {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+            // Everything else is watching the running flag rather than the token, so this is where one becomes the
+            // other
+            if cancel.is_cancelled() {
+                self.stop();
+            }
+        };
+
+        self.shutdown();
+        result
+    }
+
+    // Waits up to timeout for anyone connecting, and takes on whoever did.  False once the server has stopped, which
+    // is stop having been called or the room failing, and all that's left is shutdown.  The listener failing stops the
+    // server as well, and that's an error.
+    pub fn step(&mut self, timeout: Duration) -> Result<bool, Error> {
+        let ChatServerHandle {
+            listener,
+            websocket_listener,
+            sources,
+            events,
+            pool,
+            context,
+            room,
+            connected,
+            max_clients,
+            event_loops,
+            next_id,
+            ..
+        } = self;
+        if !context.running.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+
+        // The room only returns once we've stopped running, so finishing any earlier means something went badly
+        // wrong in there.  Nobody can chat without it, so there's no point carrying on.
+        if room.is_finished() {
+            error!("The room stopped unexpectedly, shutting down");
+            context.running.store(false, Ordering::SeqCst);
+            return Ok(false);
+        }
+
+        // Wait for something to happen on our socket, just waiting for an attempted connection
+        match sources.wait_timeout(events, timeout) {
+            Ok(_) => {}
+            Err(err)
+                if err.kind() == io::ErrorKind::TimedOut
+                    || err.kind() == io::ErrorKind::Interrupted =>
+            {
+                return Ok(true)
+            }
+            Err(err) => {
+                context.running.store(false, Ordering::SeqCst);
+                return Err(Error::Io(io::Error::new(
+                    err.kind(),
+                    format!("unable to wait for connections: {}", err),
+                )));
+            }
+        }
+
+        for (key, _event) in events.iter() {
+            // Both listeners take people on the same way, and only the handshake is different
+            let (listener, websocket) = match (key, &websocket_listener) {
+                (Source::Listener, _) => (&*listener, false),
+                (Source::WebSocketListener, Some(websocket_listener)) => (websocket_listener, true),
+                _ => continue,
+            };
+            loop {
+                let (stream, address) = match listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        context.running.store(false, Ordering::SeqCst);
+                        return Err(Error::Io(io::Error::new(
+                            err.kind(),
+                            format!("unable to accept connections: {}", err),
+                        )));
+                    }
+                };
+
+                // Addresses outside [access] don't even get a reason, they're just hung up on before we've
+                // said or read anything
+                if !context.access.lock().unwrap().permits(address.ip()) {
+                    debug!("Not allowed by access list, dropping {}", address);
+                    continue;
+                }
+
+                // Anyone we won't serve is told why before we hang up, rather than left to guess
+                if context.bans.is_ip_banned(address.ip()) {
+                    info!("Banned, rejecting {}", address);
+                    context.reject(stream, websocket, "You are banned from this server");
+                    continue;
+                }
+                if connected.load(Ordering::SeqCst) >= *max_clients {
+                    warn!("Server full, rejecting {}", address);
+                    context.reject(stream, websocket, "The server is full, try again later");
+                    continue;
+                }
+                if context.overload.is_degraded() {
+                    warn!("Server overloaded, rejecting {}", address);
+                    context.reject(stream, websocket, "The server is too busy, try again later");
+                    continue;
+                }
+                if let Err(reason) = context.hooks.on_connect(address.ip()) {
+                    info!("Refused by hooks, rejecting {}", address);
+                    context.reject(stream, websocket, &reason);
+                    continue;
+                }
+                connected.fetch_add(1, Ordering::SeqCst);
+
+                // Everything logged for this client, on whatever thread, happens inside this span
+                *next_id += 1;
+                let id = *next_id;
+                let span = info_span!("client", id, peer = %address);
+
+                // Clone our values again for threading
+                let context = context.clone();
+                let connected = connected.clone();
+
+                // Clients are dealt out to the event loops in turn.  Nothing on an event loop is allowed to
+                // wait, so the handshake happens on the blocking pool first.
+                if !event_loops.is_empty() {
+                    let event_loop = &event_loops[id as usize % event_loops.len()];
+                    let arrivals = event_loop.arrivals.clone();
+                    let waker = event_loop.waker.clone();
+                    let io_context = context.clone();
+                    io_context.io_pool.execute(move || {
+                        let stream = match span
+                            .in_scope(|| ChatServer::handshake(&context, stream, websocket))
+                        {
+                            Some(stream) => stream,
+                            None => {
+                                connected.fetch_sub(1, Ordering::SeqCst);
+                                return;
+                            }
+                        };
+                        let arrival = Arrival {
+                            id,
+                            address: address.ip(),
+                            stream,
+                            span,
+                        };
+                        if arrivals.send(arrival).is_ok() {
+                            waker.wake();
+                        }
+                    });
+                    continue;
+                }
+
+                // The accept loop mustn't wait on a full job queue, so then they're turned away as too busy.
+                // That goes over a second handle on their socket, since the first went with the job.
+                let spare = stream.try_clone();
+                let (busy_context, busy_connected) = (context.clone(), connected.clone());
+
+                // This will take our stream and process any messages until they disconnect.  It holds a
+                // worker for as long as they're connected, so anything short that's waiting goes first.
+                let queued = pool.try_execute_with_priority(Priority::Low, move || {
+                    let _entered = span.enter();
+                    info!("Connected");
+                    let metrics = context.metrics.clone();
+                    metrics.client_connected();
+
+                    // Same again for their slot, which would otherwise be gone for good
+                    let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                        ChatServer::handle_client(context, id, address.ip(), stream, websocket)
+                    }));
+
+                    metrics.client_disconnected();
+                    connected.fetch_sub(1, Ordering::SeqCst);
+                    info!("Disconnected");
+                    if let Err(payload) = handled {
+                        panic::resume_unwind(payload);
+                    }
+                });
+                if queued.is_err() {
+                    warn!("Job queue full, rejecting {}", address);
+                    busy_connected.fetch_sub(1, Ordering::SeqCst);
+                    if let Ok(stream) = spare {
+                        busy_context.reject(
+                            stream,
+                            websocket,
+                            "The server is too busy, try again later",
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Tells everyone to leave, and step stops accepting.  Nobody's waited for until shutdown.
+    pub fn stop(&self) {
+        self.context.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.context.running.load(Ordering::SeqCst)
+    }
+
+    // Where we're listening, which is how to find out the port when bind_address asked for port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // The same for the [websocket] listener, if there is one
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.websocket_listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+
+    // Everyone connected, including anyone who hasn't got as far as the room yet
+    pub fn connection_count(&self) -> usize {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    // There's only the one room for now
+    pub fn rooms(&self) -> Vec<String> {
+        vec![String::from(ROOM_NAME)]
+    }
+
+    // Who's in the room, each name once however many connections they have
+    pub fn users(&self) -> Vec<String> {
+        self.context.in_room()
+    }
+
+    // Stops, if that hasn't happened already, and waits for everyone to leave
+    pub fn shutdown(self) {
+        self.stop();
+        let ChatServerHandle {
+            pool,
+            context,
+            room,
+            ..
+        } = self;
+
+        // Every client's handler (or event loop) sees the same flag and says goodbye on its own, and dropping the pool
+        // on the way out waits for them all to finish.  What the pool is still busy with says roughly how long that'll
+        // take.
+        info!(
+            workers = pool.worker_count(),
+            active_jobs = pool.active_jobs(),
+            queued_jobs = pool.queued_jobs(),
+            "Shutting down"
+        );
+
+        // The room stops on its own too, but we wait for it here so that if it panicked, whether that's what stopped
+        // us or it happened on the way out, the log says so
+        if let Err(err) = room.join() {
+            error!("The room {}", err);
+        }
+
+        // Then everything else, though not forever
+        let timeout = Duration::from_secs(context.config.shutdown_timeout_secs);
+        if let Err(unfinished) = pool.shutdown_timeout(timeout) {
+            error!("Shut down anyway, {}", unfinished);
+        }
+    }
+}
+
+impl ChatServer {
+    pub fn builder() -> ChatServerBuilder {
+        ChatServerBuilder::default()
+    }
+
+    // Runs until the token is cancelled, or the room stops, or we can't carry on listening.  Anything that stops us
+    // from starting at all comes back straight away as Error::Start.
+    pub fn run(self, cancel: CancellationToken) -> Result<(), Error> {
+        self.start()?.run_until(cancel)
+    }
+
+    // Everything up to accepting connections: listening, loading what we need, and starting the room.  The handle
+    // that comes back does the accepting, for whoever would rather drive that themselves than call run.
+    pub fn start(self) -> Result<ChatServerHandle, StartError> {
+        let listener = match listener::bind(&self.config) {
+            Ok(listener) => listener,
+            Err(err) => return Err(StartError(err.to_string())),
+        };
+        if let Err(err) = listener.set_nonblocking(true) {
+            return Err(StartError(format!("Unable to listen: {}", err)));
+        }
+        if let Ok(address) = listener.local_addr() {
+            info!(%address, "Listening");
+        }
+
+        // Load the certificate up front so a bad path is reported once at startup instead of on every connection
+        let tls = match &self.config.tls {
+            Some(tls) => match TlsAcceptor::from_files(&tls.cert_path, &tls.key_path) {
+                Ok(acceptor) => Some(acceptor),
+                Err(err) => return Err(StartError(format!("Unable to set up TLS: {}", err))),
+            },
+            None => None,
+        };
+
+        // The browsers' listener, which is no use without WebSocket support, so a build without it refuses to start
+        // rather than have them all fail the handshake
+        let (websocket_listener, websocket) = match &self.config.websocket.bind_address {
+            Some(address) => {
+                let acceptor = match WebSocketAcceptor::new() {
+                    Ok(acceptor) => acceptor,
+                    Err(err) => {
+                        return Err(StartError(format!("Unable to set up WebSockets: {}", err)))
+                    }
+                };
+                let listener = match TcpListener::bind(address)
+                    .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+                {
+                    Ok(listener) => listener,
+                    Err(err) => {
+                        return Err(StartError(format!(
+                            "Unable to listen for WebSockets on {}: {}",
+                            address, err
+                        )))
+                    }
+                };
+                if let Ok(address) = listener.local_addr() {
+                    info!(%address, "Listening for WebSockets");
+                }
+                (Some(listener), Some(acceptor))
+            }
+            None => (None, None),
+        };
+
+        // Same for the accounts file, better to refuse to start than to run without anyone's registrations
+        let accounts = match AccountStore::load(&self.config.accounts_path) {
+            Ok(accounts) => accounts,
+            Err(err) => return Err(StartError(format!("Unable to load accounts: {}", err))),
+        };
+
+        let bans = match BanList::load(&self.config.banlist_path) {
+            Ok(bans) => bans,
+            Err(err) => return Err(StartError(format!("Unable to load bans: {}", err))),
+        };
+
+        let storage = match self.storage {
+            Some(storage) => Some(storage),
+            None if self.config.history.enabled => match Storage::open(&self.config.history.path) {
+                Ok(storage) => Some(storage),
+                Err(err) => return Err(StartError(format!("Unable to open history: {}", err))),
+            },
+            None => None,
+        };
+
+        // Scripts too, so one that doesn't compile stops us here rather than quietly never answering anyone
+        let scripts = match &self.config.scripts.dir {
+            Some(dir) => match ScriptHost::load(
+                dir,
+                self.config.scripts.max_operations,
+                self.config.max_message_bytes,
+            ) {
+                Ok(scripts) => Some(scripts),
+                Err(err) => return Err(StartError(format!("Unable to load scripts: {}", err))),
+            },
+            None => None,
+        };
+
+        // A script talks under its own name, so nobody else can have it
+        let mut names = self.config.names.clone();
+        if let Some(scripts) = &scripts {
+            names
+                .reserved
+                .extend(scripts.names().into_iter().map(String::from));
+        }
+        // The same for the Matrix bridge
+        let matrix = match &self.config.matrix.homeserver {
+            Some(_) => {
+                names.reserved.push(self.config.matrix.name.clone());
+                Some(MatrixBridge::new(&self.config.matrix))
+            }
+            None => None,
+        };
+        // Names from linked servers have an @ in them, so names here can't
+        let links = match self.config.link.enabled() {
+            true => Some(Arc::new(Links::new(
+                &self.config.link.name,
+                &self.config.link.secret,
+            ))),
+            false => None,
+        };
+        let redis = match &self.config.redis.address {
+            Some(_) => Some(RedisBus::new(&self.config.redis)),
+            None => None,
+        };
+        let mut name_policy = NamePolicy::new(&names);
+        if links.is_some() {
+            name_policy.forbid('@');
+        }
+
+        // Sources and Events are part of popol which is a polling library.  Very similar (if not identical) to c
+        // style polling of file descriptors.
+        let mut sources = Sources::new();
+        sources.register(Source::Listener, &listener, popol::interest::READ);
+        if let Some(websocket_listener) = &websocket_listener {
+            sources.register(
+                Source::WebSocketListener,
+                websocket_listener,
+                popol::interest::READ,
+            );
+        }
+
+        let events = Events::new();
+        let mut pool = ThreadPool::new(self.config.pool_size, self.config.pool_queue_size);
+        if self.config.pool_scaling.max_size > 0 {
+            pool.autoscale(
+                self.config.pool_scaling.max_size,
+                Duration::from_secs(self.config.pool_scaling.idle_secs),
+            );
+        }
+
+        let (message_sender, message_receiver) = mpsc::channel();
+
+        let io_pool = BlockingPool::new(1, 16);
+        let metrics = Arc::new(Metrics::new(
+            pool.state(),
+            pool.jobs_completed(),
+            pool.jobs_panicked(),
+            io_pool.jobs_panicked(),
+        ));
+        if let Some(address) = &self.config.metrics.bind_address {
+            match TcpListener::bind(address) {
+                Ok(listener) => {
+                    info!("Serving metrics on http://{}/metrics", address);
+                    metrics::serve(listener, metrics.clone());
+                }
+                Err(err) => {
+                    return Err(StartError(format!(
+                        "Unable to serve metrics on {}: {}",
+                        address, err
+                    )))
+                }
+            }
+        }
+
+        // Plugins start before the room does, so anything they announce on the way is waiting for it when it does
+        let announcements = Mutex::new(message_sender.clone());
+        let announcer = Announcer::new(move |text| {
+            // Once the room's gone there's nobody to tell
+            let notice = RoomMessage::new(MessageKind::Notice(NoticeKind::Info), text);
+            let _ = announcements.lock().unwrap().send(notice);
+        });
+        let mut plugins = self.plugins;
+        plugins.init(&mut PluginContext::new(&pool, announcer));
+
+        // The reference counting is so that we can point at the same values among our threads
+        let context = Arc::new(ServerContext {
+            config: self.config.clone(),
+            running: AtomicBool::new(true),
+            // Watches the pool's queue and the room's broadcast times, and flips us into a degraded mode when we're
+            // not keeping up
+            overload: OverloadMonitor::new(self.config.overload.clone(), pool.queue_depth()),
+            io_pool,
+            accounts,
+            lockouts: Lockouts::new(
+                self.config.auth_failures.max_failures,
+                Duration::from_secs(self.config.auth_failures.window_secs),
+                Duration::from_secs(self.config.auth_failures.lockout_secs),
+            ),
+            bans,
+            access: Mutex::new(self.config.access.clone()),
+            storage,
+            tls,
+            websocket,
+            hooks: self.hooks,
+            plugins,
+            scripts,
+            matrix,
+            links,
+            redis,
+            stats: Mutex::new(RoomStats::new(Duration::from_secs(
+                self.config.stats.window_minutes * 60,
+            ))),
+            metrics,
+            connections: Mutex::new(HashMap::new()),
+            mutes: Mutex::new(HashMap::new()),
+            freeze: Mutex::new(None),
+            transfers: Mutex::new(Transfers::default()),
+            clock: Mutex::new(HybridClock::new()),
+            mass_mentions: AtomicBool::new(self.config.mentions.mass_mentions),
+            mass_mentioned: Mutex::new(HashMap::new()),
+            authorizer: Authorizer::new(&self.config.permissions),
+            names: name_policy,
+            // Our message broadcaster for updating our room chat
+            fanout: Mutex::new(Fanout::new(
+                self.config.broadcast.queue_size,
+                self.config.broadcast.slow_clients,
+            )),
+            message_sender: Mutex::new(message_sender),
+        });
+
+        if let Some(address) = &self.config.api.bind_address {
+            match TcpListener::bind(address) {
+                Ok(listener) => {
+                    info!(
+                        "Serving the API on http://{}/rooms/{}/messages",
+                        address, ROOM_NAME
+                    );
+                    let limits = Limits {
+                        max_message_bytes: self.config.max_message_bytes,
+                        messages_per_second: self.config.rate_limit.messages_per_second,
+                        burst: self.config.rate_limit.burst,
+                    };
+                    api::serve(listener, context.clone(), limits);
+                }
+                Err(err) => {
+                    return Err(StartError(format!(
+                        "Unable to serve the API on {}: {}",
+                        address, err
+                    )))
+                }
+            }
+        }
+
+        // What's said on Matrix comes in here as chat from the bridge, the same as a bot posting through the API
+        if let Some(matrix) = &context.matrix {
+            let matrix_context = context.clone();
+            matrix.listen(
+                &self.config.matrix,
+                self.config.max_message_bytes,
+                move |line| {
+                    if matrix_context.running.load(Ordering::SeqCst) {
+                        matrix_context.metrics.message_received();
+                        matrix_context.send_message(RoomMessage::chat(
+                            &matrix_context.config.matrix.name,
+                            line,
+                        ));
+                    }
+                },
+            );
+        }
+
+        // What happens on linked servers comes in here.  People from elsewhere aren't members of the room as far as we're
+        // concerned, so they don't count towards its stats, and nothing of theirs is linked back out.
+        if let Some(links) = &context.links {
+            let link_context = context.clone();
+            let deliver: Arc<link::Deliver> = Arc::new(move |origin, event, clock| {
+                if !link_context.running.load(Ordering::SeqCst) {
+                    return;
+                }
+                let message = match event {
+                    link::Event::Chat { sender, body } => {
+                        RoomMessage::chat(&format!("{}@{}", sender, origin), &body)
+                    }
+                    link::Event::Joined(name) => RoomMessage::new(
+                        MessageKind::Presence,
+                        format!("{}@{} has joined the room.", name, origin),
+                    ),
+                    link::Event::Left(name) => RoomMessage::new(
+                        MessageKind::Presence,
+                        format!("{}@{} has left the room.", name, origin),
+                    ),
+                };
+                link_context.send_message(RoomMessage {
+                    clock: Some(clock),
+                    relayed: true,
+                    ..message
+                });
+            });
+
+            if let Some(address) = &self.config.link.bind_address {
+                match TcpListener::bind(address) {
+                    Ok(listener) => {
+                        info!("Taking links from other servers on {}", address);
+                        links.listen(listener, deliver.clone());
+                    }
+                    Err(err) => {
+                        return Err(StartError(format!(
+                            "Unable to take links on {}: {}",
+                            address, err
+                        )))
+                    }
+                }
+            }
+            for peer in &self.config.link.peers {
+                links.connect(peer.clone(), deliver.clone());
+            }
+        }
+
+        // The same for the other processes sharing the room through Redis, where people go by their own names
+        if let Some(redis) = &context.redis {
+            let redis_context = context.clone();
+            redis.subscribe(&self.config.redis, move |event, clock| {
+                if !redis_context.running.load(Ordering::SeqCst) {
+                    return;
+                }
+                let message = match event {
+                    redis::Event::Chat { sender, body } => RoomMessage::chat(&sender, &body),
+                    redis::Event::Joined { name } => RoomMessage::new(
+                        MessageKind::Presence,
+                        format!("{} has joined the room.", name),
+                    ),
+                    redis::Event::Left { name } => RoomMessage::new(
+                        MessageKind::Presence,
+                        format!("{} has left the room.", name),
+                    ),
+                };
+                redis_context.send_message(RoomMessage {
+                    clock,
+                    relayed: true,
+                    ..message
+                });
+            });
+        }
+
+        // The page only needs to know where to find the WebSocket listener, which validate made sure there is
+        if let (Some(address), Some(websocket_address)) = (
+            &self.config.web.bind_address,
+            websocket_listener
+                .as_ref()
+                .and_then(|listener| listener.local_addr().ok()),
+        ) {
+            match TcpListener::bind(address) {
+                Ok(listener) => {
+                    info!("Serving the web chat on http://{}/", address);
+                    let page = web::page(websocket_address.port(), self.config.tls.is_some());
+                    web::serve(listener, page, self.config.web.clone(), context.clone());
+                }
+                Err(err) => {
+                    return Err(StartError(format!(
+                        "Unable to serve the web page on {}: {}",
+                        address, err
+                    )))
+                }
+            }
+        }
+
+        // The overload check runs on the pool's timer, and whenever the state changes we tell the room
+        let overload_context = context.clone();
+        pool.execute_every(
+            Duration::from_millis(self.config.overload.check_interval_ms),
+            move || {
+                let notice = match overload_context.overload.check() {
+                    Some(Transition::Degraded) => {
+                        "The server is under heavy load, new connections are paused."
+                    }
+                    Some(Transition::Recovered) => "The server has recovered from heavy load.",
+                    None => return,
+                };
+
+                warn!("{}", notice);
+                overload_context.send_to_room(MessageKind::Notice(NoticeKind::Info), notice);
+            },
+        );
+
+        // A frozen room thaws by itself, the first time the timer finds it's run out
+        let freeze_context = context.clone();
+        pool.execute_every(Duration::from_secs(1), move || {
+            let mut freeze = freeze_context.freeze.lock().unwrap();
+            if freeze
+                .as_ref()
+                .is_some_and(|freeze| freeze.until <= Instant::now())
+            {
+                *freeze = None;
+                info!("Room thawed");
+                freeze_context.send_to_room(
+                    MessageKind::Notice(NoticeKind::Moderation),
+                    "The room is open again.",
+                );
+            }
+        });
+
+        // Offers nobody takes are called off, and the sender told, so they aren't left waiting forever
+        if self.config.files.enabled {
+            let files_context = context.clone();
+            pool.execute_every(OFFER_CHECK, move || {
+                let timeout = Duration::from_secs(files_context.config.files.offer_timeout_secs);
+                let expired = files_context.transfers.lock().unwrap().expire(timeout);
+                for (id, transfer) in expired {
+                    let reason = format!("{} didn't answer", transfer.to);
+                    let cancel = FileMessage::Cancel { id, reason }.to_line();
+                    files_context.send_control(
+                        |id, connection| {
+                            id == transfer.sender
+                                || (connection.user.eq_ignore_ascii_case(&transfer.to)
+                                    && connection.files)
+                        },
+                        || Control::Line(cancel.clone()),
+                    );
+                }
+            });
+        }
+
+        // SIGUSR1 writes a snapshot of how things stand to the log, for when there's no metrics or API to ask.  The
+        // handler only raises a flag, which the pool's timer picks up, since a signal handler can't safely do much more.
+        let snapshot_wanted = Arc::new(AtomicBool::new(false));
+        match signal_hook::flag::register(SIGUSR1, snapshot_wanted.clone()) {
+            Ok(_) => {
+                let snapshot_context = context.clone();
+                pool.execute_every(SNAPSHOT_CHECK, move || {
+                    if snapshot_wanted.swap(false, Ordering::SeqCst) {
+                        ChatServer::log_snapshot(&snapshot_context);
+                    }
+                });
+            }
+            Err(err) => warn!(
+                "Unable to listen for SIGUSR1, there won't be snapshots: {}",
+                err
+            ),
+        }
+
+        // Rather than work out how long it is until midnight (which daylight saving makes fun), we check once a minute
+        // whether the date has changed, and if it has, the day we remembered is over.
+        if self.config.digest.enabled {
+            let digest_context = context.clone();
+            let current_day = Mutex::new(Local::now().date_naive());
+            pool.execute_every(Duration::from_secs(60), move || {
+                let today = Local::now().date_naive();
+                let mut current_day = current_day.lock().unwrap();
+                if *current_day != today {
+                    digest_context.publish_digest(*current_day);
+                    *current_day = today;
+                }
+            });
+        }
+
+        // More wrapping and cloning as we spawn our room thread.  The thread pool is setup to automatically shut
+        // things down when we exit, so the only reason we keep its handle is to notice if it stops before then.  Every
+        // message goes through the room, so it jumps the queue.
+        let room_context = context.clone();
+        let room = pool.execute_with_priority(Priority::High, || {
+            ChatServer::handle_room(room_context, message_receiver)
+        });
+
+        // Every connected client holds on to a worker, so we keep count and turn people away once we're full rather
+        // than letting them queue up behind everyone else in the pool.  With the reactor they share a few workers
+        // instead, and the reactor has its own limit.
+        let connected = Arc::new(AtomicUsize::new(0));
+        let max_clients = match self.config.reactor.threads {
+            0 => self.config.max_clients,
+            _ => self.config.reactor.max_clients,
+        };
+
+        // The reactor's event loops hold on to their workers for good, like the room
+        let mut event_loops = Vec::new();
+        for index in 0..self.config.reactor.threads {
+            let (waker, wake_receiver) = match waker::pair() {
+                Ok(pair) => pair,
+                Err(err) => return Err(StartError(format!("Unable to create waker: {}", err))),
+            };
+            let waker = Arc::new(waker);
+            let (arrivals, arrival_receiver) = mpsc::channel();
+            event_loops.push(EventLoop {
+                arrivals,
+                waker: waker.clone(),
+            });
+
+            let loop_context = context.clone();
+            let connected = connected.clone();
+            pool.execute_with_priority(Priority::High, move || {
+                let _event_loop = info_span!("event_loop", index).entered();
+                ChatServer::run_event_loop(
+                    loop_context,
+                    arrival_receiver,
+                    waker,
+                    wake_receiver,
+                    connected,
+                );
+            });
+        }
+
+        Ok(ChatServerHandle {
+            _stop_on_drop: StopOnDrop(context.clone()),
+            listener,
+            websocket_listener,
+            sources,
+            events,
+            pool,
+            context,
+            room,
+            connected,
+            max_clients,
+            event_loops,
+            // Every connection gets a number for its span, so one client can be followed through the log even while
+            // several are talking at once.
+            next_id: 0,
+        })
+    }
+
+    fn handle_room(context: Arc<ServerContext>, message_receiver: mpsc::Receiver<RoomMessage>) {
+        let _room = info_span!("room", room = ROOM_NAME).entered();
+        info!("Room started");
+        let slow_broadcast =
+            Duration::from_millis(context.config.overload.max_broadcast_latency_ms);
+
+        // Room handling is pretty simple: we take any messages that we receive and simply broadcast them to all of our
+        // clients (including the one who sent it).
+        while context.running.load(Ordering::SeqCst) {
+            match message_receiver.try_recv() {
+                Ok(mut message) => {
+                    let _broadcast =
+                        debug_span!("broadcast", kind = message.kind.as_str()).entered();
+
+                    // Chat from someone who's muted goes no further than here, and they get told why
+                    if let (MessageKind::Chat, Some(sender)) = (message.kind, &message.sender) {
+                        if let Some(left) = context.muted_for(sender) {
+                            debug!(user = %sender, "Dropped message from muted user");
+                            let minutes = left.as_secs().div_ceil(60);
+                            context.notify(
+                                sender,
+                                NoticeKind::Moderation,
+                                &format!(
+                                    "You're muted for {} more minute(s), nobody saw that",
+                                    minutes
+                                ),
+                            );
+                            continue;
+                        }
+
+                        // The same while the room's frozen, for everyone here.  Chat from linked servers isn't theirs to
+                        // hold back, since the freeze is only ours.
+                        if let (Some((left, reason)), false) =
+                            (context.frozen_for(), message.relayed)
+                        {
+                            debug!(user = %sender, "Dropped message while the room is frozen");
+                            let minutes = left.as_secs().div_ceil(60);
+                            context.notify(
+                                sender,
+                                NoticeKind::Moderation,
+                                &format!(
+                                    "The room is frozen for {} more minute(s) ({}), nobody saw that",
+                                    minutes, reason
+                                ),
+                            );
+                            continue;
+                        }
+
+                        // Plugins can change it, and then whatever's embedding us gets its say on how it ended up
+                        message.body = context
+                            .plugins
+                            .transform(sender, mem::take(&mut message.body));
+                        if let Err(reason) = context.hooks.on_message(sender, &message.body) {
+                            debug!(user = %sender, "Dropped message refused by hooks");
+                            context.notify(sender, NoticeKind::Moderation, &reason);
+                            continue;
+                        }
+                    }
+
+                    // The sender's handler has already said whether they may ping everyone, but that also depends on
+                    // the room at the time it was said, so the rest is decided here
+                    if message.mass_mention {
+                        message.mass_mention = context.mass_mentions_allowed();
+                    }
+                    // Only now that plugins are done with it, since they might have changed who it names
+                    if let MessageKind::Chat = message.kind {
+                        message.mentioned = mentions::mentioned(&message.body);
+                    }
+
+                    let now = SystemTime::now();
+                    let mut clock = context.clock.lock().unwrap();
+                    message.clock = Some(match message.clock {
+                        Some(remote) => {
+                            clock.observe(remote, now);
+                            remote
+                        }
+                        None => clock.now(now),
+                    });
+                    drop(clock);
+                    message.sent = Some(now);
+                    context.record_history(&message);
+                    ChatServer::record_stats(&context, &message);
+
+                    // The message is gone once it's broadcast, so we keep what the scripts need to answer it
+                    let scripted = match (&context.scripts, message.kind, &message.sender) {
+                        (Some(_), MessageKind::Chat, Some(sender)) => {
+                            Some((sender.clone(), message.body.clone()))
+                        }
+                        _ => None,
+                    };
+
+                    // Chat goes on to Matrix, apart from what came from there in the first place
+                    if let (Some(matrix), MessageKind::Chat, Some(sender)) =
+                        (&context.matrix, message.kind, &message.sender)
+                    {
+                        if !sender.eq_ignore_ascii_case(&context.config.matrix.name) {
+                            matrix.relay(sender, &message.body);
+                        }
+                    }
+
+                    // The same for linked servers and Redis, apart from anything that came from them
+                    if !message.relayed {
+                        ChatServer::relay(&context, &message);
+                    }
+
+                    // Handing a message to every client's queue is the one thing the room does for everybody, so it's
+                    // what we time.  It never waits on a client, but it grows with the number of them.
+                    let started = Instant::now();
+                    context.fanout.lock().unwrap().broadcast(message);
+                    context.wake_all();
+                    let elapsed = started.elapsed();
+                    context.overload.record_latency(elapsed);
+                    context.metrics.message_broadcast(elapsed);
+
+                    if elapsed >= slow_broadcast {
+                        warn!(elapsed_ms = elapsed.as_millis() as u64, "Slow broadcast");
+                    } else {
+                        debug!(elapsed_us = elapsed.as_micros() as u64, "Broadcast");
+                    }
+
+                    if let Some((sender, body)) = scripted {
+                        ChatServer::run_scripts(&context, &sender, &body);
+                    }
+                }
+                Err(_) => {
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+            }
+        }
+    }
+
+    // Replies come back round through the room like anyone else's chat, so they're broadcast after the message they
+    // answer.  Scripts are skipped while we're overloaded, and don't answer each other (or themselves).
+    fn run_scripts(context: &ServerContext, sender: &str, body: &str) {
+        let scripts = match &context.scripts {
+            Some(scripts) => scripts,
+            None => return,
+        };
+        if context.overload.is_degraded()
+            || scripts
+                .names()
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(sender))
+        {
+            return;
+        }
+
+        for (script, reply) in scripts.on_message(sender, body, context.in_room()) {
+            context.send_message(RoomMessage::chat(script, &reply));
+        }
+    }
+
+    // Chat, joins and leaves from here, on their way to the linked servers and the other processes sharing the room
+    fn relay(context: &ServerContext, message: &RoomMessage) {
+        let (name, body) = match (&message.membership, &message.sender, message.kind) {
+            (Some(Membership::Joined(name)), _, _) => (name, None),
+            (Some(Membership::Left(name)), _, _) => (name, None),
+            (None, Some(sender), MessageKind::Chat) => (sender, Some(&message.body)),
+            _ => return,
+        };
+        let joined = matches!(message.membership, Some(Membership::Joined(_)));
+        // The room has always read the clock by now
+        let clock = message.clock.unwrap_or_default();
+
+        if let Some(links) = &context.links {
+            links.relay(
+                match body {
+                    Some(body) => link::Event::Chat {
+                        sender: name.clone(),
+                        body: body.clone(),
+                    },
+                    None if joined => link::Event::Joined(name.clone()),
+                    None => link::Event::Left(name.clone()),
+                },
+                clock,
+            );
+        }
+        if let Some(redis) = &context.redis {
+            redis.publish(
+                match body {
+                    Some(body) => redis::Event::Chat {
+                        sender: name.clone(),
+                        body: body.clone(),
+                    },
+                    None if joined => redis::Event::Joined { name: name.clone() },
+                    None => redis::Event::Left { name: name.clone() },
+                },
+                clock,
+            );
+        }
+    }
+
+    fn record_stats(context: &ServerContext, message: &RoomMessage) {
+        let mut stats = context.stats.lock().unwrap();
+        match (&message.sender, &message.membership) {
+            (Some(sender), _) if message.kind == MessageKind::Chat => stats.record_message(sender),
+            (_, Some(Membership::Joined(_))) => stats.member_joined(),
+            (_, Some(Membership::Left(_))) => stats.member_left(),
+            _ => {}
+        }
+    }
+
+    // A client's whole connection with the thread per client model, run on the worker it was given
+    fn handle_client(
+        context: Arc<ServerContext>,
+        id: u64,
+        address: IpAddr,
+        stream: TcpStream,
+        websocket: bool,
+    ) {
+        let stream = match ChatServer::handshake(&context, stream, websocket) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let (waker, wake_receiver) = match waker::pair() {
+            Ok(pair) => pair,
+            Err(err) => {
+                error!("Unable to create waker: {}", err);
+                return;
+            }
+        };
+        let mut client = ChatServer::open_client(&context, id, address, stream, Arc::new(waker));
+
+        // The worker lives through a panic in here (see thread_pool.rs), but their name and their place in the room
+        // wouldn't be given up unless we still close up after them before letting it carry on
+        let served = panic::catch_unwind(AssertUnwindSafe(|| {
+            ChatServer::serve_client(&context, &mut client, wake_receiver)
+        }));
+        match served {
+            Ok(Outcome::Goodbye(goodbye)) => ChatServer::drain(&mut client.stream, &goodbye),
+            Ok(_) => {}
+            Err(payload) => {
+                ChatServer::close_client(&context, &mut client);
+                panic::resume_unwind(payload);
+            }
+        }
+        ChatServer::close_client(&context, &mut client);
+    }
+
+    // The TLS and WebSocket handshakes, which happen off the accept loop so a slow handshake only holds up this client.
+    // It still holds up a thread though, on the blocking pool or a worker, so one that stalls is given up on like a
+    // goodbye in reject is.  None if either failed, in which case there's nobody to talk to.
+    fn handshake(context: &ServerContext, stream: TcpStream, websocket: bool) -> Option<Stream> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+        match context.handshake(stream, websocket) {
+            Ok(stream) => {
+                stream.tcp().set_read_timeout(None).ok();
+                stream.tcp().set_write_timeout(None).ok();
+                Some(stream)
+            }
+            Err(err) => {
+                warn!("Handshake failed: {}", err);
+                None
+            }
+        }
+    }
+
+    // Sets up everything for a new connection, however it's going to be served.  The waker is whatever wakes up the
+    // handler, which is the client's own with a thread per client and shared by every client on an event loop in the
+    // reactor.
+    fn open_client(
+        context: &ServerContext,
+        id: u64,
+        address: IpAddr,
+        stream: Stream,
+        waker: Arc<Waker>,
+    ) -> Client {
+        // With TLS a single read off the socket can decrypt into more than one read's worth of messages, and the
+        // leftovers won't wake up our poll.  So we go nonblocking and always read until there's nothing left.
+        stream.set_nonblocking(true).unwrap();
+
+        let (control_sender, control_receiver) = mpsc::channel();
+        let connection = Connection {
+            user: String::new(),
+            address,
+            client: None,
+            connected: SystemTime::now(),
+            logged_in: false,
+            in_room: false,
+            away: None,
+            files: false,
+            control: control_sender,
+            waker,
+        };
+        context.connections.lock().unwrap().insert(id, connection);
+
+        let session = Session {
+            id,
+            user: String::from(""),
+            state: ConnectionState::Connected,
+            logged_in: false,
+            bot: false,
+            address,
+            capabilities: Capabilities::default(),
+            room_receiver: None,
+            batch: Batch::new(
+                context.config.batching.max_bytes,
+                Duration::from_millis(context.config.batching.max_delay_ms),
+            ),
+            rate_limit: TokenBucket::new(
+                context.config.rate_limit.messages_per_second,
+                context.config.rate_limit.burst,
+            ),
+            outbound: VecDeque::new(),
+            dropped: 0,
+            mentions: MentionSettings::default(),
+            heartbeat: Heartbeat::new(Duration::from_secs(context.config.heartbeat.interval_secs)),
+            prefix: context.config.command_prefix,
+            login_deadline: None,
+        };
+
+        Client {
+            stream,
+            session,
+            control_receiver,
+            lines: LineReader::with_max_line(context.config.max_message_bytes),
+        }
+    }
+
+    // However the connection ended, the room (and the hooks) hear about it once
+    fn close_client(context: &Arc<ServerContext>, client: &mut Client) {
+        context
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&client.session.id);
+        ChatServer::close(context, &mut client.session);
+        context.cancel_transfers(client.session.id);
+        context
+            .hooks
+            .on_disconnect(client.session.address, &client.session.user);
+    }
+
+    // The client's event loop with a thread per client, which returns once the connection is over for any reason
+    fn serve_client(
+        context: &Arc<ServerContext>,
+        client: &mut Client,
+        wake_receiver: WakeReceiver,
+    ) -> Outcome {
+        let mut sources = Sources::new();
+        sources.register(Source::Client, &client.stream, popol::interest::READ);
+        sources.register(Source::Waker, &wake_receiver, popol::interest::READ);
+        let mut events = Events::new();
+
+        while context.running.load(Ordering::SeqCst) {
+            match client.poll(context) {
+                Outcome::Open => {}
+                outcome => return outcome,
+            }
+
+            // Only wake up for writing while there's something left to write, otherwise a socket that's always
+            // writable would have us spinning
+            if client.wants_write() {
+                sources.set(&Source::Client, popol::interest::WRITE);
+            } else {
+                sources.unset(&Source::Client, popol::interest::WRITE);
+            }
+
+            match sources.wait_timeout(&mut events, client.timeout()) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    error!("Unable to poll: {}", err);
+                    return Outcome::Gone;
+                }
+            }
+
+            for (key, event) in events.iter() {
+                let outcome = match key {
+                    Source::Waker => {
+                        wake_receiver.clear();
+                        Outcome::Open
+                    }
+                    Source::Client if event.readable => client.read(context),
+                    Source::Client if event.writable => client.write(),
+                    _ => Outcome::Open,
+                };
+                if !matches!(outcome, Outcome::Open) {
+                    return outcome;
+                }
+            }
+        }
+
+        Outcome::Goodbye(ChatServer::shut_down(&mut client.session))
+    }
+
+    // One of the reactor's event loop threads.  Where a thread per client has each handler waiting on its own client,
+    // this waits on all of its clients at once and hands each event to the client it's for, so a few threads can serve
+    // thousands of clients.  Nothing here may block, or every client on the thread would wait along with it, so
+    // goodbyes go out like anything else rather than through drain (see say_goodbye).
+    fn run_event_loop(
+        context: Arc<ServerContext>,
+        arrivals: mpsc::Receiver<Arrival>,
+        waker: Arc<Waker>,
+        wake_receiver: WakeReceiver,
+        connected: Arc<AtomicUsize>,
+    ) {
+        let mut clients: HashMap<u64, LoopClient> = HashMap::new();
+        let mut sources = Sources::new();
+        sources.register(LoopSource::Waker, &wake_receiver, popol::interest::READ);
+        let mut events = Events::new();
+        let mut finished = Vec::new();
+
+        loop {
+            let running = context.running.load(Ordering::SeqCst);
+            // Anyone who turns up while we're shutting down is just hung up on
+            while let Ok(arrival) = arrivals.try_recv() {
+                if !running {
+                    connected.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+
+                let _entered = arrival.span.enter();
+                info!("Connected");
+                context.metrics.client_connected();
+                let client = ChatServer::open_client(
+                    &context,
+                    arrival.id,
+                    arrival.address,
+                    arrival.stream,
+                    waker.clone(),
+                );
+                sources.register(
+                    LoopSource::Client(arrival.id),
+                    &client.stream,
+                    popol::interest::READ,
+                );
+                clients.insert(
+                    arrival.id,
+                    LoopClient {
+                        client,
+                        span: arrival.span.clone(),
+                        writing: false,
+                        leaving: None,
+                    },
+                );
+            }
+
+            // Everything nothing wakes us for, the same as the top of serve_client's loop, for every client
+            let now = Instant::now();
+            let mut timeout = SHUTDOWN_CHECK;
+            for (id, entry) in clients.iter_mut() {
+                let _entered = entry.span.clone().entered();
+                let outcome = match &mut entry.leaving {
+                    Some(leaving) if now >= leaving.deadline => Outcome::Gone,
+                    Some(_) => Outcome::Open,
+                    None if running => entry.client.poll(&context),
+                    None => Outcome::Goodbye(ChatServer::shut_down(&mut entry.client.session)),
+                };
+                if !entry.settle(outcome) {
+                    finished.push(*id);
+                    continue;
+                }
+
+                if entry.client.wants_write() != entry.writing {
+                    entry.writing = !entry.writing;
+                    let key = LoopSource::Client(*id);
+                    if entry.writing {
+                        sources.set(&key, popol::interest::WRITE);
+                    } else {
+                        sources.unset(&key, popol::interest::WRITE);
+                    }
+                }
+                timeout = timeout.min(match &entry.leaving {
+                    Some(leaving) => leaving.deadline.saturating_duration_since(now),
+                    None => entry.client.timeout(),
+                });
+            }
+            ChatServer::finish_loop_clients(
+                &context,
+                &mut clients,
+                &mut sources,
+                &mut finished,
+                &connected,
+            );
+
+            if !running && clients.is_empty() {
+                return;
+            }
+
+            match sources.wait_timeout(&mut events, timeout) {
+                Ok(()) => {}
+                Err(err)
+                    if err.kind() == io::ErrorKind::TimedOut
+                        || err.kind() == io::ErrorKind::Interrupted =>
+                {
+                    continue
+                }
+                Err(err) => {
+                    // Without poll there's no serving anyone, so everyone on this loop is hung up on
+                    error!("Unable to poll: {}", err);
+                    finished.extend(clients.keys().copied());
+                    ChatServer::finish_loop_clients(
+                        &context,
+                        &mut clients,
+                        &mut sources,
+                        &mut finished,
+                        &connected,
+                    );
+                    return;
+                }
+            }
+
+            for (key, event) in events.iter() {
+                let id = match key {
+                    LoopSource::Waker => {
+                        wake_receiver.clear();
+                        continue;
+                    }
+                    LoopSource::Client(id) => *id,
+                };
+                let entry = match clients.get_mut(&id) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+
+                let _entered = entry.span.clone().entered();
+                let outcome = match entry.leaving {
+                    Some(_) => Outcome::Open,
+                    None if event.readable => entry.client.read(&context),
+                    None if event.writable => entry.client.write(),
+                    None => Outcome::Open,
+                };
+                if !entry.settle(outcome) {
+                    finished.push(id);
+                }
+            }
+            ChatServer::finish_loop_clients(
+                &context,
+                &mut clients,
+                &mut sources,
+                &mut finished,
+                &connected,
+            );
+        }
+    }
+
+    // Lets go of the clients an event loop is finished with
+    fn finish_loop_clients(
+        context: &Arc<ServerContext>,
+        clients: &mut HashMap<u64, LoopClient>,
+        sources: &mut Sources<LoopSource>,
+        finished: &mut Vec<u64>,
+        connected: &AtomicUsize,
+    ) {
+        for id in finished.drain(..) {
+            if let Some(mut entry) = clients.remove(&id) {
+                let _entered = entry.span.clone().entered();
+                sources.unregister(&LoopSource::Client(id));
+                ChatServer::close_client(context, &mut entry.client);
+                context.metrics.client_disconnected();
+                connected.fetch_sub(1, Ordering::SeqCst);
+                info!("Disconnected");
+            }
+        }
+    }
+
+    // One complete line from the client.  The connection's state decides whether the command is allowed at all (see
+    // state.rs), and anything that gets past that is carried out here, moving the state along where it needs to.
+    fn handle_line(
+        context: &Arc<ServerContext>,
+        session: &mut Session,
+        stream: &Stream,
+        message: &str,
+    ) {
+        let command = Command::parse(message, session.prefix);
+        if let Err(err) = session.state.check(&command) {
+            let err = err.describe(session.prefix);
+            debug!(state = ?session.state, "Refused {:?}: {}", command, err);
+            session.error(err);
+            return;
+        }
+
+        // That says whether the command makes sense right now, this says whether they're allowed it at all
+        if let Some(name) = command.name() {
+            let role = context.role(session);
+            if !context.authorizer.allows(role, name) {
+                debug!(?role, "Not allowed {:?}", command);
+                match name {
+                    "chat" => session.error("You're not allowed to talk here"),
+                    _ => session.error(format!(
+                        "You're not allowed to use {}{}",
+                        session.prefix, name
+                    )),
+                }
+                return;
+            }
+        }
+
+        match command {
+            Command::Caps(list) => {
+                session.capabilities = Capabilities::parse(list);
+                debug!(capabilities = ?session.capabilities, "Handshake");
+                match session.capabilities.prefix {
+                    Some(prefix) if protocol::is_valid_prefix(prefix) => session.prefix = prefix,
+                    Some(prefix) => session.error(format!(
+                        "{} can't be a command prefix, using {}",
+                        prefix, session.prefix
+                    )),
+                    None => {}
+                }
+                session.apply_capabilities(&context.config, stream);
+                let client = session.capabilities.client.clone();
+                let files = session.capabilities.files;
+                context.update_connection(session.id, |connection| {
+                    connection.client = client;
+                    connection.files = files;
+                });
+                session.state = ConnectionState::Handshaking;
+            }
+            Command::User(name) => ChatServer::rename(context, session, "user", name),
+            Command::Nick(name) => ChatServer::rename(context, session, "nick", name),
+            Command::Register(password) => {
+                // Their name passed when they picked it, but the rules could have changed since if they've been around
+                // a while
+                if let Err(err) = context.names.check(&session.user) {
+                    session.error(format!("Unable to register: {}", err));
+                    return;
+                }
+                match context.accounts.register(&session.user, password) {
+                    Ok(()) => {
+                        info!(user = %session.user, "Registered");
+                        context.save_accounts();
+                        session.logged_in = true;
+                        context.update_connection(session.id, |connection| {
+                            connection.logged_in = true
+                        });
+                        let notice = format!("{} is now registered to you", session.user);
+                        session.notice(notice);
+                        ChatServer::greet(context, session);
+                    }
+                    Err(err) => session.error(format!("Unable to register: {}", err)),
+                }
+            }
+            Command::Login(credentials) => {
+                let mut credentials = credentials.split_whitespace();
+                let (name, password) = match (credentials.next(), credentials.next()) {
+                    (Some(name), Some(password)) => (name, password),
+                    _ => {
+                        session.error(format!(
+                            "Usage: {}login <name> <password> [code]",
+                            session.prefix
+                        ));
+                        return;
+                    }
+                };
+                let code = credentials.next();
+
+                if session.logged_in && name == session.user {
+                    session.error(format!("You're already logged in as {}", name));
+                    return;
+                }
+                if !ChatServer::check_lockout(context, session, name) {
+                    return;
+                }
+
+                // Same answer whether the name is unknown or the password is wrong, so nobody can go fishing for
+                // names
+                if !context.accounts.verify(name, password) {
+                    context.auth_failed(session.address, name, Failure::Password);
+                    session.error("Invalid name or password");
+                    return;
+                }
+
+                // The password was right, so it's safe to say the account wants a code as well
+                if context.accounts.needs_code(name) {
+                    match code {
+                        Some(code) if context.accounts.check_code(name, code) => {
+                            context.save_accounts()
+                        }
+                        Some(_) => {
+                            context.auth_failed(session.address, name, Failure::TwoFactorCode);
+                            session.error("Invalid two-factor code");
+                            return;
+                        }
+                        None => {
+                            session.error(format!(
+                                "{} has two-factor authentication on, log in with {}login {} <password> <code>",
+                                name, session.prefix, name
+                            ));
+                            return;
+                        }
+                    }
+                }
+
+                context.lockouts.succeed(session.address, name);
+                ChatServer::log_in(context, session, name);
+            }
+            Command::Recover(name) => ChatServer::recover(context, session, name),
+            Command::Reset(arguments) => ChatServer::reset(context, session, arguments),
+            Command::TwoFactor(arguments) => ChatServer::two_factor(context, session, arguments),
+            Command::Sessions => ChatServer::sessions(context, session),
+            Command::Logout(id) => ChatServer::logout(context, session, id),
+            Command::Accept => ChatServer::continue_welcome(context, session),
+            Command::Answer(answer) => {
+                if let ConnectionState::Authenticated(Welcome::Challenge { answer: expected }) =
+                    session.state
+                {
+                    if answer.parse() == Ok(expected) {
+                        ChatServer::join_room(context, session);
+                    } else {
+                        session.error("That's not right, here's another one");
+                        ChatServer::send_challenge(session);
+                    }
+                }
+            }
+            Command::Kick(arguments) => ChatServer::kick(context, session, arguments),
+            Command::Mute(arguments) => ChatServer::mute(context, session, arguments),
+            Command::Ban(arguments) => ChatServer::ban(context, session, arguments),
+            Command::Unban(target) => ChatServer::unban(context, session, target),
+            Command::BanList => ChatServer::ban_list(context, session),
+            Command::Join(room) => ChatServer::join(session, room),
+            Command::Mentions(arguments) => ChatServer::mentions(session, arguments),
+            Command::Msg(arguments) => ChatServer::direct(context, session, arguments),
+            Command::Who(target) => ChatServer::who(context, session, target),
+            Command::Away(message) => ChatServer::set_away(context, session, Some(message)),
+            Command::Back => ChatServer::set_away(context, session, None),
+            Command::Room(command) => ChatServer::handle_room_command(context, session, command),
+            Command::Bot(arguments) => ChatServer::bot(context, session, arguments),
+            Command::File(line) => ChatServer::file(context, session, line),
+            Command::Ping => session.batch.push_line(PONG_COMMAND),
+            Command::Pong => session.heartbeat.pong(),
+            Command::Quit => ChatServer::close(context, session),
+            Command::Say("") => session.error(format!("Usage: {}say <message>", session.prefix)),
+            Command::Say(message) | Command::Chat(message) => {
+                ChatServer::chat(context, session, message)
+            }
+            // Bots can only post, so anything that looks like a command from one is chat
+            Command::Other(..) if session.bot => ChatServer::chat(context, session, message),
+            Command::Other(name, arguments) => {
+                match context.plugins.command(&session.user, name, arguments) {
+                    Some(reply) => {
+                        for line in reply.lines() {
+                            session.notice(line);
+                        }
+                    }
+                    // Nobody's, so it was chat all along
+                    None => ChatServer::chat(context, session, message),
+                }
+            }
+        }
+    }
+
+    // Bots' chat is tagged as theirs, and nobody else can pass for one by typing the tag themselves
+    fn chat(context: &Arc<ServerContext>, session: &mut Session, message: &str) {
+        let tagged;
+        let message = if session.bot {
+            tagged = format!("{} {}", BOT_TAG, message);
+            &tagged
+        } else if message.starts_with(BOT_TAG) {
+            session.error(format!("Only bots can start a message with {}", BOT_TAG));
+            return;
+        } else {
+            message
+        };
+
+        context.metrics.message_received();
+        let mut chat = RoomMessage::chat(&session.user, message);
+        if mentions::is_mass_mention(message) {
+            chat.mass_mention = ChatServer::may_mass_mention(context, session);
+        }
+        context.send_message(chat);
+    }
+
+    // Whether they may try logging in to this name right now, telling them why not if they can't
+    fn check_lockout(context: &Arc<ServerContext>, session: &mut Session, name: &str) -> bool {
+        let left = match context.lockouts.locked_for(session.address, name) {
+            Some(left) => left,
+            None => return true,
+        };
+
+        context.auth_failed(session.address, name, Failure::LockedOut);
+        session.error(format!(
+            "Too many failed attempts, try again in {} minute(s)",
+            left.as_secs().div_ceil(60).max(1)
+        ));
+        false
+    }
+
+    // Finishes a /login or /reset, once they've shown the name is theirs
+    fn log_in(context: &Arc<ServerContext>, session: &mut Session, name: &str) {
+        if context.bans.is_nick_banned(name) {
+            warn!(user = name, "Banned name tried to log in");
+            session.error(format!("{} is banned from this server", name));
+            return;
+        }
+
+        // Whether it's a bot has to be known before set_user, which decides whether they skip the welcome
+        let was_bot = session.bot;
+        session.bot = context.accounts.bot_room(name).is_some();
+        if !ChatServer::set_user(context, session, name, true) {
+            session.bot = was_bot;
+            return;
+        }
+        info!(user = name, bot = session.bot, "Logged in");
+        session.logged_in = true;
+        context.update_connection(session.id, |connection| connection.logged_in = true);
+        session.login_deadline = None;
+        session.notice(format!("You are now logged in as {}", name));
+        ChatServer::greet(context, session);
+    }
+
+    // /recover <name>, for someone who's forgotten their password.  The op passes the token on however they like, and
+    // it's good for one /reset.
+    fn recover(context: &Arc<ServerContext>, session: &mut Session, name: &str) {
+        if name.is_empty() {
+            session.error(format!("Usage: {}recover <name>", session.prefix));
+            return;
+        }
+
+        let lifetime = Duration::from_secs(context.config.recovery_token_secs);
+        match context.accounts.issue_recovery(name, lifetime) {
+            Ok(token) => {
+                info!(user = name, by = %session.user, "Recovery token issued");
+                context.save_accounts();
+                session.notice(format!(
+                    "Recovery token for {}: {}  It works once, for the next {} minute(s), with {}reset {} {} <new password>",
+                    name,
+                    token,
+                    lifetime.as_secs().div_ceil(60),
+                    session.prefix,
+                    name,
+                    token
+                ));
+            }
+            Err(err) => session.error(format!("Unable to make a recovery token: {}", err)),
+        }
+    }
 
-impl ChatServer {
-    // A typical method definition, takes self first, a string, and a couple objects that implement certain traits
-    pub fn run(&self) {
-        let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-        listener.set_nonblocking(true).unwrap();
+    // /reset <name> <token> <new password>, which sets the new password and logs them in
+    fn reset(context: &Arc<ServerContext>, session: &mut Session, arguments: &str) {
+        let mut arguments = arguments.split_whitespace();
+        let (name, token, password) = match (arguments.next(), arguments.next(), arguments.next()) {
+            (Some(name), Some(token), Some(password)) => (name, token, password),
+            _ => {
+                session.error(format!(
+                    "Usage: {}reset <name> <token> <new password>",
+                    session.prefix
+                ));
+                return;
+            }
+        };
 
-        // Sources and Events are part of popol which is a polling library.  Very similar (if not identical) to c
-        // style polling of file descriptors.
-        let mut sources = Sources::new();
-        sources.register(Source::Listener, &listener, popol::interest::READ);
+        if !ChatServer::check_lockout(context, session, name) {
+            return;
+        }
+        if let Err(err) = context.accounts.reset_password(name, token, password) {
+            if let AccountError::InvalidToken = err {
+                context.auth_failed(session.address, name, Failure::RecoveryToken);
+            }
+            session.error(format!("Unable to reset the password: {}", err));
+            return;
+        }
+        context.lockouts.succeed(session.address, name);
+        info!(user = name, "Password reset");
+        context.save_accounts();
+        session.notice(format!("The password for {} has been changed", name));
 
-        // This is an atomic reference counted atomic bool.  The reference counting is so that we can point at the same
-        // value among our threads.  The atomic bool is so we can read and write the value safely across threads.
-        let running = Arc::new(AtomicBool::new(true));
+        // The token stands in for the password, not for their authenticator app
+        if context.accounts.needs_code(name) {
+            session.notice(format!(
+                "{} has two-factor authentication on, log in with {}login {} <password> <code>",
+                name, session.prefix, name
+            ));
+            return;
+        }
 
-        // ctrlc is actually a library to help us catch ctrlc.  This lets us setup a closure to change our boolean
-        // that tells us if we're running or not
-        let running_handler = running.clone();
-        ctrlc::set_handler(move || {
-            running_handler.store(false, Ordering::SeqCst);
-        })
-        .unwrap();
+        ChatServer::log_in(context, session, name);
+    }
 
-        let mut events = Events::new();
-        let pool = ThreadPool::new(10);
+    // /2fa enable, then /2fa confirm <code> with a code from their authenticator app, after which logging in takes a
+    // code as well.  /2fa disable <code> turns it off again.
+    fn two_factor(context: &Arc<ServerContext>, session: &mut Session, arguments: &str) {
+        if !session.logged_in {
+            session.error("Log in to a registered name to set up two-factor authentication");
+            return;
+        }
 
-        // We'll see a lot of wrapping in Arc and Mutex as we are sharing a lot things among our threads.  This wraps
-        // our message broadcaster for updating our room chat.
-        let room_sender = Arc::new(Mutex::new(Bus::new(4)));
-        // This is a multiple producer, single consumer, channel for each of our clients to send incoming messages
-        // to our room (to be broadcasted to everyone).
-        let (message_sender, message_receiver) = mpsc::channel();
+        let (action, code) = arguments
+            .split_once(' ')
+            .map(|(action, code)| (action, code.trim()))
+            .unwrap_or((arguments, ""));
+        let name = session.user.clone();
+        match (action, code) {
+            ("enable", "") => match context.accounts.enable_two_factor(&name) {
+                Ok(uri) => {
+                    context.save_accounts();
+                    session.notice(format!("Add this to your authenticator app: {}", uri));
+                    session.notice(format!(
+                        "Then turn it on with {}2fa confirm <code>",
+                        session.prefix
+                    ));
+                }
+                Err(err) => session.error(format!(
+                    "Unable to set up two-factor authentication: {}",
+                    err
+                )),
+            },
+            ("confirm", code) if !code.is_empty() => {
+                match context.accounts.confirm_two_factor(&name, code) {
+                    Ok(backup_codes) => {
+                        info!(user = %name, "Two-factor authentication on");
+                        context.save_accounts();
+                        session.notice(format!(
+                            "Two-factor authentication is on, log in with {}login {} <password> <code> from now on",
+                            session.prefix, name
+                        ));
+                        session.notice(format!(
+                            "Backup codes, each good for one login without the app.  Keep them somewhere safe: {}",
+                            backup_codes.join(" ")
+                        ));
+                    }
+                    Err(err) => session.error(format!(
+                        "Unable to turn on two-factor authentication: {}",
+                        err
+                    )),
+                }
+            }
+            ("disable", code) if !code.is_empty() => {
+                match context.accounts.disable_two_factor(&name, code) {
+                    Ok(()) => {
+                        info!(user = %name, "Two-factor authentication off");
+                        context.save_accounts();
+                        session.notice("Two-factor authentication is off");
+                    }
+                    Err(err) => session.error(format!(
+                        "Unable to turn off two-factor authentication: {}",
+                        err
+                    )),
+                }
+            }
+            _ => session.error(format!(
+                "Usage: {0}2fa enable, {0}2fa confirm <code> or {0}2fa disable <code>",
+                session.prefix
+            )),
+        }
+    }
 
-        // More wrapping and cloning as we spawn our room thread.  The thread pool is setup to automatically shut
-        // things down when we exit, so we don't do any joins or any special handling other than exiting the threads
-        let running_copy = running.clone();
-        let message_receiver_ref = Arc::new(Mutex::new(message_receiver));
-        let room_sender_ref = room_sender.clone();
-        pool.execute(|| {
-            ChatServer::handle_room(running_copy, message_receiver_ref, room_sender_ref)
-        });
+    // /sessions, every connection logged in to their account, so they can spot one they forgot to close
+    fn sessions(context: &ServerContext, session: &mut Session) {
+        if !session.logged_in {
+            session.error("Log in to a registered name to see its sessions");
+            return;
+        }
 
-        // Wrapping
-        let message_sender_ref = Arc::new(Mutex::new(message_sender));
-        while running.load(Ordering::SeqCst) {
-            // Wait for something to happen on our socket, just waiting for an attempted connection
-            sources.wait(&mut events).unwrap();
-
-            for (key, _event) in events.iter() {
-                match key {
-                    Source::Listener => loop {
-                        let stream = match listener.accept() {
-                            Ok((stream, _addr)) => stream,
-                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                            Err(_) => return,
-                        };
+        let mut sessions: Vec<(u64, String)> = context
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, connection)| {
+                connection.logged_in && connection.user.eq_ignore_ascii_case(&session.user)
+            })
+            .map(|(id, connection)| {
+                let line = format!(
+                    "{}: {} from {}, connected {}{}",
+                    id,
+                    connection.client.as_deref().unwrap_or("unknown client"),
+                    connection.address,
+                    DateTime::<Local>::from(connection.connected).format("%Y-%m-%d %H:%M:%S"),
+                    if *id == session.id { " (this one)" } else { "" }
+                );
+                (*id, line)
+            })
+            .collect();
+        sessions.sort_unstable_by_key(|(id, _)| *id);
 
-                        // Clone our values again for threading
-                        let running = running.clone();
-                        let room_receiver = room_sender.clone().lock().unwrap().add_rx();
-                        let message_sender_ref = message_sender_ref.clone();
-
-                        // This will take our stream and process any messages until they disconnect
-                        pool.execute(|| {
-                            ChatServer::handle_client(
-                                stream,
-                                running,
-                                room_receiver,
-                                message_sender_ref,
-                            );
-                        });
-                    },
-                    _ => {}
-                }
+        session.notice(format!(
+            "Sessions for {} ({}):",
+            session.user,
+            sessions.len()
+        ));
+        for (_, line) in sessions {
+            session.notice(line);
+        }
+    }
+
+    // /logout <id>, for ending one of their other sessions from /sessions, say on a shared machine they forgot about
+    fn logout(context: &ServerContext, session: &mut Session, id: &str) {
+        if !session.logged_in {
+            session.error("Log in to a registered name to log out its sessions");
+            return;
+        }
+        let id = match id.parse::<u64>() {
+            Ok(id) => id,
+            Err(_) => {
+                session.error(format!(
+                    "Usage: {}logout <session>, with a session from {}sessions",
+                    session.prefix, session.prefix
+                ));
+                return;
+            }
+        };
+        if id == session.id {
+            session.error(format!(
+                "That's this session, use {}quit to end it",
+                session.prefix
+            ));
+            return;
+        }
+
+        let user = session.user.clone();
+        let logged_out = context.send_control(
+            |other, connection| {
+                other == id && connection.logged_in && connection.user.eq_ignore_ascii_case(&user)
+            },
+            || Control::Logout,
+        );
+        match logged_out {
+            0 => session.error(format!("You have no session {}", id)),
+            _ => {
+                info!(session = id, "Logged out another session");
+                session.notice(format!("Logged out session {}", id));
             }
         }
     }
 
-    fn handle_room(
-        running: Arc<AtomicBool>,
-        message_receiver: Arc<Mutex<mpsc::Receiver<String>>>,
-        room_sender: Arc<Mutex<Bus<String>>>,
-    ) {
-        println!("Room started");
+    // The greeting for someone logged in to their account for the first time (see WelcomeConfig).  If there's no
+    // greeting set they're left unmarked, so they still get one if it's set up later.
+    fn greet(context: &Arc<ServerContext>, session: &mut Session) {
+        let greeting = &context.config.welcome.greeting;
+        if greeting.is_empty() || !context.accounts.mark_welcomed(&session.user) {
+            return;
+        }
 
-        // Room handling is pretty simple: we take any messages that we receive and simply broadcast them to all of our
-        // clients (including the one who sent it).
-        while running.load(Ordering::SeqCst) {
-            match message_receiver.lock().unwrap().try_recv() {
-                Ok(message) => {
-                    room_sender.lock().unwrap().broadcast(message);
-                }
-                Err(_) => {
-                    thread::sleep(time::Duration::from_millis(10));
+        info!(user = %session.user, "Greeted");
+        for line in greeting {
+            session.notice(line.as_str());
+        }
+        context.save_accounts();
+    }
+
+    // /user <name> picks a name before they're in the room, and /nick <name> (or /user again) changes it once they are.
+    // Either way the name has to be free, allowed and not banned, and set_user takes it from there.
+    fn rename(context: &Arc<ServerContext>, session: &mut Session, command: &str, name: &str) {
+        if name.is_empty() {
+            session.error(format!("Usage: {}{} <name>", session.prefix, command));
+            return;
+        }
+        if let Err(err) = context.names.check(name) {
+            session.error(err.to_string());
+            return;
+        }
+        // Sending the same name again changes nothing, so it shouldn't tell the room anything either
+        if name == session.user {
+            session.error(format!("You're already {}", name));
+            return;
+        }
+
+        if context.bans.is_nick_banned(name) {
+            session.error(format!("{} is banned from this server", name));
+            return;
+        }
+
+        // A registered name has to be claimed with /login, otherwise anyone could show up as anyone.  With a grace
+        // period they can use it for a little while first, and lose it if they don't log in.
+        let registered = context.accounts.is_registered(name);
+        let grace = context.config.names.registered_grace_secs;
+        if registered && grace == 0 {
+            session.error(format!(
+                "{} is a registered name, use {}login {} <password>",
+                name, session.prefix, name
+            ));
+            return;
+        }
+
+        if !ChatServer::set_user(context, session, name, false) {
+            return;
+        }
+        session.logged_in = false;
+        session.bot = false;
+        context.update_connection(session.id, |connection| connection.logged_in = false);
+        session.login_deadline = None;
+        if registered {
+            info!(user = name, "Guest took a registered name");
+            session.login_deadline = Some(Instant::now() + Duration::from_secs(grace));
+            session.error(format!(
+                "{} is a registered name.  Log in with {}login {} <password> within {} seconds or you'll be renamed",
+                name, session.prefix, name, grace
+            ));
+        }
+    }
+
+    // Their time to log in to a registered name ran out, so it goes back to its owner and they get a guest name
+    fn rename_to_guest(context: &Arc<ServerContext>, session: &mut Session) {
+        session.login_deadline = None;
+        let previous = session.user.clone();
+        loop {
+            let name = format!("guest_{:04}", OsRng.next_u32() % 10000);
+            if context.is_online(&name) || context.accounts.is_registered(&name) {
+                continue;
+            }
+            if ChatServer::set_user(context, session, &name, false) {
+                break;
+            }
+        }
+
+        info!(user = %session.user, previous = %previous, "Renamed for not logging in");
+        session.error(format!(
+            "You didn't log in as {} in time, so you're now {}",
+            previous, session.user
+        ));
+    }
+
+    // False if someone else online already has the name, in which case they're told and nothing changes.  Logging in
+    // is when the name might be shared with their other connections (see claim_name).
+    fn set_user(
+        context: &Arc<ServerContext>,
+        session: &mut Session,
+        name: &str,
+        logging_in: bool,
+    ) -> bool {
+        if !context.claim_name(session.id, name, logging_in) {
+            session.error(format!("Someone called {} is already here", name));
+            return false;
+        }
+        debug!(user = name, "Name set");
+        let previous = std::mem::replace(&mut session.user, String::from(name));
+
+        match session.state {
+            ConnectionState::Connected | ConnectionState::Handshaking => {
+                ChatServer::continue_welcome(context, session)
+            }
+            // Their other connections keep the old name, so the room might only see someone arrive or leave
+            ConnectionState::InRoom if previous != name => {
+                let (previous_here, name_here) = {
+                    let connections = context.connections.lock().unwrap();
+                    (
+                        ServerContext::others_in_room(&connections, session.id, &previous),
+                        ServerContext::others_in_room(&connections, session.id, name),
+                    )
+                };
+                match (previous_here, name_here) {
+                    (false, false) => context.send_to_room(
+                        MessageKind::Presence,
+                        format!("{} is now known as {}.", previous, name),
+                    ),
+                    (true, false) => context.send_message(RoomMessage::joined(name)),
+                    (false, true) => context.send_message(RoomMessage::left(&previous)),
+                    (true, true) => {}
                 }
             }
+            // Renaming halfway through the welcome is fine, nobody in the room knows them yet
+            _ => {}
         }
+
+        true
     }
 
-    fn handle_client(
-        mut stream: TcpStream,
-        running: Arc<AtomicBool>,
-        mut room_receiver: BusReader<String>,
-        message_sender: Arc<Mutex<mpsc::Sender<String>>>,
-    ) {
-        println!("Client connected");
+    // Moves on to whichever step of the welcome comes next and is turned on, and tells them what it wants
+    fn continue_welcome(context: &Arc<ServerContext>, session: &mut Session) {
+        let welcome = &context.config.welcome;
+        let named = matches!(
+            session.state,
+            ConnectionState::Connected | ConnectionState::Handshaking
+        );
 
-        let mut user = String::from("");
-        let mut buffer = [0; 1024];
+        // An op let the bot in when they made it, and it couldn't /accept or /answer anyway
+        if session.bot {
+            ChatServer::join_room(context, session);
+        } else if named && !welcome.rules.is_empty() {
+            for rule in &welcome.rules {
+                session.notice(rule);
+            }
+            session.notice(format!(
+                "Type {}accept to agree to the rules and continue",
+                session.prefix
+            ));
+            session.state = ConnectionState::Authenticated(Welcome::Rules);
+        } else if welcome.challenge {
+            ChatServer::send_challenge(session);
+        } else {
+            ChatServer::join_room(context, session);
+        }
+    }
 
-        let mut sources = Sources::new();
-        sources.register(Source::Client, &stream, popol::interest::ALL);
-        let mut events = Events::new();
+    // Nothing fancy, just enough that a script blindly sending lines won't get in
+    fn send_challenge(session: &mut Session) {
+        let (a, b) = (OsRng.next_u32() % 10 + 1, OsRng.next_u32() % 10 + 1);
+        session.notice(format!(
+            "Before you join, what is {} + {}?  Reply with {}answer <number>",
+            a, b, session.prefix
+        ));
+        session.state = ConnectionState::Authenticated(Welcome::Challenge { answer: a + b });
+    }
 
-        while running.load(Ordering::SeqCst) {
-            // Wait for something to happen on our sources.
-            sources.wait(&mut events).unwrap();
+    fn join_room(context: &Arc<ServerContext>, session: &mut Session) {
+        // Subscribing before the join goes out means they see their own arrival.  There's only ever the one
+        // subscription, so nothing can reach them twice.
+        session.room_receiver = Some(context.fanout.lock().unwrap().subscribe());
+        session.state = ConnectionState::InRoom;
+        if !context.mark_in_room(session.id, &session.user, true) {
+            context.send_message(RoomMessage::joined(&session.user));
+        }
 
-            for (key, event) in events.iter() {
-                match key {
-                    Source::Client if event.readable => match stream.read(&mut buffer) {
-                        Ok(bytes_read) => {
-                            // Once again, a zero byte read is a disconnect
-                            if bytes_read == 0 {
-                                if user.len() > 0 {
-                                    message_sender
-                                        .lock()
-                                        .unwrap()
-                                        .send(format!("{} has left the room.", user))
-                                        .unwrap();
-                                }
-                                return;
-                            }
+        // The message of the day only goes to the person who just joined
+        if let Some(motd) = &context.config.motd {
+            session.send_notice(NoticeKind::Motd, motd);
+        }
+    }
 
-                            let message = String::from_utf8(buffer[..bytes_read].to_vec()).unwrap();
-                            let message = message.trim();
-
-                            // We handle a few special events here, and also require the client sets a name when
-                            // before we start sending messages
-                            if message.starts_with("/user") {
-                                user = String::from(message["/user".len()..].trim());
-                                message_sender
-                                    .lock()
-                                    .unwrap()
-                                    .send(format!("{} has joined the room.", user))
-                                    .unwrap();
-                            } else if user.len() > 0 {
-                                message_sender
-                                    .lock()
-                                    .unwrap()
-                                    .send(format!("{}: {}", user, message.to_string()))
-                                    .unwrap();
-                            }
-                        }
-                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                        Err(_) => return,
+    // /join [room].  There's only the one room, and they have to be in it to get here, so this never joins anything.
+    // It's still worth answering, since a client that sends it again after reconnecting shouldn't think it failed.
+    fn join(session: &mut Session, room: &str) {
+        if room.is_empty() || room == ROOM_NAME {
+            session.notice(format!("You're already in {}", ROOM_NAME));
+        } else {
+            session.error(format!(
+                "There's no room called {}, only {}",
+                room, ROOM_NAME
+            ));
+        }
+    }
+
+    // /who [room], just for them.  Everyone online is in the one room, so the room only has to be the right one.
+    // Anyone away is marked as such.  /who <name> is where one person is instead: online, away or (for a registered
+    // name) offline.
+    fn who(context: &ServerContext, session: &mut Session, target: &str) {
+        if !target.is_empty() && target != ROOM_NAME {
+            let presence = match context.away(target) {
+                Some(message) if message.is_empty() => format!("{} is away", target),
+                Some(message) => format!("{} is away: {}", target, message),
+                None if context.is_online(target) => format!("{} is online", target),
+                None if context.accounts.is_registered(target) => format!("{} is offline", target),
+                None => {
+                    session.error(format!(
+                        "There's nobody called {} online, and no room called that either, only {}",
+                        target, ROOM_NAME
+                    ));
+                    return;
+                }
+            };
+            session.notice(presence);
+            return;
+        }
+
+        let names: Vec<String> = context
+            .online()
+            .into_iter()
+            .map(|name| match context.away(&name) {
+                Some(message) if message.is_empty() => format!("{} (away)", name),
+                Some(message) => format!("{} (away: {})", name, message),
+                None => name,
+            })
+            .collect();
+        session.notice(format!(
+            "In {} ({}): {}",
+            ROOM_NAME,
+            names.len(),
+            names.join(", ")
+        ));
+    }
+
+    // /away [message] with the message, /back without.  It goes for every connection to the name, and the room hears
+    // about it like anyone coming or going.
+    fn set_away(context: &ServerContext, session: &mut Session, message: Option<&str>) {
+        let was_away = context.away(&session.user).is_some();
+        if message.is_none() && !was_away {
+            session.error("You're not away");
+            return;
+        }
+
+        for connection in context.connections.lock().unwrap().values_mut() {
+            if connection.user.eq_ignore_ascii_case(&session.user) {
+                connection.away = message.map(String::from);
+            }
+        }
+        let announcement = match message {
+            Some("") => format!("{} is away.", session.user),
+            Some(message) => format!("{} is away: {}", session.user, message),
+            None => format!("{} is back.", session.user),
+        };
+        info!(user = %session.user, away = message.is_some(), "Presence changed");
+        context.send_to_room(MessageKind::Presence, announcement);
+    }
+
+    // Everything about sending someone a file (see FileMessage).  We only pass the offer and the answers along, along
+    // with the chunks of anything that has to come through us, and check the chunks add up to what was offered.  Each
+    // of the sender's chunks is checked before it goes anywhere, so nobody can send more than the size limit.
+    fn file(context: &Arc<ServerContext>, session: &mut Session, line: &str) {
+        let message = match FileMessage::parse(line) {
+            Some(message) => message,
+            None => {
+                session.error("That isn't a file message we understand");
+                return;
+            }
+        };
+        let config = &context.config.files;
+        fn reply(session: &mut Session, message: FileMessage) {
+            session.batch.push_line(&message.to_line());
+        }
+
+        match message {
+            FileMessage::Offer { id, .. } if !config.enabled => reply(
+                session,
+                FileMessage::Cancel {
+                    id,
+                    reason: String::from("Files are turned off here"),
+                },
+            ),
+            FileMessage::Offer {
+                id,
+                peer,
+                size,
+                direct,
+                name,
+            } => {
+                let refusal = if id.len() > MAX_FILE_ID
+                    || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                {
+                    Some(String::from("That isn't a usable id"))
+                } else if size > config.max_bytes {
+                    Some(format!(
+                        "That's over the limit of {} bytes",
+                        config.max_bytes
+                    ))
+                } else if peer.eq_ignore_ascii_case(&session.user) {
+                    Some(String::from("You can't send yourself a file"))
+                } else if !context.transfers.lock().unwrap().offer(
+                    &id,
+                    session.id,
+                    &session.user,
+                    &peer,
+                    size,
+                ) {
+                    Some(String::from("That id is already in use"))
+                } else {
+                    None
+                };
+                if let Some(reason) = refusal {
+                    reply(session, FileMessage::Cancel { id, reason });
+                    return;
+                }
+
+                // The sender only knows the port it's listening on, and we know where they are, or at least where
+                // they seem to be from here
+                let offer = FileMessage::Offer {
+                    id: id.clone(),
+                    peer: session.user.clone(),
+                    size,
+                    direct: direct
+                        .and_then(|port| port.parse().ok())
+                        .map(|port| SocketAddr::new(session.address, port).to_string()),
+                    name,
+                }
+                .to_line();
+                let offered = context.send_control(
+                    |_, connection| {
+                        connection.user.eq_ignore_ascii_case(&peer)
+                            && connection.in_room
+                            && connection.files
                     },
-                    Source::Client if event.writable => match room_receiver.try_recv() {
-                        Ok(message) => {
-                            stream.write(message.as_bytes()).unwrap();
-                            stream.flush().unwrap();
+                    || Control::Line(offer.clone()),
+                );
+                if offered == 0 {
+                    context.transfers.lock().unwrap().remove(&id);
+                    let reason = if context.is_online(&peer) {
+                        format!("{}'s client can't take files", peer)
+                    } else {
+                        format!("{} isn't here", peer)
+                    };
+                    reply(session, FileMessage::Cancel { id, reason });
+                    return;
+                }
+                info!(to = %peer, size, "Offered a file");
+            }
+            FileMessage::Accept { id, .. } => {
+                let sender = match context.transfers.lock().unwrap().get_mut(&id) {
+                    Some(transfer)
+                        if transfer.receiver.is_none()
+                            && transfer.to.eq_ignore_ascii_case(&session.user) =>
+                    {
+                        transfer.receiver = Some(session.id);
+                        transfer.sender
+                    }
+                    _ => {
+                        reply(
+                            session,
+                            FileMessage::Cancel {
+                                id,
+                                reason: String::from("There's no such file waiting for you"),
+                            },
+                        );
+                        return;
+                    }
+                };
+                let chunk = Some(config.chunk_bytes);
+                context.send_line(sender, &FileMessage::Accept { id, chunk });
+            }
+            FileMessage::Chunk { id, offset, data } => {
+                let mut transfers = context.transfers.lock().unwrap();
+                let transfer = match transfers.get_mut(&id) {
+                    Some(transfer) if transfer.sender == session.id => transfer,
+                    // Chunks skip the rate limit, so we don't answer ones that aren't for anything
+                    _ => return,
+                };
+                let fits =
+                    offset == transfer.sent && transfer.size - transfer.sent >= data.len() as u64;
+                match transfer.receiver {
+                    Some(receiver) if fits => {
+                        transfer.sent += data.len() as u64;
+                        let line = String::from(line);
+                        context
+                            .send_control(|id, _| id == receiver, || Control::Line(line.clone()));
+                    }
+                    receiver => {
+                        transfers.remove(&id);
+                        drop(transfers);
+                        warn!("File chunks didn't add up");
+                        let reason = String::from("The chunks didn't add up");
+                        if let Some(receiver) = receiver {
+                            let cancel = FileMessage::Cancel {
+                                id: id.clone(),
+                                reason: reason.clone(),
+                            };
+                            context.send_line(receiver, &cancel);
+                        }
+                        reply(session, FileMessage::Cancel { id, reason });
+                    }
+                }
+            }
+            FileMessage::Done { ref id } | FileMessage::Cancel { ref id, .. } => {
+                let transfer = {
+                    let mut transfers = context.transfers.lock().unwrap();
+                    match transfers.get_mut(id) {
+                        Some(transfer)
+                            if transfer.sender == session.id
+                                || transfer.to.eq_ignore_ascii_case(&session.user) =>
+                        {
+                            transfers.remove(id)
                         }
-                        Err(_) => {
-                            thread::sleep(Duration::from_millis(10));
+                        _ => None,
+                    }
+                };
+                let transfer = match transfer {
+                    Some(transfer) => transfer,
+                    None => return,
+                };
+
+                let message = match message {
+                    FileMessage::Cancel { id, reason } if reason.is_empty() => {
+                        FileMessage::Cancel {
+                            id,
+                            reason: format!("{} called it off", session.user),
                         }
+                    }
+                    FileMessage::Cancel { id, reason } => FileMessage::Cancel {
+                        id,
+                        reason: format!("{} called it off: {}", session.user, reason),
                     },
-                    _ => {}
+                    message => {
+                        info!(from = %transfer.from, to = %transfer.to, size = transfer.size, "Sent a file");
+                        message
+                    }
+                };
+                let line = message.to_line();
+                if transfer.sender != session.id {
+                    context.send_line(transfer.sender, &message);
+                } else if let Some(receiver) = transfer.receiver {
+                    context.send_line(receiver, &message);
+                } else {
+                    // Nobody's taken it yet, so everywhere it was offered has to hear it's off
+                    context.send_control(
+                        |_, connection| {
+                            connection.user.eq_ignore_ascii_case(&transfer.to) && connection.files
+                        },
+                        || Control::Line(line.clone()),
+                    );
+                }
+            }
+            // Anything newer than us
+            _ => {}
+        }
+    }
+
+    // Ops can always ping the whole room.  Anyone else might not be allowed to at all, or has to wait out a cooldown
+    // between pings.  Either way the message still goes out, it just doesn't ping anyone, and they're told so.
+    fn may_mass_mention(context: &ServerContext, session: &mut Session) -> bool {
+        if context.is_op(session) {
+            return true;
+        }
+
+        let config = &context.config.mentions;
+        if config.mass_mentions_ops_only {
+            session.error("Only operators can ping everyone, so nobody was pinged");
+            return false;
+        }
+
+        let cooldown = Duration::from_secs(config.mass_mentions_cooldown_secs);
+        let mut mass_mentioned = context.mass_mentioned.lock().unwrap();
+        let key = session.user.to_lowercase();
+        if let Some(since) = mass_mentioned.get(&key).map(Instant::elapsed) {
+            if since < cooldown {
+                session.error(format!(
+                    "You can ping everyone again in {} second(s), nobody was pinged this time",
+                    (cooldown - since).as_secs() + 1
+                ));
+                return false;
+            }
+        }
+        mass_mentioned.insert(key, Instant::now());
+
+        true
+    }
+
+    // /msg <name> <message>, for their eyes only.  It never goes near the room, so plugins, history and linked servers
+    // don't see it, but a mute still holds.  Their other connections get a copy of what they sent too (see Direct).
+    fn direct(context: &ServerContext, session: &mut Session, arguments: &str) {
+        let (name, body) = arguments.split_once(' ').unwrap_or((arguments, ""));
+        let body = body.trim();
+        if name.is_empty() || body.is_empty() {
+            session.error(format!("Usage: {}msg <name> <message>", session.prefix));
+            return;
+        }
+        if name.eq_ignore_ascii_case(&session.user) {
+            session.error("You can't message yourself");
+            return;
+        }
+        if let Some(left) = context.muted_for(&session.user) {
+            session.error(format!(
+                "You're muted for {} more minute(s), nobody saw that",
+                left.as_secs().div_ceil(60)
+            ));
+            return;
+        }
+
+        let received = Direct {
+            peer: session.user.clone(),
+            sent: false,
+            body: String::from(body),
+        };
+        let delivered = context.send_control(
+            |_, connection| connection.user.eq_ignore_ascii_case(name) && connection.in_room,
+            || Control::Direct(received.clone()),
+        );
+        if delivered == 0 {
+            session.error(format!("Nobody called {} is here", name));
+            return;
+        }
+        debug!(to = name, "Direct message");
+
+        let sent = Direct {
+            peer: String::from(name),
+            sent: true,
+            body: String::from(body),
+        };
+        session.send_direct(&sent);
+        let user = session.user.clone();
+        context.send_control(
+            |id, connection| id != session.id && connection.user.eq_ignore_ascii_case(&user),
+            || Control::Direct(sent.clone()),
+        );
+    }
+
+    // /mentions on its own shows their settings, and then block <name>, unblock <name> or room on|off [room]
+    fn mentions(session: &mut Session, arguments: &str) {
+        let arguments: Vec<&str> = arguments.split_whitespace().collect();
+        match arguments[..] {
+            [] => {
+                for line in session.mentions.describe() {
+                    session.notice(line);
+                }
+            }
+            ["block", name] => {
+                session.mentions.block_user(name);
+                session.notice(format!("Ignoring mentions from {}", name));
+            }
+            ["unblock", name] => match session.mentions.unblock_user(name) {
+                true => session.notice(format!("Mentions from {} are back on", name)),
+                false => session.error(format!("You weren't ignoring mentions from {}", name)),
+            },
+            ["room", setting] | ["room", setting, _] if setting == "on" || setting == "off" => {
+                let room = arguments.get(2).copied().unwrap_or(ROOM_NAME);
+                session.mentions.set_room(room, setting == "on");
+                session.notice(format!("Mentions in {} are {}", room, setting));
+            }
+            _ => session.error(format!(
+                "Usage: {}mentions [block <name> | unblock <name> | room on|off [room]]",
+                session.prefix
+            )),
+        }
+    }
+
+    // Whether they quit or just went away, the room hears about it once and nothing more gets handled
+    fn close(context: &Arc<ServerContext>, session: &mut Session) {
+        let left = RoomMessage::left(&session.user);
+        ChatServer::leave(context, session, left);
+    }
+
+    // Like close, but with a say in what the room is told, which only happens if they were in it
+    fn leave(context: &Arc<ServerContext>, session: &mut Session, farewell: RoomMessage) {
+        let still_here = context.mark_in_room(session.id, &session.user, false);
+        if session.state == ConnectionState::InRoom && !still_here {
+            context.send_message(farewell);
+        }
+        // Dropping the subscription is all it takes for the room to stop sending them anything
+        session.room_receiver = None;
+        session.state = ConnectionState::Closing;
+    }
+
+    // For kicks, bans and flooding.  The room gets a moderation notice in place of the usual "has left", and they get
+    // the reason as their last line before we hang up.
+    fn throw_out(
+        context: &Arc<ServerContext>,
+        session: &mut Session,
+        notice: String,
+        reason: String,
+    ) -> Outcome {
+        ChatServer::leave(
+            context,
+            session,
+            RoomMessage::removed(&session.user, notice),
+        );
+        session.batch.push_line(&Kicked { reason }.to_line());
+        Outcome::Goodbye(session.take_outbound())
+    }
+
+    // The server is going down.  Whatever the room already said still goes out, then a last notice so they know why,
+    // then we hang up the same way as for a kick.  The room is going too, so it isn't told they left.
+    fn shut_down(session: &mut Session) -> Vec<u8> {
+        if let Some(room_receiver) = session.room_receiver.take() {
+            while let Some(message) = room_receiver.try_recv() {
+                session.queue(&message);
+            }
+        }
+        session.send_notice(NoticeKind::Info, "The server is shutting down");
+        session.state = ConnectionState::Closing;
+        session.take_outbound()
+    }
+
+    // Writes as much of the outbound queue as the socket will take right now.  Whatever it won't take stays queued until
+    // poll says it's writable again.
+    fn write_outbound(stream: &mut Stream, session: &mut Session) -> io::Result<()> {
+        while !session.outbound.is_empty() {
+            let (front, _) = session.outbound.as_slices();
+            match stream.write(front) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    session.outbound.drain(..written);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+
+        match stream.flush() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    // Says goodbye properly to someone we're throwing out.  Whatever is waiting for them goes out, then our side of the
+    // connection is shut so they see the end right after it, and anything they were still sending is read and thrown
+    // away.  Just dropping a socket with unread data in it makes the OS reset the connection, and a reset can lose the
+    // very message we were trying to deliver.
+    fn drain(stream: &mut Stream, goodbye: &[u8]) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        stream.set_nonblocking(false).ok();
+        stream.tcp().set_write_timeout(Some(DRAIN_TIMEOUT)).ok();
+        stream.tcp().set_read_timeout(Some(DRAIN_TIMEOUT)).ok();
+
+        let sent = stream
+            .write_all(goodbye)
+            .and_then(|_| stream.flush())
+            .and_then(|_| stream.shutdown());
+        if let Err(err) = sent {
+            debug!("Unable to say goodbye: {}", err);
+            return;
+        }
+
+        // They should hang up as soon as they've read it, but we won't wait on them forever
+        let mut buffer = [0; 1024];
+        while Instant::now() < deadline {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    // /kick <name> [reason]
+    fn kick(context: &ServerContext, session: &mut Session, arguments: &str) {
+        let (name, reason) = arguments.split_once(' ').unwrap_or((arguments, ""));
+        let reason = reason.trim();
+        if name.is_empty() {
+            session.error(format!("Usage: {}kick <name> [reason]", session.prefix));
+            return;
+        }
+        if name.eq_ignore_ascii_case(&session.user) {
+            session.error("You can't kick yourself");
+            return;
+        }
+
+        // Their own handler tells the room, once they're actually gone
+        match context.kick(name, &session.user, reason) {
+            0 => session.error(format!("Nobody called {} is here", name)),
+            _ => session.notice(format!("Kicked {}", name)),
+        }
+    }
+
+    // /bot create <name> <room> | token <name> | delete <name> | list, for accounts that let a bot (a webhook, a CI job)
+    // post to a room and do nothing else.  The bot logs in with /login <name> <token>.  Like /recover, the op passes
+    // the token on however they like, and it's only ever shown the once.
+    fn bot(context: &Arc<ServerContext>, session: &mut Session, arguments: &str) {
+        let arguments: Vec<&str> = arguments.split_whitespace().collect();
+        match arguments[..] {
+            ["create", name, room] => {
+                // There's only the one room to bind a bot to for now
+                if room != ROOM_NAME {
+                    session.error(format!(
+                        "There's no room called {}, only {}",
+                        room, ROOM_NAME
+                    ));
+                    return;
+                }
+                if let Err(err) = context.names.check(name) {
+                    session.error(err.to_string());
+                    return;
+                }
+                match context.accounts.create_bot(name, room) {
+                    Ok(token) => {
+                        info!(bot = name, room, by = %session.user, "Bot created");
+                        context.save_accounts();
+                        session.notice(format!(
+                            "Created {}, which can post to {}.  It logs in with {}login {} {}",
+                            name, room, session.prefix, name, token
+                        ));
+                    }
+                    Err(err) => session.error(format!("Unable to create the bot: {}", err)),
+                }
+            }
+            ["token", name] => match context.accounts.reissue_bot_token(name) {
+                Ok(token) => {
+                    info!(bot = name, by = %session.user, "Bot token reissued");
+                    context.save_accounts();
+                    session.notice(format!(
+                        "New token for {}, the old one no longer works: {}",
+                        name, token
+                    ));
+                }
+                Err(err) => session.error(format!("Unable to make a new token: {}", err)),
+            },
+            // It's gone from the room straight away too, rather than once it next tries to log in
+            ["delete", name] => match context.accounts.delete_bot(name) {
+                Ok(()) => {
+                    info!(bot = name, by = %session.user, "Bot deleted");
+                    context.save_accounts();
+                    context.kick(name, &session.user, "bot account deleted");
+                    session.notice(format!("Deleted {}", name));
+                }
+                Err(err) => session.error(format!("Unable to delete the bot: {}", err)),
+            },
+            ["list"] => {
+                let bots = context.accounts.bots();
+                if bots.is_empty() {
+                    session.notice("There are no bots");
+                }
+                for (name, room) in bots {
+                    session.notice(format!("{} posts to {}", name, room));
+                }
+            }
+            _ => session.error(format!(
+                "Usage: {0}bot create <name> <room> | {0}bot token <name> | {0}bot delete <name> | {0}bot list",
+                session.prefix
+            )),
+        }
+    }
+
+    // /mute <name> <minutes>.  Zero minutes lifts a mute early.
+    fn mute(context: &ServerContext, session: &mut Session, arguments: &str) {
+        let mut arguments = arguments.split_whitespace();
+        let (name, minutes) = match (arguments.next(), arguments.next().map(str::parse::<u64>)) {
+            (Some(name), Some(Ok(minutes))) => (name, minutes),
+            _ => {
+                session.error(format!("Usage: {}mute <name> <minutes>", session.prefix));
+                return;
+            }
+        };
+        if name.eq_ignore_ascii_case(&session.user) {
+            session.error("You can't mute yourself");
+            return;
+        }
+
+        let mut mutes = context.mutes.lock().unwrap();
+        if minutes == 0 {
+            match mutes.remove(&name.to_lowercase()) {
+                Some(_) => {
+                    info!(user = name, "Unmuted");
+                    session.notice(format!("Unmuted {}", name));
+                    context.notify(
+                        name,
+                        NoticeKind::Moderation,
+                        &format!("{} has unmuted you", session.user),
+                    );
+                }
+                None => session.error(format!("{} isn't muted", name)),
+            }
+            return;
+        }
+
+        let until = Instant::now() + Duration::from_secs(minutes * 60);
+        mutes.insert(name.to_lowercase(), until);
+        info!(user = name, minutes, "Muted");
+        session.notice(format!("Muted {} for {} minute(s)", name, minutes));
+        context.notify(
+            name,
+            NoticeKind::Moderation,
+            &format!(
+                "You have been muted by {} for {} minute(s)",
+                session.user, minutes
+            ),
+        );
+    }
+
+    // /ban <name or address> [reason].  A name that's connected gets their address banned along with it, and anyone
+    // caught by the ban is thrown out straight away.
+    fn ban(context: &Arc<ServerContext>, session: &mut Session, arguments: &str) {
+        let (target, reason) = arguments.split_once(' ').unwrap_or((arguments, ""));
+        let reason = reason.trim();
+        if target.is_empty() {
+            session.error(format!(
+                "Usage: {}ban <name or address> [reason]",
+                session.prefix
+            ));
+            return;
+        }
+        if target.eq_ignore_ascii_case(&session.user) {
+            session.error("You can't ban yourself");
+            return;
+        }
+
+        let (own_address, target_address) = {
+            let connections = context.connections.lock().unwrap();
+            let own_address = connections.get(&session.id).map(|own| own.address);
+            let target_address = connections
+                .values()
+                .find(|connection| connection.user.eq_ignore_ascii_case(target))
+                .map(|connection| connection.address);
+            (own_address, target_address)
+        };
+
+        let ban = match target.parse::<IpAddr>() {
+            Ok(ip) if Some(ip) == own_address => {
+                session.error("You can't ban your own address");
+                return;
+            }
+            Ok(ip) => Ban {
+                ip: Some(ip),
+                nick: None,
+                by: session.user.clone(),
+                reason: String::from(reason),
+            },
+            // If they're coming from the same address as us (behind the same NAT, say), the name will have to do
+            Err(_) => Ban {
+                ip: target_address.filter(|&ip| Some(ip) != own_address),
+                nick: Some(String::from(target)),
+                by: session.user.clone(),
+                reason: String::from(reason),
+            },
+        };
+
+        info!(ban = %ban, "Banned");
+        // Same as a kick, anyone caught tells the room themselves on the way out
+        context.send_control(
+            |id, connection| {
+                id != session.id
+                    && (Some(connection.address) == ban.ip
+                        || ban
+                            .nick
+                            .as_ref()
+                            .is_some_and(|nick| connection.user.eq_ignore_ascii_case(nick)))
+            },
+            || Control::Ban {
+                by: session.user.clone(),
+                reason: String::from(reason),
+            },
+        );
+        context.bans.add(ban);
+        context.save_bans();
+
+        session.notice(format!("Banned {}", target));
+    }
+
+    // /unban <name or address>
+    fn unban(context: &Arc<ServerContext>, session: &mut Session, target: &str) {
+        if target.is_empty() {
+            session.error(format!("Usage: {}unban <name or address>", session.prefix));
+            return;
+        }
+
+        match context.bans.remove(target) {
+            0 => session.error(format!("{} isn't banned", target)),
+            _ => {
+                info!(target, "Unbanned");
+                context.save_bans();
+                session.notice(format!("Unbanned {}", target));
+            }
+        }
+    }
+
+    fn ban_list(context: &ServerContext, session: &mut Session) {
+        let bans = context.bans.list();
+        if bans.is_empty() {
+            session.notice("Nobody is banned");
+        }
+        for ban in bans {
+            session.notice(ban.to_string());
+        }
+    }
+
+    // Room commands are for operators only, at least for now
+    fn handle_room_command(context: &ServerContext, session: &mut Session, command: &str) {
+        let (command, argument) = command.split_once(' ').unwrap_or((command, ""));
+        let argument = argument.trim();
+
+        match command {
+            "pin" if !argument.is_empty() => {
+                if context.storage.is_none() {
+                    session.error("Pins are kept in the history, which is turned off");
+                    return;
+                }
+                context.send_message(RoomMessage::pin(&session.user, argument));
+            }
+            "stats" => {
+                let report = context
+                    .stats
+                    .lock()
+                    .unwrap()
+                    .report(context.config.stats.top_talkers);
+
+                session.notice(format!(
+                    "Stats for {} over the last {} minutes:",
+                    ROOM_NAME,
+                    report.window.as_secs() / 60
+                ));
+                session.notice(format!(
+                    "{:.1} messages/hour from {} active speakers",
+                    report.messages_per_hour, report.active_speakers
+                ));
+                session.notice(format!(
+                    "{} members now, {} at peak",
+                    report.members, report.peak_members
+                ));
+
+                if !report.top_talkers.is_empty() {
+                    let talkers: Vec<String> = report
+                        .top_talkers
+                        .iter()
+                        .map(|(name, count)| format!("{} ({})", name, count))
+                        .collect();
+                    session.notice(format!("Top talkers: {}", talkers.join(", ")));
+                }
+            }
+            "mass-mentions" if argument == "on" || argument == "off" => {
+                context
+                    .mass_mentions
+                    .store(argument == "on", Ordering::Relaxed);
+                info!(user = %session.user, argument, "Mass mentions switched");
+                session.notice(format!("@all and @here are {}", argument));
+            }
+            "access" if argument.is_empty() => {
+                let access = context.access.lock().unwrap();
+                for (kind, ranges) in [("Allowed", &access.allow), ("Denied", &access.deny)] {
+                    let ranges: Vec<String> =
+                        ranges.iter().map(|range| range.to_string()).collect();
+                    if ranges.is_empty() {
+                        session.notice(format!("{}: nothing listed", kind));
+                    } else {
+                        session.notice(format!("{}: {}", kind, ranges.join(", ")));
+                    }
                 }
             }
+            // Only [access] is taken from the file, everything else stays as the server started with it
+            "access" if argument == "reload" => {
+                let path = match &context.config.path {
+                    Some(path) => path,
+                    None => {
+                        session.error("There's no config file to reload from");
+                        return;
+                    }
+                };
+                match ServerConfig::load(path) {
+                    Ok(config) => {
+                        *context.access.lock().unwrap() = config.access;
+                        info!(user = %session.user, path = %path.display(), "Access list reloaded");
+                        session.notice(format!("Access list reloaded from {}", path.display()));
+                    }
+                    Err(err) => {
+                        warn!(path = %path.display(), "Unable to reload access list: {}", err);
+                        // Parse errors run over several lines to point at the mistake, so that part is only logged
+                        let err = err.to_string();
+                        let summary = err.lines().next().unwrap_or_default();
+                        session.error(format!("Access list unchanged, {}", summary));
+                    }
+                }
+            }
+            "limits" => ChatServer::limits(context, session),
+            "freeze" => ChatServer::freeze(context, session, argument),
+            _ => session.error(format!(
+                concat!(
+                    "Usage: {}room stats | limits | pin <text> | freeze <minutes> [reason] | ",
+                    "mass-mentions on|off | access [reload]"
+                ),
+                session.prefix
+            )),
+        }
+    }
+
+    // /room freeze <minutes> [reason], so nobody can say anything while things calm down or an incident is dealt with.
+    // Zero minutes lifts it early, and freezing a frozen room starts it over with the new time and reason.
+    fn freeze(context: &ServerContext, session: &mut Session, arguments: &str) {
+        let (minutes, reason) = arguments.split_once(' ').unwrap_or((arguments, ""));
+        let minutes = match minutes.parse::<u64>() {
+            Ok(minutes) => minutes,
+            Err(_) => {
+                session.error(format!(
+                    "Usage: {}room freeze <minutes> [reason]",
+                    session.prefix
+                ));
+                return;
+            }
+        };
+
+        let mut freeze = context.freeze.lock().unwrap();
+        if minutes == 0 {
+            match freeze.take() {
+                Some(_) => {
+                    info!(user = %session.user, "Room thawed");
+                    context.send_to_room(
+                        MessageKind::Notice(NoticeKind::Moderation),
+                        format!("{} has opened the room again.", session.user),
+                    );
+                }
+                None => session.error("The room isn't frozen"),
+            }
+            return;
+        }
+
+        let reason = match reason.trim() {
+            "" => String::from("no reason given"),
+            reason => String::from(reason),
+        };
+        *freeze = Some(Freeze {
+            until: Instant::now() + Duration::from_secs(minutes * 60),
+            reason: reason.clone(),
+        });
+        info!(user = %session.user, minutes, reason = %reason, "Room frozen");
+        context.send_to_room(
+            MessageKind::Notice(NoticeKind::Moderation),
+            format!(
+                "{} has frozen the room for {} minute(s): {}",
+                session.user, minutes, reason
+            ),
+        );
+    }
+
+    // Everything we know about how the server is doing, for SIGUSR1, as a handful of log lines an operator can grep for
+    // "Snapshot".  It's the same whatever the log format, so the json format gets the numbers as fields.
+    fn log_snapshot(context: &ServerContext) {
+        let metrics = &context.metrics;
+        let connections = context.connections.lock().unwrap();
+        let in_room = connections.values().filter(|c| c.in_room).count();
+        let logged_in = connections.values().filter(|c| c.logged_in).count();
+        info!(
+            connected = metrics.connected_clients(),
+            in_room,
+            logged_in,
+            since_start = metrics.connections_total(),
+            "Snapshot: connections"
+        );
+        let now = SystemTime::now();
+        for (id, connection) in connections.iter() {
+            info!(
+                id,
+                user = %connection.user,
+                address = %connection.address,
+                client = connection.client.as_deref().unwrap_or("-"),
+                connected_secs = now
+                    .duration_since(connection.connected)
+                    .map_or(0, |since| since.as_secs()),
+                in_room = connection.in_room,
+                "Snapshot: connection"
+            );
+        }
+        drop(connections);
+
+        info!(
+            room = ROOM_NAME,
+            queued = context.fanout.lock().unwrap().deepest(),
+            queue_size = context.config.broadcast.queue_size,
+            muted = context.mutes.lock().unwrap().len(),
+            received = metrics.messages_received(),
+            broadcast = metrics.messages_broadcast(),
+            degraded = context.overload.is_degraded(),
+            "Snapshot: room"
+        );
+
+        let pool = metrics.pool();
+        info!(
+            workers = pool.worker_count(),
+            busy = pool.active_jobs(),
+            queued = pool.queued_jobs(),
+            queue_size = context.config.pool_queue_size,
+            completed = metrics.jobs_completed(),
+            panicked = metrics.jobs_panicked(),
+            io_panicked = metrics.io_jobs_panicked(),
+            "Snapshot: pool"
+        );
+
+        // What the kernel says we're using, which only Linux tells us this cheaply
+        match fs::read_to_string("/proc/self/status") {
+            Ok(status) => {
+                let field = |name: &str| {
+                    status
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map_or(String::from("-"), |value| value.trim().to_string())
+                };
+                info!(
+                    resident = %field("VmRSS:"),
+                    peak = %field("VmHWM:"),
+                    threads = %field("Threads:"),
+                    "Snapshot: memory"
+                );
+            }
+            Err(_) => info!("Snapshot: memory isn't something we can see on this platform"),
+        }
+    }
+
+    // /room limits, every limit in the config next to how close we are to it right now, or how often it's been hit
+    // since we started, so there's something to go on when tuning them
+    fn limits(context: &ServerContext, session: &mut Session) {
+        let config = &context.config;
+        let percent = |used: usize, limit: usize| used * 100 / limit.max(1);
+
+        // The reactor has its own limit, the worker per client server is held to max_clients
+        let max_clients = if config.reactor.threads > 0 {
+            config.reactor.max_clients
+        } else {
+            config.max_clients
+        };
+        let connected = context.metrics.connected_clients() as usize;
+        session.notice(format!(
+            "Clients: {} of {} ({}%)",
+            connected,
+            max_clients,
+            percent(connected, max_clients)
+        ));
+
+        let mut per_address: HashMap<IpAddr, usize> = HashMap::new();
+        for connection in context.connections.lock().unwrap().values() {
+            *per_address.entry(connection.address).or_default() += 1;
+        }
+        if let Some((address, count)) = per_address.into_iter().max_by_key(|(_, count)| *count) {
+            session.notice(format!(
+                "Per address: at most {} from {}, which has no limit of its own",
+                count, address
+            ));
+        }
+
+        let pool = context.metrics.pool();
+        let workers = match config.pool_scaling.max_size {
+            0 => format!("{}", config.pool_size),
+            max_size => format!("{} (up to {})", pool.worker_count(), max_size),
+        };
+        session.notice(format!(
+            "Workers: {} busy of {}, {} of {} jobs queued ({}%)",
+            pool.active_jobs(),
+            workers,
+            pool.queued_jobs(),
+            config.pool_queue_size,
+            percent(pool.queued_jobs(), config.pool_queue_size)
+        ));
+        session.notice(format!(
+            "Overload: past {} queued jobs or a {}ms broadcast, {} now",
+            config.overload.max_queue_depth,
+            config.overload.max_broadcast_latency_ms,
+            if context.overload.is_degraded() {
+                "degraded"
+            } else {
+                "keeping up"
+            }
+        ));
+
+        let deepest = context.fanout.lock().unwrap().deepest();
+        session.notice(format!(
+            "Room queue: {} of {} messages waiting for the slowest client ({}%), then {}",
+            deepest,
+            config.broadcast.queue_size,
+            percent(deepest, config.broadcast.queue_size),
+            match config.broadcast.slow_clients {
+                Overflow::DropOldest => "they miss the oldest",
+                Overflow::Disconnect => "they're disconnected",
+            }
+        ));
+
+        session.notice(format!(
+            "Rate limit: {} messages/second, bursts of {}, {} dropped since we started",
+            config.rate_limit.messages_per_second,
+            config.rate_limit.burst,
+            context.metrics.lines_throttled()
+        ));
+        session.notice(format!(
+            "Message size: {} bytes, {} too long since we started",
+            config.max_message_bytes,
+            context.metrics.lines_too_long()
+        ));
+
+        if config.auth_failures.max_failures > 0 {
+            let (addresses, names) = context.lockouts.locked();
+            session.notice(format!(
+                "Lockouts: {} failures in {}s, {} addresses and {} names locked out now",
+                config.auth_failures.max_failures,
+                config.auth_failures.window_secs,
+                addresses,
+                names
+            ));
         }
     }
 }
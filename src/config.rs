@@ -0,0 +1,994 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::access::AccessList;
+use crate::auth_failures::AuthLogFormat;
+use crate::chat_server::ROOM_NAME;
+use crate::fanout::Overflow;
+use crate::permissions;
+use crate::permissions::Role;
+use crate::protocol;
+use crate::srv;
+
+// Where we look for a config file if the command line doesn't give us one
+pub const DEFAULT_CONFIG_PATH: &str = "chat_server.toml";
+pub const DEFAULT_CLIENT_CONFIG_PATH: &str = "chat_client.toml";
+
+// How much the server logs when RUST_LOG isn't set.  Deriving PartialOrd on an enum orders the variants by how they're
+// declared, so "level >= LogLevel::Info" works without matching on every case.
+#[derive(Deserialize, Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+        }
+    }
+}
+
+// Everything about the server that used to be hard-coded in ChatServer::run.  The serde default attribute means any
+// field missing from the file falls back to the value in our Default implementation below, so an empty file (or no
+// file at all) gives you the same server we've always had.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Where we were loaded from, so /room access reload can read the file again.  Not something you set in the file.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    pub bind_address: String,
+    pub bind: BindConfig,
+    pub pool_size: usize,
+    // How many jobs can wait for a worker before handing out more has to wait, or is turned away
+    pub pool_queue_size: usize,
+    // How long shutting down waits for jobs still running before leaving them behind
+    pub shutdown_timeout_secs: u64,
+    pub max_clients: usize,
+    pub motd: Option<String>,
+    // What commands start with for clients that don't ask for something else in their handshake
+    pub command_prefix: char,
+    // The longest line a client can send, in bytes.  Anything longer is dropped and the sender told why.
+    pub max_message_bytes: usize,
+    pub log_level: LogLevel,
+    pub overload: OverloadConfig,
+    pub tls: Option<TlsConfig>,
+    pub batching: BatchingConfig,
+    pub async_server: AsyncServerConfig,
+    pub pool_scaling: PoolScalingConfig,
+    pub reactor: ReactorConfig,
+    pub broadcast: BroadcastConfig,
+    pub rate_limit: RateLimitConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub accounts_path: PathBuf,
+    // How long a password recovery token from /recover works for
+    pub recovery_token_secs: u64,
+    // Where /ban keeps its list, which is checked for every new connection
+    pub banlist_path: PathBuf,
+    pub auth_failures: AuthFailuresConfig,
+    // Address ranges allowed to connect at all, checked before the ban list
+    pub access: AccessList,
+    pub history: HistoryConfig,
+    pub stats: StatsConfig,
+    pub digest: DigestConfig,
+    pub welcome: WelcomeConfig,
+    pub names: NamesConfig,
+    pub mentions: MentionsConfig,
+    pub metrics: MetricsConfig,
+    pub websocket: WebSocketConfig,
+    pub web: WebConfig,
+    pub api: ApiConfig,
+    pub matrix: MatrixConfig,
+    pub link: LinkConfig,
+    pub redis: RedisConfig,
+    pub files: FilesConfig,
+    pub scripts: ScriptsConfig,
+    // Registered names allowed to use the operator commands, like /room stats.  They have to be logged in to count.
+    pub ops: Vec<String>,
+    // Which roles may use each command, for any that shouldn't keep their default (see permissions.rs)
+    pub permissions: BTreeMap<String, Vec<Role>>,
+}
+
+// Failed logins, password resets and two-factor codes.  Each one is written as a line to log_path, if there is one,
+// with the address it came from, so fail2ban or the like can block whoever's guessing (see auth_failures.rs).  Apart
+// from that, max_failures inside window_secs from one address or against one name locks that address or name out for
+// lockout_secs.  Zero max_failures turns the lockout off.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthFailuresConfig {
+    pub log_path: Option<PathBuf>,
+    pub format: AuthLogFormat,
+    pub max_failures: u32,
+    pub window_secs: u64,
+    pub lockout_secs: u64,
+}
+
+impl Default for AuthFailuresConfig {
+    fn default() -> AuthFailuresConfig {
+        AuthFailuresConfig {
+            log_path: None,
+            format: AuthLogFormat::Text,
+            max_failures: 5,
+            window_secs: 600,
+            lockout_secs: 900,
+        }
+    }
+}
+
+// Where to serve Prometheus metrics over plain HTTP, e.g. "127.0.0.1:9100".  Left out, there's no metrics listener.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub bind_address: Option<String>,
+}
+
+// A second listener, e.g. "0.0.0.0:8081", for clients in a browser, which can only speak WebSocket (see websocket.rs).
+// Left out, there isn't one.  It's the same server otherwise, TLS included if [tls] is set.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub bind_address: Option<String>,
+}
+
+// Where to serve the web chat page over plain HTTP, e.g. "0.0.0.0:8083" (see web.rs).  The page talks to the room over
+// the [websocket] listener, so it needs that as well.  Left out, there's no page.
+//
+// The rooms in public_rooms can also be read there by anyone, without joining, at /rooms/<room>: the last
+// public_messages of their chat from the last public_hours, refreshing every public_refresh_secs.  With public_names
+// off, who said what is left out.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebConfig {
+    pub bind_address: Option<String>,
+    pub public_rooms: Vec<String>,
+    pub public_messages: usize,
+    pub public_hours: u64,
+    pub public_refresh_secs: u64,
+    pub public_names: bool,
+}
+
+impl Default for WebConfig {
+    fn default() -> WebConfig {
+        WebConfig {
+            bind_address: None,
+            public_rooms: Vec::new(),
+            public_messages: 50,
+            public_hours: 24,
+            public_refresh_secs: 30,
+            public_names: true,
+        }
+    }
+}
+
+// Where to serve the HTTP API over plain HTTP, e.g. "127.0.0.1:8082" (see api.rs).  Left out, there's no API.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub bind_address: Option<String>,
+}
+
+// Bridging the room to a room on Matrix (see matrix.rs), as the account access_token belongs to, which has to have
+// joined room_id (e.g. "!abc:example.org") already.  Chat from Matrix is said in the room by name, which nobody else
+// can take.  Left without a homeserver, e.g. "https://matrix.example.org", there's no bridge.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatrixConfig {
+    pub homeserver: Option<String>,
+    pub access_token: String,
+    pub room_id: String,
+    pub name: String,
+}
+
+// Linking to other servers so they all share the room (see link.rs).  name is what this server is called on the others,
+// where people from here show up as name@<name>.  Other servers link to us on bind_address, and we link to each of
+// peers, and every server has to have the same secret.  With neither an address nor peers there's no linking.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkConfig {
+    pub name: String,
+    pub secret: String,
+    pub bind_address: Option<String>,
+    pub peers: Vec<String>,
+}
+
+impl LinkConfig {
+    pub fn enabled(&self) -> bool {
+        self.bind_address.is_some() || !self.peers.is_empty()
+    }
+}
+
+// Sharing the room with other processes through Redis pub/sub at address, e.g. "127.0.0.1:6379", on channel (see
+// redis.rs).  Every process sharing the room needs the same channel.  Left without an address, the room is ours alone.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    pub address: Option<String>,
+    pub password: Option<String>,
+    pub channel: String,
+}
+
+impl Default for RedisConfig {
+    fn default() -> RedisConfig {
+        RedisConfig {
+            address: None,
+            password: None,
+            channel: String::from("chat:lobby"),
+        }
+    }
+}
+
+// Files sent from one person to another with /send (see transfers.rs).  Nothing over max_bytes is offered at all.
+// Through the server they go in chunks of chunk_bytes, which as base64 have to fit in max_message_bytes, and an offer
+// nobody answers is called off after offer_timeout_secs.  Clients that manage to connect to each other directly don't
+// go through us, but the size limit still applies to what they can offer.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    pub enabled: bool,
+    pub max_bytes: u64,
+    pub chunk_bytes: usize,
+    pub offer_timeout_secs: u64,
+}
+
+impl Default for FilesConfig {
+    fn default() -> FilesConfig {
+        FilesConfig {
+            enabled: true,
+            max_bytes: 10 * 1024 * 1024,
+            chunk_bytes: 2048,
+            offer_timeout_secs: 300,
+        }
+    }
+}
+
+impl Default for MatrixConfig {
+    fn default() -> MatrixConfig {
+        MatrixConfig {
+            homeserver: None,
+            access_token: String::new(),
+            room_id: String::new(),
+            name: String::from("matrix"),
+        }
+    }
+}
+
+// Bots written in Rhai, one per .rhai file in dir (see scripts.rs).  Left out, there are no scripts.  Each time a
+// script is called it gets max_operations steps before it's stopped, so one stuck in a loop can't hold up the room.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptsConfig {
+    pub dir: Option<PathBuf>,
+    pub max_operations: u64,
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> ScriptsConfig {
+        ScriptsConfig {
+            dir: None,
+            max_operations: 100_000,
+        }
+    }
+}
+
+// What a new connection has to get through before it's let into the room, after picking a name.  With rules set they're
+// shown one line at a time and have to be agreed to with /accept, and with challenge on they have to answer a simple
+// sum with /answer, which is enough to keep out the dumbest of bots.
+//
+// The greeting is sent, one line at a time, just to the owner of a newly registered name the first time they're logged
+// in as it.  Empty means no greeting.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WelcomeConfig {
+    pub rules: Vec<String>,
+    pub challenge: bool,
+    pub greeting: Vec<String>,
+}
+
+// What makes a good name, checked on /user, /nick and /register (see names.rs).  Lengths are in characters.  Letters
+// and digits are always allowed, and symbols lists any other characters that are, or leave it out to allow anything
+// apart from spaces.  Reserved names can't be used at all, and neither can names starting with a reserved prefix.
+//
+// A guest can pick a registered name, but has registered_grace_secs to log in as it before they're renamed to a guest
+// name.  Zero turns registered names away outright instead.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamesConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub symbols: Option<String>,
+    pub reserved: Vec<String>,
+    pub reserved_prefixes: Vec<String>,
+    pub registered_grace_secs: u64,
+}
+
+impl Default for NamesConfig {
+    fn default() -> NamesConfig {
+        NamesConfig {
+            min_length: 1,
+            max_length: 32,
+            symbols: None,
+            reserved: Vec::new(),
+            reserved_prefixes: Vec::new(),
+            registered_grace_secs: 60,
+        }
+    }
+}
+
+// Whether @all and @here ping the whole room (see mentions.rs).  Ops can turn them off and on again with /room
+// mass-mentions, and once the room has more than mass_mentions_max_members people in it they're off regardless.  A max
+// of zero means no limit.
+//
+// Ops can always ping everyone.  With mass_mentions_ops_only nobody else can, and otherwise each person has to wait
+// mass_mentions_cooldown_secs between pings.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MentionsConfig {
+    pub mass_mentions: bool,
+    pub mass_mentions_max_members: usize,
+    pub mass_mentions_ops_only: bool,
+    pub mass_mentions_cooldown_secs: u64,
+}
+
+impl Default for MentionsConfig {
+    fn default() -> MentionsConfig {
+        MentionsConfig {
+            mass_mentions: true,
+            mass_mentions_max_members: 50,
+            mass_mentions_ops_only: false,
+            mass_mentions_cooldown_secs: 300,
+        }
+    }
+}
+
+// A summary of each day in the room, made just after midnight server time (see digest.rs).  It can be posted to the
+// room, written to a JSON file in export_dir, and POSTed to webhook_url, in any combination.  It's built from the
+// history, so history has to be on.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DigestConfig {
+    pub enabled: bool,
+    pub post_to_room: bool,
+    pub export_dir: Option<PathBuf>,
+    pub webhook_url: Option<String>,
+}
+
+impl Default for DigestConfig {
+    fn default() -> DigestConfig {
+        DigestConfig {
+            enabled: false,
+            post_to_room: true,
+            export_dir: None,
+            webhook_url: None,
+        }
+    }
+}
+
+// What /room stats looks at (see stats.rs)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    pub window_minutes: u64,
+    pub top_talkers: usize,
+}
+
+impl Default for StatsConfig {
+    fn default() -> StatsConfig {
+        StatsConfig {
+            window_minutes: 60,
+            top_talkers: 5,
+        }
+    }
+}
+
+// Every message that goes through the room is saved to a SQLite database at path (see storage.rs)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    pub path: PathBuf,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            enabled: true,
+            path: PathBuf::from("history.db"),
+        }
+    }
+}
+
+// How long outgoing messages may wait to be sent together with others, and how big that write can get (see batch.rs).
+// Clients that ask for bulk delivery in their handshake get the bulk limits, and nodelay clients skip batching.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchingConfig {
+    pub max_bytes: usize,
+    pub max_delay_ms: u64,
+    pub bulk_max_bytes: usize,
+    pub bulk_max_delay_ms: u64,
+}
+
+impl Default for BatchingConfig {
+    fn default() -> BatchingConfig {
+        BatchingConfig {
+            max_bytes: 8192,
+            max_delay_ms: 10,
+            bulk_max_bytes: 65536,
+            bulk_max_delay_ms: 250,
+        }
+    }
+}
+
+// How fast each connection may send us lines (see rate_limit.rs).  Lines over the limit are dropped, and after
+// disconnect_after of them in a row the connection is closed (zero turns that off).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub messages_per_second: f64,
+    pub burst: u32,
+    pub disconnect_after: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            messages_per_second: 5.0,
+            burst: 10,
+            disconnect_after: 20,
+        }
+    }
+}
+
+// How often we ping clients that asked for the heartbeat (see heartbeat.rs), and how many pings in a row they can leave
+// unanswered before we decide the connection is dead and drop it
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    pub interval_secs: u64,
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval_secs: 30,
+            max_missed: 3,
+        }
+    }
+}
+
+// Leave the [tls] section out entirely to serve plain TCP.  Both files are PEM encoded.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+// Settings that only matter to "server --async" (see async_server.rs).  Clients there don't need a thread each, so
+// max_clients isn't tied to pool_size like it is for the usual server.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AsyncServerConfig {
+    pub max_clients: usize,
+}
+
+impl Default for AsyncServerConfig {
+    fn default() -> AsyncServerConfig {
+        AsyncServerConfig { max_clients: 10000 }
+    }
+}
+
+// What to do when bind_address is taken (see listener.rs): try these other ports on the same host, in order, then go
+// round them all again up to retries more times, waiting retry_delay_ms before the first retry and twice as long before
+// each one after that.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BindConfig {
+    pub fallback_ports: Vec<u16>,
+    pub retries: u32,
+    pub retry_delay_ms: u64,
+}
+
+impl Default for BindConfig {
+    fn default() -> BindConfig {
+        BindConfig {
+            fallback_ports: Vec::new(),
+            retries: 0,
+            retry_delay_ms: 500,
+        }
+    }
+}
+
+// Lets the thread pool grow past pool_size when every worker is busy, up to max_size, and shrink back again once
+// workers have sat idle for idle_secs (see ThreadPool::autoscale).  A max_size of 0 keeps the pool at pool_size.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolScalingConfig {
+    pub max_size: usize,
+    pub idle_secs: u64,
+}
+
+impl Default for PoolScalingConfig {
+    fn default() -> PoolScalingConfig {
+        PoolScalingConfig {
+            max_size: 0,
+            idle_secs: 60,
+        }
+    }
+}
+
+// The reactor serves clients from a few event loop threads that each wait on many sockets at once, instead of giving
+// every client a worker of its own, which takes far less memory with a lot of people connected.  Threads left at 0
+// keeps a thread per client.  With it on, max_clients here takes over from the top level one, which is tied to
+// pool_size.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReactorConfig {
+    pub threads: usize,
+    pub max_clients: usize,
+}
+
+impl Default for ReactorConfig {
+    fn default() -> ReactorConfig {
+        ReactorConfig {
+            threads: 0,
+            max_clients: 10000,
+        }
+    }
+}
+
+// How many room messages each client can have waiting before they count as falling behind, and what's done about it
+// then (see fanout.rs)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BroadcastConfig {
+    pub queue_size: usize,
+    pub slow_clients: Overflow,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> BroadcastConfig {
+        BroadcastConfig {
+            queue_size: 256,
+            slow_clients: Overflow::DropOldest,
+        }
+    }
+}
+
+// Thresholds for when the server decides it's overloaded and starts shedding work (see overload.rs)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverloadConfig {
+    pub max_queue_depth: usize,
+    pub max_broadcast_latency_ms: u64,
+    pub check_interval_ms: u64,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> OverloadConfig {
+        OverloadConfig {
+            max_queue_depth: 20,
+            max_broadcast_latency_ms: 500,
+            check_interval_ms: 1000,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            path: None,
+            bind_address: String::from("127.0.0.1:8080"),
+            bind: BindConfig::default(),
+            pool_size: 10,
+            pool_queue_size: 1024,
+            shutdown_timeout_secs: 30,
+            // Every client ties up a worker for as long as it's connected, the room needs one as well, and we leave
+            // one free for timer jobs
+            max_clients: 8,
+            motd: None,
+            command_prefix: '/',
+            max_message_bytes: 4096,
+            log_level: LogLevel::Info,
+            overload: OverloadConfig::default(),
+            tls: None,
+            batching: BatchingConfig::default(),
+            async_server: AsyncServerConfig::default(),
+            pool_scaling: PoolScalingConfig::default(),
+            reactor: ReactorConfig::default(),
+            broadcast: BroadcastConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            accounts_path: PathBuf::from("accounts.toml"),
+            recovery_token_secs: 86400,
+            banlist_path: PathBuf::from("bans.toml"),
+            auth_failures: AuthFailuresConfig::default(),
+            access: AccessList::default(),
+            history: HistoryConfig::default(),
+            stats: StatsConfig::default(),
+            digest: DigestConfig::default(),
+            welcome: WelcomeConfig::default(),
+            names: NamesConfig::default(),
+            mentions: MentionsConfig::default(),
+            metrics: MetricsConfig::default(),
+            websocket: WebSocketConfig::default(),
+            web: WebConfig::default(),
+            api: ApiConfig::default(),
+            matrix: MatrixConfig::default(),
+            link: LinkConfig::default(),
+            redis: RedisConfig::default(),
+            files: FilesConfig::default(),
+            scripts: ScriptsConfig::default(),
+            ops: Vec::new(),
+            permissions: BTreeMap::new(),
+        }
+    }
+}
+
+// Our own error type, so the caller can tell "couldn't read the file" apart from "the file is wrong".  Each variant
+// wraps the error that caused it.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "unable to read config: {}", err),
+            ConfigError::Parse(err) => write!(f, "unable to parse config: {}", err),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<ServerConfig, ConfigError> {
+        let contents = fs::read_to_string(&path).map_err(ConfigError::Io)?;
+        let mut config: ServerConfig = toml::from_str(&contents).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        config.path = Some(path.as_ref().to_path_buf());
+
+        Ok(config)
+    }
+
+    // Only the default path is allowed to be missing.  If somebody points us at a specific file and it isn't there,
+    // that's almost certainly a typo and we'd rather say so than quietly run with the defaults.
+    pub fn load_or_default(path: impl AsRef<Path>) -> Result<ServerConfig, ConfigError> {
+        match ServerConfig::load(&path) {
+            Err(ConfigError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(ServerConfig {
+                    path: Some(path.as_ref().to_path_buf()),
+                    ..ServerConfig::default()
+                })
+            }
+            result => result,
+        }
+    }
+
+    // Loading does this already, it's for configs put together in code (see ChatServerBuilder)
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.pool_size < 3 {
+            return Err(ConfigError::Invalid(String::from(
+                "pool_size must be at least 3 (one for the room, one for timers, one for a client)",
+            )));
+        }
+
+        if self.pool_queue_size == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "pool_queue_size must be greater than 0",
+            )));
+        }
+
+        if self.pool_scaling.max_size > 0 && self.pool_scaling.max_size < self.pool_size {
+            return Err(ConfigError::Invalid(String::from(
+                "pool_scaling.max_size must be at least pool_size, or 0 to turn scaling off",
+            )));
+        }
+
+        // The room holds on to a worker for good, and we keep one spare for timer jobs (like the overload check) so
+        // they still get to run when every client slot is taken.  A pool that scales can grow to fit more.  The
+        // reactor has its own limit instead.
+        let largest_pool = self.pool_size.max(self.pool_scaling.max_size);
+        if self.reactor.threads == 0
+            && (self.max_clients == 0 || self.max_clients > largest_pool - 2)
+        {
+            return Err(ConfigError::Invalid(format!(
+                "max_clients must be between 1 and {} for a pool of up to {}",
+                largest_pool - 2,
+                largest_pool
+            )));
+        }
+
+        if self.overload.check_interval_ms == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "overload.check_interval_ms must be greater than 0",
+            )));
+        }
+
+        if self.web.bind_address.is_some() && self.websocket.bind_address.is_none() {
+            return Err(ConfigError::Invalid(String::from(
+                "web needs websocket.bind_address, which is how the page talks to the room",
+            )));
+        }
+
+        if !self.web.public_rooms.is_empty() {
+            if !self.history.enabled {
+                return Err(ConfigError::Invalid(String::from(
+                    "web.public_rooms needs [history] on, which is what they show",
+                )));
+            }
+            if let Some(room) = self.web.public_rooms.iter().find(|room| *room != ROOM_NAME) {
+                return Err(ConfigError::Invalid(format!(
+                    "web.public_rooms lists {}, but the only room is {}",
+                    room, ROOM_NAME
+                )));
+            }
+            if self.web.public_messages == 0 || self.web.public_hours == 0 {
+                return Err(ConfigError::Invalid(String::from(
+                    "web.public_messages and web.public_hours must be greater than 0",
+                )));
+            }
+            if self.web.public_refresh_secs < 5 {
+                return Err(ConfigError::Invalid(String::from(
+                    "web.public_refresh_secs must be at least 5",
+                )));
+            }
+        }
+
+        if let Some(homeserver) = &self.matrix.homeserver {
+            if !homeserver.starts_with("http://") && !homeserver.starts_with("https://") {
+                return Err(ConfigError::Invalid(String::from(
+                    "matrix.homeserver has to start with http:// or https://",
+                )));
+            }
+            if self.matrix.access_token.is_empty() {
+                return Err(ConfigError::Invalid(String::from(
+                    "matrix needs the access_token of the account it bridges as",
+                )));
+            }
+            if !self.matrix.room_id.starts_with('!') || !self.matrix.room_id.contains(':') {
+                return Err(ConfigError::Invalid(String::from(
+                    "matrix.room_id has to be a room id, like \"!abc:example.org\"",
+                )));
+            }
+        }
+
+        if self.link.enabled() {
+            let name = &self.link.name;
+            if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '@') {
+                return Err(ConfigError::Invalid(String::from(
+                    "link needs a name for this server, without spaces or an @",
+                )));
+            }
+            if self.link.secret.is_empty() || self.link.secret.contains(char::is_whitespace) {
+                return Err(ConfigError::Invalid(String::from(
+                    "link needs a secret, the same on every server, without spaces",
+                )));
+            }
+        }
+
+        if self.redis.address.is_some() && self.redis.channel.is_empty() {
+            return Err(ConfigError::Invalid(String::from(
+                "redis needs a channel to share the room on",
+            )));
+        }
+
+        if self.digest.enabled && !self.history.enabled {
+            return Err(ConfigError::Invalid(String::from(
+                "digest needs history to be enabled",
+            )));
+        }
+
+        if self.stats.window_minutes == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "stats.window_minutes must be greater than 0",
+            )));
+        }
+
+        if self.rate_limit.messages_per_second <= 0.0 || self.rate_limit.burst == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "rate_limit.messages_per_second and rate_limit.burst must be greater than 0",
+            )));
+        }
+
+        if let Some(command) = self
+            .permissions
+            .keys()
+            .find(|command| !permissions::COMMANDS.contains(&command.as_str()))
+        {
+            return Err(ConfigError::Invalid(format!(
+                "permissions can't be set for {:?}",
+                command
+            )));
+        }
+
+        // Rhai takes zero to mean no limit at all, which is the one thing we don't want
+        if self.scripts.max_operations == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scripts.max_operations must be greater than 0",
+            )));
+        }
+
+        if !protocol::is_valid_prefix(self.command_prefix) {
+            return Err(ConfigError::Invalid(format!(
+                "command_prefix can't be {:?}",
+                self.command_prefix
+            )));
+        }
+
+        if self.names.min_length == 0 || self.names.min_length > self.names.max_length {
+            return Err(ConfigError::Invalid(String::from(
+                "names.min_length must be at least 1 and no more than names.max_length",
+            )));
+        }
+
+        if self.recovery_token_secs == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "recovery_token_secs must be greater than 0",
+            )));
+        }
+
+        if self.async_server.max_clients == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "async_server.max_clients must be greater than 0",
+            )));
+        }
+
+        // Each event loop holds on to a worker for good, on top of the room and the spare for timers
+        if self.reactor.threads > 0 && self.pool_size < self.reactor.threads + 2 {
+            return Err(ConfigError::Invalid(format!(
+                "pool_size must be at least {} for {} reactor thread(s)",
+                self.reactor.threads + 2,
+                self.reactor.threads
+            )));
+        }
+        if self.reactor.max_clients == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "reactor.max_clients must be greater than 0",
+            )));
+        }
+
+        let auth_failures = &self.auth_failures;
+        if auth_failures.max_failures > 0
+            && (auth_failures.window_secs == 0 || auth_failures.lockout_secs == 0)
+        {
+            return Err(ConfigError::Invalid(String::from(
+                "auth_failures.window_secs and lockout_secs must be greater than 0 when max_failures is set",
+            )));
+        }
+
+        if self.broadcast.queue_size == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "broadcast.queue_size must be greater than 0",
+            )));
+        }
+
+        if self.max_message_bytes == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "max_message_bytes must be greater than 0",
+            )));
+        }
+
+        if self.files.enabled {
+            if self.files.max_bytes == 0
+                || self.files.chunk_bytes == 0
+                || self.files.offer_timeout_secs == 0
+            {
+                return Err(ConfigError::Invalid(String::from(
+                    "files.max_bytes, files.chunk_bytes and files.offer_timeout_secs must be greater than 0",
+                )));
+            }
+            if protocol::FileMessage::chunk_line_len(self.files.chunk_bytes)
+                > self.max_message_bytes
+            {
+                return Err(ConfigError::Invalid(String::from(
+                    "files.chunk_bytes is too big for max_message_bytes, once it's base64",
+                )));
+            }
+        }
+
+        if self.heartbeat.interval_secs == 0 || self.heartbeat.max_missed == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "heartbeat.interval_secs and heartbeat.max_missed must be greater than 0",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+// Same idea as ServerConfig, for `chat_server client`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    // Where to find the server, tried in order.  After the connection drops we start again from the next one.  An entry
+    // of "srv:<domain>" looks the servers up in the domain's _chat._tcp SRV record instead.
+    pub servers: Vec<String>,
+    // Bytes a second we're allowed to send, 0 for no limit
+    pub upload_limit: u32,
+    // Whether /exec may run commands on this machine.  Off unless it's asked for.
+    pub exec: bool,
+    // Where files people send us are saved
+    pub downloads: PathBuf,
+    // Whether files we send can go straight to whoever they're for, rather than only through the server
+    pub direct_files: bool,
+    // How many lines each pane of "client --tui" keeps in memory, before older ones are written out to a temp file
+    pub scrollback: usize,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            // Take note of the port, which gives you a good indicator of what tutorial I started with.
+            servers: vec![String::from("127.0.0.1:8080")],
+            upload_limit: 0,
+            exec: false,
+            downloads: PathBuf::from("downloads"),
+            direct_files: true,
+            scrollback: 5000,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<ClientConfig, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let config: ClientConfig = toml::from_str(&contents).map_err(ConfigError::Parse)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // Loading does this already, it's for configs put together in code (see ChatClientBuilder)
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.servers.is_empty() {
+            return Err(ConfigError::Invalid(String::from(
+                "servers needs at least one address",
+            )));
+        }
+        if self.servers.iter().any(|server| server == srv::PREFIX) {
+            return Err(ConfigError::Invalid(format!(
+                "{} needs a domain to look up, e.g. \"{}example.com\"",
+                srv::PREFIX,
+                srv::PREFIX
+            )));
+        }
+        if self.scrollback == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "scrollback must be greater than 0",
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Like the server, only the default path is allowed to be missing
+    pub fn load_or_default(path: impl AsRef<Path>) -> Result<ClientConfig, ConfigError> {
+        match ClientConfig::load(path) {
+            Err(ConfigError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(ClientConfig::default())
+            }
+            result => result,
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use chrono::DateTime;
+use chrono::Local;
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::storage::Storage;
+
+#[derive(Serialize)]
+pub struct Pin {
+    pub sender: String,
+    pub body: String,
+}
+
+// A summary of one room's day, built from the history.  Times are in the server's local time zone, since "midnight"
+// means the server's midnight.  It's Serialize so the file export and the webhook can both send it as JSON.
+#[derive(Serialize)]
+pub struct Digest {
+    pub room: String,
+    pub date: String,
+    pub message_count: u64,
+    pub active_users: Vec<String>,
+    pub first_message: Option<String>,
+    pub last_message: Option<String>,
+    pub pins: Vec<Pin>,
+}
+
+#[derive(Debug)]
+pub enum DigestError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Webhook(ureq::Error),
+}
+
+impl fmt::Display for DigestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DigestError::Io(err) => write!(f, "unable to write digest: {}", err),
+            DigestError::Json(err) => write!(f, "unable to encode digest: {}", err),
+            DigestError::Webhook(err) => write!(f, "unable to send digest: {}", err),
+        }
+    }
+}
+
+// Local midnight at the start of the day, as a SystemTime the history can be searched with.  On the odd day where
+// midnight is skipped or repeated for daylight saving, the earliest match is as good as any.
+pub fn start_of(day: NaiveDate) -> SystemTime {
+    day.and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+        .map(SystemTime::from)
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+fn time_of_day(time: SystemTime) -> String {
+    DateTime::<Local>::from(time).format("%H:%M:%S").to_string()
+}
+
+impl Digest {
+    pub fn build(storage: &Storage, room: &str, day: NaiveDate) -> rusqlite::Result<Digest> {
+        let next_day = day.succ_opt().unwrap_or(day);
+        let summary = storage.summarize(room, start_of(day), start_of(next_day))?;
+
+        Ok(Digest {
+            room: String::from(room),
+            date: day.format("%Y-%m-%d").to_string(),
+            message_count: summary.message_count,
+            active_users: summary.active_users,
+            first_message: summary.first_message.map(time_of_day),
+            last_message: summary.last_message.map(time_of_day),
+            pins: summary
+                .pins
+                .into_iter()
+                .map(|(sender, body)| Pin { sender, body })
+                .collect(),
+        })
+    }
+
+    // The digest as it's posted in the room, one notice per line
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("Daily digest for {} on {}", self.room, self.date)];
+
+        match (&self.first_message, &self.last_message) {
+            (Some(first), Some(last)) => lines.push(format!(
+                "{} messages from {} people, between {} and {}",
+                self.message_count,
+                self.active_users.len(),
+                first,
+                last
+            )),
+            _ => lines.push(String::from("Nobody said anything")),
+        }
+
+        if !self.active_users.is_empty() {
+            lines.push(format!("Active: {}", self.active_users.join(", ")));
+        }
+        for pin in &self.pins {
+            lines.push(format!("Pinned by {}: {}", pin.sender, pin.body));
+        }
+
+        lines
+    }
+
+    // Written as <dir>/<room>-<date>.json, one file a day
+    pub fn export(&self, dir: &Path) -> Result<PathBuf, DigestError> {
+        fs::create_dir_all(dir).map_err(DigestError::Io)?;
+
+        let path = dir.join(format!("{}-{}.json", self.room, self.date));
+        let contents = serde_json::to_string_pretty(self).map_err(DigestError::Json)?;
+        fs::write(&path, contents).map_err(DigestError::Io)?;
+
+        Ok(path)
+    }
+
+    // POSTs the digest as JSON.  Only plain http works unless we were built with the tls feature.
+    pub fn send_webhook(&self, url: &str) -> Result<(), DigestError> {
+        ureq::post(url)
+            .send_json(self)
+            .map_err(DigestError::Webhook)?;
+
+        Ok(())
+    }
+}
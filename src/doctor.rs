@@ -0,0 +1,507 @@
+use chrono::DateTime;
+use chrono::Utc;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::config::ServerConfig;
+use crate::scripts::ScriptHost;
+use crate::storage::Storage;
+use crate::tls;
+use crate::tls::TlsAcceptor;
+
+// Checks for the things that would otherwise only go wrong once people are connected: a certificate about to expire, a
+// directory we can't save into, a clock that's wandered.  "chat_server doctor" runs all of them and prints what it
+// finds, and the server runs the ones that don't get in its own way (everything but the ports and scripts) as it
+// starts, logging anything that isn't fine.  "server --check-config" runs just the ones about what the config says,
+// check_config and check_scripts, for a deploy to try a new config before restarting with it.  Each finding says what to
+// do about it, not just what's wrong.
+//
+// None of this changes anything.  Where we need to know if we could write somewhere, we open the file to append
+// without writing, or create an empty file in the directory and remove it again straight away.
+
+// A certificate expiring sooner than this is worth a warning
+const EXPIRY_WARNING: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// A clock set before this is certainly wrong, since we didn't exist yet
+const EARLIEST_PLAUSIBLE: Duration = Duration::from_secs(1_700_000_000);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Severity {
+    Ok,
+    // Works for now, but someone should look at it
+    Warning,
+    // Something will fail, or is failing already
+    Problem,
+}
+
+pub struct Finding {
+    pub severity: Severity,
+    // What was checked, e.g. "tls" or "history"
+    pub subject: String,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn new(severity: Severity, subject: &str, message: impl Into<String>) -> Finding {
+        Finding {
+            severity,
+            subject: String::from(subject),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Problem => "PROBLEM",
+        };
+        write!(f, "{:<8}{}: {}", label, self.subject, self.message)
+    }
+}
+
+// Whether what the config points at makes sense: the addresses, the TLS files, the webhook, and whether this build can
+// do what's asked of it.  Loading the config has
+// already checked everything that can be checked from the file alone.
+pub fn check_config(config: &ServerConfig) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    check_address(&mut findings, "bind_address", &config.bind_address);
+    if let Some(address) = &config.metrics.bind_address {
+        check_address(&mut findings, "metrics", address);
+    }
+    if let Some(address) = &config.api.bind_address {
+        check_address(&mut findings, "api", address);
+    }
+    if let Some(address) = &config.web.bind_address {
+        check_address(&mut findings, "web", address);
+    }
+    if let Some(address) = &config.link.bind_address {
+        check_address(&mut findings, "link", address);
+    }
+    if let Some(address) = &config.websocket.bind_address {
+        if cfg!(feature = "websocket") {
+            check_address(&mut findings, "websocket", address);
+        } else {
+            findings.push(Finding::new(
+                Severity::Problem,
+                "websocket",
+                "[websocket] is set, which needs a build with --features websocket",
+            ));
+        }
+    }
+
+    match &config.tls {
+        Some(config) => check_tls(&mut findings, &config.cert_path, &config.key_path),
+        None => findings.push(Finding::new(
+            Severity::Ok,
+            "tls",
+            "Off, clients connect in plain text",
+        )),
+    }
+
+    if let (true, Some(url)) = (config.digest.enabled, &config.digest.webhook_url) {
+        let finding = match url.split_once("://") {
+            Some(("https", _)) if !cfg!(feature = "tls") => Finding::new(
+                Severity::Problem,
+                "digest",
+                format!(
+                    "webhook_url {} is https, which needs a build with --features tls",
+                    url
+                ),
+            ),
+            Some(("http" | "https", _)) => {
+                Finding::new(Severity::Ok, "digest", format!("Posting to {}", url))
+            }
+            _ => Finding::new(
+                Severity::Problem,
+                "digest",
+                format!("webhook_url {} has to start with http:// or https://", url),
+            ),
+        };
+        findings.push(finding);
+    }
+
+    if let Some(homeserver) = &config.matrix.homeserver {
+        let finding = match homeserver.split_once("://") {
+            Some(("https", _)) if !cfg!(feature = "tls") => Finding::new(
+                Severity::Problem,
+                "matrix",
+                format!(
+                    "homeserver {} is https, which needs a build with --features tls",
+                    homeserver
+                ),
+            ),
+            _ => Finding::new(
+                Severity::Ok,
+                "matrix",
+                format!("Bridging to {} on {}", config.matrix.room_id, homeserver),
+            ),
+        };
+        findings.push(finding);
+    }
+
+    if let Some(address) = &config.redis.address {
+        findings.push(check_redis(address, &config.redis.channel));
+    }
+
+    findings
+}
+
+// Whether Redis answers at all.  It's only a warning when it doesn't, since the server carries on without it and
+// connects once it's there.
+fn check_redis(address: &str, channel: &str) -> Finding {
+    let reached = address.to_socket_addrs().and_then(|mut addresses| {
+        let address = addresses
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses"))?;
+        TcpStream::connect_timeout(&address, Duration::from_secs(3))
+    });
+    match reached {
+        Ok(_) => Finding::new(
+            Severity::Ok,
+            "redis",
+            format!("Sharing the room on {} through {}", channel, address),
+        ),
+        Err(err) => Finding::new(
+            Severity::Warning,
+            "redis",
+            format!("Can't reach Redis at {}: {}", address, err),
+        ),
+    }
+}
+
+// Compiles the scripts, the same as the server does as it starts, so a mistake in one shows up before a restart does.
+// The server doesn't run this itself, since it's about to load them anyway.
+pub fn check_scripts(config: &ServerConfig) -> Vec<Finding> {
+    let dir = match &config.scripts.dir {
+        Some(dir) => dir,
+        None => return Vec::new(),
+    };
+
+    let finding =
+        match ScriptHost::load(dir, config.scripts.max_operations, config.max_message_bytes) {
+            Ok(scripts) if scripts.names().is_empty() => Finding::new(
+                Severity::Warning,
+                "scripts",
+                format!("There are no scripts in {}", dir.display()),
+            ),
+            Ok(scripts) => Finding::new(
+                Severity::Ok,
+                "scripts",
+                format!("Loaded {}", scripts.names().join(", ")),
+            ),
+            Err(err) => Finding::new(
+                Severity::Problem,
+                "scripts",
+                format!("Can't load the scripts in {}: {}", dir.display(), err),
+            ),
+        };
+    vec![finding]
+}
+
+// The places the server keeps things, and the clock, which is what the server checks for itself at startup along with
+// check_config
+pub fn check_server(config: &ServerConfig) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    // Both are saved by writing a new file and renaming it over the old one, so it's the directory that has to be
+    // writable
+    check_directory(&mut findings, "accounts", parent(&config.accounts_path));
+    check_directory(&mut findings, "bans", parent(&config.banlist_path));
+
+    // SQLite keeps its write-ahead log next to the database, so that needs the directory too
+    if config.history.enabled {
+        check_directory(&mut findings, "history", parent(&config.history.path));
+        if config.history.path.exists() {
+            check_appendable(&mut findings, "history", &config.history.path);
+        }
+    }
+    if let Some(path) = &config.auth_failures.log_path {
+        if path.exists() {
+            check_appendable(&mut findings, "auth_failures", path);
+        } else {
+            check_directory(&mut findings, "auth_failures", parent(path));
+        }
+    }
+    if let (true, Some(dir)) = (config.digest.enabled, &config.digest.export_dir) {
+        check_directory(&mut findings, "digest", dir);
+    }
+
+    check_clock(&mut findings, config);
+    findings
+}
+
+// Whether we could listen where the config says.  Only meaningful while the server isn't running, since then it's the
+// server that has them.
+pub fn check_ports(config: &ServerConfig) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    match TcpListener::bind(&config.bind_address) {
+        Ok(_) => findings.push(Finding::new(
+            Severity::Ok,
+            "bind_address",
+            format!("{} is free", config.bind_address),
+        )),
+        // Falling back is what the fallback ports are for, so it isn't a problem if there are some
+        Err(err) if !config.bind.fallback_ports.is_empty() => findings.push(Finding::new(
+            Severity::Warning,
+            "bind_address",
+            format!(
+                "Can't listen on {} ({}), so the server will try the fallback ports",
+                config.bind_address, err
+            ),
+        )),
+        Err(err) => findings.push(Finding::new(
+            Severity::Problem,
+            "bind_address",
+            format!(
+                "Can't listen on {}: {}.  Is the server already running, or something else using the port?",
+                config.bind_address, err
+            ),
+        )),
+    }
+
+    if let Some(address) = &config.metrics.bind_address {
+        if let Err(err) = TcpListener::bind(address) {
+            findings.push(Finding::new(
+                Severity::Problem,
+                "metrics",
+                format!("Can't serve metrics on {}: {}", address, err),
+            ));
+        }
+    }
+    if let Some(address) = &config.api.bind_address {
+        if let Err(err) = TcpListener::bind(address) {
+            findings.push(Finding::new(
+                Severity::Problem,
+                "api",
+                format!("Can't serve the API on {}: {}", address, err),
+            ));
+        }
+    }
+    if let Some(address) = &config.websocket.bind_address {
+        if let Err(err) = TcpListener::bind(address) {
+            findings.push(Finding::new(
+                Severity::Problem,
+                "websocket",
+                format!("Can't listen for WebSockets on {}: {}", address, err),
+            ));
+        }
+    }
+    if let Some(address) = &config.web.bind_address {
+        if let Err(err) = TcpListener::bind(address) {
+            findings.push(Finding::new(
+                Severity::Problem,
+                "web",
+                format!("Can't serve the web page on {}: {}", address, err),
+            ));
+        }
+    }
+    if let Some(address) = &config.link.bind_address {
+        if let Err(err) = TcpListener::bind(address) {
+            findings.push(Finding::new(
+                Severity::Problem,
+                "link",
+                format!("Can't take links on {}: {}", address, err),
+            ));
+        }
+    }
+
+    findings
+}
+
+// Whether an address is something we could listen on at all, rather than whether it's free right now
+fn check_address(findings: &mut Vec<Finding>, subject: &str, address: &str) {
+    match address
+        .to_socket_addrs()
+        .map(|mut addresses| addresses.next())
+    {
+        Ok(Some(_)) => findings.push(Finding::new(
+            Severity::Ok,
+            subject,
+            format!("Will listen on {}", address),
+        )),
+        Ok(_) => findings.push(Finding::new(
+            Severity::Problem,
+            subject,
+            format!("{} doesn't resolve to any addresses", address),
+        )),
+        Err(err) => findings.push(Finding::new(
+            Severity::Problem,
+            subject,
+            format!(
+                "{} isn't an address we can listen on ({}), it should look like 127.0.0.1:8080",
+                address, err
+            ),
+        )),
+    }
+}
+
+// A bare file name lives in the current directory, which Path::parent calls ""
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn check_directory(findings: &mut Vec<Finding>, subject: &str, dir: &Path) {
+    let probe = dir.join(format!(".chat_server_doctor.{}", process::id()));
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .and_then(|_| fs::remove_file(&probe));
+
+    match result {
+        Ok(()) => findings.push(Finding::new(
+            Severity::Ok,
+            subject,
+            format!("Can write to {}", dir.display()),
+        )),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => findings.push(Finding::new(
+            Severity::Problem,
+            subject,
+            format!("{} doesn't exist, create it first", dir.display()),
+        )),
+        Err(err) => findings.push(Finding::new(
+            Severity::Problem,
+            subject,
+            format!(
+                "Can't write to {} ({}), check who owns it and its permissions",
+                dir.display(),
+                err
+            ),
+        )),
+    }
+}
+
+fn check_appendable(findings: &mut Vec<Finding>, subject: &str, path: &Path) {
+    if let Err(err) = OpenOptions::new().append(true).open(path) {
+        findings.push(Finding::new(
+            Severity::Problem,
+            subject,
+            format!(
+                "Can't write to {} ({}), check who owns it and its permissions",
+                path.display(),
+                err
+            ),
+        ));
+    }
+}
+
+fn check_tls(findings: &mut Vec<Finding>, cert_path: &Path, key_path: &Path) {
+    // Loading them both is the same check the server does, so this catches a key that doesn't match the certificate
+    if let Some(err) = TlsAcceptor::from_files(cert_path, key_path).err() {
+        findings.push(Finding::new(
+            Severity::Problem,
+            "tls",
+            format!(
+                "Can't use {} with {}: {}",
+                cert_path.display(),
+                key_path.display(),
+                err
+            ),
+        ));
+        return;
+    }
+
+    let expiry = match tls::certificate_expiry(cert_path) {
+        Ok(expiry) => expiry,
+        Err(err) => {
+            findings.push(Finding::new(
+                Severity::Warning,
+                "tls",
+                format!("Can't tell when {} expires: {}", cert_path.display(), err),
+            ));
+            return;
+        }
+    };
+
+    let date = DateTime::<Utc>::from(expiry).format("%Y-%m-%d %H:%M UTC");
+    let finding = match expiry.duration_since(SystemTime::now()) {
+        Err(_) => Finding::new(
+            Severity::Problem,
+            "tls",
+            format!(
+                "{} expired on {}, clients will refuse to connect until it's renewed",
+                cert_path.display(),
+                date
+            ),
+        ),
+        Ok(left) if left < EXPIRY_WARNING => Finding::new(
+            Severity::Warning,
+            "tls",
+            format!(
+                "{} expires on {}, in {} day(s).  Renew it and restart the server.",
+                cert_path.display(),
+                date,
+                left.as_secs() / (24 * 60 * 60)
+            ),
+        ),
+        Ok(_) => Finding::new(
+            Severity::Ok,
+            "tls",
+            format!("{} is good until {}", cert_path.display(), date),
+        ),
+    };
+    findings.push(finding);
+}
+
+// Two-factor codes, mutes, lockouts and the history's timestamps all go by the clock.  There's nothing to compare it
+// to without going out to the network, but it can at least be wrong in ways we can spot: set years in the past, or
+// earlier than the last message we saved, which means it's gone backwards since.
+fn check_clock(findings: &mut Vec<Finding>, config: &ServerConfig) {
+    let now = SystemTime::now();
+    let date = DateTime::<Utc>::from(now).format("%Y-%m-%d %H:%M UTC");
+
+    if now < SystemTime::UNIX_EPOCH + EARLIEST_PLAUSIBLE {
+        findings.push(Finding::new(
+            Severity::Problem,
+            "clock",
+            format!(
+                "The clock says it's {}, which can't be right.  Set the time (or turn on NTP).",
+                date
+            ),
+        ));
+        return;
+    }
+
+    // Doesn't matter if there's no history yet, it's only another thing to compare with
+    let latest = match Storage::open_read_only(&config.history.path) {
+        Ok(storage) if config.history.enabled => storage.latest().ok().flatten(),
+        _ => None,
+    };
+    if let Some(ahead) = latest.and_then(|latest| latest.duration_since(now).ok()) {
+        if ahead > Duration::from_secs(60) {
+            findings.push(Finding::new(
+                Severity::Warning,
+                "clock",
+                format!(
+                    "The clock has gone back since the newest message in the history, which is {} minute(s) ahead \
+                     of it.  Check the time (and NTP).",
+                    ahead.as_secs() / 60
+                ),
+            ));
+            return;
+        }
+    }
+
+    findings.push(Finding::new(
+        Severity::Ok,
+        "clock",
+        format!("It's {}", date),
+    ));
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+use std::io;
+
+use crate::activity::ExportError;
+use crate::chat_client::ChatClientError;
+use crate::chat_server::StartError;
+use crate::config::ConfigError;
+
+// Everything the library can fail with, as one type, so a program using us can pass any of it up with ? and handle it
+// in one place rather than a different type for every call.  Each part still has its own error type, which is what's
+// inside, so a program that cares can still tell (say) a client that was thrown out from one that couldn't connect.
+// The calls that can only fail one way return that part's error, and it turns into this one with ?.
+#[derive(Debug)]
+pub enum Error {
+    // A setting that doesn't make sense, from a builder or a config file
+    Config(ConfigError),
+    // The server couldn't start, see ChatServer::start
+    Start(StartError),
+    // The client couldn't connect, or couldn't stay connected
+    Client(ChatClientError),
+    // The history database couldn't be opened or read
+    History(rusqlite::Error),
+    Export(ExportError),
+    // Reading or writing anything else, like the server's listener or the client's output
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(err) => write!(f, "{}", err),
+            Error::Start(err) => write!(f, "{}", err),
+            Error::Client(err) => write!(f, "{}", err),
+            Error::History(err) => write!(f, "unable to open history: {}", err),
+            Error::Export(err) => write!(f, "{}", err),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Error {
+        Error::Config(err)
+    }
+}
+
+impl From<StartError> for Error {
+    fn from(err: StartError) -> Error {
+        Error::Start(err)
+    }
+}
+
+impl From<ChatClientError> for Error {
+    fn from(err: ChatClientError) -> Error {
+        Error::Client(err)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::History(err)
+    }
+}
+
+impl From<ExportError> for Error {
+    fn from(err: ExportError) -> Error {
+        Error::Export(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
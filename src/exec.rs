@@ -0,0 +1,88 @@
+use std::io;
+use std::io::prelude::*;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// Running a command on our own machine for the client's /exec, so what it prints can be shared with the room.  The
+// command goes to sh, so pipes and the like work as they would in a terminal.  It gets no input, what it writes to
+// stderr is thrown away, and it's killed if it takes too long.  Only so much of what it prints is kept, since it all
+// ends up as chat, and the server only takes so many lines at once before it starts dropping them.
+
+// Long enough for the sort of thing worth sharing (uptime, git log -3, a quick curl), short enough that a command
+// waiting on input it'll never get doesn't leave /exec stuck
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// What we keep of what it prints.  With the fences around it, that's comfortably inside the server's default burst.
+const MAX_LINES: usize = 5;
+const MAX_BYTES: usize = 2048;
+
+// What a command printed, at most MAX_LINES lines of it, and whether there was more
+pub struct Captured {
+    pub lines: Vec<String>,
+    pub truncated: bool,
+    pub status: ExitStatus,
+}
+
+// Runs command and waits for it, or for the timeout, whichever comes first.  Blank lines are left out, since the server
+// wouldn't pass them on anyway.
+pub fn run(command: &str) -> io::Result<Captured> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Reading waits for the command to finish printing, which may be never, so it's done on the side while we watch
+    // the clock.  One byte past the limit is how we know there was more.
+    let stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let read = stdout
+            .take(MAX_BYTES as u64 + 1)
+            .read_to_end(&mut output)
+            .map(|_| output);
+        let _ = sender.send(read);
+    });
+    let output = match receiver.recv_timeout(TIMEOUT) {
+        Ok(read) => read?,
+        Err(_) => {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("still going after {} seconds", TIMEOUT.as_secs()),
+            ));
+        }
+    };
+
+    // Anything it had left to print is going nowhere, so there's no point letting it carry on
+    let mut truncated = output.len() > MAX_BYTES;
+    if truncated {
+        child.kill().ok();
+    }
+    let status = child.wait()?;
+
+    let output = String::from_utf8_lossy(&output[..output.len().min(MAX_BYTES)]).into_owned();
+    let mut lines: Vec<String> = output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(String::from)
+        .collect();
+    if lines.len() > MAX_LINES {
+        lines.truncate(MAX_LINES);
+        truncated = true;
+    }
+
+    Ok(Captured {
+        lines,
+        truncated,
+        status,
+    })
+}
@@ -0,0 +1,113 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// What gives when a client's queue is full because they aren't keeping up with the room
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    // Their oldest message makes room for the new one, and they're told how many they missed
+    DropOldest,
+    // They're cut off, and their handler hangs up on them
+    Disconnect,
+}
+
+// One subscriber's messages, waiting for their handler to pick them up
+struct Queue<T> {
+    messages: VecDeque<T>,
+    // Thrown away to make room since the handler last asked
+    dropped: u64,
+    cut_off: bool,
+}
+
+// Hands everything the room broadcasts to each client's own bounded queue.  The room never waits on anyone, so a client
+// that stops reading only fills up their own queue, and then the overflow policy decides what happens to them.
+//
+// A client handler subscribes when it joins the room and just drops its Subscription when it leaves.  We notice on the
+// next broadcast, when we're the only ones left holding its queue.
+pub struct Fanout<T> {
+    subscribers: Vec<Arc<Mutex<Queue<T>>>>,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+pub struct Subscription<T> {
+    queue: Arc<Mutex<Queue<T>>>,
+}
+
+impl<T: Clone> Fanout<T> {
+    pub fn new(capacity: usize, overflow: Overflow) -> Fanout<T> {
+        Fanout {
+            subscribers: Vec::new(),
+            capacity,
+            overflow,
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Subscription<T> {
+        let queue = Arc::new(Mutex::new(Queue {
+            messages: VecDeque::new(),
+            dropped: 0,
+            cut_off: false,
+        }));
+        self.subscribers.push(queue.clone());
+
+        Subscription { queue }
+    }
+
+    pub fn broadcast(&mut self, message: T) {
+        self.subscribers
+            .retain(|queue| Arc::strong_count(queue) > 1);
+
+        for queue in &self.subscribers {
+            let mut queue = queue.lock().unwrap();
+            if queue.cut_off {
+                continue;
+            }
+
+            if queue.messages.len() >= self.capacity {
+                match self.overflow {
+                    Overflow::DropOldest => {
+                        queue.messages.pop_front();
+                        queue.dropped += 1;
+                    }
+                    // They're about to be disconnected, so there's no point keeping what they haven't read
+                    Overflow::Disconnect => {
+                        queue.cut_off = true;
+                        queue.messages.clear();
+                        continue;
+                    }
+                }
+            }
+            queue.messages.push_back(message.clone());
+        }
+    }
+}
+
+impl<T> Fanout<T> {
+    // The most messages any one client has waiting, which is how close the slowest of them is to the overflow policy
+    pub fn deepest(&self) -> usize {
+        self.subscribers
+            .iter()
+            .map(|queue| queue.lock().unwrap().messages.len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl<T> Subscription<T> {
+    pub fn try_recv(&self) -> Option<T> {
+        self.queue.lock().unwrap().messages.pop_front()
+    }
+
+    // How many messages were thrown away since the last time we asked
+    pub fn take_dropped(&self) -> u64 {
+        std::mem::take(&mut self.queue.lock().unwrap().dropped)
+    }
+
+    // True once the overflow policy has given up on us
+    pub fn is_cut_off(&self) -> bool {
+        self.queue.lock().unwrap().cut_off
+    }
+}
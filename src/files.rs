@@ -0,0 +1,235 @@
+use rand_core::OsRng;
+use rand_core::RngCore;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+// The parts of the client's /send that happen away from the server: picking ids, finding somewhere to save what we're
+// sent, and the direct connections between the two clients.  For a direct transfer the sender listens on a port of its
+// own, and the receiver connects to it, sends the id on a line of its own, and gets the file back as it is, with
+// nothing around it.  Both ends do that on a thread of their own and report back on a channel, so the connection to
+// the server carries on meanwhile.
+
+// How long the receiver gives the sender to answer, which is short since the server is always there to fall back on
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How long the sender listens for, which is longer than the server keeps an offer by default
+const LISTEN_FOR: Duration = Duration::from_secs(10 * 60);
+
+// How long whoever connects to the sender gets to say which file they're after
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What the threads have to say about a direct transfer, by its id
+pub enum Update {
+    // How many bytes have gone, or come, so far
+    Progress(String, u64),
+    // All of it, either way
+    Finished(String),
+    // It didn't work, and why
+    Failed(String, io::Error),
+}
+
+// Different for every transfer, and long enough nobody can guess one that's waiting
+pub fn new_id() -> String {
+    let mut id = [0; 8];
+    OsRng.fill_bytes(&mut id);
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// What we tell the other end the file is called, which is only the last part of the path, and nothing that could
+// break the line it goes on
+pub fn offered_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_string_lossy();
+    Some(
+        name.chars()
+            .map(|c| if c.is_control() { '_' } else { c })
+            .collect(),
+    )
+}
+
+// Somewhere new in downloads to save a file sent to us, going by the name it was offered under but never outside
+// downloads, and never over anything already there.  The first file called report.pdf keeps the name, the next is
+// "report (1).pdf", and so on.
+pub fn download_path(downloads: &Path, name: &str) -> PathBuf {
+    let name = match Path::new(name).file_name().and_then(|name| name.to_str()) {
+        Some(name) if !name.starts_with('.') => name,
+        _ => "download",
+    };
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+
+    let mut path = downloads.join(name);
+    let mut count = 1;
+    while path.exists() || partial(&path).exists() {
+        path = downloads.join(format!("{} ({}){}", stem, count, extension));
+        count += 1;
+    }
+    path
+}
+
+// Where a file goes while it's still arriving, so nothing half there looks finished
+pub fn partial(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+// Starts a fresh partial file for path, or empties the one that's there
+pub fn create_partial(path: &Path) -> io::Result<File> {
+    if let Some(downloads) = path.parent() {
+        fs::create_dir_all(downloads)?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(partial(path))
+}
+
+// e.g. 1.5 MB, for telling people how big things are
+pub fn size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} bytes", bytes),
+        1024..=1048575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+// Something to say as a transfer goes, once every tenth of the way
+pub struct Progress {
+    size: u64,
+    reported: u64,
+}
+
+impl Progress {
+    pub fn new(size: u64) -> Progress {
+        Progress { size, reported: 0 }
+    }
+
+    // The percentage, if it's passed the next tenth since the last time
+    pub fn update(&mut self, done: u64) -> Option<u64> {
+        if self.size == 0 {
+            return None;
+        }
+        let tenths = done.saturating_mul(10) / self.size;
+        if tenths <= self.reported {
+            return None;
+        }
+        self.reported = tenths;
+        Some(tenths * 10)
+    }
+}
+
+// The sender's side of a direct transfer.  Listens on any free port and says which, then waits on a thread of its own
+// for the receiver, until the file's gone, or stop is set, or nobody's come for long enough.
+pub fn listen(
+    id: &str,
+    path: &Path,
+    stop: Arc<AtomicBool>,
+    updates: mpsc::Sender<Update>,
+) -> io::Result<u16> {
+    let listener = TcpListener::bind("0.0.0.0:0")?;
+    let port = listener.local_addr()?.port();
+    listener.set_nonblocking(true)?;
+
+    let id = String::from(id);
+    let path = PathBuf::from(path);
+    thread::spawn(move || {
+        let started = Instant::now();
+        while !stop.load(Ordering::Relaxed) && started.elapsed() < LISTEN_FOR {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            // Whoever it is has to know the id, or they're not who we're waiting for
+            match hello(&stream) {
+                Ok(hello) if hello == id => {}
+                _ => continue,
+            }
+            let sent =
+                File::open(&path).and_then(|file| copy(file, stream, &id, &updates).map(|_| ()));
+            let _ = updates.send(match sent {
+                Ok(()) => Update::Finished(id),
+                Err(err) => Update::Failed(id, err),
+            });
+            return;
+        }
+    });
+
+    Ok(port)
+}
+
+fn hello(stream: &TcpStream) -> io::Result<String> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let mut hello = String::new();
+    BufReader::new(stream.take(64)).read_line(&mut hello)?;
+    stream.set_read_timeout(None)?;
+    Ok(String::from(hello.trim_end()))
+}
+
+// The receiver's side of a direct transfer, on a thread of its own.  Anything that goes wrong, even partway through,
+// is a Failed, and then it's up to the server.
+pub fn fetch(id: &str, address: SocketAddr, size: u64, file: File, updates: mpsc::Sender<Update>) {
+    let id = String::from(id);
+    thread::spawn(move || {
+        let fetched =
+            TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).and_then(|mut stream| {
+                writeln!(stream, "{}", id)?;
+                match copy(stream.take(size), file, &id, &updates)? {
+                    copied if copied == size => Ok(()),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the connection ended early",
+                    )),
+                }
+            });
+        let _ = updates.send(match fetched {
+            Ok(()) => Update::Finished(id),
+            Err(err) => Update::Failed(id, err),
+        });
+    });
+}
+
+// Copies everything from one to the other, saying how far it's got as it goes.  Returns how much that was.
+fn copy(
+    mut from: impl Read,
+    mut to: impl Write,
+    id: &str,
+    updates: &mpsc::Sender<Update>,
+) -> io::Result<u64> {
+    let mut buffer = [0; 16 * 1024];
+    let mut done = 0;
+    loop {
+        let read = from.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        to.write_all(&buffer[..read])?;
+        done += read as u64;
+        let _ = updates.send(Update::Progress(String::from(id), done));
+    }
+    to.flush()?;
+    Ok(done)
+}
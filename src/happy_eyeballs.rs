@@ -0,0 +1,86 @@
+use std::io;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// Connecting to a name with more than one address the way RFC 8305 ("Happy Eyeballs") suggests.  Trying them one at a
+// time means a network with broken IPv6 (or IPv4) sits through a full connect timeout on every bad address before it
+// gets to one that works.  Instead we start on the first address, and if it hasn't connected within ATTEMPT_DELAY we
+// start on the next one as well while the first carries on, and so on down the list, alternating IPv6 and IPv4.
+// Whichever connects first wins and the rest are dropped as they finish.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Each attempt gives up after timeout.  Attempts that are left behind still finish in their own time, this just puts a
+// bound on it.
+pub fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let addresses = interleave(address.to_socket_addrs()?.collect());
+    match addresses[..] {
+        [] => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} doesn't have any addresses", address),
+            ))
+        }
+        // Nothing to race, so it's just a plain connect
+        [only] => return TcpStream::connect_timeout(&only, timeout),
+        _ => {}
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut started = 0;
+    let mut finished = 0;
+    loop {
+        if started < addresses.len() {
+            let sender = sender.clone();
+            let target = addresses[started];
+            thread::spawn(move || {
+                // Nobody's listening any more if another attempt already won, and then the stream is just dropped
+                let _ = sender.send(TcpStream::connect_timeout(&target, timeout));
+            });
+            started += 1;
+        }
+
+        // With more left to start we only wait out the delay before starting the next one.  A failure starts the next
+        // one straight away, since there's no point waiting on an attempt that's already over.
+        let result = if started < addresses.len() {
+            match receiver.recv_timeout(ATTEMPT_DELAY) {
+                Ok(result) => result,
+                Err(_) => continue,
+            }
+        } else {
+            // We still hold a sender, so this can't come back empty
+            receiver.recv().unwrap()
+        };
+
+        finished += 1;
+        match result {
+            Ok(stream) => return Ok(stream),
+            // Everything failed, so the last failure is as good an explanation as any
+            Err(err) if finished == addresses.len() => return Err(err),
+            Err(_) => {}
+        }
+    }
+}
+
+// Alternates between the two families, starting with whichever the resolver put first.  The resolver's order is
+// otherwise kept, since it already sorts by what's likely to work best (RFC 6724).
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let total = addresses.len();
+    let first_is_ipv6 = addresses.first().is_some_and(|address| address.is_ipv6());
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == first_is_ipv6);
+
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut ordered = Vec::with_capacity(total);
+    while ordered.len() < total {
+        ordered.extend(preferred.next());
+        ordered.extend(other.next());
+    }
+
+    ordered
+}
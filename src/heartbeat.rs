@@ -0,0 +1,49 @@
+use std::time::Duration;
+use std::time::Instant;
+
+// One side's half of the heartbeat (see PING_COMMAND).  Every interval we send a ping, and the other side answers with
+// a pong.  A ping still unanswered when the next one is due counts as missed, so a connection that has quietly died
+// shows up as a run of missed pings.  TCP on its own can go a very long time without noticing a half-open connection.
+pub struct Heartbeat {
+    interval: Duration,
+    next_ping: Instant,
+    waiting: bool,
+    missed: u32,
+}
+
+impl Heartbeat {
+    // The first ping goes out one interval from now
+    pub fn new(interval: Duration) -> Heartbeat {
+        Heartbeat {
+            interval,
+            next_ping: Instant::now() + interval,
+            waiting: false,
+            missed: 0,
+        }
+    }
+
+    // Whether it's time to send another ping.  Checked as often as we like, and only says yes once per interval.
+    pub fn ping_due(&mut self) -> bool {
+        let now = Instant::now();
+        if now < self.next_ping {
+            return false;
+        }
+
+        if self.waiting {
+            self.missed += 1;
+        }
+        self.waiting = true;
+        self.next_ping = now + self.interval;
+        true
+    }
+
+    pub fn pong(&mut self) {
+        self.waiting = false;
+        self.missed = 0;
+    }
+
+    // How many pings in a row have gone unanswered
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+}
@@ -0,0 +1,29 @@
+use std::net::IpAddr;
+
+// For programs embedding the server that want a say in what happens, handed over with ChatServerBuilder::hooks.  Say
+// to log every connection somewhere of their own, or to keep certain words out of the room, without having to change
+// the server to do it.  Every method starts out letting everything through, so only the ones that matter need writing.
+//
+// They're called from whichever thread the event happens on, often several at once, and whatever they're doing holds
+// that up.  So they should be quick, and anything slow (like a webhook) belongs on a thread of its own.  Refusing
+// something comes with the reason, which is what the client is told.
+pub trait ServerHooks: Send + Sync {
+    // Someone has connected, and passed the access list, the bans and the client limit.  Refusing hangs up on them
+    // before they've said anything.
+    fn on_connect(&self, _address: IpAddr) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Chat on its way to the room, after the mute check.  Refusing means nobody sees it, not even in the history.
+    fn on_message(&self, _sender: &str, _body: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // A connection has ended, however it ended.  The name is empty if they never picked one.
+    fn on_disconnect(&self, _address: IpAddr, _name: &str) {}
+}
+
+// What a server without hooks has, so there's always something to call
+pub struct NoHooks;
+
+impl ServerHooks for NoHooks {}
@@ -0,0 +1,83 @@
+// Everything the chat_server binary does, as a library, so other programs can run the server or the client
+// themselves rather than only through the binary.  The binary (main.rs) is just the command line on top of this.
+//
+// The parts meant for that are public: ChatServer and ChatClient with their builders and configs, the Error they
+// return, the events and commands ChatClient::connect hands back, BotClient for bots built on the client, the
+// CancellationToken that stops either of them, the ServerHooks a server can be handed and the plugins it can be given,
+// the ThreadPool the server runs on, the wire protocol both sides speak (re-exported from chat_protocol), the async
+// server, and the history storage and activity export the binary's activity command uses.  The rest are the pieces
+// those are built from, and stay private so they can change without breaking anyone.
+mod access;
+mod accounts;
+pub mod activity;
+mod api;
+pub mod async_server;
+mod auth_failures;
+mod bans;
+mod batch;
+mod blocking_pool;
+mod cancel;
+pub mod chat_client;
+pub mod chat_server;
+pub mod config;
+mod digest;
+pub mod doctor;
+mod error;
+mod exec;
+mod fanout;
+mod files;
+mod happy_eyeballs;
+mod heartbeat;
+mod hooks;
+mod line_editor;
+mod link;
+mod listener;
+mod matrix;
+mod mentions;
+mod metrics;
+mod names;
+mod overload;
+mod permissions;
+mod plugins;
+mod rate_limit;
+mod redis;
+mod scripts;
+mod srv;
+mod state;
+mod stats;
+pub mod storage;
+pub mod thread_pool;
+mod timer;
+mod tls;
+mod totp;
+mod transfers;
+mod transport;
+mod tui;
+mod waker;
+mod web;
+mod websocket;
+
+// The protocol is its own crate (chat_protocol), but it's still here under its old name for anyone already using it
+pub use chat_protocol as protocol;
+
+pub use cancel::CancellationToken;
+pub use chat_client::Bot;
+pub use chat_client::BotClient;
+pub use chat_client::BotEvent;
+pub use chat_client::ChatClient;
+pub use chat_client::ChatClientBuilder;
+pub use chat_client::ChatClientError;
+pub use chat_client::ClientEvent;
+pub use chat_client::CommandSender;
+pub use chat_client::EventReceiver;
+pub use chat_server::ChatServer;
+pub use chat_server::ChatServerBuilder;
+pub use chat_server::ChatServerHandle;
+pub use config::ClientConfig;
+pub use config::ServerConfig;
+pub use error::Error;
+pub use hooks::ServerHooks;
+pub use plugins::Announcer;
+pub use plugins::ChatPlugin;
+pub use plugins::PluginContext;
+pub use thread_pool::ThreadPool;
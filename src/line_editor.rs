@@ -0,0 +1,329 @@
+use rustix::termios;
+use rustix::termios::InputModes;
+use rustix::termios::LocalModes;
+use rustix::termios::OptionalActions;
+use rustix::termios::SpecialCodeIndex;
+use rustix::termios::Termios;
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::str;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// Editing what's typed before it goes, for the client on a terminal.  Left and Right (or Ctrl-B and Ctrl-F) move along
+// the line, Home and End (or Ctrl-A and Ctrl-E) go to either end, Ctrl-W rubs out the word before the cursor, Ctrl-U
+// everything before it and Ctrl-K everything after.  Up and Down (or Ctrl-P and Ctrl-N) go back through what's been
+// sent, apart from anything with a password or a code in it.  Enter sends, Ctrl-C quits, and so does Ctrl-D on an
+// empty line.
+//
+// The terminal's own line editing has to be off for that (see RawMode), which means the chat coming in has to be
+// written around what's being typed: the line is rubbed out, the chat written, and the line put back underneath (see
+// Printer).  Everything on the line has to fit on one row for that to work, so a long one scrolls sideways.
+
+// How many lines Up goes back through
+const HISTORY: usize = 100;
+
+// Commands that are never remembered, since what comes after them is a password or a two-factor code
+const SECRET: [&str; 4] = ["login", "register", "reset", "2fa"];
+
+// The terminal with its line editing, echo and signal keys off, until this is dropped and it goes back the way it was.
+// It's stdin's terminal, since that's where the keyboard is.  Output is left as it is, so a newline still starts a new
+// row.
+pub struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    // An error if stdin isn't a terminal
+    pub fn enable() -> io::Result<RawMode> {
+        let original = termios::tcgetattr(io::stdin())?;
+        let mut raw = original.clone();
+        raw.local_modes -=
+            LocalModes::ICANON | LocalModes::ECHO | LocalModes::IEXTEN | LocalModes::ISIG;
+        raw.input_modes -= InputModes::IXON | InputModes::ICRNL;
+        raw.special_codes[SpecialCodeIndex::VMIN] = 1;
+        raw.special_codes[SpecialCodeIndex::VTIME] = 0;
+        termios::tcsetattr(io::stdin(), OptionalActions::Now, &raw)?;
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    // Whatever was half typed goes with it, so the shell's prompt comes back on a clean row
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(b"\r\x1b[K").and_then(|_| stdout.flush());
+        let _ = termios::tcsetattr(io::stdin(), OptionalActions::Now, &self.original);
+    }
+}
+
+// What the keys pressed add up to
+pub enum Typed {
+    // A line to send, without the newline
+    Line(String),
+    // Ctrl-C, or Ctrl-D with nothing typed
+    Quit,
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    RubOutWord,
+    RubOutStart,
+    RubOutEnd,
+    Interrupt,
+    EndOfFile,
+    // Anything we don't do anything with, like F1 or Alt-x
+    Ignored,
+}
+
+pub struct LineEditor {
+    prefix: char,
+    line: Vec<char>,
+    cursor: usize,
+    // What's been sent, oldest first, where we are going back through it with Up, and what was being typed before that
+    history: VecDeque<String>,
+    browsing: Option<usize>,
+    draft: Vec<char>,
+    // The start of a key that hasn't all arrived yet, like half an arrow key or a character
+    pending: Vec<u8>,
+}
+
+impl LineEditor {
+    pub fn new(prefix: char) -> LineEditor {
+        LineEditor {
+            prefix,
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            browsing: None,
+            draft: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // Everything read from the keyboard, as it comes.  Returns the lines it finished, and whether they quit.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Typed> {
+        self.pending.extend_from_slice(bytes);
+        let mut typed = Vec::new();
+        while let Some((key, used)) = key(&self.pending) {
+            self.pending.drain(..used);
+            if let Some(done) = self.press(key) {
+                typed.push(done);
+            }
+        }
+        typed
+    }
+
+    fn press(&mut self, key: Key) -> Option<Typed> {
+        match key {
+            Key::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Enter if self.line.is_empty() => {}
+            Key::Enter => {
+                let line: String = mem::take(&mut self.line).into_iter().collect();
+                self.cursor = 0;
+                self.browsing = None;
+                self.remember(&line);
+                return Some(Typed::Line(line));
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::EndOfFile if self.line.is_empty() => return Some(Typed::Quit),
+            Key::Delete | Key::EndOfFile if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Up => self.back(),
+            Key::Down => self.forward(),
+            // Like a shell, a word is anything up to a space, and the spaces after it go too
+            Key::RubOutWord => {
+                let mut start = self.cursor;
+                while start > 0 && self.line[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !self.line[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                self.line.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::RubOutStart => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::RubOutEnd => self.line.truncate(self.cursor),
+            Key::Interrupt => return Some(Typed::Quit),
+            _ => {}
+        }
+        None
+    }
+
+    fn remember(&mut self, line: &str) {
+        let secret = line
+            .trim()
+            .strip_prefix(self.prefix)
+            .and_then(|command| command.split_whitespace().next())
+            .is_some_and(|name| SECRET.contains(&name));
+        if secret || self.history.back().map(String::as_str) == Some(line) {
+            return;
+        }
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(line));
+    }
+
+    fn back(&mut self) {
+        let index = match self.browsing {
+            _ if self.history.is_empty() => return,
+            None => {
+                self.draft = mem::take(&mut self.line);
+                self.history.len() - 1
+            }
+            Some(0) => return,
+            Some(index) => index - 1,
+        };
+        self.show(index);
+    }
+
+    // Past the newest is back to whatever they were typing before they went back
+    fn forward(&mut self) {
+        match self.browsing {
+            None => {}
+            Some(index) if index + 1 < self.history.len() => self.show(index + 1),
+            Some(_) => {
+                self.browsing = None;
+                self.line = mem::take(&mut self.draft);
+                self.cursor = self.line.len();
+            }
+        }
+    }
+
+    fn show(&mut self, index: usize) {
+        self.browsing = Some(index);
+        self.line = self.history[index].chars().collect();
+        self.cursor = self.line.len();
+    }
+
+    // Puts the line back on the row the cursor's on, as much of it as fits with the cursor in sight.  The last column
+    // is left empty, since writing there can wrap the cursor onto the next row.
+    pub fn draw(&self, output: &mut impl Write) -> io::Result<()> {
+        let width = termios::tcgetwinsize(io::stdout()).map_or(80, |size| size.ws_col);
+        let width = usize::from(width).saturating_sub(1).max(1);
+        let start = (self.cursor + 1).saturating_sub(width);
+        let shown: String = self.line[start..].iter().take(width).collect();
+        let after = shown.chars().count() - (self.cursor - start);
+
+        write!(output, "\r\x1b[K{}", shown)?;
+        if after > 0 {
+            write!(output, "\x1b[{}D", after)?;
+        }
+        Ok(())
+    }
+}
+
+// The key the bytes start with and how many bytes it took, or None if it hasn't all arrived yet
+fn key(bytes: &[u8]) -> Option<(Key, usize)> {
+    let key = match bytes {
+        [] | [0x1b] | [0x1b, b'['] | [0x1b, b'O'] => return None,
+        // Arrows and the like, which are ESC [ or ESC O, any numbers, and a letter (or ~) to finish
+        [0x1b, b'[' | b'O', rest @ ..] => {
+            let end = rest.iter().position(|byte| (0x40..=0x7e).contains(byte))?;
+            let key = match (&rest[..end], rest[end]) {
+                (_, b'A') => Key::Up,
+                (_, b'B') => Key::Down,
+                (_, b'C') => Key::Right,
+                (_, b'D') => Key::Left,
+                (_, b'H') | (b"1", b'~') | (b"7", b'~') => Key::Home,
+                (_, b'F') | (b"4", b'~') | (b"8", b'~') => Key::End,
+                (b"3", b'~') => Key::Delete,
+                _ => Key::Ignored,
+            };
+            return Some((key, end + 3));
+        }
+        [0x1b, ..] => return Some((Key::Ignored, 2)),
+        [b'\r' | b'\n', ..] => Key::Enter,
+        [0x01, ..] => Key::Home,
+        [0x02, ..] => Key::Left,
+        [0x03, ..] => Key::Interrupt,
+        [0x04, ..] => Key::EndOfFile,
+        [0x05, ..] => Key::End,
+        [0x06, ..] => Key::Right,
+        [0x08 | 0x7f, ..] => Key::Backspace,
+        [0x0b, ..] => Key::RubOutEnd,
+        [0x0e, ..] => Key::Down,
+        [0x10, ..] => Key::Up,
+        [0x15, ..] => Key::RubOutStart,
+        [0x17, ..] => Key::RubOutWord,
+        [byte, ..] if *byte < 0x20 => Key::Ignored,
+        // Anything else is a character, which can take up to four bytes
+        [first, ..] => {
+            let length = match first {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let bytes = bytes.get(..length)?;
+            let key = match str::from_utf8(bytes).ok().and_then(|s| s.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Char(char::REPLACEMENT_CHARACTER),
+            };
+            return Some((key, length));
+        }
+    };
+    Some((key, 1))
+}
+
+// Writes the chat out around the line being typed.  Whatever's written is held until the flush, then the line is
+// rubbed out, everything written at once, and the line drawn again after it.
+pub struct Printer<W> {
+    output: W,
+    editor: Arc<Mutex<LineEditor>>,
+    buffer: Vec<u8>,
+}
+
+impl<W> Printer<W> {
+    pub fn new(output: W, editor: Arc<Mutex<LineEditor>>) -> Printer<W> {
+        Printer {
+            output,
+            editor,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Printer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let editor = self.editor.lock().unwrap();
+            self.output.write_all(b"\r\x1b[K")?;
+            self.output.write_all(&mem::take(&mut self.buffer))?;
+            editor.draw(&mut self.output)?;
+        }
+        self.output.flush()
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::net::Shutdown;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+
+use crate::protocol::Hlc;
+use crate::protocol::LineReader;
+
+// Linking servers together, so people on each of them share one room, say one server per region.  Each server has a
+// name of its own, and links to the others over plain TCP with a shared secret.  The first line either way is the
+// handshake, the one connecting sends
+//
+//     LINK 2 <its name> <secret>
+//
+// and gets back "LINKED <our name>", or "ERROR <why>" before we hang up.  After that it's the same both ways, one event
+// per line, each starting with the server it happened on, an id that server gave it, and where it fell in that
+// server's order (see Hlc), which every other server keeps for it so the room is in the same order everywhere:
+//
+//     CHAT east 1700000000000-42 1700000000123.0 alice hello
+//     JOIN east 1700000000000-43 1700000000456.0 bob
+//     LEAVE east 1700000000000-44 1700000000456.1 bob
+//
+// Whatever comes in from one link goes out again on all the others, so servers can be linked in a chain or a ring, not
+// just every one to every other.  What stops an event going round forever is that each server remembers the last SEEN
+// events it's passed on and drops any it sees again, along with any that claim to be from itself.
+//
+// On each server, people from the others show up as name@server, and a server that's linked won't let anyone local
+// have an @ in their name, so nobody can pass for someone elsewhere.  Only chat, joins and leaves are shared.  Commands,
+// moderation and the roster stay on each server, so /who only lists who's here.
+//
+// The secret is sent as it is, so links belong on a private network or inside a tunnel.
+
+const VERSION: &str = "2";
+
+// How long a server that's connected to us gets to say who it is
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Between tries at a link that's down
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// Events waiting to go out on a link.  Past this a link has stopped keeping up, and what doesn't fit is dropped rather
+// than holding up the room.
+const QUEUE_SIZE: usize = 1024;
+
+// How many events we remember passing on.  This only has to cover the time it takes an event to go round the
+// slowest loop of links, which for a busy room is still well under this.
+const SEEN: usize = 4096;
+
+// Well past any chat line, with the origin and id in front
+const MAX_LINE: usize = 16 * 1024;
+
+// Something that happened in the room on one of the servers
+#[derive(Clone, Debug)]
+pub enum Event {
+    Chat { sender: String, body: String },
+    Joined(String),
+    Left(String),
+}
+
+impl Event {
+    fn to_line(&self, origin: &str, id: &str, clock: Hlc) -> String {
+        match self {
+            Event::Chat { sender, body } => {
+                format!("CHAT {} {} {} {} {}", origin, id, clock, sender, body)
+            }
+            Event::Joined(name) => format!("JOIN {} {} {} {}", origin, id, clock, name),
+            Event::Left(name) => format!("LEAVE {} {} {} {}", origin, id, clock, name),
+        }
+    }
+
+    // The origin, the id, the clock and the event.  None if it isn't an event we know.
+    fn parse(line: &str) -> Option<(&str, &str, Hlc, Event)> {
+        let (kind, rest) = line.split_once(' ')?;
+        let (origin, rest) = rest.split_once(' ')?;
+        let (id, rest) = rest.split_once(' ')?;
+        let (clock, rest) = rest.split_once(' ')?;
+        let clock = Hlc::parse(clock)?;
+        let event = match kind {
+            "CHAT" => {
+                let (sender, body) = rest.split_once(' ')?;
+                Event::Chat {
+                    sender: String::from(sender),
+                    body: String::from(body),
+                }
+            }
+            "JOIN" => Event::Joined(String::from(rest)),
+            "LEAVE" => Event::Left(String::from(rest)),
+            _ => return None,
+        };
+        Some((origin, id, clock, event))
+    }
+}
+
+// What the server does with an event from elsewhere, given the server it happened on and its clock there
+pub type Deliver = dyn Fn(&str, Event, Hlc) + Send + Sync;
+
+// One server on the other end of a link
+struct Peer {
+    name: String,
+    outgoing: mpsc::SyncSender<String>,
+}
+
+// The events we've passed on lately, oldest first, so the oldest can be forgotten
+#[derive(Default)]
+struct Seen {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl Seen {
+    // False if we've already seen it
+    fn insert(&mut self, origin: &str, id: &str) -> bool {
+        let key = format!("{} {}", origin, id);
+        if !self.ids.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > SEEN {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+pub struct Links {
+    name: String,
+    secret: String,
+    // Every link that's up, by a number of our own, since the same server could be linked twice for a moment while
+    // one of them is on its way out
+    peers: Mutex<HashMap<u64, Peer>>,
+    next_peer: AtomicU64,
+    seen: Mutex<Seen>,
+    // Our ids are when we started and a count, so they're still new after a restart
+    started: u128,
+    next_event: AtomicU64,
+}
+
+impl Links {
+    pub fn new(name: &str, secret: &str) -> Links {
+        Links {
+            name: String::from(name),
+            secret: String::from(secret),
+            peers: Mutex::new(HashMap::new()),
+            next_peer: AtomicU64::new(0),
+            seen: Mutex::new(Seen::default()),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis()),
+            next_event: AtomicU64::new(0),
+        }
+    }
+
+    // Something that happened here, on its way to every linked server.  Never waits.
+    pub fn relay(&self, event: Event, clock: Hlc) {
+        let id = format!(
+            "{}-{}",
+            self.started,
+            self.next_event.fetch_add(1, Ordering::Relaxed)
+        );
+        self.send(None, &event.to_line(&self.name, &id, clock));
+    }
+
+    // The names of the servers we're linked to right now
+    fn linked(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|peer| peer.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Takes links from other servers on listener until the process ends, on a thread of its own
+    pub fn listen(self: &Arc<Self>, listener: TcpListener, deliver: Arc<Deliver>) {
+        let links = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Unable to accept a link: {}", err);
+                        continue;
+                    }
+                };
+                let links = links.clone();
+                let deliver = deliver.clone();
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().map(|address| address.to_string());
+                    let _link = info_span!("link", peer = peer.as_deref().unwrap_or("")).entered();
+                    let mut lines = LineReader::with_max_line(MAX_LINE);
+                    match links.answer(&stream, &mut lines) {
+                        Ok(name) => links.run(stream, lines, name, &*deliver),
+                        Err(err) => warn!("Refused a link: {}", err),
+                    }
+                });
+            }
+        });
+    }
+
+    // Keeps a link to address up until the process ends, on a thread of its own
+    pub fn connect(self: &Arc<Self>, address: String, deliver: Arc<Deliver>) {
+        let links = self.clone();
+        thread::spawn(move || {
+            let _link = info_span!("link", peer = %address).entered();
+            loop {
+                let mut lines = LineReader::with_max_line(MAX_LINE);
+                let linked = TcpStream::connect(&address).and_then(|stream| {
+                    let name = links.introduce(&stream, &mut lines)?;
+                    Ok((stream, name))
+                });
+                match linked {
+                    Ok((stream, name)) => links.run(stream, lines, name, &*deliver),
+                    Err(err) => warn!("Unable to link: {}", err),
+                }
+                thread::sleep(RETRY_DELAY);
+            }
+        });
+    }
+
+    // Our side of the handshake when we're the one connecting.  Returns who they are.
+    fn introduce(&self, mut stream: &TcpStream, lines: &mut LineReader) -> io::Result<String> {
+        writeln!(stream, "LINK {} {} {}", VERSION, self.name, self.secret)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let reply = read_line(stream, lines)?;
+        stream.set_read_timeout(None)?;
+
+        match reply.split_once(' ') {
+            Some(("LINKED", name)) if !name.is_empty() => Ok(String::from(name)),
+            Some(("ERROR", reason)) => Err(io::Error::other(format!("they said {}", reason))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "that isn't a server we can link to",
+            )),
+        }
+    }
+
+    // Our side of the handshake when they've connected to us.  Returns who they are.
+    fn answer(&self, mut stream: &TcpStream, lines: &mut LineReader) -> io::Result<String> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let hello = read_line(stream, lines)?;
+        stream.set_read_timeout(None)?;
+
+        let fields: Vec<&str> = hello.split(' ').collect();
+        let refusal = match fields[..] {
+            ["LINK", VERSION, name, secret] if same(secret, &self.secret) => {
+                if name == self.name {
+                    "that's our own name"
+                } else if self.linked().iter().any(|linked| linked == name) {
+                    "already linked"
+                } else {
+                    writeln!(stream, "LINKED {}", self.name)?;
+                    return Ok(String::from(name));
+                }
+            }
+            ["LINK", VERSION, _, _] => "wrong secret",
+            ["LINK", ..] => "wrong version",
+            _ => "not a link",
+        };
+        writeln!(stream, "ERROR {}", refusal)?;
+        stream.shutdown(Shutdown::Both).ok();
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            String::from(refusal),
+        ))
+    }
+
+    // Passes events back and forth until the link goes down.  Writing is on a thread of its own, so a server that's
+    // slow to read never holds up one that's sending to us.
+    fn run(&self, stream: TcpStream, mut lines: LineReader, name: String, deliver: &Deliver) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(err) => {
+                warn!("Unable to link: {}", err);
+                return;
+            }
+        };
+        let id = self.next_peer.fetch_add(1, Ordering::Relaxed);
+        let (outgoing, queued) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        self.peers.lock().unwrap().insert(
+            id,
+            Peer {
+                name: name.clone(),
+                outgoing,
+            },
+        );
+        info!(server = %name, "Linked");
+
+        // Ends once we've dropped the sender, or the link has gone
+        thread::spawn(move || {
+            for line in queued {
+                if writeln!(writer, "{}", line).is_err() {
+                    break;
+                }
+            }
+            writer.shutdown(Shutdown::Both).ok();
+        });
+
+        loop {
+            match read_line(&stream, &mut lines) {
+                Ok(line) => self.receive(id, &line, deliver),
+                Err(err) => {
+                    info!(server = %name, "Link down: {}", err);
+                    break;
+                }
+            }
+        }
+        self.peers.lock().unwrap().remove(&id);
+        stream.shutdown(Shutdown::Both).ok();
+    }
+
+    // An event from the link numbered from, which is ours to show unless we've seen it, and then everyone else's
+    fn receive(&self, from: u64, line: &str, deliver: &Deliver) {
+        let (origin, id, clock, event) = match Event::parse(line) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        if origin == self.name || !self.seen.lock().unwrap().insert(origin, id) {
+            return;
+        }
+
+        deliver(origin, event, clock);
+        self.send(Some(from), line);
+    }
+
+    // To every link apart from the one it came in on, if it came in on one
+    fn send(&self, except: Option<u64>, line: &str) {
+        for (id, peer) in self.peers.lock().unwrap().iter() {
+            if Some(*id) == except {
+                continue;
+            }
+            if let Err(TrySendError::Full(_)) = peer.outgoing.try_send(String::from(line)) {
+                warn!(server = %peer.name, "Link is too far behind, dropped an event");
+            }
+        }
+    }
+}
+
+// The next whole line, or an error once the link's gone (or sent something far too long to be one of ours)
+fn read_line(mut stream: &TcpStream, lines: &mut LineReader) -> io::Result<String> {
+    let mut buffer = [0; 4096];
+    loop {
+        match lines.next_line() {
+            Some(Ok(line)) => return Ok(line),
+            Some(Err(_)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"))
+            }
+            None => {}
+        }
+        match stream.read(&mut buffer)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => lines.push(&buffer[..read]),
+        }
+    }
+}
+
+// Compares the secrets without stopping at the first difference, so how long it takes doesn't give any of it away
+fn same(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && given
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::ToSocketAddrs;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::ServerConfig;
+
+// Why we couldn't start listening, worded for whoever's reading the log rather than as a bare io error
+#[derive(Debug)]
+pub enum BindError {
+    // bind_address isn't something we can listen on at all
+    BadAddress(String, io::Error),
+    // Every address we tried failed, and this is the last one and why
+    Failed(SocketAddr, io::Error),
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindError::BadAddress(address, err) => {
+                write!(f, "bind_address {} isn't a valid address: {}", address, err)
+            }
+            BindError::Failed(address, err) => match err.kind() {
+                io::ErrorKind::AddrInUse => write!(
+                    f,
+                    "unable to listen on {}, something else is already using that port",
+                    address
+                ),
+                io::ErrorKind::PermissionDenied => write!(
+                    f,
+                    "not allowed to listen on {}, ports below 1024 usually need root or CAP_NET_BIND_SERVICE",
+                    address
+                ),
+                io::ErrorKind::AddrNotAvailable => write!(
+                    f,
+                    "unable to listen on {}, that address doesn't belong to this machine",
+                    address
+                ),
+                _ => write!(f, "unable to listen on {}: {}", address, err),
+            },
+        }
+    }
+}
+
+// Starts listening on bind_address, or on the first of the config's fallback ports (same host) that's free.  If
+// they're all taken it tries the lot again, as many times as the config says, waiting twice as long before each try.
+// Only a port being in use is worth trying past, anything else would fail the same way every time.
+pub fn bind(config: &ServerConfig) -> Result<TcpListener, BindError> {
+    let bad_address = |err| BindError::BadAddress(config.bind_address.clone(), err);
+    let primary = config
+        .bind_address
+        .to_socket_addrs()
+        .map_err(bad_address)?
+        .next()
+        .ok_or_else(|| bad_address(io::Error::from(io::ErrorKind::NotFound)))?;
+
+    let mut addresses = vec![primary];
+    for port in &config.bind.fallback_ports {
+        addresses.push(SocketAddr::new(primary.ip(), *port));
+    }
+
+    let mut delay = Duration::from_millis(config.bind.retry_delay_ms);
+    let mut retries = config.bind.retries;
+    loop {
+        let mut last_err = None;
+        for address in &addresses {
+            match TcpListener::bind(address) {
+                Ok(listener) => {
+                    if *address != primary {
+                        warn!("{} is in use, listening on {} instead", primary, address);
+                    }
+                    return Ok(listener);
+                }
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                    last_err = Some((*address, err))
+                }
+                Err(err) => return Err(BindError::Failed(*address, err)),
+            }
+        }
+
+        // There's always at least the primary address, so something failed if we're here
+        let (address, err) = last_err.unwrap();
+        if retries == 0 {
+            return Err(BindError::Failed(address, err));
+        }
+        warn!(
+            retries_left = retries,
+            "Every port we can use is taken, trying again in {}ms",
+            delay.as_millis()
+        );
+        thread::sleep(delay);
+        delay *= 2;
+        retries -= 1;
+    }
+}
@@ -1,30 +1,385 @@
-mod chat_client;
-mod chat_server;
-mod thread_pool;
-use std::{env, io};
+use chrono::Duration;
+use chrono::Local;
+use chrono::NaiveDate;
+use std::io::IsTerminal;
+use std::{env, io, process};
+use tracing::error;
+use tracing::warn;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+use chat_server::activity;
+use chat_server::activity::ExportFormat;
+use chat_server::async_server;
+use chat_server::config;
+use chat_server::config::LogLevel;
+use chat_server::doctor;
+use chat_server::doctor::Finding;
+use chat_server::doctor::Severity;
+use chat_server::protocol;
+use chat_server::storage::Storage;
+use chat_server::CancellationToken;
+use chat_server::ChatClient;
+use chat_server::ChatClientError;
+use chat_server::ChatServer;
+use chat_server::ClientConfig;
+use chat_server::Error;
+use chat_server::ServerConfig;
+
+// Text is for people reading along in a terminal, json is one object per line for log collectors
+#[derive(Clone, Copy)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+// The level from the config is only the starting point, RUST_LOG wins if it's set
+fn init_logging(level: LogLevel, format: LogFormat) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::from(level).into())
+        .from_env_lossy();
+    // Colors only make sense when someone's watching, not in a log file
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stdout().is_terminal());
+
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
 
 // Very simple main. Takes a couple of arguments and that's it.
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
-        println!("You must specify client or server");
-        return ();
+        println!("You must specify client, server, activity or doctor");
+        return;
     }
 
     match &args[1][..] {
         "server" => {
-            let server = chat_server::ChatServer {};
-            server.run()
+            let mut config_path = None;
+            let mut log_format = LogFormat::Text;
+            let mut use_async = false;
+            let mut check_config = false;
+
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                match &arg[..] {
+                    "--log-format" => match options.next().map(|format| &format[..]) {
+                        Some("text") => log_format = LogFormat::Text,
+                        Some("json") => log_format = LogFormat::Json,
+                        _ => {
+                            println!("--log-format needs to be text or json");
+                            return;
+                        }
+                    },
+                    "--async" => use_async = true,
+                    "--check-config" => check_config = true,
+                    _ => config_path = Some(arg.clone()),
+                }
+            }
+
+            // An explicit path has to exist, the default one is optional
+            let config = match config_path {
+                Some(path) => ServerConfig::load(path),
+                None => ServerConfig::load_or_default(config::DEFAULT_CONFIG_PATH),
+            };
+
+            // --check-config only reports on the config, without starting anything, so a deploy can try a new one
+            // before restarting the server with it.  It's printed rather than logged, like doctor.
+            if check_config {
+                report(&check_loaded(&config));
+                return;
+            }
+
+            let config = match config {
+                Ok(config) => config,
+                Err(err) => {
+                    init_logging(LogLevel::Error, log_format);
+                    error!("{}", err);
+                    process::exit(1);
+                }
+            };
+            init_logging(config.log_level, log_format);
+
+            // The same checks as doctor, apart from the ports and scripts, which we're about to find out about anyway.
+            // Nothing here stops us, anything that has to will when we start.
+            let mut findings = doctor::check_config(&config);
+            findings.extend(doctor::check_server(&config));
+            for finding in findings {
+                match finding.severity {
+                    Severity::Ok => {}
+                    Severity::Warning => warn!(check = %finding.subject, "{}", finding.message),
+                    Severity::Problem => error!(check = %finding.subject, "{}", finding.message),
+                }
+            }
+
+            if use_async {
+                if let Err(err) = async_server::run(config) {
+                    error!("{}", err);
+                    process::exit(1);
+                }
+                return;
+            }
+
+            let server = match ChatServer::builder().config(config).build() {
+                Ok(server) => server,
+                Err(err) => {
+                    error!("{}", err);
+                    process::exit(1);
+                }
+            };
+
+            // ctrlc is actually a library to help us catch ctrlc.  This lets us setup a closure to cancel the token
+            // that tells the server whether to keep running.
+            let cancel = CancellationToken::new();
+            let handler_cancel = cancel.clone();
+            ctrlc::set_handler(move || handler_cancel.cancel()).unwrap();
+
+            if let Err(err) = server.run(cancel) {
+                error!("{}", err);
+                process::exit(1);
+            }
         }
         "client" => {
-            let client = chat_client::ChatClient {};
-            if args.len() != 3 {
-                client.run(String::from("Nobody"), io::stdin(), io::stdout());
+            // Anything starting with -- is an option, the first thing that doesn't is our name
+            let mut user = String::from("Nobody");
+            let mut client = ChatClient::builder()
+                .color(io::stdout().is_terminal())
+                .line_editing(io::stdin().is_terminal() && io::stdout().is_terminal());
+            let mut config_path = None;
+            let mut use_tui = false;
+
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                match &arg[..] {
+                    "--tls" => client = client.tls(true),
+                    "--lite" => client = client.lite(true),
+                    "--nodelay" => client = client.nodelay(true),
+                    "--bulk" => client = client.bulk(true),
+                    "--no-reconnect" => client = client.reconnect(false),
+                    "--tui" => use_tui = true,
+                    "--prefix" => match options.next().and_then(|prefix| prefix.parse().ok()) {
+                        Some(prefix) if protocol::is_valid_prefix(prefix) => {
+                            client = client.prefix(prefix)
+                        }
+                        _ => {
+                            println!("--prefix needs a single character that isn't a letter, digit or space");
+                            return;
+                        }
+                    },
+                    "--ca-cert" => match options.next() {
+                        Some(path) => client = client.ca_cert(path),
+                        None => {
+                            println!("--ca-cert needs a path");
+                            return;
+                        }
+                    },
+                    "--config" => match options.next() {
+                        Some(path) => config_path = Some(path.clone()),
+                        None => {
+                            println!("--config needs a path");
+                            return;
+                        }
+                    },
+                    _ => user = arg.clone(),
+                }
+            }
+
+            // Same as the server, an explicit path has to exist and the default one is optional
+            let config = match config_path {
+                Some(path) => ClientConfig::load(path),
+                None => ClientConfig::load_or_default(config::DEFAULT_CLIENT_CONFIG_PATH),
+            };
+            let client = client.nickname(user);
+            let client = match config.and_then(|config| client.config(config).build()) {
+                Ok(client) => client,
+                Err(err) => {
+                    println!("{}", err);
+                    process::exit(1);
+                }
+            };
+
+            // Nothing cancels the client.  On a terminal Ctrl-C is the same as /quit (see line_editor.rs), otherwise it
+            // just ends the process like it always has.  How we stopped has been written out already, apart from TLS
+            // not working or stdout going away, and what's left is the exit code.
+            let ran = if use_tui {
+                client.run_tui(CancellationToken::new())
             } else {
-                client.run(args[2].clone(), io::stdin(), io::stdout());
+                client.run(io::stdin(), io::stdout(), CancellationToken::new())
+            };
+            match ran {
+                Ok(()) => {}
+                Err(Error::Client(ChatClientError::Unreachable(err))) => {
+                    process::exit(err.raw_os_error().unwrap_or(1))
+                }
+                Err(err @ Error::Client(ChatClientError::Tls(_))) => {
+                    println!("{}", err);
+                    process::exit(1);
+                }
+                // Nothing more is getting through stdout, so this is the one place left to say it
+                Err(err @ Error::Io(_)) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+                Err(_) => process::exit(1),
             }
         }
-        _ => println!("You must specify client or server"),
+        "activity" => export_activity(&args[2..]),
+        "doctor" => run_doctor(&args[2..]),
+        _ => println!("You must specify client, server, activity or doctor"),
+    }
+}
+
+// doctor [config_path] [--client client_config_path]
+//
+// Checks everything the server and client need before anyone relies on them (see doctor.rs), printing a line for each
+// thing checked.  Exits with 1 if anything is a problem, so it can gate a deploy script.
+fn run_doctor(args: &[String]) {
+    let mut config_path = None;
+    let mut client_config_path = None;
+
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        match &arg[..] {
+            "--client" => match options.next() {
+                Some(path) => client_config_path = Some(path.clone()),
+                None => {
+                    println!("--client needs a path");
+                    return;
+                }
+            },
+            _ => config_path = Some(arg.clone()),
+        }
+    }
+
+    let config = match config_path {
+        Some(path) => ServerConfig::load(path),
+        None => ServerConfig::load_or_default(config::DEFAULT_CONFIG_PATH),
+    };
+    let mut findings = check_loaded(&config);
+    if let Ok(config) = &config {
+        findings.extend(doctor::check_server(config));
+        findings.extend(doctor::check_ports(config));
+    }
+
+    let client_config = match &client_config_path {
+        Some(path) => ClientConfig::load(path),
+        None => ClientConfig::load_or_default(config::DEFAULT_CLIENT_CONFIG_PATH),
+    };
+    let client_config_path = client_config_path.as_deref();
+    let client_config_path = client_config_path.unwrap_or(config::DEFAULT_CLIENT_CONFIG_PATH);
+    findings.push(match client_config {
+        Ok(_) => Finding::new(
+            Severity::Ok,
+            "client config",
+            format!("{} is valid", client_config_path),
+        ),
+        Err(err) => Finding::new(Severity::Problem, "client config", err.to_string()),
+    });
+
+    report(&findings);
+}
+
+// Whether the config loaded, and then everything about it that doctor can check without looking at the machine
+fn check_loaded(config: &Result<ServerConfig, config::ConfigError>) -> Vec<Finding> {
+    let config = match config {
+        Ok(config) => config,
+        // Without a config there's nothing else to go on
+        Err(err) => return vec![Finding::new(Severity::Problem, "config", err.to_string())],
+    };
+
+    let path = config.path.clone().unwrap_or_default();
+    let mut findings = vec![Finding::new(
+        Severity::Ok,
+        "config",
+        format!("{} is valid", path.display()),
+    )];
+    findings.extend(doctor::check_config(config));
+    findings.extend(doctor::check_scripts(config));
+    findings
+}
+
+// Prints a line for each finding, then exits with 1 if any of them is a problem
+fn report(findings: &[Finding]) {
+    for finding in findings {
+        println!("{}", finding);
+    }
+    if findings
+        .iter()
+        .any(|finding| finding.severity == Severity::Problem)
+    {
+        process::exit(1);
+    }
+}
+
+fn parse_date(date: Option<&String>) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date?, "%Y-%m-%d").ok()
+}
+
+// activity [config_path] [--from YYYY-MM-DD] [--to YYYY-MM-DD] [--room name] [--format csv|json]
+//
+// Prints how busy each room was, hour by hour, straight from the history database named in the config.  Defaults to
+// the last seven days of every room, as CSV.  It only reads the database, so it's safe to run while the server is up.
+fn export_activity(args: &[String]) {
+    let today = Local::now().date_naive();
+    let mut config_path = None;
+    let mut first = today - Duration::days(6);
+    let mut last = today;
+    let mut room = None;
+    let mut format = ExportFormat::Csv;
+
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        match &arg[..] {
+            "--from" | "--to" => match parse_date(options.next()) {
+                Some(date) if arg == "--from" => first = date,
+                Some(date) => last = date,
+                None => {
+                    println!("{} needs a date like 2024-03-01", arg);
+                    return;
+                }
+            },
+            "--room" => room = options.next().cloned(),
+            "--format" => match options.next().map(|format| &format[..]) {
+                Some("csv") => format = ExportFormat::Csv,
+                Some("json") => format = ExportFormat::Json,
+                _ => {
+                    println!("--format needs to be csv or json");
+                    return;
+                }
+            },
+            _ => config_path = Some(arg.clone()),
+        }
+    }
+
+    let config = match config_path {
+        Some(path) => ServerConfig::load(path),
+        None => ServerConfig::load_or_default(config::DEFAULT_CONFIG_PATH),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let storage = match Storage::open_read_only(&config.history.path) {
+        Ok(storage) => storage,
+        Err(err) => {
+            println!("Unable to open history: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let mut output = io::stdout().lock();
+    if let Err(err) = activity::export(&storage, room.as_deref(), first, last, format, &mut output)
+    {
+        println!("{}", err);
+        process::exit(1);
     }
 }
@@ -0,0 +1,329 @@
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::mpsc::TrySendError;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tracing::info;
+use tracing::warn;
+use ureq::Agent;
+
+use crate::config::MatrixConfig;
+
+// A bridge between the room and a room on Matrix, so people there and people here can talk to each other.  It logs in
+// to the homeserver as an ordinary Matrix account, using its access token, and that account has to have joined the
+// Matrix room already.  Then there are two threads:
+//
+// - One sends chat from our room to Matrix as "<alice> hello", one message at a time, in the order it was said.  The
+//   room hands messages over without waiting, and if Matrix can't keep up the ones that don't fit in the queue are
+//   dropped, so a homeserver that's down never holds up the room.
+// - The other long-polls the homeserver's /sync for new messages in the Matrix room, and hands each one to the room as
+//   chat from the bridge, e.g. "matrix: <@bob:example.org> hi".  What the bridge sent itself comes back in the sync
+//   too, and is skipped.
+//
+// Only text is bridged, with emotes written out as "* bob waves".  Joins, leaves and everything else stay on their own
+// side.  Like the API this goes through ureq, so an https homeserver needs a build with the tls feature.
+
+// How long the homeserver may hold a sync open waiting for something to happen, and how long we'll wait on any
+// request, which is a bit longer so a slow sync isn't mistaken for a dead one
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(45);
+
+// After a request fails, before the next one, so a homeserver that's down isn't hammered
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// How many times a message is tried before it's given up on
+const ATTEMPTS: u32 = 3;
+
+// Messages waiting to go out to Matrix.  Well past anything a room says while one request is in flight.
+const QUEUE_SIZE: usize = 256;
+
+// The most lines one Matrix message turns into here, the same as /exec shares, so a pasted log can't flood the room
+const MAX_LINES: usize = 5;
+
+pub struct MatrixBridge {
+    outgoing: mpsc::SyncSender<String>,
+}
+
+impl MatrixBridge {
+    // Starts the thread that sends to Matrix.  Nothing comes the other way until listen.
+    pub fn new(config: &MatrixConfig) -> MatrixBridge {
+        let (outgoing, queued) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        let client = Client::new(config);
+        thread::spawn(move || {
+            // A transaction id has to be new for every message from this token, or the homeserver takes it for a
+            // retry of one it already has
+            let started = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis());
+            for (count, body) in queued.into_iter().enumerate() {
+                let transaction = format!("{}-{}", started, count);
+                for attempt in 1..=ATTEMPTS {
+                    match client.send(&transaction, &body) {
+                        Ok(()) => break,
+                        Err(err) if attempt == ATTEMPTS => {
+                            warn!("Gave up sending to Matrix: {}", err);
+                        }
+                        Err(err) => {
+                            warn!("Unable to send to Matrix, trying again: {}", err);
+                            thread::sleep(RETRY_DELAY);
+                        }
+                    }
+                }
+            }
+        });
+
+        MatrixBridge { outgoing }
+    }
+
+    // Chat that's gone through our room, on its way to Matrix.  Never waits.
+    pub fn relay(&self, sender: &str, body: &str) {
+        match self.outgoing.try_send(format!("<{}> {}", sender, body)) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => warn!("Matrix is too far behind, dropped a message"),
+        }
+    }
+
+    // Starts following the Matrix room on a thread of its own.  deliver is given each line to say in our room, already
+    // marked with who said it on Matrix, and each cut down to max_bytes.
+    pub fn listen(
+        &self,
+        config: &MatrixConfig,
+        max_bytes: usize,
+        deliver: impl Fn(&str) + Send + 'static,
+    ) {
+        let client = Client::new(config);
+        thread::spawn(move || {
+            // We need to know who we are to skip our own messages, and anything said before we started isn't news, so
+            // the first sync is only for where to carry on from
+            let mut next_batch = None;
+            let mut user_id = None;
+            loop {
+                if user_id.is_none() {
+                    match client.whoami() {
+                        Ok(id) => {
+                            info!(user = %id, room = %client.room_id, "Bridging to Matrix");
+                            user_id = Some(id);
+                        }
+                        Err(err) => {
+                            warn!("Unable to log in to Matrix: {}", err);
+                            thread::sleep(RETRY_DELAY);
+                            continue;
+                        }
+                    }
+                }
+
+                let sync = match client.sync(next_batch.as_deref()) {
+                    Ok(sync) => sync,
+                    Err(err) => {
+                        warn!("Unable to sync with Matrix: {}", err);
+                        thread::sleep(RETRY_DELAY);
+                        continue;
+                    }
+                };
+                let caught_up = next_batch.is_some();
+                next_batch = Some(sync.next_batch);
+                if !caught_up {
+                    continue;
+                }
+
+                let events = sync
+                    .rooms
+                    .join
+                    .get(&client.room_id)
+                    .map(|room| room.timeline.events.as_slice())
+                    .unwrap_or_default();
+                for event in events {
+                    if event.kind != "m.room.message" || Some(&event.sender) == user_id.as_ref() {
+                        continue;
+                    }
+                    for line in lines(event, max_bytes) {
+                        deliver(&line);
+                    }
+                }
+            }
+        });
+    }
+}
+
+// What a message from Matrix says, as lines for our room.  Nothing for anything that isn't text.
+fn lines(event: &Event, max_bytes: usize) -> Vec<String> {
+    let body = event.content.get("body").and_then(Value::as_str);
+    let msgtype = event.content.get("msgtype").and_then(Value::as_str);
+    let body = match (msgtype, body) {
+        (Some("m.text" | "m.notice"), Some(body)) => body,
+        (Some("m.emote"), Some(body)) => {
+            return vec![truncate(
+                format!("* {} {}", event.sender, body.lines().next().unwrap_or("")),
+                max_bytes,
+            )]
+        }
+        _ => return Vec::new(),
+    };
+
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .take(MAX_LINES)
+        .map(|line| truncate(format!("<{}> {}", event.sender, line), max_bytes))
+        .collect()
+}
+
+fn truncate(mut line: String, max_bytes: usize) -> String {
+    if line.len() > max_bytes {
+        let mut end = max_bytes;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        line.truncate(end);
+    }
+    line
+}
+
+// The parts of the client-server API we use, for one account and one room
+struct Client {
+    agent: Agent,
+    homeserver: String,
+    access_token: String,
+    room_id: String,
+    // The sync filter, which only asks for messages in our room
+    filter: String,
+}
+
+#[derive(Deserialize)]
+struct WhoAmI {
+    user_id: String,
+}
+
+#[derive(Deserialize)]
+struct Sync {
+    next_batch: String,
+    #[serde(default)]
+    rooms: SyncRooms,
+}
+
+#[derive(Deserialize, Default)]
+struct SyncRooms {
+    #[serde(default)]
+    join: HashMap<String, JoinedRoom>,
+}
+
+#[derive(Deserialize)]
+struct JoinedRoom {
+    #[serde(default)]
+    timeline: Timeline,
+}
+
+#[derive(Deserialize, Default)]
+struct Timeline {
+    #[serde(default)]
+    events: Vec<Event>,
+}
+
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    sender: String,
+    #[serde(default)]
+    content: Value,
+}
+
+impl Client {
+    fn new(config: &MatrixConfig) -> Client {
+        let agent = Agent::new_with_config(
+            Agent::config_builder()
+                .timeout_global(Some(REQUEST_TIMEOUT))
+                .build(),
+        );
+        let filter = json!({
+            "room": {
+                "rooms": [config.room_id],
+                "timeline": { "types": ["m.room.message"] },
+                "state": { "types": [] },
+                "ephemeral": { "types": [] },
+                "account_data": { "types": [] },
+            },
+            "presence": { "types": [] },
+            "account_data": { "types": [] },
+        });
+
+        Client {
+            agent,
+            homeserver: config
+                .homeserver
+                .as_deref()
+                .unwrap_or_default()
+                .trim_end_matches('/')
+                .to_string(),
+            access_token: config.access_token.clone(),
+            room_id: config.room_id.clone(),
+            filter: filter.to_string(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/_matrix/client/v3{}", self.homeserver, path)
+    }
+
+    fn authorization(&self) -> String {
+        format!("Bearer {}", self.access_token)
+    }
+
+    fn whoami(&self) -> Result<String, ureq::Error> {
+        let whoami: WhoAmI = self
+            .agent
+            .get(&self.url("/account/whoami"))
+            .header("Authorization", &self.authorization())
+            .call()?
+            .body_mut()
+            .read_json()?;
+        Ok(whoami.user_id)
+    }
+
+    // Without since it answers straight away with where things stand, otherwise it waits for something new
+    fn sync(&self, since: Option<&str>) -> Result<Sync, ureq::Error> {
+        let mut request = self
+            .agent
+            .get(&self.url("/sync"))
+            .header("Authorization", &self.authorization())
+            .query("filter", &self.filter);
+        request = match since {
+            Some(since) => request
+                .query("since", since)
+                .query("timeout", SYNC_TIMEOUT.as_millis().to_string()),
+            None => request.query("timeout", "0"),
+        };
+        request.call()?.body_mut().read_json()
+    }
+
+    fn send(&self, transaction: &str, body: &str) -> Result<(), ureq::Error> {
+        let path = format!(
+            "/rooms/{}/send/m.room.message/{}",
+            encode(&self.room_id),
+            encode(transaction)
+        );
+        self.agent
+            .put(&self.url(&path))
+            .header("Authorization", &self.authorization())
+            .send_json(json!({ "msgtype": "m.text", "body": body }))?;
+        Ok(())
+    }
+}
+
+// Room ids look like "!abc:example.org", which has to be escaped to go in a path
+fn encode(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
@@ -0,0 +1,91 @@
+use std::collections::HashSet;
+
+// A mention is @name anywhere in a chat message.  Clients that asked for notices get the message flagged (see
+// protocol::Mention) so they can alert the person, everyone else just sees the chat.
+//
+// @all and @here mention everyone in the room at once.  In a big room that's a lot of people being pinged, so the
+// room decides whether they count (see MentionsConfig), and the people being pinged can opt out of mentions from
+// particular people or whole rooms.
+const MASS_MENTIONS: [&str; 2] = ["all", "here"];
+
+// The @words in a message, without the @ and without any punctuation stuck to the end, like the comma in "@bob, hi"
+fn mentioned_names(body: &str) -> impl Iterator<Item = &str> {
+    body.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_' && c != '-'))
+        .filter(|name| !name.is_empty())
+}
+
+// Everyone the message names, lowercased and only once each, in the order they came up
+pub fn mentioned(body: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for name in mentioned_names(body).map(str::to_lowercase) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+pub fn is_mass_mention(body: &str) -> bool {
+    mentioned_names(body).any(|mentioned| {
+        MASS_MENTIONS
+            .iter()
+            .any(|mass| mentioned.eq_ignore_ascii_case(mass))
+    })
+}
+
+// What one connection doesn't want to be pinged by, set with /mentions.  Names and rooms are kept lowercased.
+#[derive(Default)]
+pub struct MentionSettings {
+    blocked_users: HashSet<String>,
+    blocked_rooms: HashSet<String>,
+}
+
+impl MentionSettings {
+    pub fn wants(&self, sender: &str, room: &str) -> bool {
+        !self.blocked_users.contains(&sender.to_lowercase())
+            && !self.blocked_rooms.contains(&room.to_lowercase())
+    }
+
+    pub fn block_user(&mut self, name: &str) {
+        self.blocked_users.insert(name.to_lowercase());
+    }
+
+    // False if they weren't blocked in the first place
+    pub fn unblock_user(&mut self, name: &str) -> bool {
+        self.blocked_users.remove(&name.to_lowercase())
+    }
+
+    pub fn set_room(&mut self, room: &str, on: bool) {
+        if on {
+            self.blocked_rooms.remove(&room.to_lowercase());
+        } else {
+            self.blocked_rooms.insert(room.to_lowercase());
+        }
+    }
+
+    // For showing back to them, sorted so it reads the same every time
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (what, set) in [
+            ("people", &self.blocked_users),
+            ("rooms", &self.blocked_rooms),
+        ] {
+            let mut names: Vec<&str> = set.iter().map(String::as_str).collect();
+            names.sort_unstable();
+            if !names.is_empty() {
+                lines.push(format!(
+                    "Ignoring mentions from {}: {}",
+                    what,
+                    names.join(", ")
+                ));
+            }
+        }
+        if lines.is_empty() {
+            lines.push(String::from("You get every mention"));
+        }
+
+        lines
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt::Write as _;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::thread_pool::JobsCompleted;
+use crate::thread_pool::JobsPanicked;
+use crate::thread_pool::PoolState;
+
+// Upper bounds of the broadcast latency histogram buckets, in microseconds.  Anything slower only lands in +Inf.
+const LATENCY_BUCKETS_US: [u64; 8] = [
+    500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000,
+];
+
+// Counters for the metrics endpoint.  Everything is a plain atomic, so bumping one from a client handler or the room
+// costs about as much as the increment itself, and nobody waits on a lock because Prometheus came calling.
+//
+// Rates like messages per second are left to Prometheus, e.g. rate(chat_messages_broadcast_total[1m]), which is the
+// usual way to do it and doesn't depend on how often we're scraped.
+pub struct Metrics {
+    connected_clients: AtomicU64,
+    connections_total: AtomicU64,
+    messages_received: AtomicU64,
+    messages_broadcast: AtomicU64,
+    // Lines that never got as far as being handled, for being over max_message_bytes or the rate limit
+    lines_too_long: AtomicU64,
+    lines_throttled: AtomicU64,
+    // Count per bucket (not cumulative, we add them up when rendering), then the sum and count of every observation
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_US.len()],
+    latency_sum_us: AtomicU64,
+    latency_count: AtomicU64,
+    pool: PoolState,
+    jobs_completed: JobsCompleted,
+    jobs_panicked: JobsPanicked,
+    // The same again for the pool that does the disk writes and webhooks (see blocking_pool.rs)
+    io_jobs_panicked: JobsPanicked,
+}
+
+impl Metrics {
+    pub fn new(
+        pool: PoolState,
+        jobs_completed: JobsCompleted,
+        jobs_panicked: JobsPanicked,
+        io_jobs_panicked: JobsPanicked,
+    ) -> Metrics {
+        Metrics {
+            connected_clients: AtomicU64::new(0),
+            connections_total: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            messages_broadcast: AtomicU64::new(0),
+            lines_too_long: AtomicU64::new(0),
+            lines_throttled: AtomicU64::new(0),
+            latency_buckets: Default::default(),
+            latency_sum_us: AtomicU64::new(0),
+            latency_count: AtomicU64::new(0),
+            pool,
+            jobs_completed,
+            jobs_panicked,
+            io_jobs_panicked,
+        }
+    }
+
+    pub fn client_connected(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn message_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn line_too_long(&self) {
+        self.lines_too_long.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn line_throttled(&self) {
+        self.lines_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // What /room limits shows next to the limits themselves, and the snapshot on SIGUSR1 shows with the rest
+    pub fn connected_clients(&self) -> u64 {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    pub fn connections_total(&self) -> u64 {
+        self.connections_total.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_received(&self) -> u64 {
+        self.messages_received.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_broadcast(&self) -> u64 {
+        self.messages_broadcast.load(Ordering::Relaxed)
+    }
+
+    pub fn jobs_completed(&self) -> u64 {
+        self.jobs_completed.get()
+    }
+
+    pub fn jobs_panicked(&self) -> u64 {
+        self.jobs_panicked.get()
+    }
+
+    pub fn io_jobs_panicked(&self) -> u64 {
+        self.io_jobs_panicked.get()
+    }
+
+    pub fn lines_too_long(&self) -> u64 {
+        self.lines_too_long.load(Ordering::Relaxed)
+    }
+
+    pub fn lines_throttled(&self) -> u64 {
+        self.lines_throttled.load(Ordering::Relaxed)
+    }
+
+    pub fn pool(&self) -> &PoolState {
+        &self.pool
+    }
+
+    pub fn message_broadcast(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        if let Some(bucket) = LATENCY_BUCKETS_US.iter().position(|&bound| micros <= bound) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_sum_us.fetch_add(micros, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.messages_broadcast.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The Prometheus text format, see https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+
+        metric(
+            "chat_connected_clients",
+            "gauge",
+            "Clients connected right now.",
+            self.connected_clients.load(Ordering::Relaxed),
+        );
+        metric(
+            "chat_connections_total",
+            "counter",
+            "Connections accepted since the server started.",
+            self.connections_total.load(Ordering::Relaxed),
+        );
+        metric(
+            "chat_messages_received_total",
+            "counter",
+            "Chat messages received from clients.",
+            self.messages_received.load(Ordering::Relaxed),
+        );
+        metric(
+            "chat_messages_broadcast_total",
+            "counter",
+            "Messages broadcast by the room, including joins and notices.",
+            self.messages_broadcast.load(Ordering::Relaxed),
+        );
+        metric(
+            "chat_lines_too_long_total",
+            "counter",
+            "Lines from clients dropped for being over max_message_bytes.",
+            self.lines_too_long.load(Ordering::Relaxed),
+        );
+        metric(
+            "chat_lines_throttled_total",
+            "counter",
+            "Lines from clients dropped for being over the rate limit.",
+            self.lines_throttled.load(Ordering::Relaxed),
+        );
+        metric(
+            "chat_thread_pool_queue_depth",
+            "gauge",
+            "Jobs waiting for a worker.",
+            self.pool.queued_jobs() as u64,
+        );
+        metric(
+            "chat_thread_pool_active_jobs",
+            "gauge",
+            "Jobs a worker is running right now.",
+            self.pool.active_jobs() as u64,
+        );
+        metric(
+            "chat_thread_pool_workers",
+            "gauge",
+            "Worker threads running, including any finishing a last job before they stop.",
+            self.pool.worker_count() as u64,
+        );
+        metric(
+            "chat_thread_pool_jobs_completed_total",
+            "counter",
+            "Jobs the workers have finished.",
+            self.jobs_completed.get(),
+        );
+        metric(
+            "chat_thread_pool_jobs_panicked_total",
+            "counter",
+            "Jobs that panicked.  The worker carries on, but each one is a bug.",
+            self.jobs_panicked.get(),
+        );
+        metric(
+            "chat_io_pool_jobs_panicked_total",
+            "counter",
+            "Jobs on the blocking IO pool that panicked.  The thread carries on, but each one is a bug.",
+            self.io_jobs_panicked.get(),
+        );
+
+        let name = "chat_broadcast_latency_seconds";
+        let _ = writeln!(out, "# HELP {} How long each broadcast took.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS_US.iter().zip(&self.latency_buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let bound = *bound as f64 / 1_000_000.0;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        let sum = self.latency_sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        out
+    }
+}
+
+// Answers every request on the listener with the current metrics.  This is just enough HTTP for a Prometheus scrape, one
+// request per connection, and it gets its own thread so a slow scraper can never hold up a worker.
+pub fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &metrics));
+            if let Err(err) = result {
+                warn!("Metrics request failed: {}", err);
+            }
+        }
+    });
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // We only care about the request line, the rest of the headers can go unread
+    let mut request = [0; 1024];
+    let read = stream.read(&mut request)?;
+    let request = String::from_utf8_lossy(&request[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", String::from("Not found, try /metrics\n"))
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
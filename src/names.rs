@@ -0,0 +1,111 @@
+use std::fmt;
+
+use crate::config::NamesConfig;
+
+// Why a name was turned down.  The Display text is what the client gets told, so it says which rule they broke.
+#[derive(Debug, Eq, PartialEq)]
+pub enum NameError {
+    TooShort(usize),
+    TooLong(usize),
+    Whitespace,
+    LooksLikeCommand,
+    Character(char),
+    Reserved(String),
+    ReservedPrefix(String),
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameError::TooShort(min) => write!(f, "Names have to be at least {} characters", min),
+            NameError::TooLong(max) => write!(f, "Names can be at most {} characters", max),
+            NameError::Whitespace => write!(f, "Names can't have spaces in them"),
+            NameError::LooksLikeCommand => write!(f, "Names can't start with /"),
+            NameError::Character(c) => write!(f, "Names can't have {:?} in them", c),
+            NameError::Reserved(name) => write!(f, "{} is reserved", name),
+            NameError::ReservedPrefix(prefix) => {
+                write!(f, "Names can't start with {}", prefix)
+            }
+        }
+    }
+}
+
+// The rules a name has to follow, from the config's [names].  Lengths count characters rather than bytes, and reserved
+// names and prefixes are matched without regard to case, the same as registered names, so "Admin" is as taken as
+// "admin".
+pub struct NamePolicy {
+    min_length: usize,
+    max_length: usize,
+    symbols: Option<String>,
+    reserved: Vec<String>,
+    reserved_prefixes: Vec<String>,
+    // Characters nobody can have whatever the symbols say, because they mean something else in a name
+    forbidden: Vec<char>,
+}
+
+impl NamePolicy {
+    pub fn new(config: &NamesConfig) -> NamePolicy {
+        let lowercase = |names: &[String]| names.iter().map(|name| name.to_lowercase()).collect();
+
+        NamePolicy {
+            min_length: config.min_length,
+            max_length: config.max_length,
+            symbols: config.symbols.clone(),
+            reserved: lowercase(&config.reserved),
+            reserved_prefixes: lowercase(&config.reserved_prefixes),
+            forbidden: Vec::new(),
+        }
+    }
+
+    // Like the @ in names from linked servers (see link.rs)
+    pub fn forbid(&mut self, c: char) {
+        self.forbidden.push(c);
+    }
+
+    pub fn check(&self, name: &str) -> Result<(), NameError> {
+        // Names start every chat line, so these two hold whatever the config says, or a name could pass for the
+        // server or a command
+        if name.contains(char::is_whitespace) {
+            return Err(NameError::Whitespace);
+        }
+        if name.starts_with('/') {
+            return Err(NameError::LooksLikeCommand);
+        }
+
+        let length = name.chars().count();
+        if length < self.min_length {
+            return Err(NameError::TooShort(self.min_length));
+        }
+        if length > self.max_length {
+            return Err(NameError::TooLong(self.max_length));
+        }
+
+        if let Some(c) = name.chars().find(|c| self.forbidden.contains(c)) {
+            return Err(NameError::Character(c));
+        }
+
+        // Letters and digits are always fine, and any other character only if it's one of the symbols
+        if let Some(symbols) = &self.symbols {
+            if let Some(c) = name
+                .chars()
+                .find(|&c| !c.is_alphanumeric() && !symbols.contains(c))
+            {
+                return Err(NameError::Character(c));
+            }
+        }
+
+        let lowercase = name.to_lowercase();
+        if self.reserved.contains(&lowercase) {
+            return Err(NameError::Reserved(String::from(name)));
+        }
+        if let Some(prefix) = self
+            .reserved_prefixes
+            .iter()
+            .find(|prefix| lowercase.starts_with(prefix.as_str()))
+        {
+            return Err(NameError::ReservedPrefix(prefix.clone()));
+        }
+
+        Ok(())
+    }
+}
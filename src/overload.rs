@@ -0,0 +1,72 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::config::OverloadConfig;
+use crate::thread_pool::QueueDepth;
+
+// What changed the last time we checked, so the caller knows whether there's something to announce
+#[derive(Eq, PartialEq, Debug)]
+pub enum Transition {
+    Degraded,
+    Recovered,
+}
+
+// Keeps track of whether the server is keeping up.  We look at two things: how many jobs are waiting for a worker,
+// and the slowest broadcast the room has done since we last checked.  If either one goes over its limit we switch to
+// degraded mode, and we only switch back once both have fallen to half their limit.  That gap is there so a server
+// sitting right on the line doesn't flap back and forth announcing itself every second.
+//
+// While degraded the server stops accepting new connections, and anything optional that can be skipped to save work
+// should check is_degraded() and skip it.
+pub struct OverloadMonitor {
+    config: OverloadConfig,
+    queue_depth: QueueDepth,
+    degraded: AtomicBool,
+    // Stored as microseconds since there's no atomic Duration
+    worst_latency: AtomicU64,
+}
+
+impl OverloadMonitor {
+    pub fn new(config: OverloadConfig, queue_depth: QueueDepth) -> OverloadMonitor {
+        OverloadMonitor {
+            config,
+            queue_depth,
+            degraded: AtomicBool::new(false),
+            worst_latency: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::SeqCst)
+    }
+
+    // Called by the room every time it broadcasts.  We only keep the worst one, a single stuck broadcast is exactly
+    // what we want to notice.
+    pub fn record_latency(&self, latency: Duration) {
+        self.worst_latency
+            .fetch_max(latency.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    // Meant to be run on a timer.  Each call looks at the window since the last call, so the latency is reset here.
+    pub fn check(&self) -> Option<Transition> {
+        let queue_depth = self.queue_depth.get();
+        let latency = Duration::from_micros(self.worst_latency.swap(0, Ordering::SeqCst));
+        let max_latency = Duration::from_millis(self.config.max_broadcast_latency_ms);
+
+        let overloaded = queue_depth > self.config.max_queue_depth || latency > max_latency;
+        let recovered =
+            queue_depth <= self.config.max_queue_depth / 2 && latency <= max_latency / 2;
+
+        if overloaded && !self.is_degraded() {
+            self.degraded.store(true, Ordering::SeqCst);
+            Some(Transition::Degraded)
+        } else if recovered && self.is_degraded() {
+            self.degraded.store(false, Ordering::SeqCst);
+            Some(Transition::Recovered)
+        } else {
+            None
+        }
+    }
+}
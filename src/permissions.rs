@@ -0,0 +1,64 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// Who someone is, as far as permissions go.  Guests haven't logged in, users have, and ops are logged in as one of the
+// names in the config's ops.  An op is a user as well, so anything allowed to users is allowed to ops.  Bots are
+// logged in to an account an op made for them with /bot create, and count as users for what little they can do.
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Guest,
+    User,
+    Op,
+    Bot,
+}
+
+impl Role {
+    fn is(&self, role: Role) -> bool {
+        *self == role || (matches!(*self, Role::Op | Role::Bot) && role == Role::User)
+    }
+}
+
+// Every command a permission can be set for, by the name it's typed as.  Chat is "chat".  The handshake, the heartbeat
+// and /quit aren't here, since nobody should be stopped from connecting or leaving.
+pub const COMMANDS: [&str; 27] = [
+    "user", "nick", "register", "login", "recover", "reset", "2fa", "sessions", "logout", "accept",
+    "answer", "kick", "mute", "ban", "unban", "banlist", "join", "mentions", "msg", "who", "away",
+    "back", "room", "bot", "file", "say", "chat",
+];
+
+// The commands that are only for ops unless the config says otherwise.  Everything else is open to everyone.
+const OP_COMMANDS: [&str; 8] = [
+    "kick", "mute", "ban", "unban", "banlist", "room", "recover", "bot",
+];
+
+// All a bot can ever do is post, whatever the config opens up to everyone else
+const BOT_COMMANDS: [&str; 2] = ["say", "chat"];
+
+// Decides who may run what.  The config's permissions map a command to the roles allowed to use it, and any command it
+// leaves out keeps its default, so a config that only opens up /who doesn't also hand /ban to everyone.
+pub struct Authorizer {
+    rules: BTreeMap<String, Vec<Role>>,
+}
+
+impl Authorizer {
+    pub fn new(permissions: &BTreeMap<String, Vec<Role>>) -> Authorizer {
+        let mut rules: BTreeMap<String, Vec<Role>> = OP_COMMANDS
+            .iter()
+            .map(|command| (String::from(*command), vec![Role::Op]))
+            .collect();
+        rules.extend(permissions.clone());
+
+        Authorizer { rules }
+    }
+
+    pub fn allows(&self, role: Role, command: &str) -> bool {
+        if role == Role::Bot && !BOT_COMMANDS.contains(&command) {
+            return false;
+        }
+        match self.rules.get(command) {
+            Some(roles) => roles.iter().any(|allowed| role.is(*allowed)),
+            None => true,
+        }
+    }
+}
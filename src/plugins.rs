@@ -0,0 +1,120 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+use crate::thread_pool::ThreadPool;
+
+// Features that live outside the server, handed to it with ChatServerBuilder::plugin.  A plugin can answer commands the
+// server doesn't have (say /weather), change chat on its way to the room, and run things on a timer.  Where hooks (see
+// hooks.rs) only watch and refuse, plugins add things.
+//
+// Like the hooks, every method except name starts out doing nothing, and they're called from whichever thread needs
+// them, so anything slow holds that thread up.  A plugin with state of its own keeps it behind a Mutex or an atomic.
+pub trait ChatPlugin: Send + Sync {
+    // What the log calls it
+    fn name(&self) -> &str;
+
+    // Once, as the server starts and before anyone can connect.  This is where a plugin schedules what it runs on a
+    // timer, and keeps the announcer if it wants to say things in the room later.
+    fn init(&self, _context: &mut PluginContext) {}
+
+    // A command the server doesn't have, e.g. "weather" and "London" for /weather London.  Taking it means returning
+    // the reply for whoever typed it, and None leaves it for the next plugin.  Any that no plugin takes is chat, like
+    // it would be without plugins.
+    fn command(&self, _sender: &str, _name: &str, _arguments: &str) -> Option<String> {
+        None
+    }
+
+    // Chat on its way to the room, returning what the room gets instead.  Each plugin is handed what the one before it
+    // returned.
+    fn transform(&self, _sender: &str, body: String) -> String {
+        body
+    }
+
+    // Once, when the server has stopped and everyone has left
+    fn shutdown(&self) {}
+}
+
+// What a plugin gets to set itself up with
+pub struct PluginContext<'a> {
+    pool: &'a ThreadPool,
+    announcer: Announcer,
+}
+
+impl PluginContext<'_> {
+    pub fn new(pool: &ThreadPool, announcer: Announcer) -> PluginContext<'_> {
+        PluginContext { pool, announcer }
+    }
+
+    // Runs the task once every interval on the server's pool, until the server shuts down
+    pub fn schedule(&mut self, interval: Duration, task: impl Fn() + Send + Sync + 'static) {
+        self.pool.execute_every(interval, task);
+    }
+
+    pub fn announcer(&self) -> Announcer {
+        self.announcer.clone()
+    }
+}
+
+// How a plugin says something in the room, which everyone sees as a notice from the server.  Once the server is gone
+// it quietly does nothing, so it's fine to hang on to.
+#[derive(Clone)]
+pub struct Announcer(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl Announcer {
+    pub fn new(announce: impl Fn(&str) + Send + Sync + 'static) -> Announcer {
+        Announcer(Arc::new(announce))
+    }
+
+    pub fn announce(&self, text: &str) {
+        (self.0)(text);
+    }
+}
+
+// Every plugin the server was given, asked in the order they were registered.  Shutting them down happens when this is
+// dropped, which is when the last of the server goes, so it happens once however the server was stopped.  Plugins that
+// never started (because the server didn't) aren't shut down.
+#[derive(Default)]
+pub struct PluginRegistry {
+    plugins: Vec<Box<dyn ChatPlugin>>,
+    started: bool,
+}
+
+impl PluginRegistry {
+    pub fn register(&mut self, plugin: Box<dyn ChatPlugin>) {
+        self.plugins.push(plugin);
+    }
+
+    pub fn init(&mut self, context: &mut PluginContext) {
+        for plugin in &self.plugins {
+            info!(plugin = plugin.name(), "Starting plugin");
+            plugin.init(context);
+        }
+        self.started = true;
+    }
+
+    pub fn command(&self, sender: &str, name: &str, arguments: &str) -> Option<String> {
+        self.plugins
+            .iter()
+            .find_map(|plugin| plugin.command(sender, name, arguments))
+    }
+
+    pub fn transform(&self, sender: &str, body: String) -> String {
+        self.plugins
+            .iter()
+            .fold(body, |body, plugin| plugin.transform(sender, body))
+    }
+}
+
+impl Drop for PluginRegistry {
+    // The last to start is the first to stop, in case it was relying on one started before it
+    fn drop(&mut self) {
+        if !self.started {
+            return;
+        }
+        for plugin in self.plugins.iter().rev() {
+            plugin.shutdown();
+            info!(plugin = plugin.name(), "Stopped plugin");
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::Instant;
+
+// A token bucket for one connection's incoming lines.  The bucket holds up to burst tokens and refills at per_second
+// tokens a second, and every line takes one.  So a client can send a quick handful of lines (like the /caps and /user
+// at connect) but can't keep up more than per_second for long.  The client uses one for bytes instead, to keep its
+// uploads under a limit (see available and spend).
+pub struct TokenBucket {
+    tokens: f64,
+    burst: f64,
+    per_second: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    // Starts full, so nobody is throttled for the first few things they say
+    pub fn new(per_second: f64, burst: u32) -> TokenBucket {
+        TokenBucket {
+            tokens: f64::from(burst),
+            burst: f64::from(burst),
+            per_second,
+            refilled: Instant::now(),
+        }
+    }
+
+    // Takes a token if there's one to take.  Tokens are topped up here, from the time since the last call, rather than
+    // by a timer.
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // How many whole tokens there are right now.  For when the caller doesn't know how many it'll use until it tries,
+    // like a write that might only get partway, and spends what it actually used afterwards.
+    pub fn available(&mut self) -> usize {
+        self.refill();
+        self.tokens as usize
+    }
+
+    pub fn spend(&mut self, tokens: usize) {
+        self.tokens -= tokens as f64;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.refilled = now;
+    }
+}
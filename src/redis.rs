@@ -0,0 +1,241 @@
+use rand_core::OsRng;
+use rand_core::RngCore;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::sync::mpsc::TrySendError;
+use std::thread;
+use std::time::Duration;
+use tracing::info;
+use tracing::warn;
+
+use crate::config::RedisConfig;
+use crate::protocol::Hlc;
+
+// Sharing the room between several server processes through Redis pub/sub, so they can sit behind a TCP load balancer
+// and it doesn't matter which one someone lands on.  Every process publishes what happens in its room (chat, joins and
+// leaves) to one channel, as JSON along with an id it picked for itself as it started, and subscribes to the same
+// channel to hear what happened in everyone else's.  Its own come back too, and are skipped.  Everything else about
+// the room, like the history and the stats, stays with the process it happened on.
+//
+// People on the other processes show up under their own names, since as far as anyone can tell it's one server.  That
+// means the processes should share an accounts file, or someone could register a name on one that's taken on another.
+//
+// Redis is spoken to directly, with just enough of its protocol for AUTH, PUBLISH and SUBSCRIBE, over plain TCP.
+
+// After the connection fails, before trying again
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// Events waiting to be published.  Past this Redis isn't keeping up, and the rest are dropped rather than holding up
+// the room.
+const QUEUE_SIZE: usize = 1024;
+
+// Anything longer than this from Redis isn't one of ours
+const MAX_BULK: usize = 64 * 1024;
+
+// Something that happened in the room on one of the processes
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    Chat { sender: String, body: String },
+    Joined { name: String },
+    Left { name: String },
+}
+
+// The clock is where the event fell in its process's order (see Hlc), kept as it is so the room is in the same order
+// on every process.  It's left out by processes from before there was one.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    server: String,
+    #[serde(default)]
+    clock: Option<String>,
+    #[serde(flatten)]
+    event: Event,
+}
+
+pub struct RedisBus {
+    server: String,
+    outgoing: mpsc::SyncSender<String>,
+}
+
+impl RedisBus {
+    // Starts publishing, on a thread of its own.  Nothing comes the other way until subscribe.
+    pub fn new(config: &RedisConfig) -> RedisBus {
+        let mut id = [0; 8];
+        OsRng.fill_bytes(&mut id);
+        let server: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        let (outgoing, queued) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        let config = config.clone();
+        thread::spawn(move || {
+            let mut connection = None;
+            for payload in queued {
+                // One try at reconnecting for each event, so a Redis that's down costs us the events said meanwhile,
+                // not a backlog that arrives all at once when it's back
+                if connection.is_none() {
+                    connection = Connection::open(&config)
+                        .map_err(|err| warn!("Unable to connect to Redis: {}", err))
+                        .ok();
+                }
+                if let Some(redis) = &mut connection {
+                    let published = redis
+                        .command(&["PUBLISH", &config.channel, &payload])
+                        .and_then(|_| redis.reply());
+                    if let Err(err) = published {
+                        warn!("Unable to publish to Redis: {}", err);
+                        connection = None;
+                    }
+                }
+            }
+        });
+
+        RedisBus { server, outgoing }
+    }
+
+    // Something that happened here, on its way to the other processes.  Never waits.
+    pub fn publish(&self, event: Event, clock: Hlc) {
+        let envelope = Envelope {
+            server: self.server.clone(),
+            clock: Some(clock.to_string()),
+            event,
+        };
+        let payload = match serde_json::to_string(&envelope) {
+            Ok(payload) => payload,
+            Err(_) => return,
+        };
+        if let Err(TrySendError::Full(_)) = self.outgoing.try_send(payload) {
+            warn!("Redis is too far behind, dropped an event");
+        }
+    }
+
+    // Starts listening for what happens on the other processes, on a thread of its own, until the process ends.  deliver
+    // is given each event, and its clock if it came with one.
+    pub fn subscribe(
+        &self,
+        config: &RedisConfig,
+        deliver: impl Fn(Event, Option<Hlc>) + Send + 'static,
+    ) {
+        let server = self.server.clone();
+        let config = config.clone();
+        thread::spawn(move || loop {
+            let subscribed = Connection::open(&config).and_then(|mut redis| -> io::Result<()> {
+                redis.command(&["SUBSCRIBE", &config.channel])?;
+                info!(channel = %config.channel, "Subscribed to Redis");
+                loop {
+                    // Messages come as ["message", channel, payload], and anything else (like the confirmation that
+                    // we've subscribed) is skipped
+                    let payload = match redis.reply()? {
+                        Reply::Array(items) if items.len() == 3 && items[0] == "message" => {
+                            items.into_iter().nth(2).unwrap_or_default()
+                        }
+                        _ => continue,
+                    };
+                    match serde_json::from_str::<Envelope>(&payload) {
+                        Ok(envelope) if envelope.server != server => deliver(
+                            envelope.event,
+                            envelope.clock.as_deref().and_then(Hlc::parse),
+                        ),
+                        Ok(_) => {}
+                        Err(err) => warn!("Skipped something from Redis we can't read: {}", err),
+                    }
+                }
+            });
+            if let Err(err) = subscribed {
+                warn!("Lost Redis subscription: {}", err);
+            }
+            thread::sleep(RETRY_DELAY);
+        });
+    }
+}
+
+// What Redis answered.  Only arrays (the messages we're subscribed to) are ever looked into, and everything in them is
+// kept as a string.
+enum Reply {
+    Value,
+    Array(Vec<String>),
+}
+
+struct Connection {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(config: &RedisConfig) -> io::Result<Connection> {
+        let writer = TcpStream::connect(config.address.as_deref().unwrap_or_default())?;
+        let reader = BufReader::new(writer.try_clone()?);
+        let mut connection = Connection { writer, reader };
+        if let Some(password) = &config.password {
+            connection.command(&["AUTH", password])?;
+            connection.reply()?;
+        }
+        Ok(connection)
+    }
+
+    // Every command is sent as an array of bulk strings, which works for any of them
+    fn command(&mut self, arguments: &[&str]) -> io::Result<()> {
+        let mut command = format!("*{}\r\n", arguments.len());
+        for argument in arguments {
+            command.push_str(&format!("${}\r\n{}\r\n", argument.len(), argument));
+        }
+        self.writer.write_all(command.as_bytes())
+    }
+
+    // Errors from Redis come back as io errors, since there's nothing we'd do differently for them
+    fn reply(&mut self) -> io::Result<Reply> {
+        let line = self.line()?;
+        let (kind, rest) = line.split_at(line.len().min(1));
+        match kind {
+            "+" | ":" => Ok(Reply::Value),
+            "-" => Err(io::Error::other(format!("Redis said {}", rest))),
+            "$" => self.bulk(rest).map(|_| Reply::Value),
+            "*" => {
+                let count: usize = rest.parse().map_err(|_| invalid())?;
+                let mut items = Vec::with_capacity(count.min(16));
+                for _ in 0..count {
+                    let line = self.line()?;
+                    let item = match line.split_at(line.len().min(1)) {
+                        ("$", length) => self.bulk(length)?,
+                        ("+" | ":", value) => String::from(value),
+                        _ => return Err(invalid()),
+                    };
+                    items.push(item);
+                }
+                Ok(Reply::Array(items))
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    fn line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(String::from(line.trim_end_matches("\r\n")))
+    }
+
+    // The body of a bulk string, given the length from its first line.  A null one (-1) is as good as empty.
+    fn bulk(&mut self, length: &str) -> io::Result<String> {
+        let length: i64 = length.parse().map_err(|_| invalid())?;
+        if length < 0 {
+            return Ok(String::new());
+        }
+        let length = length as usize;
+        if length > MAX_BULK {
+            return Err(invalid());
+        }
+        let mut bulk = vec![0; length + 2];
+        self.reader.read_exact(&mut bulk)?;
+        bulk.truncate(length);
+        Ok(String::from_utf8_lossy(&bulk).into_owned())
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "that doesn't look like Redis")
+}
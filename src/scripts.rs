@@ -0,0 +1,224 @@
+use std::io;
+use std::path::Path;
+
+// Bots that operators write themselves, as Rhai scripts (https://rhai.rs) dropped into the config's [scripts] dir.
+// Each .rhai file is one bot, named after the file, so greeter.rhai talks as "greeter".  A script defines
+//
+//     fn on_message(sender, body) {
+//         if body == "!hello" {
+//             send_message("Hello " + sender + "!");
+//         }
+//     }
+//
+// and the room calls it with every chat message, after it's been broadcast.  That's all a script can do: send_message
+// queues a reply, which goes to the room as chat from the script, and get_users lists who's in the room.  Scripts can't
+// touch files, load other scripts or eval strings, and each call stops after the configured number of operations, so a
+// broken one only costs the room a moment.  Anything a script prints ends up in our log.
+//
+// Scripts run on the room's thread, so they're skipped while we're overloaded, along with the history.  They never hear
+// each other's replies, or they could set each other off forever.
+//
+// Like TLS, Rhai is behind a cargo feature ("scripting") so a plain build doesn't need it.  Without it ScriptHost is an
+// empty enum, and asking for one is an error.
+
+#[cfg(feature = "scripting")]
+pub use enabled::*;
+
+#[cfg(not(feature = "scripting"))]
+pub use disabled::*;
+
+#[cfg(feature = "scripting")]
+mod enabled {
+    use super::*;
+    use rhai::module_resolvers::DummyModuleResolver;
+    use rhai::Array;
+    use rhai::CallFnOptions;
+    use rhai::Dynamic;
+    use rhai::Engine;
+    use rhai::Scope;
+    use rhai::AST;
+    use std::fs;
+    use std::mem;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use tracing::info;
+    use tracing::warn;
+
+    // Replies past this from one script for one message are dropped, so a loop of send_message can't flood the room
+    const MAX_REPLIES: usize = 5;
+
+    struct Script {
+        name: String,
+        ast: AST,
+    }
+
+    // What the functions a script calls work with while it's handling one message: who was in the room when it came in,
+    // and the replies it's queued so far
+    #[derive(Default)]
+    struct Turn {
+        users: Vec<String>,
+        replies: Vec<String>,
+    }
+
+    pub struct ScriptHost {
+        engine: Engine,
+        scripts: Vec<Script>,
+        turn: Arc<Mutex<Turn>>,
+    }
+
+    impl ScriptHost {
+        // Compiles every script in dir up front, so one with a mistake in it is reported at startup rather than the
+        // first time somebody talks.  Strings a script builds can't be longer than a client's messages can.
+        pub fn load(
+            dir: &Path,
+            max_operations: u64,
+            max_string_bytes: usize,
+        ) -> io::Result<ScriptHost> {
+            let mut engine = Engine::new();
+            engine.set_max_operations(max_operations);
+            engine.set_max_string_size(max_string_bytes);
+            engine.set_max_array_size(10_000);
+            engine.set_max_map_size(10_000);
+            engine.set_max_call_levels(32);
+            engine.set_module_resolver(DummyModuleResolver::new());
+            engine.disable_symbol("eval");
+            engine.on_print(|text| info!(text, "Script printed"));
+            engine.on_debug(|text, _, position| info!(text, %position, "Script debug"));
+
+            let turn = Arc::new(Mutex::new(Turn::default()));
+            let replies = turn.clone();
+            engine.register_fn("send_message", move |text: &str| {
+                replies.lock().unwrap().replies.push(String::from(text));
+            });
+            let users = turn.clone();
+            engine.register_fn("get_users", move || -> Array {
+                users
+                    .lock()
+                    .unwrap()
+                    .users
+                    .iter()
+                    .cloned()
+                    .map(Dynamic::from)
+                    .collect()
+            });
+
+            let mut paths: Vec<_> = fs::read_dir(dir)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<_>>()?;
+            paths.retain(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "rhai")
+            });
+            paths.sort();
+
+            let mut scripts = Vec::new();
+            for path in paths {
+                let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                    Some(name) => String::from(name),
+                    None => continue,
+                };
+                let ast = engine.compile_file(path.clone()).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: {}", path.display(), err),
+                    )
+                })?;
+
+                if !ast
+                    .iter_functions()
+                    .any(|function| function.name == "on_message")
+                {
+                    warn!(script = %name, "Script has no on_message, so it would never run");
+                    continue;
+                }
+                info!(script = %name, "Loaded script");
+                scripts.push(Script { name, ast });
+            }
+
+            Ok(ScriptHost {
+                engine,
+                scripts,
+                turn,
+            })
+        }
+
+        pub fn names(&self) -> Vec<&str> {
+            self.scripts
+                .iter()
+                .map(|script| script.name.as_str())
+                .collect()
+        }
+
+        // Hands a chat message to every script in turn, and returns what they want to say back as (script, reply).  A
+        // script that fails, or runs out of operations, is logged and its replies for this message are thrown away.
+        pub fn on_message(
+            &self,
+            sender: &str,
+            body: &str,
+            users: Vec<String>,
+        ) -> Vec<(&str, String)> {
+            self.turn.lock().unwrap().users = users;
+
+            let mut replies = Vec::new();
+            for script in &self.scripts {
+                // Only the function runs, never the file's top level, since there's nothing it could keep between calls
+                let result = self.engine.call_fn_with_options::<Dynamic>(
+                    CallFnOptions::new().eval_ast(false),
+                    &mut Scope::new(),
+                    &script.ast,
+                    "on_message",
+                    (String::from(sender), String::from(body)),
+                );
+
+                let mut queued = mem::take(&mut self.turn.lock().unwrap().replies);
+                if let Err(err) = result {
+                    warn!(script = %script.name, %err, "Script failed");
+                    continue;
+                }
+                if queued.len() > MAX_REPLIES {
+                    warn!(script = %script.name, replies = queued.len(), "Script replied too much, dropped the rest");
+                    queued.truncate(MAX_REPLIES);
+                }
+                replies.extend(
+                    queued
+                        .into_iter()
+                        .map(|reply| (script.name.as_str(), reply)),
+                );
+            }
+
+            replies
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+mod disabled {
+    use super::*;
+
+    pub enum ScriptHost {}
+
+    impl ScriptHost {
+        pub fn load(
+            _dir: &Path,
+            _max_operations: u64,
+            _max_string_bytes: usize,
+        ) -> io::Result<ScriptHost> {
+            Err(io::Error::other(
+                "Scripting support was not compiled in, rebuild with --features scripting",
+            ))
+        }
+
+        pub fn names(&self) -> Vec<&str> {
+            match *self {}
+        }
+
+        pub fn on_message(
+            &self,
+            _sender: &str,
+            _body: &str,
+            _users: Vec<String>,
+        ) -> Vec<(&str, String)> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use rand_core::OsRng;
+use rand_core::RngCore;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+// Finding servers through DNS SRV records (RFC 2782), so whoever runs them can move them about without every client
+// having to change its config.  The client asks for _chat._tcp.<domain> and gets back a list of host, port, priority
+// and weight.  This is just enough DNS to ask that one question of the system's resolver and read the answer, rather
+// than a whole resolver library for one record type.
+const SERVICE: &str = "_chat._tcp";
+
+// How a config asks for a lookup instead of giving an address, e.g. "srv:example.com"
+pub const PREFIX: &str = "srv:";
+
+// Who we ask when resolv.conf doesn't say
+const DEFAULT_NAMESERVER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const DNS_PORT: u16 = 53;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// The most a plain UDP answer can be.  Anything bigger comes back truncated and we ask again over TCP.
+const UDP_SIZE: usize = 512;
+
+// One server from the record
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+// The record we look up for a domain
+pub fn record_name(domain: &str) -> String {
+    format!("{}.{}", SERVICE, domain.trim_end_matches('.'))
+}
+
+// The servers listed for the domain, in the order to try them.  An empty list means the domain doesn't have any,
+// either because there's no record or because it's the single "." target that says the service isn't offered there.
+pub fn lookup(domain: &str) -> io::Result<Vec<Target>> {
+    let name = record_name(domain);
+    let id = OsRng.next_u32() as u16;
+    let query = query(id, &name)?;
+
+    // Each nameserver gets one go, and if none of them answer the error is the last one's
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no nameservers to ask");
+    for nameserver in nameservers() {
+        match ask(nameserver, id, &query) {
+            Ok(targets) => return Ok(order(targets)),
+            Err(err) => last_err = err,
+        }
+    }
+
+    Err(last_err)
+}
+
+// The nameservers from resolv.conf, like everything else on the machine uses
+fn nameservers() -> Vec<SocketAddr> {
+    let contents = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let mut nameservers: Vec<SocketAddr> = contents
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|address| address.trim().parse().ok())
+        .map(|address| SocketAddr::new(address, DNS_PORT))
+        .collect();
+    if nameservers.is_empty() {
+        nameservers.push(SocketAddr::new(DEFAULT_NAMESERVER, DNS_PORT));
+    }
+
+    nameservers
+}
+
+fn ask(nameserver: SocketAddr, id: u16, query: &[u8]) -> io::Result<Vec<Target>> {
+    let bind = if nameserver.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.connect(nameserver)?;
+    socket.send(query)?;
+
+    // Anything that isn't the answer to our question, like a late answer to an earlier one, is ignored
+    let mut buffer = [0; UDP_SIZE];
+    loop {
+        let size = socket.recv(&mut buffer)?;
+        match parse(&buffer[..size], id) {
+            Some(Answer::Truncated) => return ask_tcp(nameserver, id, query),
+            Some(answer) => return answer.into_targets(),
+            None => continue,
+        }
+    }
+}
+
+// Over TCP every message goes with its length in front
+fn ask_tcp(nameserver: SocketAddr, id: u16, query: &[u8]) -> io::Result<Vec<Target>> {
+    let mut stream = TcpStream::connect_timeout(&nameserver, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+    stream.write_all(query)?;
+
+    let mut size = [0; 2];
+    stream.read_exact(&mut size)?;
+    let mut message = vec![0; u16::from_be_bytes(size) as usize];
+    stream.read_exact(&mut message)?;
+
+    match parse(&message, id) {
+        Some(Answer::Truncated) | None => Err(invalid("the nameserver's answer didn't make sense")),
+        Some(answer) => answer.into_targets(),
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// A header asking for recursion, then our one question
+fn query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        if label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a valid domain name", name),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+enum Answer {
+    Targets(Vec<Target>),
+    // The name doesn't exist at all, which is as good as no servers
+    NoSuchName,
+    // The nameserver couldn't help, with the response code it gave
+    Failed(u8),
+    Truncated,
+}
+
+impl Answer {
+    fn into_targets(self) -> io::Result<Vec<Target>> {
+        match self {
+            Answer::Targets(targets) => Ok(targets),
+            Answer::NoSuchName => Ok(Vec::new()),
+            Answer::Failed(code) => Err(io::Error::other(format!(
+                "the nameserver couldn't answer (response code {})",
+                code
+            ))),
+            Answer::Truncated => Err(invalid("the nameserver's answer was cut short")),
+        }
+    }
+}
+
+// None if it isn't an answer to our question, or doesn't parse
+fn parse(message: &[u8], id: u16) -> Option<Answer> {
+    let header = message.get(..12)?;
+    let is_response = header[2] & 0x80 != 0;
+    if u16::from_be_bytes([header[0], header[1]]) != id || !is_response {
+        return None;
+    }
+    if header[2] & 0x02 != 0 {
+        return Some(Answer::Truncated);
+    }
+    match header[3] & 0x0f {
+        0 => {}
+        3 => return Some(Answer::NoSuchName),
+        code => return Some(Answer::Failed(code)),
+    }
+
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut position = 12;
+    for _ in 0..questions {
+        let (_, end) = read_name(message, position)?;
+        position = end + 4;
+    }
+
+    let mut targets = Vec::new();
+    for _ in 0..answers {
+        let (_, end) = read_name(message, position)?;
+        let fields = message.get(end..end + 10)?;
+        let kind = u16::from_be_bytes([fields[0], fields[1]]);
+        let length = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+        let data = end + 10;
+        position = data + length;
+        if position > message.len() {
+            return None;
+        }
+
+        // There could be a CNAME or two on the way, we only want the records themselves
+        if kind != TYPE_SRV {
+            continue;
+        }
+        let record = message.get(data..data + 6)?;
+        let (host, _) = read_name(message, data + 6)?;
+        targets.push(Target {
+            host,
+            priority: u16::from_be_bytes([record[0], record[1]]),
+            weight: u16::from_be_bytes([record[2], record[3]]),
+            port: u16::from_be_bytes([record[4], record[5]]),
+        });
+    }
+
+    // A lone target of "." means there's deliberately nothing here
+    if targets.len() == 1 && targets[0].host.is_empty() {
+        targets.clear();
+    }
+
+    Some(Answer::Targets(targets))
+}
+
+// A name, and where whatever follows it starts.  Names can end by pointing back at part of an earlier one to save
+// space, so we follow those, but only so many times in case they point in a circle.
+fn read_name(message: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut position = start;
+    let mut end = None;
+    for _ in 0..128 {
+        let length = *message.get(position)? as usize;
+        if length == 0 {
+            let name = labels.join(".");
+            return Some((name, end.unwrap_or(position + 1)));
+        }
+        if length & 0xc0 == 0xc0 {
+            let pointer = ((length & 0x3f) << 8) | *message.get(position + 1)? as usize;
+            end.get_or_insert(position + 2);
+            position = pointer;
+            continue;
+        }
+
+        let label = message.get(position + 1..position + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        position += 1 + length;
+    }
+
+    None
+}
+
+// Lowest priority first.  Within a priority, a weighted shuffle, so a target with twice the weight of another is
+// tried first about twice as often.
+fn order(mut targets: Vec<Target>) -> Vec<Target> {
+    // The RFC puts zero weights first, so they still get a chance when the pick lands on zero
+    targets.sort_by_key(|target| (target.priority, target.weight != 0));
+
+    let mut ordered = Vec::new();
+    while !targets.is_empty() {
+        let priority = targets[0].priority;
+        let same = targets
+            .iter()
+            .take_while(|target| target.priority == priority)
+            .count();
+        let total: u32 = targets[..same]
+            .iter()
+            .map(|target| u32::from(target.weight))
+            .sum();
+
+        let pick = OsRng.next_u32() % (total + 1);
+        let mut running = 0;
+        let chosen = targets[..same]
+            .iter()
+            .position(|target| {
+                running += u32::from(target.weight);
+                running >= pick
+            })
+            .unwrap_or(0);
+        ordered.push(targets.remove(chosen));
+    }
+
+    ordered
+}
@@ -0,0 +1,244 @@
+use crate::protocol::CAPS_COMMAND;
+use crate::protocol::FILE_COMMAND;
+use crate::protocol::PING_COMMAND;
+use crate::protocol::PONG_COMMAND;
+
+// One line from a client, sorted into what it's asking for.  Anything that doesn't start with a command is chat.  One
+// that starts with a command we don't know is Other, which the server offers its plugins (see plugins.rs) and
+// otherwise treats as chat too, so "/shrug" still gets through to the room.
+//
+// Commands start with the connection's prefix, which is / unless the server or client picked something else (see
+// Capabilities).  Two prefixes in a row is how to say something that starts with one, so "//shrug" is the chat
+// "/shrug", and so is "/say /shrug".  The handshake and heartbeat are part of the protocol rather than commands anyone
+// types, so they always use /.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Command<'a> {
+    Caps(&'a str),
+    User(&'a str),
+    Nick(&'a str),
+    Register(&'a str),
+    Login(&'a str),
+    Recover(&'a str),
+    Reset(&'a str),
+    TwoFactor(&'a str),
+    Sessions,
+    Logout(&'a str),
+    Accept,
+    Answer(&'a str),
+    Kick(&'a str),
+    Mute(&'a str),
+    Ban(&'a str),
+    Unban(&'a str),
+    BanList,
+    Join(&'a str),
+    Mentions(&'a str),
+    Msg(&'a str),
+    Who(&'a str),
+    Away(&'a str),
+    Back,
+    Room(&'a str),
+    Bot(&'a str),
+    // The whole line, which is one of FILE_COMMAND's (see FileMessage)
+    File(&'a str),
+    Ping,
+    Pong,
+    Quit,
+    // Chat that's allowed to look like a command, e.g. "/say /user is how you pick a name"
+    Say(&'a str),
+    Chat(&'a str),
+    // The name and arguments, e.g. ("weather", "London") for "/weather London"
+    Other(&'a str, &'a str),
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(line: &'a str, prefix: char) -> Command<'a> {
+        let (word, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        match word {
+            _ if word == CAPS_COMMAND => return Command::Caps(rest),
+            _ if word == PING_COMMAND => return Command::Ping,
+            _ if word == PONG_COMMAND => return Command::Pong,
+            _ if word == FILE_COMMAND => return Command::File(line),
+            _ => {}
+        }
+
+        let name = match word.strip_prefix(prefix) {
+            Some(name) if name.starts_with(prefix) => {
+                return Command::Chat(&line[prefix.len_utf8()..])
+            }
+            Some(name) => name,
+            None => return Command::Chat(line),
+        };
+
+        match name {
+            "user" => Command::User(rest),
+            "nick" => Command::Nick(rest),
+            "register" => Command::Register(rest),
+            "login" => Command::Login(rest),
+            "recover" => Command::Recover(rest),
+            "reset" => Command::Reset(rest),
+            "2fa" => Command::TwoFactor(rest),
+            "sessions" => Command::Sessions,
+            "logout" => Command::Logout(rest),
+            "accept" => Command::Accept,
+            "answer" => Command::Answer(rest),
+            "kick" => Command::Kick(rest),
+            "mute" => Command::Mute(rest),
+            "ban" => Command::Ban(rest),
+            "unban" => Command::Unban(rest),
+            "banlist" => Command::BanList,
+            "join" => Command::Join(rest),
+            "mentions" => Command::Mentions(rest),
+            "msg" => Command::Msg(rest),
+            "who" => Command::Who(rest),
+            "away" => Command::Away(rest),
+            "back" => Command::Back,
+            "room" => Command::Room(rest),
+            "bot" => Command::Bot(rest),
+            "quit" => Command::Quit,
+            "say" => Command::Say(rest),
+            _ => Command::Other(name, rest),
+        }
+    }
+}
+
+impl Command<'_> {
+    // The name permissions know the command by (see permissions.rs), if it's one they cover
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self {
+            Command::Caps(_) | Command::Ping | Command::Pong | Command::Quit => return None,
+            Command::User(_) => "user",
+            Command::Nick(_) => "nick",
+            Command::Register(_) => "register",
+            Command::Login(_) => "login",
+            Command::Recover(_) => "recover",
+            Command::Reset(_) => "reset",
+            Command::TwoFactor(_) => "2fa",
+            Command::Sessions => "sessions",
+            Command::Logout(_) => "logout",
+            Command::Accept => "accept",
+            Command::Answer(_) => "answer",
+            Command::Kick(_) => "kick",
+            Command::Mute(_) => "mute",
+            Command::Ban(_) => "ban",
+            Command::Unban(_) => "unban",
+            Command::BanList => "banlist",
+            Command::Join(_) => "join",
+            Command::Mentions(_) => "mentions",
+            Command::Msg(_) => "msg",
+            Command::Who(_) => "who",
+            Command::Away(_) => "away",
+            Command::Back => "back",
+            Command::Room(_) => "room",
+            Command::Bot(_) => "bot",
+            Command::File(_) => "file",
+            Command::Say(_) => "say",
+            // Whatever a plugin makes of it, it's typed in the room like chat
+            Command::Chat(_) | Command::Other(..) => "chat",
+        })
+    }
+}
+
+// The steps of the welcome a connection can be stuck on after picking a name (see WelcomeConfig)
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Welcome {
+    Rules,
+    Challenge { answer: u32 },
+}
+
+// Where a connection is in its life.  They always move forward through these, apart from skipping steps:
+//
+//   Connected -> Handshaking -> Authenticated -> InRoom -> Closing
+//
+// Handshaking is only for clients that send /caps, and Authenticated only lasts while there's some of the welcome left
+// to do.  Which commands are allowed depends on the state, see check.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ConnectionState {
+    // Nothing from the client yet
+    Connected,
+    // Capabilities agreed, waiting for a name
+    Handshaking,
+    // Has a name, but hasn't finished the welcome
+    Authenticated(Welcome),
+    InRoom,
+    // On the way out, nothing else gets handled
+    Closing,
+}
+
+// Why a command was turned down.  What the client gets told is from describe, since it names other commands and those
+// start with the connection's prefix.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ProtocolError {
+    CapsTooLate,
+    NoName,
+    NotInRoom(ConnectionState),
+    NothingToAccept,
+    NothingToAnswer,
+    Closing,
+}
+
+impl ProtocolError {
+    pub fn describe(&self, prefix: char) -> String {
+        match self {
+            ProtocolError::CapsTooLate => {
+                format!("{} has to come before anything else", CAPS_COMMAND)
+            }
+            ProtocolError::NoName
+            | ProtocolError::NotInRoom(ConnectionState::Connected)
+            | ProtocolError::NotInRoom(ConnectionState::Handshaking) => {
+                format!("Pick a name with {}user <name> first", prefix)
+            }
+            ProtocolError::NotInRoom(ConnectionState::Authenticated(Welcome::Rules)) => {
+                format!("Type {}accept to agree to the rules first", prefix)
+            }
+            ProtocolError::NotInRoom(ConnectionState::Authenticated(Welcome::Challenge {
+                ..
+            })) => {
+                format!("Answer the question with {}answer <number> first", prefix)
+            }
+            ProtocolError::NotInRoom(_) => String::from("You're not in the room"),
+            ProtocolError::NothingToAccept => String::from("There's nothing to accept"),
+            ProtocolError::NothingToAnswer => String::from("There's no question to answer"),
+            ProtocolError::Closing => String::from("This connection is closing"),
+        }
+    }
+}
+
+impl ConnectionState {
+    // Whether the command makes sense right now.  This only says yes or no, moving to the next state is up to whoever
+    // carries the command out.
+    pub fn check(&self, command: &Command) -> Result<(), ProtocolError> {
+        use ConnectionState::*;
+
+        match (self, command) {
+            (Closing, _) => Err(ProtocolError::Closing),
+            (_, Command::Quit) => Ok(()),
+            // The heartbeat keeps going whatever else is happening
+            (_, Command::Ping) | (_, Command::Pong) => Ok(()),
+
+            (Connected, Command::Caps(_)) => Ok(()),
+            (_, Command::Caps(_)) => Err(ProtocolError::CapsTooLate),
+
+            // Picking a name (or a new one) is fine at any point
+            (_, Command::User(_)) | (_, Command::Login(_)) => Ok(()),
+            // So is getting back into an account with a recovery token, which picks the name as well
+            (_, Command::Reset(_)) => Ok(()),
+
+            (Connected, Command::Register(_)) | (Handshaking, Command::Register(_)) => {
+                Err(ProtocolError::NoName)
+            }
+            (_, Command::Register(_)) => Ok(()),
+
+            (Authenticated(Welcome::Rules), Command::Accept) => Ok(()),
+            (_, Command::Accept) => Err(ProtocolError::NothingToAccept),
+
+            (Authenticated(Welcome::Challenge { .. }), Command::Answer(_)) => Ok(()),
+            (_, Command::Answer(_)) => Err(ProtocolError::NothingToAnswer),
+
+            // Everything else is for people in the room
+            (InRoom, _) => Ok(()),
+            (state, _) => Err(ProtocolError::NotInRoom(*state)),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+// What /room stats shows.  Everything is over the last window, except members which is right now.
+pub struct StatsReport {
+    pub window: Duration,
+    pub messages_per_hour: f64,
+    pub active_speakers: usize,
+    pub members: usize,
+    pub peak_members: usize,
+    // Most messages first
+    pub top_talkers: Vec<(String, usize)>,
+}
+
+// Keeps a rolling window of what happened in a room.  The room feeds it every message as it goes past, and anything
+// older than the window is forgotten the next time it's touched, so it only ever holds the last window's worth.
+//
+// This is cheap enough to run right in the room: every call is a few pushes and pops, nothing touches the disk.
+pub struct RoomStats {
+    window: Duration,
+    started: Instant,
+    // Who said something and when, oldest first
+    messages: VecDeque<(Instant, String)>,
+    members: usize,
+    // The member count after each change, so we can find the peak within the window
+    membership: VecDeque<(Instant, usize)>,
+    // The count going into the window, which is gone from membership but still counts towards the peak
+    members_at_cutoff: usize,
+}
+
+impl RoomStats {
+    pub fn new(window: Duration) -> RoomStats {
+        RoomStats {
+            window,
+            started: Instant::now(),
+            messages: VecDeque::new(),
+            members: 0,
+            membership: VecDeque::new(),
+            members_at_cutoff: 0,
+        }
+    }
+
+    pub fn record_message(&mut self, sender: &str) {
+        let now = Instant::now();
+        self.messages.push_back((now, String::from(sender)));
+        self.prune(now);
+    }
+
+    pub fn member_joined(&mut self) {
+        self.members += 1;
+        self.record_membership();
+    }
+
+    pub fn member_left(&mut self) {
+        self.members = self.members.saturating_sub(1);
+        self.record_membership();
+    }
+
+    fn record_membership(&mut self) {
+        let now = Instant::now();
+        self.membership.push_back((now, self.members));
+        self.prune(now);
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let cutoff = match now.checked_sub(self.window) {
+            Some(cutoff) => cutoff,
+            None => return,
+        };
+
+        while matches!(self.messages.front(), Some((at, _)) if *at < cutoff) {
+            self.messages.pop_front();
+        }
+        while matches!(self.membership.front(), Some((at, _)) if *at < cutoff) {
+            if let Some((_, members)) = self.membership.pop_front() {
+                self.members_at_cutoff = members;
+            }
+        }
+    }
+
+    pub fn members(&self) -> usize {
+        self.members
+    }
+
+    pub fn report(&mut self, top: usize) -> StatsReport {
+        let now = Instant::now();
+        self.prune(now);
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (_, sender) in &self.messages {
+            *counts.entry(sender).or_insert(0) += 1;
+        }
+
+        // Ties go alphabetically so the list doesn't jump around between calls
+        let mut top_talkers: Vec<(String, usize)> = counts
+            .iter()
+            .map(|(sender, count)| (String::from(*sender), *count))
+            .collect();
+        top_talkers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_talkers.truncate(top);
+
+        // A server that's only been up ten minutes shouldn't have its rate spread over the whole hour, but a few seconds
+        // is too little to go on, so we call it at least a minute
+        let covered = self.window.min(now.duration_since(self.started));
+        let hours = covered.as_secs_f64().max(60.0) / 3600.0;
+
+        let peak_members = self
+            .membership
+            .iter()
+            .map(|(_, members)| *members)
+            .fold(self.members.max(self.members_at_cutoff), usize::max);
+
+        StatsReport {
+            window: self.window,
+            messages_per_hour: self.messages.len() as f64 / hours,
+            active_speakers: counts.len(),
+            members: self.members,
+            peak_members,
+            top_talkers,
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// One row of history.  System messages (joins, notices) have no sender.
+pub struct StoredMessage<'a> {
+    pub room: &'a str,
+    pub sender: Option<&'a str>,
+    pub kind: &'a str,
+    pub body: &'a str,
+    pub timestamp: SystemTime,
+}
+
+// What a room got up to between two points in time, as pulled out of the history for the daily digest
+pub struct Summary {
+    pub message_count: u64,
+    // Everyone who said something, in alphabetical order
+    pub active_users: Vec<String>,
+    pub first_message: Option<SystemTime>,
+    pub last_message: Option<SystemTime>,
+    // Sender and text of each pin, oldest first
+    pub pins: Vec<(String, String)>,
+}
+
+fn to_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis as u64)
+}
+
+// How busy one room was during one hour of local time, e.g. "2024-03-01 14:00"
+#[derive(Serialize)]
+pub struct HourlyActivity {
+    pub room: String,
+    pub hour: String,
+    pub messages: u64,
+    pub speakers: u64,
+}
+
+// One row of history as it's handed out, e.g. by the HTTP API.  Time is milliseconds since the unix epoch, the same as
+// it's stored.
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    pub sender: Option<String>,
+    pub kind: String,
+    pub body: String,
+    pub time: i64,
+}
+
+// Message history in an embedded SQLite database, so it survives a restart.  A rusqlite Connection can be sent between
+// threads but not shared, so it lives behind a mutex.  Every call here touches the disk, so they belong on the blocking
+// pool rather than in the room itself.
+pub struct Storage {
+    connection: Mutex<Connection>,
+}
+
+impl Storage {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Storage> {
+        let connection = Connection::open(path)?;
+
+        // WAL lets readers keep going while we write, and we'd rather not fsync every single chat line
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;
+             CREATE TABLE IF NOT EXISTS messages (
+                 id INTEGER PRIMARY KEY,
+                 room TEXT NOT NULL,
+                 sender TEXT,
+                 kind TEXT NOT NULL,
+                 timestamp INTEGER NOT NULL,
+                 body TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS messages_room_timestamp ON messages (room, timestamp);",
+        )?;
+
+        Ok(Storage {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // For tools that only look at the history, like the activity export.  Unlike open, this won't create an empty
+    // database if the path is wrong.
+    pub fn open_read_only(path: impl AsRef<Path>) -> rusqlite::Result<Storage> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        Ok(Storage {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // When the newest message was sent, or None for an empty history
+    pub fn latest(&self) -> rusqlite::Result<Option<SystemTime>> {
+        let latest: Option<i64> = self.connection.lock().unwrap().query_row(
+            "SELECT MAX(timestamp) FROM messages",
+            [],
+            |row| row.get(0),
+        )?;
+
+        Ok(latest.map(from_millis))
+    }
+
+    pub fn insert(&self, message: &StoredMessage) -> rusqlite::Result<()> {
+        // Milliseconds since the unix epoch, which sorts properly and doesn't need a date library to store
+        let timestamp = to_millis(message.timestamp);
+
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO messages (room, sender, kind, timestamp, body) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                message.room,
+                message.sender,
+                message.kind,
+                timestamp,
+                message.body
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    // Only chat counts as a message here, joins and notices aren't anybody talking.  The range includes from but not
+    // to, so back to back ranges never count a message twice.
+    pub fn summarize(
+        &self,
+        room: &str,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> rusqlite::Result<Summary> {
+        let connection = self.connection.lock().unwrap();
+        let (from, to) = (to_millis(from), to_millis(to));
+
+        let (message_count, first, last): (i64, Option<i64>, Option<i64>) = connection.query_row(
+            "SELECT COUNT(*), MIN(timestamp), MAX(timestamp) FROM messages
+             WHERE room = ?1 AND kind = 'chat' AND timestamp >= ?2 AND timestamp < ?3",
+            params![room, from, to],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+
+        let mut statement = connection.prepare(
+            "SELECT DISTINCT sender FROM messages
+             WHERE room = ?1 AND kind = 'chat' AND sender IS NOT NULL AND timestamp >= ?2 AND timestamp < ?3
+             ORDER BY sender",
+        )?;
+        let active_users = statement
+            .query_map(params![room, from, to], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        let mut statement = connection.prepare(
+            "SELECT sender, body FROM messages
+             WHERE room = ?1 AND kind = 'pin' AND timestamp >= ?2 AND timestamp < ?3
+             ORDER BY timestamp",
+        )?;
+        let pins = statement
+            .query_map(params![room, from, to], |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                    row.get(1)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+
+        Ok(Summary {
+            message_count: message_count as u64,
+            active_users,
+            first_message: first.map(from_millis),
+            last_message: last.map(from_millis),
+            pins,
+        })
+    }
+
+    // Chat messages and distinct speakers per room per hour, bucketed by the server's local time.  Hours where nobody
+    // said anything are left out rather than returned as zeros.
+    pub fn hourly_activity(
+        &self,
+        room: Option<&str>,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> rusqlite::Result<Vec<HourlyActivity>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT room, strftime('%Y-%m-%d %H:00', timestamp / 1000, 'unixepoch', 'localtime') AS hour,
+                    COUNT(*), COUNT(DISTINCT sender)
+             FROM messages
+             WHERE kind = 'chat' AND timestamp >= ?1 AND timestamp < ?2 AND (?3 IS NULL OR room = ?3)
+             GROUP BY room, hour
+             ORDER BY room, hour",
+        )?;
+
+        let rows = statement.query_map(params![to_millis(from), to_millis(to), room], |row| {
+            Ok(HourlyActivity {
+                room: row.get(0)?,
+                hour: row.get(1)?,
+                messages: row.get::<_, i64>(2)? as u64,
+                speakers: row.get::<_, i64>(3)? as u64,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    // The last limit things said in the room after since (chat and pins, none of the comings and goings), oldest first
+    pub fn recent_chat(
+        &self,
+        room: &str,
+        since: SystemTime,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<HistoryEntry>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT sender, kind, body, timestamp FROM messages
+             WHERE room = ?1 AND timestamp > ?2 AND kind IN ('chat', 'pin')
+             ORDER BY timestamp DESC, id DESC
+             LIMIT ?3",
+        )?;
+
+        let rows = statement.query_map(params![room, to_millis(since), limit as i64], |row| {
+            Ok(HistoryEntry {
+                sender: row.get(0)?,
+                kind: row.get(1)?,
+                body: row.get(2)?,
+                time: row.get(3)?,
+            })
+        })?;
+
+        let mut entries = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    // Everything in the room after since (not including it), oldest first, at most limit of it
+    pub fn messages_since(
+        &self,
+        room: &str,
+        since: SystemTime,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<HistoryEntry>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT sender, kind, body, timestamp FROM messages
+             WHERE room = ?1 AND timestamp > ?2
+             ORDER BY timestamp, id
+             LIMIT ?3",
+        )?;
+
+        let rows = statement.query_map(params![room, to_millis(since), limit as i64], |row| {
+            Ok(HistoryEntry {
+                sender: row.get(0)?,
+                kind: row.get(1)?,
+                body: row.get(2)?,
+                time: row.get(3)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+}
@@ -1,9 +1,28 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::sync::Condvar;
 use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+use crate::timer::RepeatingJob;
+use crate::timer::Timer;
+use crate::timer::TimerHandle;
+
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
 
 // A good example of using a fancier enum to allow for additional information to be passed along.  We can pattern
 // match to get the value of the Job in NewJob.
@@ -12,33 +31,528 @@ enum Message {
     Terminate,
 }
 
+// Which jobs a free worker takes first.  Anything waiting at a higher priority goes ahead of everything at a lower
+// one, and jobs at the same priority go in the order they came.  That means a steady stream of high priority work can
+// keep the low priority jobs waiting indefinitely, so High is for things that are small and can't wait, like the room.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+const PRIORITIES: usize = 3;
+
+// Jobs waiting for a worker, one line per priority.  A plain channel can only hand things out in the order they went
+// in, so this is a mutex and a couple of condvars instead, much like the blocking pool.
+struct Queue {
+    state: Mutex<QueueState>,
+    // Idle workers wait on this for a job (or a terminate)
+    not_empty: Condvar,
+    // execute waits on this for room when the queue is full
+    not_full: Condvar,
+    capacity: usize,
+}
+
+struct QueueState {
+    jobs: [VecDeque<Job>; PRIORITIES],
+    // Workers we've told to stop.  Each one goes once there are no jobs left for it, so shutting down still runs
+    // everything that was queued first.  These don't count towards the capacity.
+    terminates: usize,
+}
+
+impl QueueState {
+    fn len(&self) -> usize {
+        self.jobs.iter().map(VecDeque::len).sum()
+    }
+}
+
+impl Queue {
+    fn new(capacity: usize) -> Queue {
+        Queue {
+            state: Mutex::new(QueueState {
+                jobs: Default::default(),
+                terminates: 0,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+        }
+    }
+
+    // Waits for room if it's full
+    fn push(&self, priority: Priority, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        while state.len() >= self.capacity {
+            state = self.not_full.wait(state).unwrap();
+        }
+        state.jobs[priority as usize].push_back(job);
+        self.not_empty.notify_one();
+    }
+
+    // Hands the job back if it's full
+    fn try_push(&self, priority: Priority, job: Job) -> Result<(), Job> {
+        let mut state = self.state.lock().unwrap();
+        if state.len() >= self.capacity {
+            return Err(job);
+        }
+        state.jobs[priority as usize].push_back(job);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    fn terminate(&self, count: usize) {
+        self.state.lock().unwrap().terminates += count;
+        self.not_empty.notify_all();
+    }
+
+    // The oldest job at the highest priority there is, waiting for one if there's nothing to do
+    fn pop(&self) -> Message {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.jobs.iter_mut().rev().find_map(VecDeque::pop_front) {
+                self.not_full.notify_one();
+                return Message::NewJob(job);
+            }
+            if state.terminates > 0 {
+                state.terminates -= 1;
+                return Message::Terminate;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+}
+
+// What try_execute says when the queue already has as many jobs waiting as it can hold.  The job is dropped.
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the job queue is full")
+    }
+}
+
+// A cheap, cloneable, read-only view of how many jobs are sitting in the queue waiting for a worker.  Handing this out
+// instead of the pool itself lets things like the overload monitor keep an eye on us from inside a job.
+#[derive(Clone)]
+pub struct QueueDepth(Arc<AtomicUsize>);
+
+impl QueueDepth {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Same idea, for how many jobs the workers have finished since the pool started
+#[derive(Clone)]
+pub struct JobsCompleted(Arc<AtomicU64>);
+
+impl JobsCompleted {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// And for how many panicked instead.  The worker lives through it, but anything above zero is a bug worth finding.
+// The BlockingPool keeps one of its own.
+#[derive(Clone, Default)]
+pub struct JobsPanicked(Arc<AtomicU64>);
+
+impl JobsPanicked {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn record(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Everything a debugger or the metrics might want to know about how busy the pool is right now, in one view
+#[derive(Clone)]
+pub struct PoolState(Counters);
+
+impl PoolState {
+    // Jobs a worker is running right now
+    pub fn active_jobs(&self) -> usize {
+        self.0.busy.load(Ordering::SeqCst)
+    }
+
+    // Jobs waiting for a worker
+    pub fn queued_jobs(&self) -> usize {
+        self.0.queued.load(Ordering::SeqCst)
+    }
+
+    // Worker threads that are still running.  Right after the pool shrinks this can be more than size() for a while.
+    pub fn worker_count(&self) -> usize {
+        self.0.workers.load(Ordering::SeqCst)
+    }
+}
+
+// Why a job didn't give us its result
+#[derive(Debug)]
+pub enum JobError {
+    // What it panicked with, when that was a string (it nearly always is)
+    Panicked(String),
+    // The pool shut down before a worker got to it
+    NeverRan,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::Panicked(message) => write!(f, "panicked: {}", message),
+            JobError::NeverRan => write!(f, "never ran"),
+        }
+    }
+}
+
+// What execute hands back, a lot like the JoinHandle from thread::spawn.  The caller can check now and then whether
+// the job has finished, or wait for its result.  Anyone who doesn't care can just drop it, and the job goes on
+// regardless.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JobError>>,
+    // Kept here once is_finished has seen it, until join asks for it
+    result: Option<Result<T, JobError>>,
+}
+
+impl<T> JobHandle<T> {
+    // Never waits, so it's fine to call from a loop that has other things to do
+    pub fn is_finished(&mut self) -> bool {
+        if self.result.is_none() {
+            self.result = match self.receiver.try_recv() {
+                Ok(result) => Some(result),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => Some(Err(JobError::NeverRan)),
+            };
+        }
+
+        self.result.is_some()
+    }
+
+    // Wait for the job to finish, however long that takes, and hand over what it returned
+    pub fn join(self) -> Result<T, JobError> {
+        match self.result {
+            Some(result) => result,
+            None => self.receiver.recv().unwrap_or(Err(JobError::NeverRan)),
+        }
+    }
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => String::from("(not a string)"),
+        },
+    }
+}
+
+// How often the scaler thread looks at the queue, when the pool scales itself
+const SCALE_CHECK: Duration = Duration::from_millis(100);
+
+// How often shutdown_timeout checks whether a worker has finished yet
+const SHUTDOWN_CHECK: Duration = Duration::from_millis(10);
+
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    // Shared with the scaler thread, which resizes the pool while jobs are being handed out
+    workers: Arc<Mutex<Workers>>,
+    queue: Arc<Queue>,
+    timer: Option<Timer>,
+    // Only there when the pool scales itself.  Dropping the sender tells the scaler thread to stop.
+    scaler: Option<(mpsc::Sender<()>, thread::JoinHandle<()>)>,
+    counters: Counters,
+}
+
+// What the workers keep up to date for everyone else to read
+#[derive(Clone)]
+struct Counters {
+    queued: Arc<AtomicUsize>,
+    // Workers in the middle of a job right now
+    busy: Arc<AtomicUsize>,
+    // Worker threads still running, including any that have been told to stop but are finishing a job first
+    workers: Arc<AtomicUsize>,
+    completed: Arc<AtomicU64>,
+    panicked: Arc<AtomicU64>,
+}
+
+// The workers themselves and everything it takes to start more or stop some
+struct Workers {
+    list: Vec<Worker>,
+    // How many there'll be once any we've told to stop have finished their last job
+    size: usize,
+    next_id: usize,
+    queue: Arc<Queue>,
+    counters: Counters,
+}
+
+impl Workers {
+    fn resize(&mut self, size: usize) {
+        // Anyone who's already stopped is forgotten.  Their threads are done, so there's nothing to wait for.
+        self.list.retain(|worker| {
+            worker
+                .thread
+                .as_ref()
+                .is_some_and(|thread| !thread.is_finished())
+        });
+
+        // Growing is just starting more.  Shrinking can't pick which workers go, since most of ours are busy with a
+        // client for as long as it's connected, so it queues a terminate for each one too many and lets whichever
+        // workers are free take them.
+        for _ in self.size..size {
+            self.list.push(Worker::new(
+                self.next_id,
+                self.queue.clone(),
+                self.counters.clone(),
+            ));
+            self.next_id += 1;
+        }
+        self.queue.terminate(self.size.saturating_sub(size));
+
+        debug!("Thread pool resized from {} to {} workers", self.size, size);
+        self.size = size;
+    }
 }
 
 impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
+    // The queue holds at most queue_capacity jobs waiting for a worker.  Past that execute waits for room, and
+    // try_execute gives up straight away, so whoever's handing out jobs faster than we can run them is held back
+    // rather than the queue growing until we run out of memory.
+    pub fn new(size: usize, queue_capacity: usize) -> ThreadPool {
         assert!(size > 0);
+        assert!(queue_capacity > 0);
+
+        let queue = Arc::new(Queue::new(queue_capacity));
+        let counters = Counters {
+            queued: Arc::new(AtomicUsize::new(0)),
+            busy: Arc::new(AtomicUsize::new(0)),
+            workers: Arc::new(AtomicUsize::new(0)),
+            completed: Arc::new(AtomicU64::new(0)),
+            panicked: Arc::new(AtomicU64::new(0)),
+        };
 
+        let mut workers = Workers {
+            list: Vec::with_capacity(size),
+            size: 0,
+            next_id: 0,
+            queue: queue.clone(),
+            counters: counters.clone(),
+        };
+        workers.resize(size);
+
+        // The timer shares the queue so that anything it decides is due goes in with the jobs handed to execute.  It
+        // never waits for room, since that would hold up every other timer too, so a job that's due when the queue is
+        // full is skipped (a repeating one gets another go next time round).
+        let timer_queue = queue.clone();
+        let timer_queued = counters.queued.clone();
+        let timer = Timer::new(move |job| {
+            timer_queued.fetch_add(1, Ordering::SeqCst);
+            if timer_queue.try_push(Priority::Normal, job).is_err() {
+                timer_queued.fetch_sub(1, Ordering::SeqCst);
+                warn!("Job queue is full, skipping a timer job");
+            }
+        });
+
+        ThreadPool {
+            workers: Arc::new(Mutex::new(workers)),
+            queue,
+            timer: Some(timer),
+            scaler: None,
+            counters,
+        }
+    }
+
+    // Waits for room in the queue if it's full
+    pub fn execute<T, R>(&self, func: T) -> JobHandle<R>
+    where
+        T: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.execute_with_priority(Priority::Normal, func)
+    }
+
+    // Same, but ahead of (or behind) the jobs at other priorities
+    pub fn execute_with_priority<T, R>(&self, priority: Priority, func: T) -> JobHandle<R>
+    where
+        T: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (job, handle) = ThreadPool::job(func);
+
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+        self.queue.push(priority, job);
+
+        handle
+    }
+
+    // Never waits, for callers that would rather turn the work away than be held up
+    pub fn try_execute<T, R>(&self, func: T) -> Result<JobHandle<R>, QueueFull>
+    where
+        T: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.try_execute_with_priority(Priority::Normal, func)
+    }
+
+    pub fn try_execute_with_priority<T, R>(
+        &self,
+        priority: Priority,
+        func: T,
+    ) -> Result<JobHandle<R>, QueueFull>
+    where
+        T: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (job, handle) = ThreadPool::job(func);
+
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+        match self.queue.try_push(priority, job) {
+            Ok(()) => Ok(handle),
+            Err(_) => {
+                self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+                Err(QueueFull)
+            }
+        }
+    }
+
+    fn job<T, R>(func: T) -> (Job, JobHandle<R>)
+    where
+        T: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        // A panic is handed back as the result, then carries on up to the worker, which logs it and keeps going.
+        // Nobody listening (the handle was dropped) is fine too.
         let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let job: Job = Box::new(move || match panic::catch_unwind(AssertUnwindSafe(func)) {
+            Ok(value) => {
+                let _ = sender.send(Ok(value));
+            }
+            Err(payload) => {
+                let _ = sender.send(Err(JobError::Panicked(panic_message(&*payload))));
+                panic::resume_unwind(payload);
+            }
+        });
 
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, receiver.clone()));
+        let handle = JobHandle {
+            receiver,
+            result: None,
+        };
+        (job, handle)
+    }
+
+    // Start or stop workers until there are this many.  Busy workers always finish what they're doing first, so a
+    // smaller pool can take a while to actually shrink.
+    pub fn resize(&self, size: usize) {
+        assert!(size > 0);
+        self.workers.lock().unwrap().resize(size);
+    }
+
+    // How many workers there are, not counting any on their way out
+    pub fn size(&self) -> usize {
+        self.workers.lock().unwrap().size
+    }
+
+    // Let the pool size itself between what it is now and max.  A thread of its own keeps an eye on the queue: any job
+    // left waiting means every worker is busy, so we add enough workers for all of them.  Once more than one worker
+    // has been sitting idle for idle_after we stop one, and keep doing that until we're back down to where we started
+    // or the spare is needed.  That one spare stays so a timer job never has to wait on us to notice it.
+    //
+    // This has to be its own thread rather than a job of ours, since the time it matters most is when no worker is
+    // free to run it.
+    pub fn autoscale(&mut self, max: usize, idle_after: Duration) {
+        let min = self.size();
+        assert!(max >= min);
+        if self.scaler.is_some() {
+            return;
         }
 
-        ThreadPool { workers, sender }
+        let (stop, stopped) = mpsc::channel::<()>();
+        let workers = self.workers.clone();
+        let counters = self.counters.clone();
+        let scaler = thread::spawn(move || {
+            let mut idle_since: Option<Instant> = None;
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(SCALE_CHECK) {
+                let mut workers = workers.lock().unwrap();
+                let waiting = counters.queued.load(Ordering::SeqCst);
+                let idle = workers
+                    .size
+                    .saturating_sub(counters.busy.load(Ordering::SeqCst));
+
+                if waiting > 0 && workers.size < max {
+                    let size = (workers.size + waiting).min(max);
+                    info!(waiting, "Growing the thread pool to {} workers", size);
+                    workers.resize(size);
+                    idle_since = None;
+                } else if idle > 1 && workers.size > min {
+                    let since = *idle_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= idle_after {
+                        let size = workers.size - 1;
+                        info!(idle, "Shrinking the thread pool to {} workers", size);
+                        workers.resize(size);
+                        // Another full idle_after before the next one goes
+                        idle_since = None;
+                    }
+                } else {
+                    idle_since = None;
+                }
+            }
+        });
+
+        self.scaler = Some((stop, scaler));
+    }
+
+    pub fn active_jobs(&self) -> usize {
+        self.state().active_jobs()
+    }
+
+    pub fn queued_jobs(&self) -> usize {
+        self.state().queued_jobs()
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.state().worker_count()
+    }
+
+    pub fn state(&self) -> PoolState {
+        PoolState(self.counters.clone())
+    }
+
+    pub fn queue_depth(&self) -> QueueDepth {
+        QueueDepth(self.counters.queued.clone())
     }
 
-    pub fn execute<T>(&self, func: T)
+    pub fn jobs_completed(&self) -> JobsCompleted {
+        JobsCompleted(self.counters.completed.clone())
+    }
+
+    pub fn jobs_panicked(&self) -> JobsPanicked {
+        JobsPanicked(self.counters.panicked.clone())
+    }
+
+    // Run a job once after the delay has passed.  The job still runs on one of our workers, the timer only decides
+    // when it gets queued.
+    pub fn execute_after<T>(&self, delay: Duration, func: T) -> TimerHandle
     where
         T: FnOnce() + Send + 'static,
     {
-        let job = Message::NewJob(Box::new(func));
+        self.timer().schedule_once(delay, Box::new(func))
+    }
 
-        self.sender.send(job).unwrap()
+    // Run a job over and over, once per interval, until the returned handle is cancelled or the pool is dropped.  This
+    // is meant to replace the ad-hoc "loop { sleep; do_thing }" threads for periodic work.
+    pub fn execute_every<T>(&self, interval: Duration, func: T) -> TimerHandle
+    where
+        T: Fn() + Send + Sync + 'static,
+    {
+        let job: RepeatingJob = Arc::new(func);
+        self.timer().schedule_every(interval, job)
+    }
+
+    fn timer(&self) -> &Timer {
+        // The timer is only ever taken when we shut down, so it's always there while anyone can still call us
+        self.timer.as_ref().unwrap()
     }
 }
 
@@ -46,20 +560,93 @@ impl ThreadPool {
 // and shut everything down.
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        println!("Sending terminate to all workers");
+        // Nothing to report, waiting forever means every job finished
+        let _ = self.shutdown(None);
+    }
+}
 
-        // One of those tricks with concurrency, we can guarantee that the terminate message is the last message any
-        // of our workers will get, so we don't need to worry about one thread consuming multiple terminate messages
-        for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+// What shutdown_timeout had to give up on
+#[derive(Debug)]
+pub struct Unfinished {
+    // Each worker still in the middle of a job, by thread name, and how long that job had been running
+    pub stuck: Vec<(String, Duration)>,
+    // Jobs still waiting in the queue, which will never run now
+    pub queued: usize,
+}
+
+impl fmt::Display for Unfinished {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} job(s) never finished", self.stuck.len())?;
+        for (index, (name, running)) in self.stuck.iter().enumerate() {
+            let separator = if index == 0 { ": " } else { ", " };
+            write!(f, "{}{} (running {}s)", separator, name, running.as_secs())?;
         }
+        if self.queued > 0 {
+            write!(f, ", and {} queued job(s) never started", self.queued)?;
+        }
+        Ok(())
+    }
+}
+
+impl ThreadPool {
+    // Dropping the pool waits for every job to finish, however long that takes, so one hung job hangs shutdown with
+    // it.  This waits at most timeout instead.  Any worker still busy after that is left behind, its thread detached
+    // to carry on (or not) until the process exits, and the error says which workers those were.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Result<(), Unfinished> {
+        self.shutdown(Some(Instant::now() + timeout))
+    }
+
+    fn shutdown(&mut self, deadline: Option<Instant>) -> Result<(), Unfinished> {
+        // The scaler goes first so it can't start or stop anyone while we're shutting down, then the timer so it can't
+        // queue any new jobs behind our terminate messages.  No timer means we've already been shut down, and this is
+        // the drop that comes after shutdown_timeout.
+        if let Some((stop, scaler)) = self.scaler.take() {
+            drop(stop);
+            scaler.join().unwrap();
+        }
+        if self.timer.take().is_none() {
+            return Ok(());
+        }
+
+        info!("Sending terminate to all workers");
+        let mut workers = self.workers.lock().unwrap();
+
+        // One of those tricks with concurrency, we can guarantee that the terminate message is the last message any
+        // of our workers will get, so we don't need to worry about one thread consuming multiple terminate messages.
+        // Any we told to stop when shrinking already have theirs waiting in the queue, so this is one for each of the
+        // rest.
+        self.queue.terminate(workers.size);
 
         // We used an option here, because we must take ownership in order to join the thread.  Option allows us to
         // swap the Some value in our worker with a None value.
-        for worker in &mut self.workers {
+        let mut stuck = Vec::new();
+        for worker in &mut workers.list {
             // If for whatever reason we had already processed this worker, this pattern match would fail (it would
             // be None instead of Some(thread)).
             if let Some(thread) = worker.thread.take() {
+                // Join has no timeout, so with a deadline we wait for the thread to finish first and only join once
+                // it has.  Dropping the handle of one that hasn't detaches it.
+                if let Some(deadline) = deadline {
+                    while !thread.is_finished() && Instant::now() < deadline {
+                        thread::sleep(SHUTDOWN_CHECK);
+                    }
+                    if !thread.is_finished() {
+                        let name = thread.thread().name().unwrap_or("worker").to_string();
+                        let running = worker
+                            .busy_since
+                            .lock()
+                            .unwrap()
+                            .map_or(Duration::ZERO, |since| since.elapsed());
+                        warn!(
+                            "Gave up waiting for {}, its job has been running for {}s",
+                            name,
+                            running.as_secs()
+                        );
+                        stuck.push((name, running));
+                        continue;
+                    }
+                }
+
                 // Join is actually defined as join(self) instead of join(&self).  This means it will consume the
                 // variable it is called on, not allowing us to use it again.
                 thread.join().unwrap();
@@ -68,7 +655,14 @@ impl Drop for ThreadPool {
                 // thread.join().unwrap();
             }
 
-            println!("Shutdown worker {}", worker.id);
+            debug!("Shutdown worker {}", worker.id);
+        }
+
+        let queued = self.counters.queued.load(Ordering::SeqCst);
+        if stuck.is_empty() && queued == 0 {
+            Ok(())
+        } else {
+            Err(Unfinished { stuck, queued })
         }
     }
 }
@@ -76,33 +670,60 @@ impl Drop for ThreadPool {
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
+    // When the job it's running started, if it's running one, for reporting the ones that hold up shutdown
+    busy_since: Arc<Mutex<Option<Instant>>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+    fn new(id: usize, queue: Arc<Queue>, counters: Counters) -> Worker {
+        // Named so they're easy to pick out in a debugger, top -H, or a panic message
+        let builder = thread::Builder::new().name(format!("chat-worker-{}", id));
+        counters.workers.fetch_add(1, Ordering::SeqCst);
+        let busy_since = Arc::new(Mutex::new(None));
+        let started = busy_since.clone();
+
         // Really simple message loop, a message is either a job to execute or a termination.
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
+        let thread = builder.spawn(move || loop {
+            let message = queue.pop();
 
             match message {
                 Message::NewJob(job) => {
-                    println!("Worker {} got a job; executing.", id);
-
-                    job();
+                    counters.queued.fetch_sub(1, Ordering::SeqCst);
+                    counters.busy.fetch_add(1, Ordering::SeqCst);
+                    *started.lock().unwrap() = Some(Instant::now());
+                    debug!("Worker {} got a job; executing.", id);
 
-                    println!("Worker {} finished job.", id);
+                    // A panic would otherwise end this thread for good and quietly leave the pool one worker
+                    // short, so we catch it here and go back for the next job as if this one had finished.  Jobs
+                    // share nothing with us but the queue, which we don't hold while they run, so there's nothing
+                    // they could have left half done on our side.
+                    match panic::catch_unwind(AssertUnwindSafe(job)) {
+                        Ok(()) => {
+                            counters.completed.fetch_add(1, Ordering::Relaxed);
+                            debug!("Worker {} finished job.", id);
+                        }
+                        Err(payload) => {
+                            counters.panicked.fetch_add(1, Ordering::Relaxed);
+                            error!("Worker {} job panicked: {}", id, panic_message(&*payload));
+                        }
+                    }
+                    *started.lock().unwrap() = None;
+                    counters.busy.fetch_sub(1, Ordering::SeqCst);
                 }
                 Message::Terminate => {
-                    println!("Worker {} terminating", id);
+                    debug!("Worker {} terminating", id);
+                    counters.workers.fetch_sub(1, Ordering::SeqCst);
 
                     break;
                 }
             }
         });
+        let thread = thread.unwrap();
 
         Worker {
             id,
             thread: Some(thread),
+            busy_since,
         }
     }
 }
@@ -0,0 +1,189 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::thread_pool::Job;
+
+// The wheel turns one slot every tick, so anything scheduled is rounded up to the next tick.  10ms lines up with the
+// sleeps we already use elsewhere, and 512 slots covers a little over 5 seconds per revolution.  Anything further out
+// than that just rides around the wheel a few extra times.
+const TICK: Duration = Duration::from_millis(10);
+const SLOTS: usize = 512;
+
+// A repeating job has to be callable more than once, so it can't be a FnOnce like our normal jobs.  We share it with
+// an Arc so each firing can hand a cheap clone off to the thread pool.
+pub type RepeatingJob = Arc<dyn Fn() + Send + Sync + 'static>;
+
+enum Task {
+    Once(Job),
+    Every(RepeatingJob, Duration),
+}
+
+struct Entry {
+    // How many more full turns of the wheel to wait before this entry is due
+    rounds: usize,
+    task: Task,
+    cancelled: Arc<AtomicBool>,
+}
+
+enum Command {
+    Schedule(Duration, Task, Arc<AtomicBool>),
+    Shutdown,
+}
+
+// Returned from every schedule call so the caller can stop a job before it fires (or stop a repeating job from
+// firing again).  Dropping the handle does not cancel anything, so fire-and-forget callers can just ignore it.
+#[derive(Clone)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// A hashed timer wheel.  Rather than keeping every timer sorted by deadline, we drop each one into a bucket based on
+// how many ticks away it is, and on every tick we only look at a single bucket.  Scheduling and firing are both
+// constant time, which is exactly what we want when lots of small features (keepalives, mutes, slow mode) each want
+// a timer of their own.
+struct Wheel {
+    slots: Vec<Vec<Entry>>,
+    cursor: usize,
+}
+
+impl Wheel {
+    fn new() -> Wheel {
+        let mut slots = Vec::with_capacity(SLOTS);
+        slots.resize_with(SLOTS, Vec::new);
+
+        Wheel { slots, cursor: 0 }
+    }
+
+    fn insert(&mut self, delay: Duration, task: Task, cancelled: Arc<AtomicBool>) {
+        let tick = TICK.as_millis();
+        let ticks = delay.as_millis().div_ceil(tick).max(1) as usize;
+
+        let slot = (self.cursor + ticks) % SLOTS;
+        let rounds = (ticks - 1) / SLOTS;
+        self.slots[slot].push(Entry {
+            rounds,
+            task,
+            cancelled,
+        });
+    }
+
+    // Move the wheel forward one slot and hand back anything that's due.  Entries that still have rounds to go are
+    // put back in the same slot for next time around.
+    fn advance(&mut self) -> Vec<Entry> {
+        self.cursor = (self.cursor + 1) % SLOTS;
+
+        let (due, waiting) = self.slots[self.cursor]
+            .drain(..)
+            .filter(|entry| !entry.cancelled.load(Ordering::SeqCst))
+            .partition(|entry| entry.rounds == 0);
+
+        self.slots[self.cursor] = waiting;
+        for entry in &mut self.slots[self.cursor] {
+            entry.rounds -= 1;
+        }
+
+        due
+    }
+}
+
+pub struct Timer {
+    sender: mpsc::Sender<Command>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    // The timer doesn't run any jobs itself, it just decides when they're due and passes them to dispatch (which for
+    // the thread pool means queueing them for a worker).  Keeping the timer thread free of real work is what keeps
+    // the ticks on time.
+    pub fn new<F>(dispatch: F) -> Timer
+    where
+        F: Fn(Job) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || Timer::run(receiver, dispatch));
+
+        Timer {
+            sender,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn schedule_once(&self, delay: Duration, job: Job) -> TimerHandle {
+        self.schedule(delay, Task::Once(job))
+    }
+
+    pub fn schedule_every(&self, interval: Duration, job: RepeatingJob) -> TimerHandle {
+        self.schedule(interval, Task::Every(job, interval))
+    }
+
+    fn schedule(&self, delay: Duration, task: Task) -> TimerHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        // If the timer thread is already gone we're shutting down, and there's nobody left to run the job anyway
+        self.sender
+            .send(Command::Schedule(delay, task, cancelled.clone()))
+            .ok();
+
+        TimerHandle { cancelled }
+    }
+
+    fn run(receiver: mpsc::Receiver<Command>, dispatch: impl Fn(Job)) {
+        let mut wheel = Wheel::new();
+        let mut next_tick = Instant::now() + TICK;
+
+        loop {
+            // Sleep until the next tick, but wake up early if somebody schedules something
+            let timeout = next_tick.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(Command::Schedule(delay, task, cancelled)) => {
+                    wheel.insert(delay, task, cancelled)
+                }
+                Ok(Command::Shutdown) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+
+            // If we fell behind (a busy machine, or a slow dispatch) catch up one slot at a time so nothing is skipped
+            while Instant::now() >= next_tick {
+                next_tick += TICK;
+
+                for entry in wheel.advance() {
+                    match entry.task {
+                        Task::Once(job) => dispatch(job),
+                        Task::Every(job, interval) => {
+                            let repeat = job.clone();
+                            dispatch(Box::new(move || repeat()));
+                            wheel.insert(interval, Task::Every(job, interval), entry.cancelled);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Same idea as the thread pool: stop the thread when we leave scope.  Anything still on the wheel is dropped without
+// running.
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.sender.send(Command::Shutdown).ok();
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
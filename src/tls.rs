@@ -0,0 +1,242 @@
+use std::io;
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::transport::Stream;
+
+// TLS support is behind the "tls" cargo feature so a plain build doesn't need to compile a crypto library.  The
+// acceptor and connector types exist either way so the server and client can hold an Option of them without any cfg
+// attributes of their own.  Without the feature they're empty enums, which can never be constructed, so the only
+// thing you can do is ask for one and get an error back.
+
+#[cfg(feature = "tls")]
+pub use enabled::*;
+
+#[cfg(not(feature = "tls"))]
+pub use disabled::*;
+
+#[cfg(feature = "tls")]
+mod enabled {
+    use super::*;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+    use rustls::pki_types::PrivateKeyDer;
+    use rustls::pki_types::ServerName;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn invalid_data(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+
+    // rustls needs to be told which crypto library to use.  We pick ring explicitly rather than relying on a process
+    // wide default being installed somewhere.
+    fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+        Arc::new(rustls::crypto::ring::default_provider())
+    }
+
+    // Holds the server certificate and key, loaded once at startup and shared by every connection
+    pub struct TlsAcceptor {
+        config: Arc<rustls::ServerConfig>,
+    }
+
+    impl TlsAcceptor {
+        pub fn from_files(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+            let certs = CertificateDer::pem_file_iter(cert_path)
+                .map_err(invalid_data)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(invalid_data)?;
+            let key = PrivateKeyDer::from_pem_file(key_path).map_err(invalid_data)?;
+
+            let config = rustls::ServerConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .map_err(invalid_data)?
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .map_err(invalid_data)?;
+
+            Ok(TlsAcceptor {
+                config: Arc::new(config),
+            })
+        }
+
+        // Runs the handshake to completion before handing the stream back.  This blocks, so it should be called from
+        // the connection's own worker and never from the accept loop, with a timeout on the socket so a client that
+        // stops halfway can't keep it waiting forever.
+        pub fn accept(&self, mut tcp: TcpStream) -> io::Result<Stream> {
+            let mut connection =
+                rustls::ServerConnection::new(self.config.clone()).map_err(invalid_data)?;
+            while connection.is_handshaking() {
+                connection.complete_io(&mut tcp)?;
+            }
+
+            Ok(Stream::ServerTls(Box::new(rustls::StreamOwned::new(
+                connection, tcp,
+            ))))
+        }
+    }
+
+    // Holds the certificates we trust.  With no CA file we fall back to the usual public roots, which is what you want
+    // for a server with a real certificate.  For a self-signed server, pass its certificate as the CA.
+    pub struct TlsConnector {
+        config: Arc<rustls::ClientConfig>,
+    }
+
+    impl TlsConnector {
+        pub fn new(ca_path: Option<&Path>) -> io::Result<TlsConnector> {
+            let mut roots = rustls::RootCertStore::empty();
+            match ca_path {
+                Some(path) => {
+                    for cert in CertificateDer::pem_file_iter(path).map_err(invalid_data)? {
+                        roots
+                            .add(cert.map_err(invalid_data)?)
+                            .map_err(invalid_data)?;
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+
+            let config = rustls::ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .map_err(invalid_data)?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+            Ok(TlsConnector {
+                config: Arc::new(config),
+            })
+        }
+
+        // Same as accept, the handshake is finished before we return.  The server name is checked against the
+        // certificate the server sends, and can be a hostname or an IP address.
+        pub fn connect(&self, server_name: &str, mut tcp: TcpStream) -> io::Result<Stream> {
+            let server_name =
+                ServerName::try_from(server_name.to_string()).map_err(invalid_data)?;
+            let mut connection = rustls::ClientConnection::new(self.config.clone(), server_name)
+                .map_err(invalid_data)?;
+            while connection.is_handshaking() {
+                connection.complete_io(&mut tcp)?;
+            }
+
+            Ok(Stream::ClientTls(Box::new(rustls::StreamOwned::new(
+                connection, tcp,
+            ))))
+        }
+    }
+
+    // When the first certificate in the file (the server's own, ahead of any chain) stops being valid.  rustls doesn't
+    // look at that until a client connects, and then the client is the one that finds out, so "doctor" asks here.
+    pub fn certificate_expiry(cert_path: &Path) -> io::Result<SystemTime> {
+        let cert = CertificateDer::pem_file_iter(cert_path)
+            .map_err(invalid_data)?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "there's no certificate in it")
+            })?
+            .map_err(invalid_data)?;
+
+        not_after(&cert).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "couldn't find when the certificate expires",
+            )
+        })
+    }
+
+    // Splits the DER element at the start of der into its tag, its contents and whatever follows it
+    fn element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let (&tag, rest) = der.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        // Short lengths fit in the one byte, longer ones say how many bytes they take up after it
+        let (length, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return None;
+            }
+            let (length, rest) = rest.split_at(count);
+            let length = length
+                .iter()
+                .fold(0, |length, &byte| length << 8 | byte as usize);
+            (length, rest)
+        };
+
+        if rest.len() < length {
+            return None;
+        }
+        let (contents, rest) = rest.split_at(length);
+        Some((tag, contents, rest))
+    }
+
+    // Walks just far enough into the certificate (RFC 5280 section 4.1) to reach notAfter, rather than pulling in a
+    // whole X.509 library for one date:
+    //
+    //   Certificate ::= SEQUENCE { tbsCertificate, ... }
+    //   tbsCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature, issuer, validity, ... }
+    //   validity ::= SEQUENCE { notBefore, notAfter }
+    fn not_after(der: &[u8]) -> Option<SystemTime> {
+        let (_, certificate, _) = element(der)?;
+        let (_, tbs, _) = element(certificate)?;
+        // Version 1 certificates leave the version out, and then the first thing is the serial number
+        let (tag, _, mut rest) = element(tbs)?;
+        if tag == 0xa0 {
+            rest = element(rest)?.2;
+        }
+        let (_, _, rest) = element(rest)?;
+        let (_, _, rest) = element(rest)?;
+        let (_, validity, _) = element(rest)?;
+        let (_, _, validity) = element(validity)?;
+        let (tag, time, _) = element(validity)?;
+
+        // UTCTime only has two digits of year, which mean 1950 to 2049.  GeneralizedTime has all four.
+        let time = std::str::from_utf8(time).ok()?;
+        let time = match tag {
+            0x17 if time.get(..2)? < "50" => format!("20{}", time),
+            0x17 => format!("19{}", time),
+            0x18 => String::from(time),
+            _ => return None,
+        };
+        let time = chrono::NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ").ok()?;
+
+        Some(SystemTime::from(time.and_utc()))
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+mod disabled {
+    use super::*;
+
+    fn unsupported() -> io::Error {
+        io::Error::other("TLS support was not compiled in, rebuild with --features tls")
+    }
+
+    pub enum TlsAcceptor {}
+
+    impl TlsAcceptor {
+        pub fn from_files(_cert_path: &Path, _key_path: &Path) -> io::Result<TlsAcceptor> {
+            Err(unsupported())
+        }
+
+        pub fn accept(&self, _tcp: TcpStream) -> io::Result<Stream> {
+            match *self {}
+        }
+    }
+
+    pub enum TlsConnector {}
+
+    impl TlsConnector {
+        pub fn new(_ca_path: Option<&Path>) -> io::Result<TlsConnector> {
+            Err(unsupported())
+        }
+
+        pub fn connect(&self, _server_name: &str, _tcp: TcpStream) -> io::Result<Stream> {
+            match *self {}
+        }
+    }
+
+    pub fn certificate_expiry(_cert_path: &Path) -> io::Result<SystemTime> {
+        Err(unsupported())
+    }
+}
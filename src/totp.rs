@@ -0,0 +1,185 @@
+use hmac::Hmac;
+use hmac::Mac;
+use sha1::Sha1;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Time-based one-time passwords (RFC 6238), the six digit codes authenticator apps show.  We and the app share a
+// secret, and the code for each 30 second step is an HMAC of the step number keyed with it, cut down to six digits.
+// These are the settings every app assumes when the otpauth URI doesn't say otherwise.
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+// How many steps either side of now still count, for clocks that are a little out and people who are a little slow
+const WINDOW: u64 = 1;
+
+// Shown in the app next to the name, so people can tell this account apart from their others
+const ISSUER: &str = "chat_server";
+
+// Secrets are written in base32, which is what goes in the URI and what people type in if they can't scan it
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / STEP_SECS)
+        .unwrap_or(0)
+}
+
+fn code(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes a key of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // The last four bits pick where in the hash the code comes from
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
+
+// Which step the code was for, if it's right for any step close enough to this one
+pub fn verify(secret: &[u8], code: &str, step: u64) -> Option<u64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    (step.saturating_sub(WINDOW)..=step + WINDOW).find(|&step| self::code(secret, step) == code)
+}
+
+// What authenticator apps scan to add the account, usually from a QR code
+pub fn uri(account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&digits={}&period={}",
+        percent_encode(ISSUER),
+        percent_encode(account),
+        to_base32(secret),
+        percent_encode(ISSUER),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Five bits to a character, without the = padding, which the apps don't want
+pub fn to_base32(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    text
+}
+
+pub fn from_base32(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = ALPHABET.iter().position(|&letter| letter == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 key from RFC 6238 Appendix B
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    // Appendix B gives eight digits, and our six are the last six of them
+    #[test]
+    fn codes_match_the_rfc_6238_vectors() {
+        let vectors = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ];
+        for (time, expected) in vectors {
+            assert_eq!(code(SECRET, time / STEP_SECS), expected, "at {}", time);
+        }
+    }
+
+    #[test]
+    fn verify_takes_a_step_either_side() {
+        let step = 1111111109 / STEP_SECS;
+        for found in [step - 1, step, step + 1] {
+            let code = format!("{:06}", code(SECRET, found));
+            assert_eq!(verify(SECRET, &code, step), Some(found));
+        }
+        for outside in [step - 2, step + 2] {
+            let code = format!("{:06}", code(SECRET, outside));
+            assert_eq!(verify(SECRET, &code, step), None);
+        }
+    }
+
+    #[test]
+    fn verify_keeps_leading_zeros_and_refuses_anything_else() {
+        let step = 1111111109 / STEP_SECS;
+        assert_eq!(verify(SECRET, "081804", step), Some(step));
+        assert_eq!(verify(SECRET, "81804", step), None);
+        assert_eq!(verify(SECRET, "0818040", step), None);
+        assert_eq!(verify(SECRET, "08180a", step), None);
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648_without_padding() {
+        assert_eq!(to_base32(b""), "");
+        assert_eq!(to_base32(b"f"), "MY");
+        assert_eq!(to_base32(b"fo"), "MZXQ");
+        assert_eq!(to_base32(b"foo"), "MZXW6");
+        assert_eq!(to_base32(b"foob"), "MZXW6YQ");
+        assert_eq!(to_base32(b"fooba"), "MZXW6YTB");
+        assert_eq!(to_base32(b"foobar"), "MZXW6YTBOI");
+    }
+
+    // Five bytes fit exactly into eight characters, so these are the lengths where the last character is part padding
+    #[test]
+    fn base32_round_trips_lengths_that_arent_multiples_of_five() {
+        let bytes: Vec<u8> = (0..=255).rev().collect();
+        for length in [1, 2, 3, 4, 6, 7, 8, 9, 11, 33] {
+            let text = to_base32(&bytes[..length]);
+            assert_eq!(from_base32(&text).as_deref(), Some(&bytes[..length]));
+        }
+    }
+
+    #[test]
+    fn from_base32_refuses_letters_outside_the_alphabet() {
+        assert_eq!(from_base32("MZXW6YTB01"), None);
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+// Files on their way from one person to another, by the id the sender picked (see FileMessage in chat_protocol).  The
+// server only keeps track of who's sending what to whom and how far it's got, never the file itself.  Chunks are
+// passed along as they come, and anything sent directly between the clients doesn't touch us at all until the
+// receiver says it's done.
+pub struct Transfer {
+    // The connection that offered it, under what name, and who to
+    pub sender: u64,
+    pub from: String,
+    pub to: String,
+    // The connection that took it through the server, which is the only one that gets the chunks.  Until someone does,
+    // any connection logged in to the name can.
+    pub receiver: Option<u64>,
+    pub size: u64,
+    // How much of it has been through us, which is where the next chunk has to start
+    pub sent: u64,
+    offered: Instant,
+}
+
+#[derive(Default)]
+pub struct Transfers {
+    transfers: HashMap<String, Transfer>,
+}
+
+impl Transfers {
+    // False if the id is already taken, which a sender picking them at random should never see
+    pub fn offer(&mut self, id: &str, sender: u64, from: &str, to: &str, size: u64) -> bool {
+        if self.transfers.contains_key(id) {
+            return false;
+        }
+        self.transfers.insert(
+            String::from(id),
+            Transfer {
+                sender,
+                from: String::from(from),
+                to: String::from(to),
+                receiver: None,
+                size,
+                sent: 0,
+                offered: Instant::now(),
+            },
+        );
+        true
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Transfer> {
+        self.transfers.get_mut(id)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Transfer> {
+        self.transfers.remove(id)
+    }
+
+    // Offers nobody has taken within the timeout, which are forgotten.  Ones that are already going through us are left
+    // alone, however long they take.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<(String, Transfer)> {
+        self.take(|transfer| transfer.receiver.is_none() && transfer.offered.elapsed() >= timeout)
+    }
+
+    // Everything the connection was sending, or taking through us, since none of it can finish once it's gone
+    pub fn disconnected(&mut self, connection: u64) -> Vec<(String, Transfer)> {
+        self.take(|transfer| transfer.sender == connection || transfer.receiver == Some(connection))
+    }
+
+    fn take(&mut self, matches: impl Fn(&Transfer) -> bool) -> Vec<(String, Transfer)> {
+        let ids: Vec<String> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| matches(transfer))
+            .map(|(id, _)| id.clone())
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| self.transfers.remove_entry(&id))
+            .collect()
+    }
+}
@@ -0,0 +1,120 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::os::unix::prelude::AsRawFd;
+use std::os::unix::prelude::RawFd;
+
+#[cfg(feature = "websocket")]
+use crate::websocket::WebSocket;
+
+// One side of a chat connection, which is either a plain TcpStream or a TcpStream wrapped in TLS, or on the server a
+// WebSocket over either of those.  Both the client and the server only ever talk to a Stream, so everything past the
+// connect/accept doesn't care which one it got.
+//
+// The TLS and WebSocket variants are boxed because they're quite a bit bigger than a TcpStream, and we'd rather not
+// make every plain connection pay for that.
+pub enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    ServerTls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+    #[cfg(feature = "tls")]
+    ClientTls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<WebSocket>),
+}
+
+impl Stream {
+    // The socket underneath, for the handful of things (polling, socket options) that TLS doesn't change
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Stream::ServerTls(stream) => &stream.sock,
+            #[cfg(feature = "tls")]
+            Stream::ClientTls(stream) => &stream.sock,
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => stream.inner().tcp(),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.tcp().set_nonblocking(nonblocking)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.tcp().set_nodelay(nodelay)
+    }
+
+    // Tells the other side we won't be sending anything else, while still letting us read what they send.  TLS gets a
+    // close_notify first, so they can tell a real goodbye from a dropped connection, and a WebSocket a close frame
+    // before that.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(_) => {}
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => return stream.shutdown(),
+            #[cfg(feature = "tls")]
+            Stream::ServerTls(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()?;
+            }
+            #[cfg(feature = "tls")]
+            Stream::ClientTls(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()?;
+            }
+        }
+
+        self.tcp().shutdown(Shutdown::Write)
+    }
+}
+
+// These just hand the call to whichever stream we're holding.  For TLS, rustls takes care of encrypting on the way out
+// and decrypting on the way in.
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::ServerTls(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::ClientTls(stream) => stream.read(buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::ServerTls(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::ClientTls(stream) => stream.write(buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::ServerTls(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::ClientTls(stream) => stream.flush(),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => stream.flush(),
+        }
+    }
+}
+
+// popol polls file descriptors, so this is what lets a Stream be registered as a source
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tcp().as_raw_fd()
+    }
+}
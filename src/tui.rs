@@ -0,0 +1,807 @@
+use crate::cancel::CancellationToken;
+use crate::chat_client::ChatClientError;
+use crate::chat_client::CommandSender;
+use crate::chat_client::EventReceiver;
+use crate::error::Error;
+
+// The client with a screen of its own, picked with "client --tui" in a build with the "tui" feature.  The chat scrolls
+// by in the middle, who's in the room is down the right, a status bar says where we're connected, and what's typed goes
+// on the bottom line.  Underneath it's the same as the line by line client: connect() does the talking to the server
+// and hands us its events, and what's typed goes back as commands, so everything the plain client can do works here
+// too.  Lines are shown by the same Renderer, so /filter and /timestamps work as well.
+//
+// Keys: Enter sends, Page Up and Page Down scroll back through the chat, and Ctrl-C quits like /quit does.  Each pane
+// only keeps its last few thousand lines in memory (scrollback in chat_client.toml), so a session left open for a week
+// doesn't keep growing.  Older ones
+// are written out to a file in the temp directory and read back a page at a time when someone scrolls that far, and
+// the file goes when we do.
+//
+// Direct messages (/msg <name> <message>) get a pane of their own beside the room, one conversation at a time.  It opens
+// with the first one, and whatever's typed goes to whichever pane has the focus: Tab moves it between the two, Ctrl-N
+// opens the next conversation, and Esc closes the pane.  In the direct pane a command still goes as it is, so "/who"
+// asks the server as usual, and "//who" says "/who" to them.
+//
+// Like TLS, the feature is off by default so a plain build doesn't need ratatui.  Without it run only returns an
+// error, before connecting.
+
+#[cfg(feature = "tui")]
+pub use enabled::*;
+
+#[cfg(not(feature = "tui"))]
+pub use disabled::*;
+
+type Connection = Result<(EventReceiver, CommandSender), ChatClientError>;
+
+#[cfg(feature = "tui")]
+mod enabled {
+    use super::*;
+    use chrono::Local;
+    use chrono::TimeZone;
+    use ratatui::crossterm::event;
+    use ratatui::crossterm::event::Event;
+    use ratatui::crossterm::event::KeyCode;
+    use ratatui::crossterm::event::KeyEvent;
+    use ratatui::crossterm::event::KeyEventKind;
+    use ratatui::crossterm::event::KeyModifiers;
+    use ratatui::layout::Constraint;
+    use ratatui::layout::Layout;
+    use ratatui::layout::Position;
+    use ratatui::layout::Rect;
+    use ratatui::style::Color;
+    use ratatui::style::Modifier;
+    use ratatui::style::Style;
+    use ratatui::text::Line;
+    use ratatui::text::Span;
+    use ratatui::widgets::Block;
+    use ratatui::widgets::Borders;
+    use ratatui::widgets::List;
+    use ratatui::widgets::Paragraph;
+    use ratatui::Frame;
+    use std::collections::VecDeque;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::io::BufWriter;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+    use std::mem;
+    use std::ops::Range;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::TryRecvError;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use crate::chat_client::ClientEvent;
+    use crate::chat_client::Renderer;
+    use crate::chat_client::Shown;
+    use crate::chat_client::Tone;
+    use crate::protocol::Direct;
+    use crate::protocol::MentionKind;
+    use crate::protocol::NoticeKind;
+
+    // How many lines are read back from a spill file at a time
+    const PAGE_IN: usize = 200;
+
+    // Makes each spill file's name different from the others we have open
+    static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+    // How wide the roster is, borders and all
+    const SIDEBAR_WIDTH: u16 = 20;
+
+    // How much of the screen beside the roster the direct messages get, when they're open
+    const DIRECT_PERCENT: u16 = 40;
+
+    // How long we wait on the keyboard before checking for events again
+    const POLL: Duration = Duration::from_millis(10);
+
+    // Sets up the screen and connects, then shows the events and sends on what's typed until they run out.  The screen
+    // goes back the way it was before we return, and anything the server said as we were thrown out or dropped is
+    // printed after, since it would go with the screen otherwise.
+    pub fn run(
+        connect: impl FnOnce() -> Connection,
+        prefix: char,
+        scrollback: usize,
+        cancel: CancellationToken,
+    ) -> Result<(), Error> {
+        let mut terminal = ratatui::try_init()?;
+        let (events, commands) = match connect() {
+            Ok(connection) => connection,
+            Err(err) => {
+                ratatui::restore();
+                return Err(err.into());
+            }
+        };
+
+        let mut screen = Screen::new(scrollback);
+        let shown = screen.relay(&mut terminal, &events, &commands, prefix, &cancel);
+        if shown.is_err() {
+            commands.quit();
+        }
+        ratatui::restore();
+
+        if let Some(reason) = &screen.ended {
+            println!("{}", reason);
+        }
+        let finished = events.finish();
+        shown?;
+        Ok(finished?)
+    }
+
+    // Everything on the screen
+    struct Screen {
+        renderer: Renderer,
+        room: Buffer,
+        // Everyone we've had direct messages with, in the order they started, and which of them is open beside the
+        // room, if any
+        directs: Vec<Conversation>,
+        open: Option<usize>,
+        // Whether what's typed goes to the open conversation rather than the room
+        focus_direct: bool,
+        roster: Vec<String>,
+        // How many lines each pane keeps in memory
+        scrollback: usize,
+        // Where we're connected, or why we're not
+        status: String,
+        // Why the connection went, if it has
+        ended: Option<String>,
+        // Only said when something went wrong first, the same as run (see ChatClient::show)
+        fell_back: bool,
+        input: Vec<char>,
+        cursor: usize,
+    }
+
+    impl Screen {
+        fn new(scrollback: usize) -> Screen {
+            Screen {
+                renderer: Renderer::new(true),
+                room: Buffer::new(scrollback),
+                directs: Vec::new(),
+                open: None,
+                focus_direct: false,
+                roster: Vec::new(),
+                scrollback,
+                status: String::from("Connecting"),
+                ended: None,
+                fell_back: false,
+                input: Vec::new(),
+                cursor: 0,
+            }
+        }
+
+        fn relay(
+            &mut self,
+            terminal: &mut ratatui::DefaultTerminal,
+            events: &EventReceiver,
+            commands: &CommandSender,
+            prefix: char,
+            cancel: &CancellationToken,
+        ) -> io::Result<()> {
+            terminal.draw(|frame| self.draw(frame))?;
+            loop {
+                if cancel.is_cancelled() {
+                    commands.quit();
+                }
+
+                let mut changed = false;
+                loop {
+                    match events.try_recv() {
+                        Ok(event) => {
+                            self.event(event);
+                            changed = true;
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return Ok(()),
+                    }
+                }
+
+                if event::poll(POLL)? {
+                    match event::read()? {
+                        Event::Key(key) if key.kind == KeyEventKind::Press => {
+                            self.key(key, commands, prefix)
+                        }
+                        _ => {}
+                    }
+                    changed = true;
+                }
+
+                if changed {
+                    terminal.draw(|frame| self.draw(frame))?;
+                }
+            }
+        }
+
+        fn event(&mut self, event: ClientEvent) {
+            match event {
+                ClientEvent::Connected {
+                    address,
+                    reconnected,
+                } => {
+                    if reconnected {
+                        self.notice(format!("*** Reconnected to {}", address));
+                    } else if self.fell_back {
+                        self.notice(format!("*** Connected to {}", address));
+                    }
+                    self.status = format!("Connected to {}", address);
+                    self.ended = None;
+                }
+                ClientEvent::RosterUpdate(names) => self.roster = names,
+                ClientEvent::MessageReceived { line, time } => {
+                    if let Some(direct) = Direct::parse(&line) {
+                        self.direct(direct);
+                        return;
+                    }
+                    let shown = match self.renderer.render(&line, time) {
+                        Some(shown) => shown,
+                        None => return,
+                    };
+                    if let Some(marker) = time.and_then(|time| self.renderer.marker(time)) {
+                        self.room.push(marker);
+                    }
+                    // A mention of us in particular rings the bell, the same as on a plain terminal
+                    if shown.tone == Tone::Mention(MentionKind::Direct) {
+                        bell();
+                    }
+                    self.room.push(shown);
+                }
+                ClientEvent::Status(text) => {
+                    for line in text.lines() {
+                        self.notice(format!("*** {}", line));
+                    }
+                }
+                ClientEvent::Error(text) => {
+                    self.fell_back = true;
+                    self.room.push(Shown {
+                        tone: Tone::Notice(NoticeKind::Error),
+                        time: None,
+                        text: format!("*** {}", text),
+                    });
+                }
+                ClientEvent::Disconnected(reason) => {
+                    self.status = String::from("Disconnected");
+                    self.roster.clear();
+                    self.room.push(Shown {
+                        tone: Tone::Notice(NoticeKind::Error),
+                        time: None,
+                        text: reason.clone(),
+                    });
+                    self.ended = Some(reason);
+                }
+            }
+        }
+
+        fn notice(&mut self, text: String) {
+            self.room.push(Shown {
+                tone: Tone::Notice(NoticeKind::Info),
+                time: None,
+                text,
+            });
+        }
+
+        // Goes in the conversation with whoever it's to or from, which opens if nothing else is.  One we sent opens
+        // anyway and takes the focus, since we're the one who started talking there.
+        fn direct(&mut self, direct: Direct) {
+            let index = match self
+                .directs
+                .iter()
+                .position(|conversation| conversation.peer.eq_ignore_ascii_case(&direct.peer))
+            {
+                Some(index) => index,
+                None => {
+                    self.directs.push(Conversation {
+                        peer: direct.peer.clone(),
+                        buffer: Buffer::new(self.scrollback),
+                        unread: 0,
+                    });
+                    self.directs.len() - 1
+                }
+            };
+
+            // The time is whenever it got here, since it never went through the room to be given one
+            let mut shown = match self
+                .renderer
+                .render(&direct.to_line(), Some(SystemTime::now()))
+            {
+                Some(shown) => shown,
+                None => return,
+            };
+            let sender = if direct.sent { "you" } else { &direct.peer };
+            shown.text = format!("{}: {}", sender, direct.body);
+            self.directs[index].buffer.push(shown);
+
+            if direct.sent {
+                self.open(index);
+                return;
+            }
+            bell();
+            match self.open {
+                None => self.open = Some(index),
+                Some(open) if open == index => {}
+                Some(_) => self.directs[index].unread += 1,
+            }
+        }
+
+        fn focused(&mut self) -> &mut Buffer {
+            match self.open {
+                Some(open) if self.focus_direct => &mut self.directs[open].buffer,
+                _ => &mut self.room,
+            }
+        }
+
+        fn open(&mut self, index: usize) {
+            self.directs[index].unread = 0;
+            self.open = Some(index);
+            self.focus_direct = true;
+        }
+
+        fn key(&mut self, key: KeyEvent, commands: &CommandSender, prefix: char) {
+            if key.modifiers.contains(KeyModifiers::CONTROL) {
+                match key.code {
+                    KeyCode::Char('c') | KeyCode::Char('d') => commands.quit(),
+                    // The one after the open one, or the first if none is
+                    KeyCode::Char('n') if !self.directs.is_empty() => {
+                        let next = self.open.map_or(0, |open| (open + 1) % self.directs.len());
+                        self.open(next);
+                    }
+                    _ => {}
+                }
+                return;
+            }
+
+            match key.code {
+                KeyCode::Enter => {
+                    let line: String = mem::take(&mut self.input).into_iter().collect();
+                    self.cursor = 0;
+                    self.focused().scrolled = 0;
+                    if line.trim().is_empty() {
+                        return;
+                    }
+                    // How things look is up to us, everything else goes to the server, the same as run
+                    match self.renderer.command(line.trim(), prefix) {
+                        Some(reply) => self.notice(reply),
+                        None => {
+                            let _ = commands.send(self.outgoing(line, prefix));
+                        }
+                    }
+                }
+                KeyCode::Tab if self.open.is_some() => {
+                    self.focus_direct = !self.focus_direct;
+                    if let Some(open) = self.open {
+                        self.directs[open].unread = 0;
+                    }
+                }
+                KeyCode::Esc => {
+                    self.open = None;
+                    self.focus_direct = false;
+                }
+                KeyCode::Char(c) => {
+                    self.input.insert(self.cursor, c);
+                    self.cursor += 1;
+                }
+                KeyCode::Backspace if self.cursor > 0 => {
+                    self.cursor -= 1;
+                    self.input.remove(self.cursor);
+                }
+                KeyCode::Delete if self.cursor < self.input.len() => {
+                    self.input.remove(self.cursor);
+                }
+                KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+                KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+                KeyCode::Home => self.cursor = 0,
+                KeyCode::End => self.cursor = self.input.len(),
+                KeyCode::PageUp => {
+                    let buffer = self.focused();
+                    buffer.scrolled += buffer.page;
+                }
+                KeyCode::PageDown => {
+                    let buffer = self.focused();
+                    buffer.scrolled = buffer.scrolled.saturating_sub(buffer.page);
+                }
+                _ => {}
+            }
+        }
+
+        // What's typed in the direct pane is a /msg to whoever it's with, unless it's a command
+        fn outgoing(&self, line: String, prefix: char) -> String {
+            let peer = match self.open {
+                Some(open) if self.focus_direct => &self.directs[open].peer,
+                _ => return line,
+            };
+            match line.strip_prefix(prefix) {
+                Some(rest) if !rest.starts_with(prefix) => line,
+                Some(rest) => format!("{}msg {} {}", prefix, peer, rest),
+                None => format!("{}msg {} {}", prefix, peer, line),
+            }
+        }
+
+        fn draw(&mut self, frame: &mut Frame) {
+            let [body, status, input] = Layout::vertical([
+                Constraint::Min(1),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+            let [chat, sidebar] =
+                Layout::horizontal([Constraint::Min(1), Constraint::Length(SIDEBAR_WIDTH)])
+                    .areas(body);
+
+            match self.open {
+                Some(open) => {
+                    let [room, direct] = Layout::horizontal([
+                        Constraint::Min(1),
+                        Constraint::Percentage(DIRECT_PERCENT),
+                    ])
+                    .areas(chat);
+                    self.room.draw(frame, room);
+
+                    // The focus is wherever the title stands out
+                    let mut title = Style::default().add_modifier(Modifier::BOLD);
+                    if self.focus_direct {
+                        title = title.add_modifier(Modifier::REVERSED);
+                    }
+                    let conversation = &mut self.directs[open];
+                    let block = Block::default()
+                        .borders(Borders::LEFT)
+                        .title(Span::styled(format!(" {} ", conversation.peer), title));
+                    let inner = block.inner(direct);
+                    frame.render_widget(block, direct);
+                    conversation.buffer.draw(frame, inner);
+                }
+                None => self.room.draw(frame, chat),
+            }
+
+            let roster = List::new(self.roster.iter().map(String::as_str)).block(
+                Block::default()
+                    .borders(Borders::LEFT)
+                    .title(format!(" {} here ", self.roster.len())),
+            );
+            frame.render_widget(roster, sidebar);
+
+            let mut bar = format!(" {}", self.status);
+            let unread: Vec<String> = self
+                .directs
+                .iter()
+                .filter(|conversation| conversation.unread > 0)
+                .map(|conversation| format!("{} ({})", conversation.peer, conversation.unread))
+                .collect();
+            if !unread.is_empty() {
+                bar.push_str(&format!(" | unread from {}, Ctrl-N", unread.join(", ")));
+            }
+            if self.focused().scrolled > 0 {
+                bar.push_str(" | scrolled back, Page Down to return");
+            }
+            frame.render_widget(
+                Paragraph::new(bar).style(Style::default().bg(Color::Blue).fg(Color::White)),
+                status,
+            );
+
+            // A line longer than the screen scrolls sideways to keep the cursor in sight.  Anything going to a
+            // direct message says who to.
+            let prompt = match self.open {
+                Some(open) if self.focus_direct => format!("{}> ", self.directs[open].peer),
+                _ => String::from("> "),
+            };
+            let prompt_width = prompt.chars().count();
+            let width = usize::from(input.width).saturating_sub(prompt_width).max(1);
+            let start = (self.cursor + 1).saturating_sub(width);
+            let shown: String = self.input[start..].iter().take(width).collect();
+            frame.render_widget(Paragraph::new(format!("{}{}", prompt, shown)), input);
+            frame.set_cursor_position(Position::new(
+                input.x + (prompt_width + self.cursor - start) as u16,
+                input.y,
+            ));
+        }
+    }
+
+    // Direct messages with one person
+    struct Conversation {
+        peer: String,
+        buffer: Buffer,
+        // How many came in while another conversation was open
+        unread: usize,
+    }
+
+    // Lines for one pane, oldest first, and how many rows back from the newest they've scrolled.  At most capacity lines
+    // are kept in memory.
+    struct Buffer {
+        lines: VecDeque<Shown>,
+        capacity: usize,
+        // Anything older than what's in lines, which isn't made until there is some.  If it can't be made, or stops
+        // working, the older lines are dropped instead, the way they always used to be.
+        spill: Option<Spill>,
+        spill_failed: bool,
+        scrolled: usize,
+        // How big the pane was the last time it was drawn.  Page Up goes back by its height.
+        page: usize,
+        width: usize,
+    }
+
+    impl Buffer {
+        fn new(capacity: usize) -> Buffer {
+            Buffer {
+                lines: VecDeque::new(),
+                capacity,
+                spill: None,
+                spill_failed: false,
+                scrolled: 0,
+                page: 1,
+                width: 1,
+            }
+        }
+
+        // Anyone scrolled back stays looking at the same lines, so the new one moves them that much further back
+        fn push(&mut self, shown: Shown) {
+            if self.scrolled > 0 {
+                self.scrolled += wrap(spans(&shown), self.width).len();
+            }
+            if self.lines.len() >= self.capacity {
+                if let Some(oldest) = self.lines.pop_front() {
+                    self.spill(&oldest);
+                }
+            }
+            self.lines.push_back(shown);
+        }
+
+        fn spill(&mut self, shown: &Shown) {
+            if self.spill.is_none() && !self.spill_failed {
+                self.spill = Spill::create().ok();
+                self.spill_failed = self.spill.is_none();
+            }
+            if let Some(spill) = &mut self.spill {
+                if spill.write(shown).is_err() {
+                    self.spill = None;
+                    self.spill_failed = true;
+                }
+            }
+        }
+
+        // The newest lines that fit, or older ones if they've scrolled back, wrapped to the width of the pane
+        fn draw(&mut self, frame: &mut Frame, area: Rect) {
+            let height = usize::from(area.height);
+            let width = usize::from(area.width).max(1);
+            self.page = height.max(1);
+            self.width = width;
+
+            let mut rows: Vec<Line<'static>> = Vec::new();
+            for shown in self.lines.iter().rev() {
+                let mut wrapped = wrap(spans(shown), width);
+                wrapped.reverse();
+                rows.extend(wrapped);
+                if rows.len() >= self.scrolled + height {
+                    break;
+                }
+            }
+
+            // Scrolled back past everything in memory, so the rest comes from the spill file, newest first
+            if let Some(spill) = &mut self.spill {
+                let mut before = spill.offsets.len();
+                while rows.len() < self.scrolled + height && before > 0 {
+                    let start = before.saturating_sub(PAGE_IN);
+                    let older = match spill.read(start..before) {
+                        Ok(older) => older,
+                        Err(_) => break,
+                    };
+                    for shown in older.iter().rev() {
+                        let mut wrapped = wrap(spans(shown), width);
+                        wrapped.reverse();
+                        rows.extend(wrapped);
+                    }
+                    before = start;
+                }
+            }
+
+            // There's only so far back to go
+            self.scrolled = self.scrolled.min(rows.len().saturating_sub(height));
+            let visible: Vec<Line<'static>> = rows
+                .into_iter()
+                .skip(self.scrolled)
+                .take(height)
+                .rev()
+                .collect();
+            frame.render_widget(Paragraph::new(visible), area);
+        }
+    }
+
+    // The lines a pane has pushed out of memory, one to a row in a file of their own that's removed when the pane is
+    // dropped.  Each row is the tone, the time in milliseconds (or "-" without one), and the text as a JSON string, so
+    // a newline in it can't split the row.
+    struct Spill {
+        path: PathBuf,
+        file: BufWriter<File>,
+        // Where each line starts, oldest first, and where the next one will
+        offsets: Vec<u64>,
+        end: u64,
+    }
+
+    impl Spill {
+        fn create() -> io::Result<Spill> {
+            let path = env::temp_dir().join(format!(
+                "chat_client.{}.{}.scrollback",
+                process::id(),
+                SPILLS.fetch_add(1, Ordering::Relaxed)
+            ));
+            // Appending, so the writes still go on the end after a read has moved the file along
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create_new(true)
+                .open(&path)?;
+            Ok(Spill {
+                path,
+                file: BufWriter::new(file),
+                offsets: Vec::new(),
+                end: 0,
+            })
+        }
+
+        fn write(&mut self, shown: &Shown) -> io::Result<()> {
+            let time = match shown.time {
+                Some(time) => time.timestamp_millis().to_string(),
+                None => String::from("-"),
+            };
+            let row = format!(
+                "{} {} {}\n",
+                tone_name(shown.tone),
+                time,
+                serde_json::to_string(&shown.text)?
+            );
+            self.file.write_all(row.as_bytes())?;
+            self.offsets.push(self.end);
+            self.end += row.len() as u64;
+            Ok(())
+        }
+
+        // The lines in range, oldest first.  A row that doesn't read back is left out.
+        fn read(&mut self, range: Range<usize>) -> io::Result<Vec<Shown>> {
+            self.file.flush()?;
+            let start = self.offsets[range.start];
+            let end = self.offsets.get(range.end).copied().unwrap_or(self.end);
+            let mut file = self.file.get_ref();
+            file.seek(SeekFrom::Start(start))?;
+            let mut rows = String::new();
+            file.take(end - start).read_to_string(&mut rows)?;
+            Ok(rows.lines().filter_map(parse_row).collect())
+        }
+    }
+
+    impl Drop for Spill {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn parse_row(row: &str) -> Option<Shown> {
+        let mut parts = row.splitn(3, ' ');
+        let tone = parse_tone(parts.next()?)?;
+        let time = match parts.next()? {
+            "-" => None,
+            millis => Local.timestamp_millis_opt(millis.parse().ok()?).single(),
+        };
+        let text = serde_json::from_str(parts.next()?).ok()?;
+        Some(Shown { tone, time, text })
+    }
+
+    fn tone_name(tone: Tone) -> String {
+        match tone {
+            Tone::Chat => String::from("chat"),
+            Tone::Mention(kind) => format!("mention:{}", kind.as_str()),
+            Tone::Notice(kind) => format!("notice:{}", kind.as_str()),
+            Tone::Direct => String::from("direct"),
+            Tone::Marker => String::from("marker"),
+        }
+    }
+
+    fn parse_tone(name: &str) -> Option<Tone> {
+        match name.split_once(':') {
+            Some(("mention", kind)) => MentionKind::parse(kind).map(Tone::Mention),
+            Some(("notice", kind)) => NoticeKind::parse(kind).map(Tone::Notice),
+            Some(_) => None,
+            None => match name {
+                "chat" => Some(Tone::Chat),
+                "direct" => Some(Tone::Direct),
+                "marker" => Some(Tone::Marker),
+                _ => None,
+            },
+        }
+    }
+
+    // For a mention of us in particular, or a direct message to us
+    fn bell() {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(b"\x07").and_then(|_| stdout.flush());
+    }
+
+    fn style(tone: Tone) -> Style {
+        let style = Style::default();
+        match tone {
+            Tone::Chat => style,
+            Tone::Mention(MentionKind::Direct) => style
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD | Modifier::REVERSED),
+            Tone::Mention(_) => style.add_modifier(Modifier::BOLD),
+            Tone::Direct => style.fg(Color::Magenta),
+            Tone::Marker => style.fg(Color::DarkGray),
+            Tone::Notice(NoticeKind::Presence) => style.fg(Color::DarkGray),
+            Tone::Notice(NoticeKind::Moderation) => style.fg(Color::Yellow),
+            Tone::Notice(NoticeKind::Motd) => style.fg(Color::Cyan).add_modifier(Modifier::BOLD),
+            Tone::Notice(NoticeKind::Error) => style.fg(Color::Red),
+            // Info, and any kinds newer than us
+            Tone::Notice(_) => style.fg(Color::Cyan),
+        }
+    }
+
+    // The pieces of a line and how each looks.  Whoever's talking is in bold, which is anything before ": " without a
+    // space in it, the same as the web page does it.
+    fn spans(shown: &Shown) -> Vec<(String, Style)> {
+        let style = style(shown.tone);
+        let mut spans = Vec::new();
+        if let Some(time) = shown.time {
+            spans.push((
+                format!("[{}] ", time.format("%H:%M")),
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        let sender = match shown.tone {
+            Tone::Chat | Tone::Mention(_) | Tone::Direct => shown
+                .text
+                .split_once(": ")
+                .filter(|(sender, _)| !sender.is_empty() && !sender.contains(' ')),
+            _ => None,
+        };
+        match sender {
+            Some((sender, body)) => {
+                spans.push((format!("{}: ", sender), style.add_modifier(Modifier::BOLD)));
+                spans.push((String::from(body), style));
+            }
+            None => spans.push((shown.text.clone(), style)),
+        }
+        spans
+    }
+
+    // Cuts the spans into rows of at most width characters, keeping each piece's look
+    fn wrap(spans: Vec<(String, Style)>, width: usize) -> Vec<Line<'static>> {
+        let mut rows = Vec::new();
+        let mut row: Vec<Span<'static>> = Vec::new();
+        let mut used = 0;
+        for (text, style) in spans {
+            let mut piece = String::new();
+            for c in text.chars() {
+                if used == width {
+                    row.push(Span::styled(mem::take(&mut piece), style));
+                    rows.push(Line::from(mem::take(&mut row)));
+                    used = 0;
+                }
+                piece.push(c);
+                used += 1;
+            }
+            if !piece.is_empty() {
+                row.push(Span::styled(piece, style));
+            }
+        }
+        rows.push(Line::from(row));
+        rows
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+mod disabled {
+    use super::*;
+    use std::io;
+
+    pub fn run(
+        _connect: impl FnOnce() -> Connection,
+        _prefix: char,
+        _scrollback: usize,
+        _cancel: CancellationToken,
+    ) -> Result<(), Error> {
+        Err(Error::Io(io::Error::other(
+            "TUI support was not compiled in, rebuild with --features tui",
+        )))
+    }
+}
@@ -0,0 +1,54 @@
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::net::UnixStream;
+use std::os::unix::prelude::AsRawFd;
+use std::os::unix::prelude::RawFd;
+
+// Lets other threads interrupt a client handler's poll.  The room's broadcasts and control messages like kicks come in
+// through channels, which poll can't see, so whoever sends one also pokes the connection's Waker.  The WakeReceiver end
+// is registered alongside the client's socket, so the handler wakes up as if the client had said something.
+//
+// Underneath it's a pair of connected Unix sockets with a byte written for every wake.  Several wakes before the
+// handler gets round to them come out as one, which is all the handler needs to know.
+pub struct Waker {
+    sender: UnixStream,
+}
+
+pub struct WakeReceiver {
+    receiver: UnixStream,
+}
+
+pub fn pair() -> io::Result<(Waker, WakeReceiver)> {
+    let (sender, receiver) = UnixStream::pair()?;
+    sender.set_nonblocking(true)?;
+    receiver.set_nonblocking(true)?;
+
+    Ok((Waker { sender }, WakeReceiver { receiver }))
+}
+
+impl Waker {
+    // If the socket is full there are already wakes waiting to be read, so one more wouldn't change anything.  Any
+    // other error means the handler is gone, and there's nobody left to wake.
+    pub fn wake(&self) {
+        (&self.sender).write_all(&[1]).ok();
+    }
+}
+
+impl WakeReceiver {
+    // Reads every wake that has piled up, so the next poll waits for a new one
+    pub fn clear(&self) {
+        let mut buffer = [0; 64];
+        while let Ok(bytes_read) = (&self.receiver).read(&mut buffer) {
+            if bytes_read == 0 {
+                break;
+            }
+        }
+    }
+}
+
+// So the receiving end can be registered with popol
+impl AsRawFd for WakeReceiver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.receiver.as_raw_fd()
+    }
+}
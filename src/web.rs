@@ -0,0 +1,204 @@
+use chrono::DateTime;
+use chrono::Utc;
+use std::fmt::Write as _;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tracing::warn;
+
+use crate::config::WebConfig;
+use crate::storage::HistoryEntry;
+
+// The web chat page, for people who'd rather not install anything.  It's one file, web/index.html, built into the
+// binary, which joins the room through the [websocket] listener like any other client would over TCP.  So there's no
+// room state here at all, the page is just another connection, and it sees everything the TCP clients do.
+//
+// The page only needs to know where the WebSocket listener is, and whether to use wss, which is filled in as it's
+// served.  It takes the host from the address bar, so it works however the server was reached.  Like the metrics
+// it's answered one request at a time on a thread of its own, which is plenty for handing out the same page.
+//
+// Rooms the config makes public can also be read at /rooms/<room>, by anyone, without a name or a connection.  That's
+// a plain HTML page of what was said lately, straight out of the history, with no script at all, which reloads itself
+// every so often.  Rooms that aren't public get the same 404 as rooms that don't exist, so nobody can find out which
+// is which.
+
+const PAGE: &str = include_str!("web/index.html");
+
+// What the public view needs from the server, which the server's context provides
+pub trait PublicRooms: Send + Sync {
+    // The room's chat after since, oldest first and at most limit of it.  None if there's no such room, or its history
+    // can't be read right now.
+    fn recent_chat(&self, room: &str, since: SystemTime, limit: usize)
+        -> Option<Vec<HistoryEntry>>;
+}
+
+// The page with the WebSocket port and scheme filled in
+pub fn page(websocket_port: u16, tls: bool) -> String {
+    PAGE.replace("{{websocket_port}}", &websocket_port.to_string())
+        .replace("{{websocket_scheme}}", if tls { "wss" } else { "ws" })
+}
+
+pub fn serve(listener: TcpListener, page: String, config: WebConfig, rooms: Arc<dyn PublicRooms>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &page, &config, &*rooms));
+            if let Err(err) = result {
+                warn!("Web request failed: {}", err);
+            }
+        }
+    });
+}
+
+fn respond(
+    mut stream: TcpStream,
+    page: &str,
+    config: &WebConfig,
+    rooms: &dyn PublicRooms,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // Same as the metrics, the request line is all we need
+    let mut request = [0; 1024];
+    let read = stream.read(&mut request)?;
+    let request = String::from_utf8_lossy(&request[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let path = path.split('?').next().unwrap_or(path);
+
+    let public = path
+        .strip_prefix("/rooms/")
+        .filter(|room| config.public_rooms.iter().any(|public| public == room));
+    let view;
+    let (status, content_type, body) = match (path, public) {
+        ("/" | "/index.html", _) => ("200 OK", "text/html; charset=utf-8", page),
+        (_, Some(room)) => {
+            // However many hours it's set to, it can't go back further than everything
+            let since = config
+                .public_hours
+                .checked_mul(60 * 60)
+                .and_then(|secs| SystemTime::now().checked_sub(Duration::from_secs(secs)))
+                .unwrap_or(UNIX_EPOCH);
+            match rooms.recent_chat(room, since, config.public_messages) {
+                Some(entries) => {
+                    view = render(room, &entries, config);
+                    ("200 OK", "text/html; charset=utf-8", &view[..])
+                }
+                None => (
+                    "503 Service Unavailable",
+                    "text/plain",
+                    "The history can't be read right now\n",
+                ),
+            }
+        }
+        _ => (
+            "404 Not Found",
+            "text/plain",
+            "Not found, the chat is at /\n",
+        ),
+    };
+
+    // The public view has no script and loads nothing, so it can say so
+    let policy = match public {
+        Some(_) => concat!(
+            "Content-Security-Policy: default-src 'none'; style-src 'unsafe-inline'\r\n",
+            "Cache-Control: no-store\r\n"
+        ),
+        None => "",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nX-Content-Type-Options: nosniff\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        policy,
+        body
+    )?;
+    stream.flush()
+}
+
+// The public view of a room.  Everything from the history is escaped, since it's whatever anyone typed.
+fn render(room: &str, entries: &[HistoryEntry], config: &WebConfig) -> String {
+    let room = escape(room);
+    let mut html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta http-equiv="refresh" content="{refresh}">
+<title>{room}</title>
+<style>
+  body {{ margin: 0; font: 15px/1.4 system-ui, sans-serif; }}
+  header {{ padding: 8px 12px; background: #234; color: #fff; display: flex; justify-content: space-between; }}
+  ul {{ padding: 8px 12px; margin: 0; list-style: none; }}
+  li {{ white-space: pre-wrap; word-wrap: break-word; }}
+  .time {{ color: #888; margin-right: 6px; font-size: 12px; }}
+  .sender {{ font-weight: bold; }}
+  .pin {{ background: #fff3c4; }}
+  p {{ padding: 8px 12px; color: #666; font-style: italic; }}
+</style>
+</head>
+<body>
+<header><span>{room}</span><span>Read only, refreshes every {refresh}s</span></header>
+"#,
+        refresh = config.public_refresh_secs,
+        room = room,
+    );
+
+    if entries.is_empty() {
+        let _ = writeln!(
+            html,
+            "<p>Nothing's been said in the last {} hour(s).</p>",
+            config.public_hours
+        );
+    } else {
+        html.push_str("<ul>\n");
+        for entry in entries {
+            let time: DateTime<Utc> =
+                (UNIX_EPOCH + Duration::from_millis(entry.time as u64)).into();
+            let sender = match (&entry.sender, config.public_names) {
+                (Some(sender), true) => escape(sender),
+                _ => String::from("someone"),
+            };
+            let (class, said) = match entry.kind.as_str() {
+                "pin" => (" class=\"pin\"", " pinned:"),
+                _ => ("", ":"),
+            };
+            let _ = writeln!(
+                html,
+                "<li{}><span class=\"time\" title=\"{}\">{}</span><span class=\"sender\">{}{}</span> {}</li>",
+                class,
+                time.format("%Y-%m-%d %H:%M:%S UTC"),
+                time.format("%b %-d %H:%M"),
+                sender,
+                said,
+                escape(&entry.body)
+            );
+        }
+        html.push_str("</ul>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,172 @@
+use std::io;
+
+use crate::transport::Stream;
+
+// WebSocket support, for clients in a browser, which can't open a plain TCP connection.  Once the upgrade is done the
+// connection is just another Stream: every text message that comes in is read as one line, and every line we write
+// goes out as one text message.  So a browser sends "hello" or "/who" as a message on its own, without the newline,
+// and everything past the accept (sessions, the room, batching) never knows the difference.  Ping, pong and close
+// frames are tungstenite's business.
+//
+// Like TLS this is behind a cargo feature ("websocket"), and without it WebSocketAcceptor is an empty enum that can
+// never be made.
+
+#[cfg(feature = "websocket")]
+pub use enabled::*;
+
+#[cfg(not(feature = "websocket"))]
+pub use disabled::*;
+
+#[cfg(feature = "websocket")]
+mod enabled {
+    use super::*;
+    use std::cmp;
+    use std::io::prelude::*;
+    use std::time::Duration;
+    use tungstenite::handshake::HandshakeError;
+    use tungstenite::protocol::WebSocketConfig;
+    use tungstenite::Message;
+
+    // A browser gets this long to ask for the upgrade once it's connected, so one that never does can't keep a worker
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // The most a browser can send in one message.  Well past any line we'd take, so a message that's too long still
+    // gets the usual error (see LineReader) rather than being hung up on, but nobody can have us hold megabytes.
+    const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+    fn to_io(err: tungstenite::Error) -> io::Error {
+        match err {
+            tungstenite::Error::Io(err) => err,
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                io::ErrorKind::BrokenPipe.into()
+            }
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+
+    pub struct WebSocketAcceptor {
+        config: WebSocketConfig,
+    }
+
+    impl WebSocketAcceptor {
+        pub fn new() -> io::Result<WebSocketAcceptor> {
+            let config = WebSocketConfig::default()
+                .max_message_size(Some(MAX_MESSAGE_BYTES))
+                .max_frame_size(Some(MAX_MESSAGE_BYTES));
+            Ok(WebSocketAcceptor { config })
+        }
+
+        // Reads the browser's upgrade request and answers it, over TLS if the stream already is.  Blocks until that's
+        // done, so it happens off the accept loop like the TLS handshake.
+        pub fn accept(&self, stream: Stream) -> io::Result<Stream> {
+            stream.tcp().set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            let socket = match tungstenite::accept_with_config(stream, Some(self.config)) {
+                Ok(socket) => socket,
+                // With a blocking socket the only way to be stopped halfway is the read timeout
+                Err(HandshakeError::Interrupted(_)) => return Err(io::ErrorKind::TimedOut.into()),
+                Err(HandshakeError::Failure(err)) => return Err(to_io(err)),
+            };
+            socket.get_ref().tcp().set_read_timeout(None)?;
+
+            Ok(Stream::WebSocket(Box::new(WebSocket {
+                socket,
+                incoming: Vec::new(),
+                outgoing: Vec::new(),
+            })))
+        }
+    }
+
+    pub struct WebSocket {
+        socket: tungstenite::WebSocket<Stream>,
+        // The latest message, as a line, for however many reads it takes to hand out
+        incoming: Vec<u8>,
+        // Whatever we've been given past the last whole line, which can't be sent until the rest of it turns up
+        outgoing: Vec<u8>,
+    }
+
+    impl WebSocket {
+        // The stream the WebSocket is on, which is plain TCP or TLS
+        pub fn inner(&self) -> &Stream {
+            self.socket.get_ref()
+        }
+
+        // The close frame goes first, then whatever's underneath says its own goodbye
+        pub fn shutdown(&mut self) -> io::Result<()> {
+            self.socket.close(None).map_err(to_io)?;
+            match self.socket.flush() {
+                Ok(()) | Err(tungstenite::Error::ConnectionClosed) => {}
+                Err(err) => return Err(to_io(err)),
+            }
+            self.socket.get_mut().shutdown()
+        }
+    }
+
+    impl Read for WebSocket {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.incoming.is_empty() {
+                match self.socket.read() {
+                    Ok(Message::Text(text)) => self.incoming.extend_from_slice(text.as_bytes()),
+                    Ok(Message::Binary(data)) => self.incoming.extend_from_slice(&data),
+                    // Either they're saying goodbye or it's over, and both are the end as far as we're concerned
+                    Ok(Message::Close(_))
+                    | Err(tungstenite::Error::ConnectionClosed)
+                    | Err(tungstenite::Error::AlreadyClosed) => return Ok(0),
+                    Ok(_) => continue,
+                    Err(err) => return Err(to_io(err)),
+                }
+                self.incoming.push(b'\n');
+            }
+
+            let read = cmp::min(buf.len(), self.incoming.len());
+            buf[..read].copy_from_slice(&self.incoming[..read]);
+            self.incoming.drain(..read);
+            Ok(read)
+        }
+    }
+
+    impl Write for WebSocket {
+        // Nothing more is taken on while the last of what we wrote is still waiting on the socket, so the caller's
+        // outbound queue is what backs up when a browser falls behind, the same as with TCP
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.socket.flush().map_err(to_io)?;
+
+            self.outgoing.extend_from_slice(buf);
+            while let Some(end) = self.outgoing.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = self.outgoing.drain(..=end).collect();
+                let text = String::from_utf8_lossy(&line[..end]);
+                self.socket
+                    .write(Message::text(text.trim_end_matches('\r')))
+                    .map_err(to_io)?;
+            }
+
+            match self.socket.flush() {
+                Err(tungstenite::Error::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => result.map_err(to_io)?,
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.socket.flush().map_err(to_io)
+        }
+    }
+}
+
+#[cfg(not(feature = "websocket"))]
+mod disabled {
+    use super::*;
+
+    pub enum WebSocketAcceptor {}
+
+    impl WebSocketAcceptor {
+        pub fn new() -> io::Result<WebSocketAcceptor> {
+            Err(io::Error::other(
+                "WebSocket support was not compiled in, rebuild with --features websocket",
+            ))
+        }
+
+        pub fn accept(&self, _stream: Stream) -> io::Result<Stream> {
+            match *self {}
+        }
+    }
+}